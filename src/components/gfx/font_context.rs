@@ -8,6 +8,7 @@ use font::{SpecifiedFontStyle, UsedFontStyle};
 use font_list::FontList;
 use servo_util::cache::Cache;
 use servo_util::cache::LRUCache;
+use servo_util::mem::{FontCountCategory, MemoryProfilerChan};
 use servo_util::time::ProfilerChan;
 
 use platform::font::FontHandle;
@@ -75,6 +76,11 @@ pub impl<'self> FontContext {
         }
     }
 
+    /// Reports the number of font instances kept in this context to the memory profiler.
+    fn report_memory(&self, memory_profiler_chan: &MemoryProfilerChan) {
+        memory_profiler_chan.report(FontCountCategory, self.instance_cache.len());
+    }
+
     priv fn get_font_list(&'self self) -> &'self FontList {
         self.font_list.get_ref()
     }
@@ -15,9 +15,39 @@ pub struct Opts {
     tile_size: uint,
     profiler_period: Option<f64>,
 
+    /// The file to write every profiled span to when servo exits, as Chrome trace events.
+    trace_file: Option<~str>,
+
+    /// How often to print the memory used by each part of servo, in seconds, if at all.
+    memory_profiler_period: Option<f64>,
+
     /// A scale factor to apply to tiles, to allow rendering tiles at higher resolutions for
     /// testing pan and zoom code.
     zoom: uint,
+
+    /// The file to write the rendered page to, as a PNG. If present, Servo renders headlessly
+    /// instead of opening a window, and exits once the page has finished loading.
+    output_file: Option<~str>,
+
+    /// The directory to keep the HTTP cache in, so that responses survive between runs. If
+    /// absent, responses are only cached in memory.
+    cache_dir: Option<~str>,
+
+    /// The file to load cookies from and save them to, in the Netscape `cookies.txt` format.
+    cookie_file: Option<~str>,
+
+    /// The directory to record every fetched resource into, for later replay.
+    record_dir: Option<~str>,
+
+    /// The directory of a recording to serve every resource from, instead of fetching it.
+    replay_dir: Option<~str>,
+
+    /// The most bytes decoded images may take up in the image cache before the least recently
+    /// used ones are evicted.
+    image_cache_size: uint,
+
+    /// The number of images decoded at once.
+    n_decode_threads: uint,
 }
 
 #[allow(non_implicitly_copyable_typarams)]
@@ -28,10 +58,18 @@ pub fn from_cmdline_args(args: &[~str]) -> Opts {
 
     let opts = ~[
         getopts::optopt(~"o"),  // output file
+        getopts::optopt(~"c"),  // cache directory
+        getopts::optopt(~"k"),  // cookie file
+        getopts::optopt(~"record"),  // directory to record resources into
+        getopts::optopt(~"replay"),  // directory to replay resources from
+        getopts::optopt(~"image-cache-size"),  // megabytes of decoded images to keep
+        getopts::optopt(~"decode-threads"),  // threads to decode images with
         getopts::optopt(~"r"),  // rendering backend
         getopts::optopt(~"s"),  // size of tiles
         getopts::optopt(~"t"),  // threads to render with
         getopts::optflagopt(~"p"),  // profiler flag and output interval
+        getopts::optopt(~"trace"),  // file to write profiler trace events to
+        getopts::optflagopt(~"m"),  // memory profiler flag and output interval
         getopts::optopt(~"z"),  // zoom level
     ];
 
@@ -45,8 +83,26 @@ pub fn from_cmdline_args(args: &[~str]) -> Opts {
         copy opt_match.free
     };
 
-    if getopts::opt_present(&opt_match, ~"o") {
-        fail!(~"servo cannot treat 'o' option now.")
+    let output_file = getopts::opt_maybe_str(&opt_match, ~"o");
+    let cache_dir = getopts::opt_maybe_str(&opt_match, ~"c");
+    let cookie_file = getopts::opt_maybe_str(&opt_match, ~"k");
+    let record_dir = getopts::opt_maybe_str(&opt_match, ~"record");
+    let replay_dir = getopts::opt_maybe_str(&opt_match, ~"replay");
+    if record_dir.is_some() && replay_dir.is_some() {
+        fail!(~"servo can't record and replay at the same time")
+    }
+
+    let image_cache_size: uint = match getopts::opt_maybe_str(&opt_match, ~"image-cache-size") {
+        Some(megabytes) => uint::from_str(megabytes).get() * 1024 * 1024,
+        None => 64 * 1024 * 1024,
+    };
+
+    let n_decode_threads: uint = match getopts::opt_maybe_str(&opt_match, ~"decode-threads") {
+        Some(n_decode_threads_str) => uint::from_str(n_decode_threads_str).get(),
+        None => 4,
+    };
+    if n_decode_threads == 0 {
+        fail!(~"servo needs at least one thread to decode images with")
     }
 
     let render_backend = match getopts::opt_maybe_str(&opt_match, ~"r") {
@@ -85,6 +141,15 @@ pub fn from_cmdline_args(args: &[~str]) -> Opts {
         None => None,
     };
 
+    let trace_file = getopts::opt_maybe_str(&opt_match, ~"trace");
+
+    let memory_profiler_period: Option<f64> =
+        // if only flag is present, default to 5 second period
+        match getopts::opt_default(&opt_match, ~"m", ~"5") {
+        Some(period) => Some(f64::from_str(period).get()),
+        None => None,
+    };
+
     let zoom: uint = match getopts::opt_maybe_str(&opt_match, ~"z") {
         Some(zoom_str) => uint::from_str(zoom_str).get(),
         None => 1,
@@ -96,6 +161,15 @@ pub fn from_cmdline_args(args: &[~str]) -> Opts {
         n_render_threads: n_render_threads,
         tile_size: tile_size,
         profiler_period: profiler_period,
+        trace_file: trace_file,
+        memory_profiler_period: memory_profiler_period,
         zoom: zoom,
+        output_file: output_file,
+        cache_dir: cache_dir,
+        cookie_file: cookie_file,
+        record_dir: record_dir,
+        replay_dir: replay_dir,
+        image_cache_size: image_cache_size,
+        n_decode_threads: n_decode_threads,
     }
 }
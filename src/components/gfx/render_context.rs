@@ -17,6 +17,7 @@ use geom::size::Size2D;
 use servo_net::image::base::Image;
 use std::arc;
 use std::arc::ARC;
+use std::time::precise_time_ns;
 
 pub struct RenderContext<'self> {
     canvas: &'self LayerBuffer,
@@ -58,7 +59,8 @@ pub impl<'self> RenderContext<'self>  {
 
         self.canvas.draw_target.make_current();
         let draw_target_ref = &self.canvas.draw_target;
-        let azure_surface = draw_target_ref.create_source_surface_from_data(image.data, size,
+        let data = image.data_at(precise_time_ns());
+        let azure_surface = draw_target_ref.create_source_surface_from_data(data, size,
                                                                             stride as i32, B8G8R8A8);
         let source_rect = Rect(Point2D(0 as AzFloat, 0 as AzFloat),
                                Size2D(image.width as AzFloat, image.height as AzFloat));
@@ -19,13 +19,18 @@ use render_context::RenderContext;
 
 use core::cell::Cell;
 use core::comm::{Chan, Port, SharedChan};
+use core::ptr;
 
+use servo_util::mem::{MemoryProfilerChan, TileBufferBytesCategory};
 use servo_util::time::{ProfilerChan, profile};
 use servo_util::time;
 
 pub struct RenderLayer {
     display_list: DisplayList<()>,
-    size: Size2D<uint>
+    size: Size2D<uint>,
+    /// The URL of the page the layer shows, which the time spent rendering and compositing it is
+    /// attributed to
+    page: ~str
 }
 
 pub enum Msg<C> {
@@ -61,15 +66,25 @@ impl<C: RenderListener + Owned> RenderChan<C> {
 pub fn create_render_task<C: RenderListener + Owned>(port: Port<Msg<C>>,
                                                      compositor: C,
                                                      opts: Opts,
-                                                     profiler_chan: ProfilerChan) {
+                                                     profiler_chan: ProfilerChan,
+                                                     memory_profiler_chan: MemoryProfilerChan) {
     let compositor_cell = Cell(compositor);
     let opts_cell = Cell(opts);
     let port = Cell(port);
 
     do spawn {
+        let _reports = memory_profiler_chan.guard_reports();
         let compositor = compositor_cell.take();
-        let share_gl_context = compositor.get_gl_context();
         let opts = opts_cell.with_ref(|o| copy *o);
+
+        // When rendering headlessly there is no window, and hence no GL context to share; tiles
+        // are drawn into CPU-backed draw targets instead.
+        let share_gl_context = if opts.output_file.is_some() {
+            ptr::null()
+        } else {
+            compositor.get_gl_context()
+        };
+
         let profiler_chan = profiler_chan.clone();
         let profiler_chan_copy = profiler_chan.clone();
 
@@ -82,6 +97,11 @@ pub fn create_render_task<C: RenderListener + Owned>(port: Port<Msg<C>>,
                                             profiler_chan),
             opts: opts_cell.take(),
             profiler_chan: profiler_chan_copy,
+            memory_profiler_chan: if opts.memory_profiler_period.is_some() {
+                Some(memory_profiler_chan.clone())
+            } else {
+                None
+            },
             share_gl_context: share_gl_context,
             render_layer: None,
         };
@@ -99,6 +119,9 @@ priv struct Renderer<C> {
     /// A channel to the profiler.
     profiler_chan: ProfilerChan,
 
+    /// A channel to the memory profiler, if memory is being profiled.
+    memory_profiler_chan: Option<MemoryProfilerChan>,
+
     share_gl_context: AzGLContext,
 
     /// The layer to be rendered
@@ -113,6 +136,7 @@ impl<C: RenderListener + Owned> Renderer<C> {
             match self.port.recv() {
                 AttachCompositorMsg(compositor) => self.compositor = compositor,
                 RenderMsg(render_layer) => {
+                    self.profiler_chan = self.profiler_chan.tagged(copy render_layer.page);
                     self.render_layer = Some(render_layer);
                     self.render(1.0);
                 }
@@ -161,10 +185,8 @@ impl<C: RenderListener + Owned> Renderer<C> {
                         let screen_rect = Rect(Point2D(x, y), Size2D(width, height));
 
                         let buffer = LayerBuffer {
-                            draw_target: DrawTarget::new_with_fbo(self.opts.render_backend,
-                                                                  self.share_gl_context,
-                                                                  Size2D(width as i32, height as i32),
-                                                                  B8G8R8A8),
+                            draw_target: self.create_draw_target(Size2D(width as i32,
+                                                                        height as i32)),
                             rect: tile_rect,
                             screen_pos: screen_rect,
                             stride: (width * 4) as uint
@@ -205,8 +227,18 @@ impl<C: RenderListener + Owned> Renderer<C> {
                 }
             }
 
+            for self.memory_profiler_chan.each |memory_profiler_chan| {
+                let mut tile_bytes = 0;
+                for new_buffers.each |buffer| {
+                    tile_bytes += buffer.stride * buffer.screen_pos.size.height;
+                }
+                memory_profiler_chan.report(TileBufferBytesCategory, tile_bytes);
+                self.font_ctx.report_memory(memory_profiler_chan);
+            }
+
             let layer_buffer_set = LayerBufferSet {
                 buffers: new_buffers,
+                page: copy render_layer.page,
             };
 
             debug!("renderer: returning surface");
@@ -214,5 +246,18 @@ impl<C: RenderListener + Owned> Renderer<C> {
             self.compositor.set_render_state(IdleRenderState);
         }
     }
+
+    /// Creates a draw target for a single tile. These are backed by the shared GL context when
+    /// rendering to a window, and by system memory when rendering headlessly.
+    fn create_draw_target(&self, size: Size2D<i32>) -> DrawTarget {
+        if self.opts.output_file.is_some() {
+            DrawTarget::new(self.opts.render_backend, size, B8G8R8A8)
+        } else {
+            DrawTarget::new_with_fbo(self.opts.render_backend,
+                                     self.share_gl_context,
+                                     size,
+                                     B8G8R8A8)
+        }
+    }
 }
 
@@ -523,6 +523,13 @@ pub impl<'self> GlyphStore {
         self.detail_store.ensure_sorted();
     }
 
+    // The bytes taken up by the glyph data, for memory profiling.
+    fn byte_size(&self) -> uint {
+        self.entry_buffer.len() * sys::size_of::<GlyphEntry>() +
+            self.detail_store.detail_buffer.len() * sys::size_of::<DetailedGlyph>() +
+            self.detail_store.detail_lookup.len() * sys::size_of::<DetailedGlyphRecord>()
+    }
+
     fn add_glyph_for_char_index(&mut self, i: uint, data: &GlyphData) {
         fn glyph_is_compressible(data: &GlyphData) -> bool {
             is_simple_glyph_id(data.index)
@@ -3,20 +3,27 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use platform::{Application, Window};
-use script::dom::event::{Event, ClickEvent, MouseDownEvent, MouseUpEvent, ResizeEvent};
+use script::dom::event::{Event, ClickEvent, KeyDownEvent, KeyPressEvent, KeyUpEvent};
+use script::dom::event::{MouseDownEvent, MouseMoveEvent, MouseUpEvent, ResizeEvent};
 use script::script_task::{LoadMsg, SendEventMsg};
-use script::layout_interface::{LayoutChan, RouteScriptMsg};
+use script::layout_interface::{LayoutChan, RouteScriptMsg, SetScrollPositionMsg, SetZoomMsg};
 use windowing::{ApplicationMethods, WindowMethods, WindowMouseEvent, WindowClickEvent};
-use windowing::{WindowMouseDownEvent, WindowMouseUpEvent};
+use windowing::{WindowKeyDownEvent, WindowKeyEvent, WindowKeyPressEvent, WindowKeyUpEvent};
+use windowing::{WindowMouseDownEvent, WindowMouseMoveEvent, WindowMouseUpEvent};
 use servo_msg::compositor::{RenderListener, LayerBufferSet, RenderState};
 use servo_msg::compositor::{ReadyState, ScriptListener};
+use servo_msg::compositor::{FinishedLoading, IdleRenderState, Loading, PerformingLayout};
+use servo_msg::compositor::{RenderingRenderState};
+use gfx::opts::Opts;
 use gfx::render_task::{RenderChan, ReRenderMsg};
 
 use azure::azure_hl::{DataSourceSurface, DrawTarget, SourceSurfaceMethods, current_gl_context};
 use azure::azure::AzGLContext;
 use core::cell::Cell;
 use core::comm::{Chan, SharedChan, Port};
+use core::io;
 use core::num::Orderable;
+use core::ptr;
 use core::util;
 use geom::matrix::identity;
 use geom::point::Point2D;
@@ -26,6 +33,8 @@ use layers::layers::{ImageData, WithDataFn};
 use layers::layers::{TextureLayerKind, TextureLayer, TextureManager};
 use layers::rendergl;
 use layers::scene::Scene;
+use servo_net::image::base::Image;
+use servo_net::image::png;
 use servo_util::{time, url};
 use servo_util::time::profile;
 use servo_util::time::ProfilerChan;
@@ -116,17 +125,20 @@ impl ImageData for AzureDrawTargetImageData {
 }
 
 pub struct CompositorTask {
+    opts: Opts,
     port: Port<Msg>,
     profiler_chan: ProfilerChan,
     shutdown_chan: SharedChan<()>,
 }
 
 impl CompositorTask {
-    pub fn new(port: Port<Msg>,
+    pub fn new(opts: Opts,
+               port: Port<Msg>,
                profiler_chan: ProfilerChan,
                shutdown_chan: Chan<()>)
                -> CompositorTask {
         CompositorTask {
+            opts: opts,
             port: port,
             profiler_chan: profiler_chan,
             shutdown_chan: SharedChan::new(shutdown_chan),
@@ -134,20 +146,98 @@ impl CompositorTask {
     }
 
     /// Starts the compositor, which listens for messages on the specified port. 
-    pub fn create(port: Port<Msg>,
-                                  profiler_chan: ProfilerChan,
-                                  shutdown_chan: Chan<()>) {
+    pub fn create(opts: Opts,
+                  port: Port<Msg>,
+                  profiler_chan: ProfilerChan,
+                  shutdown_chan: Chan<()>) {
+        let opts = Cell(opts);
         let port = Cell(port);
         let shutdown_chan = Cell(shutdown_chan);
         do on_osmain {
-            let compositor_task = CompositorTask::new(port.take(),
+            let compositor_task = CompositorTask::new(opts.take(),
+                                                      port.take(),
                                                       profiler_chan.clone(),
                                                       shutdown_chan.take());
-            debug!("preparing to enter main loop");
-            compositor_task.run_main_loop();
+            match compositor_task.opts.output_file {
+                Some(ref output_file) => {
+                    debug!("preparing to render headlessly to %s", *output_file);
+                    compositor_task.run_headless(*output_file);
+                }
+                None => {
+                    debug!("preparing to enter main loop");
+                    compositor_task.run_main_loop();
+                }
+            }
         };
     }
 
+    /// Runs the compositor without a window. Frames from the renderer are kept until the page has
+    /// finished loading and rendering, then stitched into a single image and written out as a PNG.
+    fn run_headless(&self, output_file: &str) {
+        let mut layer_buffer_set = None;
+        let mut page_size = Size2D(0u, 0u);
+        let mut ready_state = Loading;
+        let mut render_state = IdleRenderState;
+        // Whether the render in progress started after the latest layout did. Frames from
+        // renders that started before it show an earlier state of the page.
+        let mut render_is_current = false;
+
+        loop {
+            match self.port.recv() {
+                Exit => break,
+
+                ChangeReadyState(new_ready_state) => {
+                    match new_ready_state {
+                        Loading | PerformingLayout => {
+                            layer_buffer_set = None;
+                            render_is_current = false;
+                        }
+                        FinishedLoading => {}
+                    }
+                    ready_state = new_ready_state
+                }
+                ChangeRenderState(new_render_state) => {
+                    match new_render_state {
+                        RenderingRenderState => render_is_current = true,
+                        IdleRenderState => {}
+                    }
+                    render_state = new_render_state
+                }
+
+                // Without a window there are no events to route to layout or rerender requests to
+                // send to the renderer.
+                SetLayoutChan(*) | SetRenderChan(*) => {}
+
+                // The renderer draws into system memory when headless, so there is no GL context
+                // to share.
+                GetGLContext(chan) => chan.send(ptr::null()),
+
+                Paint(new_layer_buffer_set, new_size) => {
+                    debug!("compositor: received new frame");
+                    if render_is_current {
+                        layer_buffer_set = Some(new_layer_buffer_set);
+                        page_size = new_size;
+                    }
+                }
+            }
+
+            let finished = match (ready_state, render_state) {
+                (FinishedLoading, IdleRenderState) => true,
+                _ => false,
+            };
+            if finished && layer_buffer_set.is_some() {
+                let image = stitch_layer_buffers(layer_buffer_set.get_ref(), page_size);
+                match io::file_writer(&Path(output_file), [io::Create, io::Truncate]) {
+                    Ok(writer) => writer.write(png::encode(&image)),
+                    Err(msg) => fail!(fmt!("failed to write %s: %s", output_file, msg)),
+                }
+                break
+            }
+        }
+
+        self.shutdown_chan.send(())
+    }
+
     fn run_main_loop(&self) {
         let app: Application = ApplicationMethods::new();
         let window: @mut Window = WindowMethods::new(&app);
@@ -168,6 +258,9 @@ impl CompositorTask {
         let page_size = @mut Size2D(0f32, 0f32);
         let window_size = @mut Size2D(800, 600);
 
+        // Attributes the time spent compositing to the page last painted.
+        let profiler_chan = @mut self.profiler_chan.clone();
+
         // Keeps track of the current zoom factor
         let world_zoom = @mut 1f32;
         // Keeps track of local zoom factor. Reset to 1 after a rerender event.
@@ -175,6 +268,21 @@ impl CompositorTask {
         // Channel to the current renderer.
         // FIXME: This probably shouldn't be stored like this.
         let render_chan: @mut Option<RenderChan<CompositorChan>> = @mut None;
+        // Channel to the current layout task.
+        let current_layout_chan: @mut Option<LayoutChan> = @mut None;
+        // The position the pointer last moved to, until it is sent to script. The windowing
+        // system reports motion far more often than script can hit test it, so only the latest
+        // position in each turn of the main loop is sent.
+        let pending_mouse_move: @mut Option<Point2D<f32>> = @mut None;
+
+        let send_pending_mouse_move: @fn() = || {
+            match (util::replace(&mut *pending_mouse_move, None), &*current_layout_chan) {
+                (Some(point), &Some(ref layout_chan)) => {
+                    layout_chan.chan.send(RouteScriptMsg(SendEventMsg(MouseMoveEvent(point))));
+                }
+                (Some(_), &None) | (None, _) => {}
+            }
+        };
 
         let update_layout_callbacks: @fn(LayoutChan) = |layout_chan: LayoutChan| {
             let layout_chan_clone = layout_chan.clone();
@@ -218,10 +326,33 @@ impl CompositorTask {
                             }
                             None => {} // Nothing to do
                         }
+                        // Layout has the images decoded at the size they are now drawn at.
+                        layout_chan_clone.chan.send(SetZoomMsg(*world_zoom));
+                        let scroll_position = Point2D(world_offset.x / *world_zoom,
+                                                      world_offset.y / *world_zoom);
+                        layout_chan_clone.chan.send(SetScrollPositionMsg(scroll_position));
                         
                         event = MouseUpEvent(button, world_mouse_point(layer_mouse_point));
                     }
+                    WindowMouseMoveEvent(layer_mouse_point) => {
+                        *pending_mouse_move = Some(world_mouse_point(layer_mouse_point));
+                        return
+                    }
                 }
+                // Script sees the pointer move to where a button event happens first.
+                send_pending_mouse_move();
+                layout_chan_clone.chan.send(RouteScriptMsg(SendEventMsg(event)));
+            }
+
+            let layout_chan_clone = layout_chan.clone();
+
+            // Key events go to whatever has the focus in the page, which script keeps track of.
+            do window.set_key_callback |window_key_event: WindowKeyEvent| {
+                let event = match window_key_event {
+                    WindowKeyDownEvent(key, modifiers) => KeyDownEvent(key, modifiers),
+                    WindowKeyUpEvent(key, modifiers) => KeyUpEvent(key, modifiers),
+                    WindowKeyPressEvent(c, modifiers) => KeyPressEvent(c, modifiers),
+                };
                 layout_chan_clone.chan.send(RouteScriptMsg(SendEventMsg(event)));
             }
         };
@@ -236,6 +367,7 @@ impl CompositorTask {
                     ChangeRenderState(render_state) => window.set_render_state(render_state),
 
                     SetLayoutChan(layout_chan) => {
+                        *current_layout_chan = Some(layout_chan.clone());
                         update_layout_callbacks(layout_chan);
                     }
 
@@ -248,6 +380,8 @@ impl CompositorTask {
                     Paint(new_layer_buffer_set, new_size) => {
                         debug!("osmain: received new frame");
 
+                        *profiler_chan = profiler_chan.tagged(copy new_layer_buffer_set.page);
+
                         *page_size = Size2D(new_size.width as f32, new_size.height as f32);
 
                         let mut new_layer_buffer_set = new_layer_buffer_set;
@@ -320,7 +454,6 @@ impl CompositorTask {
             }
         };
 
-        let profiler_chan = self.profiler_chan.clone();
         do window.set_composite_callback {
             do profile(time::CompositingCategory, profiler_chan.clone()) {
                 debug!("compositor: compositing");
@@ -347,6 +480,16 @@ impl CompositorTask {
             world_offset.y = world_offset.y.clamp(&0.0, &max_y).round();
             
             debug!("compositor: scrolled to %?", *world_offset);
+
+            // Layout decodes the images that have been scrolled into view first.
+            match *current_layout_chan {
+                Some(ref layout_chan) => {
+                    let scroll_position = Point2D(world_offset.x / *world_zoom,
+                                                  world_offset.y / *world_zoom);
+                    layout_chan.chan.send(SetScrollPositionMsg(scroll_position));
+                }
+                None => {}
+            }
             
             
             let mut scroll_transform = identity();
@@ -410,12 +553,44 @@ impl CompositorTask {
 
             // Check for messages coming from the windowing system.
             window.check_loop();
+            send_pending_mouse_move();
         }
 
         self.shutdown_chan.send(())
     }
 }
 
+/// Copies the tiles of a layer buffer set into a single image covering the whole page.
+fn stitch_layer_buffers(layer_buffer_set: &LayerBufferSet, page_size: Size2D<uint>) -> Image {
+    let mut data = vec::from_elem(page_size.width * page_size.height * 4, 0u8);
+
+    for layer_buffer_set.buffers.each |buffer| {
+        let origin = buffer.screen_pos.origin;
+        let size = buffer.screen_pos.size;
+        if origin.x >= page_size.width || origin.y >= page_size.height {
+            loop
+        }
+
+        // Tiles rendered at a zoom level can extend beyond the page; clip them to it.
+        let width = uint::min(size.width, page_size.width - origin.x);
+        let height = uint::min(size.height, page_size.height - origin.y);
+
+        let surface = buffer.draw_target.snapshot().get_data_surface();
+        let stride = surface.stride() as uint;
+        do surface.with_data |tile_data| {
+            for uint::range(0, height) |row| {
+                let src = row * stride;
+                let dest = ((origin.y + row) * page_size.width + origin.x) * 4;
+                for uint::range(0, width * 4) |i| {
+                    data[dest + i] = tile_data[src + i];
+                }
+            }
+        }
+    }
+
+    Image(page_size.width, page_size.height, 4, data)
+}
+
 /// A function for spawning into the platform's main thread.
 fn on_osmain(f: ~fn()) {
     // FIXME: rust#6399
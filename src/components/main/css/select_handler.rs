@@ -86,6 +86,10 @@ impl SelectHandler<AbstractNode<LayoutView>> for NodeSelectHandler {
         }
     }
 
+    fn node_is_hover(&self, node: &AbstractNode<LayoutView>) -> bool {
+        node.is_hovered()
+    }
+
     fn with_node_classes<R>(&self, node: &AbstractNode<LayoutView>, f: &fn(Option<&str>) -> R) -> R {
         if !node.is_element() {
             fail!(~"attempting to style non-element node");
@@ -14,11 +14,12 @@ use servo_msg::compositor::{ScriptListener, ReadyState};
 use servo_msg::engine::{EngineChan, ExitMsg, LoadUrlMsg, Msg};
 use script::layout_interface::LayoutChan;
 use script::layout_interface;
-use script::script_task::{ExecuteMsg, LoadMsg, ScriptMsg, ScriptContext, ScriptChan};
+use script::script_task::{LoadMsg, ScriptMsg, ScriptContext, ScriptChan};
 use script::script_task;
 use servo_net::image_cache_task::{ImageCacheTask, ImageCacheTaskClient};
 use servo_net::resource_task::ResourceTask;
 use servo_net::resource_task;
+use servo_util::mem::MemoryProfilerChan;
 use servo_util::time::{ProfilerChan};
 
 pub struct Engine {
@@ -37,7 +38,8 @@ impl Engine {
                  opts: &Opts,
                  resource_task: ResourceTask,
                  image_cache_task: ImageCacheTask,
-                 profiler_chan: ProfilerChan)
+                 profiler_chan: ProfilerChan,
+                 memory_profiler_chan: MemoryProfilerChan)
                  -> EngineChan {
         macro_rules! closure_stream(
             ($Msg:ty, $Chan:ident) => (
@@ -66,6 +68,13 @@ impl Engine {
 
         let compositor_chan = Cell(compositor_chan);
 
+        // Script only counts its nodes if memory is being profiled.
+        let script_memory_profiler_chan = Cell(if opts.memory_profiler_period.is_some() {
+            Some(memory_profiler_chan.clone())
+        } else {
+            None
+        });
+
         let opts = Cell(copy *opts);
 
         {
@@ -75,7 +84,8 @@ impl Engine {
                 render_task::create_render_task(render_port.take(),
                                                 compositor_chan.clone(),
                                                 opts.with_ref(|o| copy *o),
-                                                profiler_chan.clone());
+                                                profiler_chan.clone(),
+                                                memory_profiler_chan.clone());
 
                 let opts = opts.take();
 
@@ -84,7 +94,8 @@ impl Engine {
                                                 render_chan.clone(),
                                                 image_cache_task.clone(),
                                                 opts,
-                                                profiler_chan.clone());
+                                                profiler_chan.clone(),
+                                                memory_profiler_chan.clone());
 
                 let compositor_chan_clone = compositor_chan.clone();
                 ScriptContext::create_script_context(layout_chan.clone(),
@@ -95,7 +106,8 @@ impl Engine {
                                                          compositor_chan_clone.set_ready_state(msg)
                                                      },
                                                      resource_task.clone(),
-                                                     image_cache_task.clone());
+                                                     image_cache_task.clone(),
+                                                     script_memory_profiler_chan.take());
 
                 Engine {
                     request_port: engine_port.take(),
@@ -121,11 +133,7 @@ impl Engine {
     fn handle_request(&self, request: Msg) -> bool {
         match request {
             LoadUrlMsg(url) => {
-                if url.path.ends_with(".js") {
-                    self.script_chan.send(ExecuteMsg(url))
-                } else {
-                    self.script_chan.send(LoadMsg(url))
-                }
+                self.script_chan.send(LoadMsg(url));
                 return true
             }
 
@@ -572,7 +572,7 @@ pub impl RenderBox {
     /// items, each box puts its display items into the correct stack layer according to CSS 2.1
     /// Appendix E. Finally, the builder flattens the list.
     fn build_display_list<E:ExtraDisplayListData>(&self,
-                                                  _: &DisplayListBuilder,
+                                                  builder: &DisplayListBuilder,
                                                   dirty: &Rect<Au>,
                                                   offset: &Point2D<Au>,
                                                   list: &Cell<DisplayList<E>>) {
@@ -681,7 +681,16 @@ pub impl RenderBox {
                 // Add the background to the list, if applicable.
                 self.paint_background_if_applicable(list, &absolute_box_bounds);
 
-                match image_box.image.get_image() {
+                if absolute_box_bounds.intersects(&builder.ctx.viewport) {
+                    builder.ctx.image_cache.mark_in_viewport(&image_box.image.url);
+                }
+
+                // The image is decoded at the size it is drawn at, in device pixels.
+                let zoom = builder.ctx.zoom;
+                let width = (absolute_box_bounds.size.width.to_px() as f32 * zoom).ceil();
+                let height = (absolute_box_bounds.size.height.to_px() as f32 * zoom).ceil();
+                let size = Size2D(uint::max(width as uint, 1), uint::max(height as uint, 1));
+                match image_box.image.get_image_at_size(size) {
                     Some(image) => {
                         debug!("(building display list) building image box");
 
@@ -13,5 +13,9 @@ use servo_net::local_image_cache::LocalImageCache;
 pub struct LayoutContext {
     font_ctx: @mut FontContext,
     image_cache: @mut LocalImageCache,
-    screen_size: Rect<Au>
+    screen_size: Rect<Au>,
+    /// The part of the page shown in the window, which may be scrolled and zoomed
+    viewport: Rect<Au>,
+    /// The number of device pixels per CSS pixel
+    zoom: f32
 }
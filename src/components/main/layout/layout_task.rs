@@ -9,7 +9,7 @@ use compositing::CompositorChan;
 use css::matching::MatchMethods;
 use css::select::new_css_select_ctx;
 use layout::aux::{LayoutData, LayoutAuxMethods};
-use layout::box::RenderBox;
+use layout::box::{RenderBox, TextRenderBoxClass};
 use layout::box_builder::LayoutTreeBuilder;
 use layout::context::LayoutContext;
 use layout::display_list_builder::{DisplayListBuilder, FlowDisplayListBuilderMethods};
@@ -18,6 +18,8 @@ use layout::flow::FlowContext;
 use core::cast::transmute;
 use core::cell::Cell;
 use core::comm::{Chan, Port};
+use core::hashmap::HashSet;
+use core::ptr;
 use geom::point::Point2D;
 use geom::rect::Rect;
 use geom::size::Size2D;
@@ -36,29 +38,35 @@ use script::layout_interface::{HitTestQuery, ContentBoxResponse, HitTestResponse
 use script::layout_interface::{ContentBoxesQuery, ContentBoxesResponse, ExitMsg, LayoutQuery};
 use script::layout_interface::{LayoutResponse, MatchSelectorsDocumentDamage, Msg};
 use script::layout_interface::{QueryMsg, RouteScriptMsg, Reflow, ReflowDocumentDamage};
-use script::layout_interface::{ReflowForDisplay, ReflowMsg};
+use script::layout_interface::{ReflowForDisplay, ReflowMsg, SetScrollPositionMsg, SetZoomMsg};
 use script::script_task::{ReflowCompleteMsg, ScriptChan, ScriptMsg, SendEventMsg};
 use servo_net::image_cache_task::{ImageCacheTask, ImageResponseMsg};
 use servo_net::local_image_cache::LocalImageCache;
+use servo_util::mem::{BoxCountCategory, FlowCountCategory, GlyphStoreBytesCategory};
+use servo_util::mem::MemoryProfilerChan;
 use servo_util::tree::{TreeNodeRef, TreeUtils};
 use servo_util::time::{ProfilerChan, profile, time};
 use servo_util::time;
 use std::net::url::Url;
+use std::net::url;
 
 pub fn create_layout_task(port: Port<Msg>,
                           script_chan: ScriptChan,
                           render_chan: RenderChan<CompositorChan>,
                           img_cache_task: ImageCacheTask,
                           opts: Opts,
-                          profiler_chan: ProfilerChan) {
+                          profiler_chan: ProfilerChan,
+                          memory_profiler_chan: MemoryProfilerChan) {
     let port = Cell(port);
     do spawn {
+        let _reports = memory_profiler_chan.guard_reports();
         let mut layout = Layout::new(port.take(),
                                      script_chan.clone(),
                                      render_chan.clone(),
                                      img_cache_task.clone(),
                                      &opts,
-                                     profiler_chan.clone());
+                                     profiler_chan.clone(),
+                                     memory_profiler_chan.clone());
         layout.start();
     };
 }
@@ -72,12 +80,19 @@ struct Layout {
     font_ctx: @mut FontContext,
     doc_url: Option<Url>,
     screen_size: Option<Size2D<Au>>,
+    /// The number of device pixels per CSS pixel, as last told by the compositor
+    zoom: f32,
+    /// The point of the page in the top left corner of the window, in CSS pixels, as last told by
+    /// the compositor
+    scroll_position: Point2D<f32>,
 
     /// This is used to root reader data.
     layout_refs: ~[@mut LayoutData],
 
     css_select_ctx: @mut SelectCtx,
     profiler_chan: ProfilerChan,
+    /// A channel to the memory profiler, if memory is being profiled
+    memory_profiler_chan: Option<MemoryProfilerChan>,
 }
 
 impl Layout {
@@ -86,7 +101,8 @@ impl Layout {
            render_chan: RenderChan<CompositorChan>, 
            image_cache_task: ImageCacheTask,
            opts: &Opts,
-           profiler_chan: ProfilerChan)
+           profiler_chan: ProfilerChan,
+           memory_profiler_chan: MemoryProfilerChan)
            -> Layout {
         let fctx = @mut FontContext::new(opts.render_backend, true, profiler_chan.clone());
 
@@ -99,10 +115,17 @@ impl Layout {
             font_ctx: fctx,
             doc_url: None,
             screen_size: None,
+            zoom: 1.0,
+            scroll_position: Point2D(0.0, 0.0),
             
             layout_refs: ~[],
             css_select_ctx: @mut new_css_select_ctx(),
             profiler_chan: profiler_chan,
+            memory_profiler_chan: if opts.memory_profiler_period.is_some() {
+                Some(memory_profiler_chan)
+            } else {
+                None
+            },
         }
     }
 
@@ -118,10 +141,17 @@ impl Layout {
         let font_ctx = self.font_ctx;
         let screen_size = self.screen_size.unwrap();
 
+        let viewport_origin = Point2D(Au::from_frac_px(self.scroll_position.x as float),
+                                      Au::from_frac_px(self.scroll_position.y as float));
+        let viewport_size = Size2D(screen_size.width.scale_by(1.0 / (self.zoom as float)),
+                                   screen_size.height.scale_by(1.0 / (self.zoom as float)));
+
         LayoutContext {
             image_cache: image_cache,
             font_ctx: font_ctx,
             screen_size: Rect(Point2D(Au(0), Au(0)), screen_size),
+            viewport: Rect(viewport_origin, viewport_size),
+            zoom: self.zoom,
         }
     }
 
@@ -129,6 +159,8 @@ impl Layout {
         match self.port.recv() {
             AddStylesheetMsg(sheet) => self.handle_add_stylesheet(sheet),
             ReflowMsg(data) => {
+                // Attribute the time spent from here on to the page being laid out.
+                self.profiler_chan = self.profiler_chan.tagged(url::to_str(&data.url));
                 let data = Cell(data);
 
                 do profile(time::LayoutPerformCategory, self.profiler_chan.clone()) {
@@ -144,6 +176,12 @@ impl Layout {
             RouteScriptMsg(script_msg) => {
                 self.route_script_msg(script_msg);
             }
+            SetZoomMsg(zoom) => self.handle_set_zoom(zoom),
+            SetScrollPositionMsg(position) => {
+                // Scrolling alone doesn't lay the page out again; the images in view are looked
+                // at again on the next reflow, which every image that arrives triggers.
+                self.scroll_position = position;
+            }
             ExitMsg => {
                 debug!("layout: ExitMsg received");
                 return false
@@ -158,6 +196,15 @@ impl Layout {
         self.css_select_ctx.append_sheet(sheet.take(), OriginAuthor);
     }
 
+    /// Lays the page out again when the zoom changes, so that its images are drawn from copies
+    /// decoded for the new size.
+    fn handle_set_zoom(&mut self, zoom: f32) {
+        if zoom != self.zoom {
+            self.zoom = zoom;
+            self.script_chan.send(SendEventMsg(ReflowEvent));
+        }
+    }
+
     /// The high-level routine that performs layout tasks.
     fn handle_reflow(&mut self, data: &Reflow) {
         // FIXME: Isolate this transmutation into a "bridge" module.
@@ -195,8 +242,12 @@ impl Layout {
         match data.damage.level {
             ReflowDocumentDamage => {}
             MatchSelectorsDocumentDamage => {
+                // Nodes outside the damaged subtree keep the styles they were given last time.
+                let damage_root: &AbstractNode<LayoutView> = unsafe {
+                    transmute(&data.damage.root)
+                };
                 do profile(time::LayoutSelectorMatchCategory, self.profiler_chan.clone()) {
-                    node.restyle_subtree(self.css_select_ctx);
+                    damage_root.restyle_subtree(self.css_select_ctx);
                 }
             }
         }
@@ -215,6 +266,7 @@ impl Layout {
 
             layout_root
         };
+        self.report_memory(layout_root);
 
         // Perform the primary layout passes over the flow tree to compute the locations of all
         // the boxes.
@@ -249,10 +301,12 @@ impl Layout {
 
                 let render_layer = RenderLayer {
                     display_list: display_list.take(),
-                    size: Size2D(root_size.width.to_px() as uint, root_size.height.to_px() as uint)
+                    size: Size2D(root_size.width.to_px() as uint, root_size.height.to_px() as uint),
+                    page: url::to_str(&data.url),
                 };
 
                 self.render_chan.send(RenderMsg(render_layer));
+                self.local_image_cache.send_viewport_images();
             } // time(layout: display list building)
         }
 
@@ -266,6 +320,38 @@ impl Layout {
         data.script_chan.send(ReflowCompleteMsg);
     }
 
+    /// Reports the flows and boxes in a new flow tree, the glyphs shaped for them and the fonts
+    /// used to shape them to the memory profiler.
+    fn report_memory(&self, layout_root: FlowContext) {
+        let memory_profiler_chan = match self.memory_profiler_chan {
+            Some(ref memory_profiler_chan) => memory_profiler_chan,
+            None => return,
+        };
+        let mut flow_count = 0;
+        let mut box_count = 0;
+        let mut glyph_bytes = 0;
+        // Text runs are shared between the boxes that a line break splits them into.
+        let mut runs = HashSet::new();
+        for layout_root.traverse_preorder |flow| {
+            flow_count += 1;
+            for flow.iter_all_boxes |box| {
+                box_count += 1;
+                match box {
+                    TextRenderBoxClass(text_box) => {
+                        if runs.insert(ptr::to_unsafe_ptr(&*text_box.run) as uint) {
+                            glyph_bytes += text_box.run.glyphs.byte_size();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        memory_profiler_chan.report(FlowCountCategory, flow_count);
+        memory_profiler_chan.report(BoxCountCategory, box_count);
+        memory_profiler_chan.report(GlyphStoreBytesCategory, glyph_bytes);
+        self.font_ctx.report_memory(memory_profiler_chan);
+    }
+
     /// Handles a query from the script task. This is the main routine that DOM functions like
     /// `getClientRects()` or `getBoundingClientRect()` ultimately invoke.
     fn handle_query(&self, query: LayoutQuery, reply_chan: Chan<Result<LayoutResponse,()>>) {
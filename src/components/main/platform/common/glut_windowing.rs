@@ -7,17 +7,23 @@
 /// GLUT is a very old and bare-bones toolkit. However, it has good cross-platform support, at
 /// least on desktops. It is designed for testing Servo without the need of a UI.
 
-use windowing::{ApplicationMethods, CompositeCallback, LoadUrlCallback, MouseCallback};
-use windowing::{ResizeCallback, ScrollCallback, WindowMethods, WindowMouseEvent, WindowClickEvent};
-use windowing::{WindowMouseDownEvent, WindowMouseUpEvent, ZoomCallback};
+use windowing::{ApplicationMethods, CompositeCallback, KeyCallback, LoadUrlCallback};
+use windowing::{MouseCallback, ResizeCallback, ScrollCallback, WindowMethods, WindowMouseEvent};
+use windowing::{WindowClickEvent, WindowKeyDownEvent, WindowKeyEvent, WindowKeyPressEvent};
+use windowing::{WindowKeyUpEvent, WindowMouseDownEvent, WindowMouseMoveEvent, WindowMouseUpEvent};
+use windowing::{ZoomCallback};
 
 use alert::{Alert, AlertMethods};
 use core::libc::c_int;
 use geom::point::Point2D;
 use geom::size::Size2D;
+use script::dom::event::{ArrowDownKey, ArrowLeftKey, ArrowRightKey, ArrowUpKey, BackspaceKey};
+use script::dom::event::{CharacterKey, DeleteKey, EndKey, EnterKey, EscapeKey, FunctionKey};
+use script::dom::event::{HomeKey, InsertKey, Key, KeyModifiers, PageDownKey, PageUpKey, TabKey};
 use servo_msg::compositor::{IdleRenderState, RenderState, RenderingRenderState};
 use servo_msg::compositor::{FinishedLoading, Loading, PerformingLayout, ReadyState};
-use glut::glut::{ACTIVE_CTRL, DOUBLE, HAVE_PRECISE_MOUSE_WHEEL, WindowHeight, WindowWidth};
+use glut::glut::{ACTIVE_ALT, ACTIVE_CTRL, ACTIVE_SHIFT, DOUBLE, HAVE_PRECISE_MOUSE_WHEEL};
+use glut::glut::{WindowHeight, WindowWidth};
 use glut::glut;
 use glut::machack;
 
@@ -42,6 +48,7 @@ pub struct Window {
     resize_callback: Option<ResizeCallback>,
     load_url_callback: Option<LoadUrlCallback>,
     mouse_callback: Option<MouseCallback>,
+    key_callback: Option<KeyCallback>,
     scroll_callback: Option<ScrollCallback>,
     zoom_callback: Option<ZoomCallback>,
 
@@ -71,6 +78,7 @@ impl WindowMethods<Application> for Window {
             resize_callback: None,
             load_url_callback: None,
             mouse_callback: None,
+            key_callback: None,
             scroll_callback: None,
             zoom_callback: None,
 
@@ -113,11 +121,26 @@ impl WindowMethods<Application> for Window {
         do glut::keyboard_func |key, _, _| {
             window.handle_key(key)
         }
+        do glut::keyboard_up_func |key, _, _| {
+            window.handle_key_up(key)
+        }
+        do glut::special_func |key, _, _| {
+            window.handle_special_key(key, true)
+        }
+        do glut::special_up_func |key, _, _| {
+            window.handle_special_key(key, false)
+        }
         do glut::mouse_func |button, state, x, y| {
             if button < 3 {
                 window.handle_mouse(button, state, x, y);
             }
         }
+        do glut::motion_func |x, y| {
+            window.handle_mouse_move(x, y)
+        }
+        do glut::passive_motion_func |x, y| {
+            window.handle_mouse_move(x, y)
+        }
         do glut::mouse_wheel_func |wheel, direction, _x, _y| {
             let delta = if HAVE_PRECISE_MOUSE_WHEEL {
                 (direction as f32) / 10000.0
@@ -168,6 +191,11 @@ impl WindowMethods<Application> for Window {
         self.mouse_callback = Some(new_mouse_callback)
     }
 
+    /// Registers a callback to be run when a key is pressed or released.
+    pub fn set_key_callback(&mut self, new_key_callback: KeyCallback) {
+        self.key_callback = Some(new_key_callback)
+    }
+
     /// Registers a callback to be run when the user scrolls.
     pub fn set_scroll_callback(&mut self, new_scroll_callback: ScrollCallback) {
         self.scroll_callback = Some(new_scroll_callback)
@@ -225,7 +253,7 @@ impl Window {
         }
     }
 
-    /// Helper function to handle keyboard events.
+    /// Helper function to handle keyboard events. Browser shortcuts aren't passed on to content.
     fn handle_key(&self, key: u8) {
         debug!("got key: %d", key as int);
         match key {
@@ -240,7 +268,42 @@ impl Window {
                     callback(-0.1);
                 }
             }
-            _ => {}
+            _ => {
+                let modifiers = key_modifiers();
+                let key = key_from_ascii(key, modifiers);
+                self.send_key_event(WindowKeyDownEvent(key, modifiers));
+                match key {
+                    CharacterKey(c) if !modifiers.ctrl && !modifiers.alt => {
+                        self.send_key_event(WindowKeyPressEvent(c, modifiers))
+                    }
+                    EnterKey => self.send_key_event(WindowKeyPressEvent('\r', modifiers)),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Helper function to handle the release of a key that GLUT reports as an ASCII code.
+    fn handle_key_up(&self, key: u8) {
+        let modifiers = key_modifiers();
+        self.send_key_event(WindowKeyUpEvent(key_from_ascii(key, modifiers), modifiers))
+    }
+
+    /// Helper function to handle keys that don't produce characters, like the arrow keys.
+    fn handle_special_key(&self, key: c_int, down: bool) {
+        debug!("got special key: %d", key as int);
+        let modifiers = key_modifiers();
+        match key_from_special(key) {
+            Some(key) if down => self.send_key_event(WindowKeyDownEvent(key, modifiers)),
+            Some(key) => self.send_key_event(WindowKeyUpEvent(key, modifiers)),
+            None => {}
+        }
+    }
+
+    fn send_key_event(&self, event: WindowKeyEvent) {
+        match self.key_callback {
+            None => {}
+            Some(callback) => callback(event),
         }
     }
 
@@ -278,6 +341,14 @@ impl Window {
         }
     }
 
+    /// Helper function to handle the pointer moving, with or without a button held down.
+    fn handle_mouse_move(&self, x: c_int, y: c_int) {
+        match self.mouse_callback {
+            None => {}
+            Some(callback) => callback(WindowMouseMoveEvent(Point2D(x as f32, y as f32))),
+        }
+    }
+
     /// Helper function to handle a scroll.
     fn handle_scroll(&mut self, delta: Point2D<f32>) {
         match self.scroll_callback {
@@ -313,3 +384,44 @@ impl Window {
     }
 }
 
+/// Returns the modifier keys held down during the current GLUT input callback.
+fn key_modifiers() -> KeyModifiers {
+    let modifiers = glut::get_modifiers();
+    KeyModifiers {
+        shift: (modifiers & ACTIVE_SHIFT) != 0,
+        ctrl: (modifiers & ACTIVE_CTRL) != 0,
+        alt: (modifiers & ACTIVE_ALT) != 0,
+        meta: false,
+    }
+}
+
+/// Converts a key GLUT reports as an ASCII code. With the control key held, letters arrive as
+/// control characters.
+fn key_from_ascii(key: u8, modifiers: KeyModifiers) -> Key {
+    match key {
+        8 => BackspaceKey,
+        9 => TabKey,
+        13 => EnterKey,
+        1 .. 26 if modifiers.ctrl => CharacterKey(('a' as u8 + key - 1) as char),
+        27 => EscapeKey,
+        127 => DeleteKey,
+        _ => CharacterKey(key as char),
+    }
+}
+
+/// Converts a key GLUT reports as a special key code. Returns None for keys content doesn't see.
+fn key_from_special(key: c_int) -> Option<Key> {
+    match key {
+        1 .. 12 => Some(FunctionKey(key as uint)),
+        100 => Some(ArrowLeftKey),
+        101 => Some(ArrowUpKey),
+        102 => Some(ArrowRightKey),
+        103 => Some(ArrowDownKey),
+        104 => Some(PageUpKey),
+        105 => Some(PageDownKey),
+        106 => Some(HomeKey),
+        107 => Some(EndKey),
+        108 => Some(InsertKey),
+        _ => None,
+    }
+}
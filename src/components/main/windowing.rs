@@ -6,12 +6,21 @@
 
 use geom::point::Point2D;
 use geom::size::Size2D;
+use script::dom::event::{Key, KeyModifiers};
 use servo_msg::compositor::{ReadyState, RenderState};
 
 pub enum WindowMouseEvent {
     WindowClickEvent(uint, Point2D<f32>),
     WindowMouseDownEvent(uint, Point2D<f32>),
     WindowMouseUpEvent(uint, Point2D<f32>),
+    WindowMouseMoveEvent(Point2D<f32>),
+}
+
+pub enum WindowKeyEvent {
+    WindowKeyDownEvent(Key, KeyModifiers),
+    WindowKeyUpEvent(Key, KeyModifiers),
+    /// Follows the key down event of a key that produces a character.
+    WindowKeyPressEvent(char, KeyModifiers),
 }
 
 /// Type of the function that is called when the screen is to be redisplayed.
@@ -23,9 +32,13 @@ pub type ResizeCallback = @fn(uint, uint);
 /// Type of the function that is called when a new URL is to be loaded.
 pub type LoadUrlCallback = @fn(&str);
 
-/// Type of the function that is called when a mouse hit test is to be performed.
+/// Type of the function that is called when a mouse hit test is to be performed, including when
+/// the pointer moves.
 pub type MouseCallback = @fn(WindowMouseEvent);
 
+/// Type of the function that is called when the user presses or releases a key.
+pub type KeyCallback = @fn(WindowKeyEvent);
+
 /// Type of the function that is called when the user scrolls.
 pub type ScrollCallback = @fn(Point2D<f32>);
 
@@ -53,6 +66,8 @@ pub trait WindowMethods<A> {
     pub fn set_load_url_callback(&mut self, new_load_url_callback: LoadUrlCallback);
     /// Registers a callback to run when the user clicks.
     pub fn set_mouse_callback(&mut self, new_mouse_callback: MouseCallback);
+    /// Registers a callback to run when the user presses or releases a key.
+    pub fn set_key_callback(&mut self, new_key_callback: KeyCallback);
     /// Registers a callback to run when the user scrolls.
     pub fn set_scroll_callback(&mut self, new_scroll_callback: ScrollCallback);
     /// Registers a callback to run when the user zooms.
@@ -23,7 +23,9 @@ pub struct LayerBuffer {
 /// A set of layer buffers. This is an atomic unit used to switch between the front and back
 /// buffers.
 pub struct LayerBufferSet {
-    buffers: ~[LayerBuffer]
+    buffers: ~[LayerBuffer],
+    /// The URL of the page the buffers show
+    page: ~str
 }
 
 /// The status of the renderer.
@@ -0,0 +1,122 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A loader for the built-in `about:` pages.
+//!
+//! `about:blank` is an empty document. `about:diagnostics` reports the times collected by the
+//! profiler and the state of the image cache, as registered with the resource task.
+
+use image_cache_task::DescribeImages;
+use resource_task::{Done, GetDiagnosticSources, LoaderTask, LoaderTaskFactory, Metadata};
+use resource_task::{Payload, ResourceTask};
+
+use core::comm::{GenericSmartChan, stream};
+use core::task;
+use servo_util::time::{BucketsMsg, summarize};
+use std::net::url;
+
+/// Creates loaders for `about:` URLs. The resource task is asked for the tasks the diagnostics
+/// page reports on.
+pub fn factory(resource_task: ResourceTask) -> LoaderTaskFactory {
+    let factory: LoaderTaskFactory = || {
+        let resource_task = resource_task.clone();
+        let loader: LoaderTask = |url, _headers, progress_chan| {
+            assert!("about" == url.scheme);
+            let resource_task = resource_task.clone();
+            do task::spawn {
+                let page = match url.path {
+                    ~"blank" => Some(~""),
+                    ~"diagnostics" => Some(diagnostics_page(&resource_task)),
+                    _ => None,
+                };
+                match page {
+                    Some(page) => {
+                        let mut metadata = Metadata::default(url.clone());
+                        metadata.set_content_type("text/html; charset=utf-8");
+                        if progress_chan.try_send(Metadata(metadata)) &&
+                                progress_chan.try_send(Payload(str::to_bytes(page))) {
+                            progress_chan.try_send(Done(Ok(())));
+                        }
+                    }
+                    None => {
+                        debug!("about_loader: no such page %s", url::to_str(&url));
+                        progress_chan.try_send(Done(Err(())));
+                    }
+                }
+            }
+        };
+        loader
+    };
+    factory
+}
+
+fn diagnostics_page(resource_task: &ResourceTask) -> ~str {
+    let (sources_port, sources_chan) = stream();
+    resource_task.send(GetDiagnosticSources(sources_chan));
+    let (profiler_chan, image_cache_task) = match sources_port.recv() {
+        Some(sources) => sources,
+        None => {
+            return ~"<html><head><title>Diagnostics</title></head><body>\
+                     <p>No diagnostics are available.</p></body></html>"
+        }
+    };
+
+    let mut page = ~"<html><head><title>Diagnostics</title></head><body>";
+
+    page.push_str("<h1>Profiler</h1><table><tr><th>Category</th><th>Mean (ms)</th>\
+                   <th>Median (ms)</th><th>p90 (ms)</th><th>p99 (ms)</th><th>Min (ms)</th>\
+                   <th>Max (ms)</th><th>Count</th></tr>");
+    let (buckets_port, buckets_chan) = stream();
+    profiler_chan.send(BucketsMsg(buckets_chan));
+    let mut buckets = buckets_port.recv();
+    for vec::each_mut(buckets) |bucket| {
+        match *bucket {
+            (category, ref mut data) => {
+                match summarize(*data) {
+                    Some(summary) => {
+                        page.push_str(fmt!("<tr><td>%s</td><td>%.4f</td><td>%.4f</td>\
+                                            <td>%.4f</td><td>%.4f</td><td>%.4f</td>\
+                                            <td>%.4f</td><td>%u</td></tr>",
+                                           escape(category.format()), summary.mean as float,
+                                           summary.median as float, summary.p90 as float,
+                                           summary.p99 as float, summary.min as float,
+                                           summary.max as float, summary.count));
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+    page.push_str("</table>");
+
+    page.push_str("<h1>Image cache</h1><table><tr><th>URL</th><th>State</th></tr>");
+    let (images_port, images_chan) = stream();
+    image_cache_task.send(DescribeImages(images_chan));
+    for images_port.recv().each |&(ref url, ref state)| {
+        page.push_str(fmt!("<tr><td>%s</td><td>%s</td></tr>",
+                           escape(url::to_str(url)), escape(*state)));
+    }
+    page.push_str("</table></body></html>");
+    page
+}
+
+/// Escapes text for inclusion in HTML.
+fn escape(text: &str) -> ~str {
+    let mut escaped = ~"";
+    for str::each_char(text) |c| {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push_char(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn should_escape_html() {
+    assert!(escape("<a href=\"?x&y\">") == ~"&lt;a href=&quot;?x&amp;y&quot;&gt;");
+}
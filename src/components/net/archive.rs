@@ -0,0 +1,454 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Recording the resources a run fetches into an archive, and replaying them without the network.
+//!
+//! An archive is a directory. Each load that finishes while recording is written to a numbered
+//! file holding its progress messages, payloads included byte for byte, and the `index` file lists
+//! the entries in the order they finished along with their URLs. Replaying hands out the entries
+//! for each URL in the order they were recorded, so a page that fetches a resource twice sees both
+//! responses again.
+
+use resource_task::{Aborted, Done, LoaderTask, LoaderTaskFactory, Metadata, Payload};
+use resource_task::ProgressMsg;
+
+use core::cell::Cell;
+use core::comm::{Chan, GenericSmartChan, Port, SharedChan, stream};
+use core::hashmap::HashMap;
+use core::io::{Reader, ReaderUtil, Writer, WriterUtil};
+use core::task;
+use core::util::replace;
+use std::net::url::Url;
+use std::net::url;
+
+/// What to do with the resources fetched during a run.
+pub enum ArchiveMode {
+    /// Fetch resources as usual, and record them into the given directory
+    Record(Path),
+    /// Serve resources from the archive in the given directory instead of fetching them
+    Replay(Path),
+}
+
+pub enum Msg {
+    /// Record the progress messages of a finished load
+    Store(Url, ~[ProgressMsg]),
+    /// Get the progress messages of the next recorded load of a URL, if any are left
+    Take(Url, Chan<Option<~[ProgressMsg]>>),
+}
+
+pub type ArchiveTask = SharedChan<Msg>;
+
+/// Creates a task that records into or replays from an archive. When recording, the directory is
+/// created if it does not exist and any archive already in it is replaced.
+pub fn ArchiveTask(mode: ArchiveMode) -> ArchiveTask {
+    let (port, chan) = stream();
+    let port = Cell(port);
+    let mode = Cell(mode);
+    do task::spawn {
+        let mut archive = Archive {
+            port: port.take(),
+            mode: mode.take(),
+            next_entry: 0,
+            entries: HashMap::new(),
+        };
+        archive.run();
+    }
+    SharedChan::new(chan)
+}
+
+/// Wraps the loader of each network scheme so that its loads are recorded, or replaces it with a
+/// loader serving the archived loads, depending on the mode. Loaders that don't use the network,
+/// such as those of `about:` and `data:` URLs, are left alone.
+pub fn wrap_loaders(mode: ArchiveMode, loaders: ~[(~str, LoaderTaskFactory)])
+                    -> ~[(~str, LoaderTaskFactory)] {
+    let replaying = match mode {
+        Record(*) => false,
+        Replay(*) => true,
+    };
+    let archive = ArchiveTask(mode);
+    do vec::map_consume(loaders) |loader| {
+        let (scheme, factory) = loader;
+        if !is_network_scheme(scheme) {
+            (scheme, factory)
+        } else if replaying {
+            (scheme, replay_factory(archive.clone()))
+        } else {
+            (scheme, recording_factory(archive.clone(), factory))
+        }
+    }
+}
+
+fn is_network_scheme(scheme: &str) -> bool {
+    scheme == "http"
+}
+
+/// Creates loaders that pass on the progress of the given factory's loaders, recording each load
+/// into the archive once it is done.
+pub fn recording_factory(archive: ArchiveTask, factory: LoaderTaskFactory) -> LoaderTaskFactory {
+    let recording_factory: LoaderTaskFactory = || {
+        let loader = factory();
+        let archive = archive.clone();
+        let recording_loader: LoaderTask = |url, headers, progress_chan| {
+            let (loader_port, loader_chan) = stream();
+            loader(url.clone(), headers, loader_chan);
+
+            let archive = archive.clone();
+            do task::spawn {
+                let mut msgs = ~[];
+                loop {
+                    let msg = match loader_port.try_recv() {
+                        Some(msg) => msg,
+                        None => break,
+                    };
+                    msgs.push(copy msg);
+                    let done = match msg {
+                        Done(*) | Aborted => true,
+                        Metadata(*) | Payload(*) => false,
+                    };
+                    if done {
+                        // Record the load before reporting that it's done, so that it's in the
+                        // archive by the time anyone can ask for it.
+                        archive.send(Store(url.clone(), replace(&mut msgs, ~[])));
+                        progress_chan.try_send(msg);
+                        break
+                    }
+                    // Loads that are cancelled aren't recorded.
+                    if !progress_chan.try_send(msg) {
+                        break
+                    }
+                }
+            }
+        };
+        recording_loader
+    };
+    recording_factory
+}
+
+/// Creates loaders that replay the loads recorded in the archive. URLs that were not recorded, or
+/// not as many times as they are loaded, fail to load.
+pub fn replay_factory(archive: ArchiveTask) -> LoaderTaskFactory {
+    let replay_factory: LoaderTaskFactory = || {
+        let archive = archive.clone();
+        let replay_loader: LoaderTask = |url, _headers, progress_chan| {
+            let archive = archive.clone();
+            do task::spawn {
+                let (response_port, response_chan) = stream();
+                archive.send(Take(url.clone(), response_chan));
+                match response_port.recv() {
+                    Some(msgs) => {
+                        for msgs.each |msg| {
+                            if !progress_chan.try_send(copy *msg) {
+                                break
+                            }
+                        }
+                    }
+                    None => {
+                        debug!("archive: no recorded load of %s", url::to_str(&url));
+                        progress_chan.try_send(Done(Err(())));
+                    }
+                }
+            }
+        };
+        replay_loader
+    };
+    replay_factory
+}
+
+struct Archive {
+    port: Port<Msg>,
+    mode: ArchiveMode,
+    /// The number of the next entry to record
+    next_entry: uint,
+    /// The recorded entries not yet replayed for each URL, in the order they were recorded
+    entries: HashMap<~str, ~[uint]>,
+}
+
+impl Archive {
+    fn run(&mut self) {
+        let replaying = match self.mode {
+            Record(ref dir) => {
+                if !os::path_is_dir(dir) && !os::mkdir_recursive(dir, 0x1ed /* 0755 */) {
+                    debug!("archive: failed to create %s", dir.to_str());
+                }
+                match io::file_writer(&dir.push("index"), [io::Create, io::Truncate]) {
+                    Ok(*) => {}
+                    Err(message) => debug!("archive: failed to create index: %s", message),
+                }
+                false
+            }
+            Replay(*) => true,
+        };
+        if replaying {
+            self.read_index()
+        }
+
+        // The archive is done with once every loader has gone away.
+        loop {
+            match self.port.try_recv() {
+                Some(Store(url, msgs)) => self.store(&url, msgs),
+                Some(Take(url, response_chan)) => response_chan.send(self.take(&url)),
+                None => break,
+            }
+        }
+    }
+
+    fn dir<'a>(&'a self) -> &'a Path {
+        match self.mode {
+            Record(ref dir) | Replay(ref dir) => dir,
+        }
+    }
+
+    /// Reads the index, whose lines hold an entry number and the URL recorded in it.
+    fn read_index(&mut self) {
+        let reader = match io::file_reader(&self.dir().push("index")) {
+            Ok(reader) => reader,
+            Err(message) => {
+                debug!("archive: failed to read index: %s", message);
+                return
+            }
+        };
+        while !reader.eof() {
+            let line = reader.read_line();
+            match str::find_char(line, ' ') {
+                Some(space) => {
+                    match uint::from_str(line.slice(0, space)) {
+                        Some(entry) => {
+                            let url = line.slice(space + 1, line.len()).to_owned();
+                            if !self.entries.contains_key(&url) {
+                                self.entries.insert(copy url, ~[]);
+                            }
+                            self.entries.find_mut(&url).unwrap().push(entry);
+                        }
+                        None => debug!("archive: malformed index line `%s`", line),
+                    }
+                }
+                None if line.is_empty() => {}
+                None => debug!("archive: malformed index line `%s`", line),
+            }
+        }
+    }
+
+    fn store(&mut self, url: &Url, msgs: ~[ProgressMsg]) {
+        let entry = self.next_entry;
+        self.next_entry += 1;
+        debug!("archive: recording %s as entry %u", url::to_str(url), entry);
+
+        let path = self.dir().push(entry.to_str());
+        match io::file_writer(&path, [io::Create, io::Truncate]) {
+            Ok(writer) => write_entry(writer, msgs),
+            Err(message) => {
+                debug!("archive: failed to write %s: %s", path.to_str(), message);
+                return
+            }
+        }
+        match io::file_writer(&self.dir().push("index"), [io::Create, io::Append]) {
+            Ok(writer) => writer.write_line(fmt!("%u %s", entry, url::to_str(url))),
+            Err(message) => debug!("archive: failed to update index: %s", message),
+        }
+    }
+
+    fn take(&mut self, url: &Url) -> Option<~[ProgressMsg]> {
+        let entry = match self.entries.find_mut(&url::to_str(url)) {
+            Some(entries) if !entries.is_empty() => entries.remove(0),
+            _ => return None,
+        };
+        let path = self.dir().push(entry.to_str());
+        let result = match io::file_reader(&path) {
+            Ok(reader) => read_entry(reader),
+            Err(message) => Err(message),
+        };
+        match result {
+            Ok(msgs) => Some(msgs),
+            Err(message) => {
+                debug!("archive: failed to read %s: %s", path.to_str(), message);
+                None
+            }
+        }
+    }
+}
+
+/// Writes progress messages one per line, as a keyword followed by its arguments. Metadata fields
+/// get lines of their own after the `metadata` line, and payloads are followed by their bytes.
+fn write_entry(writer: @Writer, msgs: &[ProgressMsg]) {
+    for msgs.each |msg| {
+        match *msg {
+            Metadata(ref metadata) => {
+                writer.write_line(~"metadata " + url::to_str(&metadata.final_url));
+                for metadata.status.each |status| {
+                    writer.write_line(fmt!("status %u", *status));
+                }
+                for metadata.content_type.each |&(ref type_, ref subtype)| {
+                    writer.write_line(fmt!("content-type %s %s", *type_, *subtype));
+                }
+                for metadata.charset.each |charset| {
+                    writer.write_line(~"charset " + *charset);
+                }
+                for metadata.headers.each |&(ref name, ref value)| {
+                    writer.write_line(fmt!("header %s: %s", *name, *value));
+                }
+            }
+            Payload(ref data) => {
+                writer.write_line(fmt!("payload %u", data.len()));
+                writer.write(*data);
+                writer.write_line("");
+            }
+            Done(Ok(())) => writer.write_line("done ok"),
+            Done(Err(())) => writer.write_line("done err"),
+            Aborted => writer.write_line("aborted"),
+        }
+    }
+}
+
+/// Reads progress messages written by `write_entry`.
+fn read_entry(reader: @Reader) -> Result<~[ProgressMsg], ~str> {
+    let mut msgs = ~[];
+    let mut metadata = None;
+    while !reader.eof() {
+        let line = reader.read_line();
+        if line.is_empty() {
+            loop
+        }
+        let (keyword, rest) = match str::find_char(line, ' ') {
+            Some(space) => (line.slice(0, space).to_owned(),
+                            line.slice(space + 1, line.len()).to_owned()),
+            None => (copy line, ~""),
+        };
+
+        // Metadata fields belong to the last `metadata` line.
+        let is_field = match keyword {
+            ~"status" | ~"content-type" | ~"charset" | ~"header" => true,
+            _ => false,
+        };
+        if is_field {
+            let metadata = match metadata {
+                Some(ref mut metadata) => metadata,
+                None => return Err(fmt!("`%s` outside metadata", line)),
+            };
+            match keyword {
+                ~"status" => metadata.status = uint::from_str(rest),
+                ~"content-type" => {
+                    match str::find_char(rest, ' ') {
+                        Some(space) => {
+                            metadata.content_type = Some((rest.slice(0, space).to_owned(),
+                                                          rest.slice(space + 1,
+                                                                     rest.len()).to_owned()))
+                        }
+                        None => return Err(fmt!("malformed content type `%s`", rest)),
+                    }
+                }
+                ~"charset" => metadata.charset = Some(rest),
+                _ => {
+                    match str::find_str(rest, ": ") {
+                        Some(colon) => {
+                            let name = rest.slice(0, colon).to_owned();
+                            let value = rest.slice(colon + 2, rest.len()).to_owned();
+                            metadata.headers.push((name, value));
+                        }
+                        None => return Err(fmt!("malformed header `%s`", rest)),
+                    }
+                }
+            }
+            loop
+        }
+
+        match replace(&mut metadata, None) {
+            Some(metadata) => msgs.push(Metadata(metadata)),
+            None => {}
+        }
+        match keyword {
+            ~"metadata" => {
+                match url::from_str(rest) {
+                    Ok(url) => metadata = Some(Metadata::default(url)),
+                    Err(message) => return Err(message),
+                }
+            }
+            ~"payload" => {
+                match uint::from_str(rest) {
+                    Some(length) => msgs.push(Payload(reader.read_bytes(length))),
+                    None => return Err(fmt!("malformed payload length `%s`", rest)),
+                }
+            }
+            ~"done" => msgs.push(Done(if rest == ~"ok" { Ok(()) } else { Err(()) })),
+            ~"aborted" => msgs.push(Aborted),
+            _ => return Err(fmt!("unknown record `%s`", line)),
+        }
+    }
+    match metadata {
+        Some(metadata) => msgs.push(Metadata(metadata)),
+        None => {}
+    }
+    Ok(msgs)
+}
+
+#[test]
+fn should_replay_recorded_loads() {
+    let dir = os::tmpdir().push(fmt!("servo-archive-test-%?", std::time::precise_time_ns()));
+    let url = url::from_str(~"http://example.com/").get();
+    let mut metadata = Metadata::default(url.clone());
+    metadata.status = Some(200);
+    metadata.headers = ~[(~"Content-Type", ~"text/html; charset=utf-8"), (~"X-Empty", ~"")];
+    metadata.set_content_type("text/html; charset=utf-8");
+    // Payloads may contain anything, including what looks like records.
+    let msgs = ~[
+        Metadata(metadata),
+        Payload(str::to_bytes("done ok\n")),
+        Payload(~[0, 10, 255]),
+        Done(Ok(())),
+    ];
+
+    let msgs_cell = Cell(copy msgs);
+    let factory: LoaderTaskFactory = || {
+        let loader: LoaderTask = |_url, _headers, progress_chan| {
+            for msgs_cell.take().each |msg| {
+                progress_chan.send(copy *msg);
+            }
+        };
+        loader
+    };
+    let archive = ArchiveTask(Record(copy dir));
+    let recording_loader = recording_factory(archive.clone(), factory)();
+    let (progress_port, progress_chan) = stream();
+    recording_loader(url.clone(), ~[], progress_chan);
+    for msgs.each |msg| {
+        assert!(progress_port.recv() == *msg);
+    }
+    // Wait for the archive to write the entry.
+    let (response_port, response_chan) = stream();
+    archive.send(Take(url.clone(), response_chan));
+    assert!(response_port.recv().is_none());
+
+    let replay_loader = replay_factory(ArchiveTask(Replay(copy dir)))();
+    let (progress_port, progress_chan) = stream();
+    replay_loader(url.clone(), ~[], progress_chan);
+    for msgs.each |msg| {
+        assert!(progress_port.recv() == *msg);
+    }
+
+    // The load was only recorded once.
+    let (progress_port, progress_chan) = stream();
+    replay_loader(url.clone(), ~[], progress_chan);
+    assert!(progress_port.recv() == Done(Err(())));
+}
+
+#[test]
+fn should_only_replay_network_schemes() {
+    let dir = os::tmpdir().push(fmt!("servo-archive-test-%?", std::time::precise_time_ns()));
+    fn done_factory() -> LoaderTaskFactory {
+        || {
+            let loader: LoaderTask = |_url, _headers, progress_chan| {
+                progress_chan.send(Done(Ok(())));
+            };
+            loader
+        }
+    }
+    let loaders = ~[(~"data", done_factory()), (~"http", done_factory())];
+    let loaders = wrap_loaders(Replay(dir), loaders);
+    for loaders.each |&(ref scheme, ref factory)| {
+        let url = url::from_str(fmt!("%s:,", *scheme)).get();
+        let (progress_port, progress_chan) = stream();
+        (*factory)()(url, ~[], progress_chan);
+        let expected = if *scheme == ~"http" { Done(Err(())) } else { Done(Ok(())) };
+        assert!(progress_port.recv() == expected);
+    }
+}
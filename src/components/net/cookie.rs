@@ -0,0 +1,424 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Cookies, as described in RFC 6265, and the jar the resource task keeps them in.
+
+use util::parse_http_date;
+
+use core::io::{ReaderUtil, WriterUtil};
+use std::net::url::Url;
+use std::net::url;
+use std::sort;
+
+/// Where a cookie is being read or written from. Cookies marked `HttpOnly` can only be read and
+/// replaced by HTTP responses, not by scripts.
+#[deriving(Eq)]
+pub enum CookieSource {
+    HTTP,
+    NonHTTP,
+}
+
+#[deriving(Eq)]
+pub struct Cookie {
+    name: ~str,
+    value: ~str,
+    /// The lowercased host that set the cookie, or the domain named by its `Domain` attribute.
+    domain: ~str,
+    /// True if the cookie had no `Domain` attribute, and so is only sent to `domain` itself.
+    host_only: bool,
+    path: ~str,
+    secure: bool,
+    http_only: bool,
+    /// When the cookie expires, in seconds since the epoch. Session cookies never do.
+    expires: Option<i64>,
+}
+
+pub impl Cookie {
+    /// Parses the value of a `Set-Cookie` header, or a string assigned to `document.cookie`, on
+    /// behalf of the given URL. Returns `None` for malformed cookies and for cookies the URL may
+    /// not set.
+    fn parse(header: &str, url: &Url, now: i64) -> Option<Cookie> {
+        let mut parts = ~[];
+        for str::each_split_char(header, ';') |part| {
+            parts.push(part.trim().to_owned());
+        }
+        if parts.is_empty() {
+            return None
+        }
+
+        let (name, value) = match split_pair(parts[0]) {
+            Some(pair) => pair,
+            None => return None,
+        };
+        if name.is_empty() {
+            return None
+        }
+
+        let host = str::to_lower(url.host);
+        let mut cookie = Cookie {
+            name: name,
+            value: value,
+            domain: copy host,
+            host_only: true,
+            path: default_path(url),
+            secure: false,
+            http_only: false,
+            expires: None,
+        };
+
+        let mut max_age = None;
+        for parts.tailn(1).each |attribute| {
+            let (attribute_name, attribute_value) = match split_pair(*attribute) {
+                Some((name, value)) => (str::to_lower(name), value),
+                None => (str::to_lower(*attribute), ~""),
+            };
+            match attribute_name {
+                ~"expires" => {
+                    match parse_http_date(attribute_value) {
+                        Some(expires) => cookie.expires = Some(expires),
+                        None => {}
+                    }
+                }
+                ~"max-age" => {
+                    match i64::from_str(attribute_value) {
+                        Some(seconds) => max_age = Some(seconds),
+                        None => {}
+                    }
+                }
+                ~"domain" => {
+                    let domain = str::to_lower(attribute_value.trim_left_chars(&['.']));
+                    if domain.is_empty() {
+                        loop
+                    }
+                    // A domain without any dots, such as `com`, would cover too many sites.
+                    if !domain_matches(host, domain) ||
+                            (domain != host && !domain.contains_char('.')) {
+                        debug!("cookie: %s may not set cookies for %s", url::to_str(url), domain);
+                        return None
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                ~"path" => {
+                    if attribute_value.starts_with("/") {
+                        cookie.path = attribute_value;
+                    }
+                }
+                ~"secure" => cookie.secure = true,
+                ~"httponly" => cookie.http_only = true,
+                _ => {}
+            }
+        }
+
+        // Max-Age takes precedence over Expires. A zero or negative age expires the cookie at once.
+        match max_age {
+            Some(seconds) => cookie.expires = Some(now + seconds),
+            None => {}
+        }
+
+        Some(cookie)
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        match self.expires {
+            Some(expires) => expires <= now,
+            None => false,
+        }
+    }
+
+    /// Returns true if the cookie should be sent with a request for the URL.
+    fn matches(&self, url: &Url, now: i64) -> bool {
+        let host = str::to_lower(url.host);
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(host, self.domain)
+        };
+        let path = if url.path.is_empty() { ~"/" } else { copy url.path };
+
+        domain_ok && path_matches(path, self.path) && (!self.secure || url.scheme == ~"https") &&
+            !self.is_expired(now)
+    }
+}
+
+/// Splits `name=value` at the first `=`, trimming both halves.
+fn split_pair(pair: &str) -> Option<(~str, ~str)> {
+    match str::find_char(pair, '=') {
+        Some(equals) => {
+            Some((pair.slice(0, equals).trim().to_owned(),
+                  pair.slice(equals + 1, pair.len()).trim().to_owned()))
+        }
+        None => None,
+    }
+}
+
+/// Returns true if the host is the domain or one of its subdomains.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    if host == domain {
+        return true
+    }
+    host.ends_with(domain) && host.len() > domain.len() &&
+        host[host.len() - domain.len() - 1] == '.' as u8
+}
+
+/// Returns true if the request path is the cookie path or lies beneath it.
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    if request_path == cookie_path {
+        return true
+    }
+    request_path.starts_with(cookie_path) &&
+        (cookie_path.ends_with("/") || request_path[cookie_path.len()] == '/' as u8)
+}
+
+/// The path used for cookies without a `Path` attribute: the directory of the URL's path.
+fn default_path(url: &Url) -> ~str {
+    if !url.path.starts_with("/") {
+        return ~"/"
+    }
+    match str::rfind_char(url.path, '/') {
+        Some(0) | None => ~"/",
+        Some(slash) => url.path.slice(0, slash).to_owned(),
+    }
+}
+
+/// Marks `HttpOnly` cookies in `cookies.txt` files, as curl does.
+static HTTP_ONLY_PREFIX: &'static str = "#HttpOnly_";
+
+/// The cookies known to a resource task.
+pub struct CookieJar {
+    cookies: ~[Cookie],
+}
+
+pub impl CookieJar {
+    fn new() -> CookieJar {
+        CookieJar {
+            cookies: ~[],
+        }
+    }
+
+    /// Stores a cookie, replacing any with the same name, domain and path. An expired cookie
+    /// just deletes the one it replaces.
+    fn set_cookie(&mut self, cookie: Cookie, source: CookieSource, now: i64) {
+        if cookie.http_only && source == NonHTTP {
+            return
+        }
+
+        let existing = do self.cookies.position |c| {
+            c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path
+        };
+        match existing {
+            Some(index) => {
+                if self.cookies[index].http_only && source == NonHTTP {
+                    return
+                }
+                self.cookies.remove(index);
+            }
+            None => {}
+        }
+
+        if !cookie.is_expired(now) {
+            self.cookies.push(cookie);
+        }
+    }
+
+    /// Returns the cookies to send with a request for the URL, formatted as the value of a
+    /// `Cookie` header. Cookies with longer paths come first.
+    fn cookies_for_url(&self, url: &Url, source: CookieSource, now: i64) -> Option<~str> {
+        let mut matching = ~[];
+        for self.cookies.each |cookie| {
+            if cookie.matches(url, now) && !(cookie.http_only && source == NonHTTP) {
+                matching.push(cookie);
+            }
+        }
+        if matching.is_empty() {
+            return None
+        }
+
+        let matching = sort::merge_sort(matching, |a, b| a.path.len() >= b.path.len());
+        let pairs = do matching.map |cookie| {
+            fmt!("%s=%s", cookie.name, cookie.value)
+        };
+        Some(str::connect(pairs, "; "))
+    }
+
+    /// Adds the cookies from a file in the Netscape `cookies.txt` format, as written by `save`.
+    fn load(&mut self, path: &Path, now: i64) -> Result<(), ~str> {
+        let reader = match io::file_reader(path) {
+            Ok(reader) => reader,
+            Err(message) => return Err(message),
+        };
+
+        while !reader.eof() {
+            let mut line = reader.read_line();
+            let http_only = line.starts_with(HTTP_ONLY_PREFIX);
+            if http_only {
+                line = line.slice(HTTP_ONLY_PREFIX.len(), line.len()).to_owned();
+            }
+            if line.is_empty() || line.starts_with("#") {
+                loop
+            }
+
+            let fields = str::split_char(line, '\t');
+            if fields.len() != 7 {
+                debug!("cookie: ignoring malformed line `%s`", line);
+                loop
+            }
+            let expires = match i64::from_str(fields[4]) {
+                Some(0) => None,
+                Some(expires) => Some(expires),
+                None => loop,
+            };
+            let cookie = Cookie {
+                name: copy fields[5],
+                value: copy fields[6],
+                domain: fields[0].trim_left_chars(&['.']).to_owned(),
+                host_only: fields[1] != ~"TRUE",
+                path: copy fields[2],
+                secure: fields[3] == ~"TRUE",
+                http_only: http_only,
+                expires: expires,
+            };
+            self.set_cookie(cookie, HTTP, now);
+        }
+        Ok(())
+    }
+
+    /// Writes the cookies that outlive the session to a file in the Netscape `cookies.txt` format.
+    fn save(&self, path: &Path, now: i64) -> Result<(), ~str> {
+        let writer = match io::file_writer(path, [io::Create, io::Truncate]) {
+            Ok(writer) => writer,
+            Err(message) => return Err(message),
+        };
+
+        writer.write_line("# Netscape HTTP Cookie File");
+        for self.cookies.each |cookie| {
+            let expires = match cookie.expires {
+                Some(expires) if expires > now => expires,
+                _ => loop,
+            };
+            let prefix = if cookie.http_only { HTTP_ONLY_PREFIX } else { "" };
+            let domain = if cookie.host_only {
+                copy cookie.domain
+            } else {
+                ~"." + cookie.domain
+            };
+            let flag = |value: bool| if value { "TRUE" } else { "FALSE" };
+            writer.write_line(fmt!("%s%s\t%s\t%s\t%s\t%s\t%s\t%s",
+                                   prefix,
+                                   domain,
+                                   flag(!cookie.host_only),
+                                   cookie.path,
+                                   flag(cookie.secure),
+                                   expires.to_str(),
+                                   cookie.name,
+                                   cookie.value));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn parse_for(header: &str, url: &str) -> Option<Cookie> {
+    Cookie::parse(header, &url::from_str(url.to_owned()).get(), 1000)
+}
+
+#[test]
+fn should_parse_attributes() {
+    let header = "id=a3fWa; Max-Age=60; Domain=.Example.com; Path=/docs; Secure; HttpOnly";
+    let cookie = parse_for(header, "http://www.example.com/").get();
+    assert!(cookie.name == ~"id" && cookie.value == ~"a3fWa");
+    assert!(cookie.domain == ~"example.com" && !cookie.host_only);
+    assert!(cookie.path == ~"/docs");
+    assert!(cookie.secure && cookie.http_only);
+    assert!(cookie.expires == Some(1060));
+}
+
+#[test]
+fn should_default_to_host_and_directory() {
+    let cookie = parse_for("a=b; Path=relative", "http://www.example.com/docs/page.html").get();
+    assert!(cookie.domain == ~"www.example.com" && cookie.host_only);
+    assert!(cookie.path == ~"/docs");
+    assert!(cookie.expires.is_none());
+
+    let cookie = parse_for("a=b", "http://www.example.com/page.html").get();
+    assert!(cookie.path == ~"/");
+}
+
+#[test]
+fn should_reject_foreign_domains_and_malformed_cookies() {
+    assert!(parse_for("a=b; Domain=other.com", "http://www.example.com/").is_none());
+    assert!(parse_for("a=b; Domain=com", "http://www.example.com/").is_none());
+    assert!(parse_for("a=b; Domain=ample.com", "http://www.example.com/").is_none());
+    assert!(parse_for("no-equals-sign", "http://www.example.com/").is_none());
+    assert!(parse_for("=b", "http://www.example.com/").is_none());
+}
+
+#[test]
+fn should_send_matching_cookies_longest_path_first() {
+    let mut jar = CookieJar::new();
+    let url = url::from_str(~"http://www.example.com/docs/").get();
+    for [
+        "root=1; Path=/",
+        "docs=2; Path=/docs",
+        "other=3; Path=/other",
+        "secure=4; Path=/; Secure",
+        "sub=5; Domain=sub.www.example.com",
+    ].each |header| {
+        match Cookie::parse(*header, &url, 1000) {
+            Some(cookie) => jar.set_cookie(cookie, HTTP, 1000),
+            None => {}
+        }
+    }
+
+    assert!(jar.cookies_for_url(&url, HTTP, 1000) == Some(~"docs=2; root=1"));
+    let secure_url = url::from_str(~"https://www.example.com/").get();
+    assert!(jar.cookies_for_url(&secure_url, HTTP, 1000) == Some(~"root=1; secure=4"));
+    let other_host = url::from_str(~"http://example.com/docs/").get();
+    assert!(jar.cookies_for_url(&other_host, HTTP, 1000).is_none());
+}
+
+#[test]
+fn should_replace_and_expire_cookies() {
+    let mut jar = CookieJar::new();
+    let url = url::from_str(~"http://example.com/").get();
+    jar.set_cookie(Cookie::parse("a=1", &url, 1000).get(), HTTP, 1000);
+    jar.set_cookie(Cookie::parse("a=2; Max-Age=10", &url, 1000).get(), HTTP, 1000);
+    assert!(jar.cookies_for_url(&url, HTTP, 1000) == Some(~"a=2"));
+    assert!(jar.cookies_for_url(&url, HTTP, 1010).is_none());
+
+    jar.set_cookie(Cookie::parse("a=3", &url, 1000).get(), HTTP, 1000);
+    jar.set_cookie(Cookie::parse("a=; Max-Age=0", &url, 1000).get(), HTTP, 1000);
+    assert!(jar.cookies.is_empty());
+}
+
+#[test]
+fn should_hide_http_only_cookies_from_scripts() {
+    let mut jar = CookieJar::new();
+    let url = url::from_str(~"http://example.com/").get();
+    jar.set_cookie(Cookie::parse("session=1; HttpOnly", &url, 1000).get(), HTTP, 1000);
+    jar.set_cookie(Cookie::parse("theme=dark", &url, 1000).get(), NonHTTP, 1000);
+    // Scripts can neither see nor overwrite the HttpOnly cookie.
+    jar.set_cookie(Cookie::parse("session=2", &url, 1000).get(), NonHTTP, 1000);
+
+    assert!(jar.cookies_for_url(&url, NonHTTP, 1000) == Some(~"theme=dark"));
+    assert!(jar.cookies_for_url(&url, HTTP, 1000) == Some(~"session=1; theme=dark"));
+}
+
+#[test]
+fn should_save_and_load_persistent_cookies() {
+    use std::time;
+
+    let path = os::tmpdir().push(fmt!("servo-cookie-test-%?", time::precise_time_ns()));
+    let url = url::from_str(~"http://www.example.com/").get();
+    let mut jar = CookieJar::new();
+    jar.set_cookie(Cookie::parse("a=1; Max-Age=60; HttpOnly", &url, 1000).get(), HTTP, 1000);
+    jar.set_cookie(Cookie::parse("b=2; Max-Age=60; Domain=example.com", &url, 1000).get(),
+                   HTTP, 1000);
+    jar.set_cookie(Cookie::parse("session=3", &url, 1000).get(), HTTP, 1000);
+    assert!(jar.save(&path, 1000).is_ok());
+
+    let mut loaded = CookieJar::new();
+    assert!(loaded.load(&path, 1000).is_ok());
+    assert!(loaded.cookies == vec::from_slice(vec::slice(jar.cookies, 0, 2)));
+}
@@ -0,0 +1,270 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A loader for `data:` URLs, as described in RFC 2397.
+
+use resource_task::{Done, LoaderTask, Metadata, Payload};
+
+use core::comm::GenericSmartChan;
+use core::task;
+use std::net::url::Url;
+use std::net::url;
+use servo_util::url_parser;
+
+/// The media type assumed when a `data:` URL does not declare one.
+static DEFAULT_MEDIA_TYPE: &'static str = "text/plain";
+/// The charset assumed when a `data:` URL declares neither a media type nor a charset.
+static DEFAULT_CHARSET: &'static str = "us-ascii";
+
+/// The decoded contents of a `data:` URL.
+pub struct DataUrl {
+    /// The declared media type, lowercased and without parameters, e.g. `image/png`.
+    media_type: ~str,
+    /// The declared charset, lowercased, if any.
+    charset: Option<~str>,
+    /// The decoded payload.
+    data: ~[u8],
+}
+
+pub fn factory() -> LoaderTask {
+    let f: LoaderTask = |url, _headers, progress_chan| {
+        assert!("data" == url.scheme);
+        do task::spawn {
+            match parse(&url) {
+                Ok(data_url) => {
+                    debug!("data_loader: loaded %u bytes of %s", data_url.data.len(),
+                           data_url.media_type);
+                    let DataUrl { media_type: media_type, charset: charset, data: data } = data_url;
+                    let mut metadata = Metadata::default(url.clone());
+                    metadata.set_content_type(media_type);
+                    metadata.charset = charset;
+                    if progress_chan.try_send(Metadata(metadata)) &&
+                            progress_chan.try_send(Payload(data)) {
+                        progress_chan.try_send(Done(Ok(())));
+                    }
+                }
+                Err(()) => {
+                    debug!("data_loader: malformed url %s", url::to_str(&url));
+                    progress_chan.try_send(Done(Err(())));
+                }
+            }
+        }
+    };
+    f
+}
+
+/// Splits a `data:` URL into its media type, charset and decoded payload. Both base64 and
+/// percent-encoded payloads are supported.
+pub fn parse(url: &Url) -> Result<DataUrl, ()> {
+    assert!("data" == url.scheme);
+
+    // The URL parser splits off anything after a '?' as a query, but for `data:` URLs it is part
+    // of the payload. The fragment isn't.
+    let mut body = copy url.path;
+    match url_parser::query_str(url) {
+        Some(query) => body = body + "?" + query,
+        None => {}
+    }
+
+    let comma = match str::find_char(body, ',') {
+        Some(comma) => comma,
+        None => return Err(()),
+    };
+    let header = body.slice(0, comma);
+    let payload = body.slice(comma + 1, body.len());
+
+    let mut params = ~[];
+    for str::each_split_char(header, ';') |param| {
+        params.push(param.trim().to_owned());
+    }
+
+    let is_base64 = params.len() > 1 && str::to_lower(*params.last()) == ~"base64";
+    if is_base64 {
+        params.pop();
+    }
+
+    let mut media_type = str::to_lower(params[0]);
+    let mut charset = None;
+    for params.tailn(1).each |param| {
+        let lower = str::to_lower(*param);
+        if lower.starts_with("charset=") {
+            charset = Some(lower.slice(8, lower.len()).to_owned());
+        }
+    }
+
+    if media_type.is_empty() {
+        media_type = DEFAULT_MEDIA_TYPE.to_owned();
+        if charset.is_none() {
+            charset = Some(DEFAULT_CHARSET.to_owned());
+        }
+    }
+
+    let data = percent_decode(str::to_bytes(payload));
+    let data = if is_base64 {
+        match base64_decode(data) {
+            Ok(data) => data,
+            Err(()) => return Err(()),
+        }
+    } else {
+        data
+    };
+
+    Ok(DataUrl {
+        media_type: media_type,
+        charset: charset,
+        data: data,
+    })
+}
+
+/// Replaces `%XX` escapes with the bytes they encode. Malformed escapes are left alone.
+fn percent_decode(bytes: &[u8]) -> ~[u8] {
+    let mut result = vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == '%' as u8 && i + 2 < bytes.len() {
+            match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    result.push(high * 16 + low);
+                    i += 3;
+                    loop
+                }
+                _ => {}
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    result
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte as char {
+        '0'..'9' => Some(byte - ('0' as u8)),
+        'a'..'f' => Some(byte - ('a' as u8) + 10),
+        'A'..'F' => Some(byte - ('A' as u8) + 10),
+        _ => None,
+    }
+}
+
+/// Decodes base64, ignoring whitespace and trailing padding.
+fn base64_decode(bytes: &[u8]) -> Result<~[u8], ()> {
+    let mut result = vec::with_capacity(bytes.len() * 3 / 4);
+    let mut accumulator = 0u32;
+    let mut bits = 0u;
+    let mut seen_padding = false;
+
+    for bytes.each |&byte| {
+        let value = match byte as char {
+            'A'..'Z' => byte - ('A' as u8),
+            'a'..'z' => byte - ('a' as u8) + 26,
+            '0'..'9' => byte - ('0' as u8) + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            '=' => {
+                seen_padding = true;
+                loop
+            }
+            ' ' | '\t' | '\n' | '\r' | '\x0c' => loop,
+            _ => return Err(()),
+        };
+
+        // Nothing but padding and whitespace may follow padding.
+        if seen_padding {
+            return Err(());
+        }
+
+        accumulator = (accumulator << 6) | (value as u32);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((accumulator >> bits) as u8);
+        }
+    }
+
+    // A single leftover character can't encode a whole byte.
+    if bits >= 6 {
+        return Err(());
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+fn load(url_str: ~str) -> ~[::resource_task::ProgressMsg] {
+    let (port, chan) = comm::stream();
+    factory()(url_parser::parse(url_str, None).get(), ~[], chan);
+
+    let mut messages = ~[];
+    loop {
+        let msg = port.recv();
+        let done = match msg { Done(*) => true, _ => false };
+        messages.push(msg);
+        if done {
+            return messages
+        }
+    }
+}
+
+#[test]
+fn should_load_percent_encoded_data() {
+    let messages = load(~"data:,hello%20world");
+    assert!(messages.len() == 3);
+    assert!(messages[1] == Payload(str::to_bytes("hello world")));
+    assert!(messages[2] == Done(Ok(())));
+}
+
+#[test]
+fn should_load_base64_data() {
+    let messages = load(~"data:text/plain;base64,aGVsbG8gd29ybGQ=");
+    assert!(messages.len() == 3);
+    assert!(messages[1] == Payload(str::to_bytes("hello world")));
+    assert!(messages[2] == Done(Ok(())));
+}
+
+#[test]
+fn should_send_declared_media_type_as_metadata() {
+    let messages = load(~"data:image/png;base64,AAAA");
+    match messages[0] {
+        Metadata(ref metadata) => {
+            assert!(metadata.content_type == Some((~"image", ~"png")));
+            assert!(metadata.charset.is_none());
+        }
+        _ => fail!(~"expected metadata before the payload")
+    }
+}
+
+#[test]
+fn should_fail_on_invalid_base64() {
+    let messages = load(~"data:text/plain;base64,*****");
+    assert!(messages == ~[Done(Err(()))]);
+}
+
+#[test]
+fn should_fail_without_comma() {
+    let messages = load(~"data:text/html");
+    assert!(messages == ~[Done(Err(()))]);
+}
+
+#[test]
+fn should_default_to_us_ascii_text() {
+    let data_url = result::unwrap(parse(&url::from_str(~"data:,abc").get()));
+    assert!(data_url.media_type == ~"text/plain");
+    assert!(data_url.charset == Some(~"us-ascii"));
+}
+
+#[test]
+fn should_report_declared_media_type_and_charset() {
+    let url = url::from_str(~"data:Text/HTML;charset=UTF-8,%3Cp%3E").get();
+    let data_url = result::unwrap(parse(&url));
+    assert!(data_url.media_type == ~"text/html");
+    assert!(data_url.charset == Some(~"utf-8"));
+    assert!(data_url.data == str::to_bytes("<p>"));
+}
+
+#[test]
+fn should_keep_query_but_not_fragment_in_payload() {
+    let url = url_parser::parse("data:,a?b=c+d%26e&&f#g", None).get();
+    let data_url = result::unwrap(parse(&url));
+    assert!(data_url.data == str::to_bytes("a?b=c+d&e&&f"));
+}
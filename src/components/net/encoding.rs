@@ -0,0 +1,765 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Detecting the character encoding of HTML documents and style sheets, and decoding them to
+//! Unicode.
+//!
+//! The encoding is taken from a byte order mark, then the charset from the `Content-Type` header,
+//! then a `<meta>` element or `@charset` rule near the start of the resource. Encodings are named
+//! as in the WHATWG Encoding Standard, which also lists the labels that may be used for them.
+//! UTF-8, UTF-16 and windows-1252 are decoded here; other encodings are decoded by iconv.
+
+use core::libc::consts::os::posix88::{E2BIG, EINVAL};
+use core::libc::{c_char, c_int, c_void, size_t};
+use core::util::replace;
+
+/// The number of bytes searched for a `<meta>` element or `@charset` rule.
+pub static PRESCAN_LENGTH: uint = 1024;
+
+/// The character that replaces malformed input.
+static REPLACEMENT_CHARACTER: char = '�';
+
+/// The code points of the bytes 0x80 to 0x9f in windows-1252. The rest are the same as in
+/// Latin-1.
+static WINDOWS_1252_HIGH: [u16, ..32] = [
+    0x20ac, 0x0081, 0x201a, 0x0192, 0x201e, 0x2026, 0x2020, 0x2021,
+    0x02c6, 0x2030, 0x0160, 0x2039, 0x0152, 0x008d, 0x017d, 0x008f,
+    0x0090, 0x2018, 0x2019, 0x201c, 0x201d, 0x2022, 0x2013, 0x2014,
+    0x02dc, 0x2122, 0x0161, 0x203a, 0x0153, 0x009d, 0x017e, 0x0178,
+];
+
+/// The kinds of resource whose encoding can be detected.
+pub enum Syntax {
+    HtmlSyntax,
+    CssSyntax,
+}
+
+/// Returns the name of the encoding with the given label, if there is one.
+pub fn resolve_label(label: &str) -> Option<~str> {
+    let name = match str::to_lower(label.trim()) {
+        ~"unicode-1-1-utf-8" | ~"utf-8" | ~"utf8" => "UTF-8",
+        ~"csunicode" | ~"iso-10646-ucs-2" | ~"ucs-2" | ~"unicode" | ~"unicodefeff" |
+        ~"utf-16" | ~"utf-16le" => "UTF-16LE",
+        ~"unicodefffe" | ~"utf-16be" => "UTF-16BE",
+        ~"ansi_x3.4-1968" | ~"ascii" | ~"cp1252" | ~"cp819" | ~"csisolatin1" | ~"ibm819" |
+        ~"iso-8859-1" | ~"iso-ir-100" | ~"iso8859-1" | ~"iso88591" | ~"iso_8859-1" |
+        ~"iso_8859-1:1987" | ~"l1" | ~"latin1" | ~"us-ascii" | ~"windows-1252" |
+        ~"x-cp1252" => "windows-1252",
+        ~"866" | ~"cp866" | ~"csibm866" | ~"ibm866" => "IBM866",
+        ~"csisolatin2" | ~"iso-8859-2" | ~"iso-ir-101" | ~"iso8859-2" | ~"iso88592" |
+        ~"iso_8859-2" | ~"iso_8859-2:1987" | ~"l2" | ~"latin2" => "ISO-8859-2",
+        ~"csisolatin3" | ~"iso-8859-3" | ~"iso-ir-109" | ~"iso8859-3" | ~"iso88593" |
+        ~"iso_8859-3" | ~"l3" | ~"latin3" => "ISO-8859-3",
+        ~"csisolatin4" | ~"iso-8859-4" | ~"iso-ir-110" | ~"iso8859-4" | ~"iso88594" |
+        ~"iso_8859-4" | ~"l4" | ~"latin4" => "ISO-8859-4",
+        ~"csisolatincyrillic" | ~"cyrillic" | ~"iso-8859-5" | ~"iso-ir-144" | ~"iso8859-5" |
+        ~"iso88595" | ~"iso_8859-5" => "ISO-8859-5",
+        ~"arabic" | ~"asmo-708" | ~"csisolatinarabic" | ~"ecma-114" | ~"iso-8859-6" |
+        ~"iso-ir-127" | ~"iso8859-6" | ~"iso88596" | ~"iso_8859-6" => "ISO-8859-6",
+        ~"csisolatingreek" | ~"ecma-118" | ~"elot_928" | ~"greek" | ~"greek8" |
+        ~"iso-8859-7" | ~"iso-ir-126" | ~"iso8859-7" | ~"iso88597" | ~"iso_8859-7" => "ISO-8859-7",
+        ~"csisolatinhebrew" | ~"hebrew" | ~"iso-8859-8" | ~"iso-ir-138" | ~"iso8859-8" |
+        ~"iso88598" | ~"iso_8859-8" | ~"visual" => "ISO-8859-8",
+        ~"csisolatin6" | ~"iso-8859-10" | ~"iso-ir-157" | ~"iso8859-10" | ~"iso885910" |
+        ~"l6" | ~"latin6" => "ISO-8859-10",
+        ~"iso-8859-13" | ~"iso8859-13" | ~"iso885913" => "ISO-8859-13",
+        ~"iso-8859-14" | ~"iso8859-14" | ~"iso885914" => "ISO-8859-14",
+        ~"csisolatin9" | ~"iso-8859-15" | ~"iso8859-15" | ~"iso885915" | ~"iso_8859-15" |
+        ~"l9" => "ISO-8859-15",
+        ~"iso-8859-16" => "ISO-8859-16",
+        ~"cskoi8r" | ~"koi" | ~"koi8" | ~"koi8-r" | ~"koi8_r" => "KOI8-R",
+        ~"koi8-ru" | ~"koi8-u" => "KOI8-U",
+        ~"dos-874" | ~"iso-8859-11" | ~"iso8859-11" | ~"iso885911" | ~"tis-620" |
+        ~"windows-874" => "windows-874",
+        ~"cp1250" | ~"windows-1250" | ~"x-cp1250" => "windows-1250",
+        ~"cp1251" | ~"windows-1251" | ~"x-cp1251" => "windows-1251",
+        ~"cp1253" | ~"windows-1253" | ~"x-cp1253" => "windows-1253",
+        ~"cp1254" | ~"csisolatin5" | ~"iso-8859-9" | ~"iso-ir-148" | ~"iso8859-9" |
+        ~"iso88599" | ~"iso_8859-9" | ~"l5" | ~"latin5" | ~"windows-1254" |
+        ~"x-cp1254" => "windows-1254",
+        ~"cp1255" | ~"windows-1255" | ~"x-cp1255" => "windows-1255",
+        ~"cp1256" | ~"windows-1256" | ~"x-cp1256" => "windows-1256",
+        ~"cp1257" | ~"windows-1257" | ~"x-cp1257" => "windows-1257",
+        ~"cp1258" | ~"windows-1258" | ~"x-cp1258" => "windows-1258",
+        ~"chinese" | ~"csgb2312" | ~"csiso58gb231280" | ~"gb2312" | ~"gb_2312" |
+        ~"gb_2312-80" | ~"gbk" | ~"iso-ir-58" | ~"x-gbk" => "GBK",
+        ~"gb18030" => "gb18030",
+        ~"big5" | ~"big5-hkscs" | ~"cn-big5" | ~"csbig5" | ~"x-x-big5" => "Big5",
+        ~"cseucpkdfmtjapanese" | ~"euc-jp" | ~"x-euc-jp" => "EUC-JP",
+        ~"csiso2022jp" | ~"iso-2022-jp" => "ISO-2022-JP",
+        ~"csshiftjis" | ~"ms932" | ~"ms_kanji" | ~"shift-jis" | ~"shift_jis" | ~"sjis" |
+        ~"windows-31j" | ~"x-sjis" => "Shift_JIS",
+        ~"cseuckr" | ~"csksc56011987" | ~"euc-kr" | ~"iso-ir-149" | ~"korean" |
+        ~"ks_c_5601-1987" | ~"ks_c_5601-1989" | ~"ksc5601" | ~"ksc_5601" |
+        ~"windows-949" => "EUC-KR",
+        _ => return None,
+    };
+    Some(name.to_owned())
+}
+
+/// Picks the encoding of a resource from its first bytes and the charset from its `Content-Type`
+/// header. Returns the name of the encoding and the length of the byte order mark, if any.
+///
+/// HTML documents that declare no encoding are decoded as UTF-8 if their first bytes are valid
+/// UTF-8, and as windows-1252 otherwise. Style sheets that declare none are decoded as UTF-8.
+pub fn sniff(syntax: Syntax, start: &[u8], http_charset: Option<~str>) -> (~str, uint) {
+    match byte_order_mark(start) {
+        Some(found) => return found,
+        None => {}
+    }
+    match http_charset.chain(|label| resolve_label(label)) {
+        Some(encoding) => return (encoding, 0),
+        None => {}
+    }
+
+    let declared = match syntax {
+        HtmlSyntax => prescan(start),
+        CssSyntax => charset_rule(start),
+    };
+    match declared {
+        // A declaration that could be read as ASCII can't really be UTF-16.
+        Some(~"UTF-16LE") | Some(~"UTF-16BE") => (~"UTF-8", 0),
+        Some(encoding) => (encoding, 0),
+        None => match syntax {
+            HtmlSyntax if !is_utf8(start) => (~"windows-1252", 0),
+            HtmlSyntax | CssSyntax => (~"UTF-8", 0),
+        }
+    }
+}
+
+fn byte_order_mark(start: &[u8]) -> Option<(~str, uint)> {
+    if start.len() >= 3 && start[0] == 0xef && start[1] == 0xbb && start[2] == 0xbf {
+        Some((~"UTF-8", 3))
+    } else if start.len() >= 2 && start[0] == 0xfe && start[1] == 0xff {
+        Some((~"UTF-16BE", 2))
+    } else if start.len() >= 2 && start[0] == 0xff && start[1] == 0xfe {
+        Some((~"UTF-16LE", 2))
+    } else {
+        None
+    }
+}
+
+/// Returns true if the bytes are valid UTF-8, except perhaps for an incomplete character at the
+/// end.
+fn is_utf8(bytes: &[u8]) -> bool {
+    let mut errors = 0;
+    decode_utf8(bytes, &mut ~"", &mut errors);
+    errors == 0
+}
+
+/// Looks for the encoding declared by a `<meta charset>` or `<meta http-equiv="Content-Type">`
+/// element near the start of an HTML document, following the prescan algorithm of the HTML
+/// specification in a simplified form.
+fn prescan(start: &[u8]) -> Option<~str> {
+    let bytes = vec::slice(start, 0, uint::min(start.len(), PRESCAN_LENGTH));
+    let mut i = 0;
+    while i < bytes.len() {
+        if starts_with_at(bytes, i, "<!--") {
+            i = match find_at(bytes, i + 4, "-->") {
+                Some(end) => end + 3,
+                None => return None,
+            };
+            loop
+        }
+
+        if starts_with_at(bytes, i, "<meta") && i + 5 < bytes.len() &&
+                (is_space(bytes[i + 5]) || bytes[i + 5] == '/' as u8) {
+            i += 5;
+            let mut charset = None;
+            let mut content = None;
+            let mut is_content_type = false;
+            loop {
+                match next_attribute(bytes, &mut i) {
+                    Some((name, value)) => {
+                        if name == ~"charset" && charset.is_none() {
+                            charset = Some(value);
+                        } else if name == ~"content" && content.is_none() {
+                            content = Some(value);
+                        } else if name == ~"http-equiv" {
+                            is_content_type = value == ~"content-type";
+                        }
+                    }
+                    None => break,
+                }
+            }
+            let label = match charset {
+                Some(charset) => Some(charset),
+                None if is_content_type => content.chain(|content| charset_from_content(content)),
+                None => None,
+            };
+            match label.chain(|label| resolve_label(label)) {
+                Some(encoding) => return Some(encoding),
+                None => loop,
+            }
+        }
+
+        if bytes[i] == '<' as u8 && i + 1 < bytes.len() {
+            // Skip other tags, processing instructions and the like.
+            let next = bytes[i + 1];
+            if is_letter(next) || next == '/' as u8 || next == '!' as u8 || next == '?' as u8 {
+                i = match find_at(bytes, i + 1, ">") {
+                    Some(end) => end + 1,
+                    None => return None,
+                };
+                loop
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Reads the next attribute of a tag, starting at `*i`. Returns None at the end of the tag.
+fn next_attribute(bytes: &[u8], i: &mut uint) -> Option<(~str, ~str)> {
+    while *i < bytes.len() && (is_space(bytes[*i]) || bytes[*i] == '/' as u8) {
+        *i += 1;
+    }
+    if *i >= bytes.len() || bytes[*i] == '>' as u8 {
+        return None
+    }
+
+    let mut name = ~"";
+    while *i < bytes.len() {
+        let byte = bytes[*i];
+        if is_space(byte) || byte == '/' as u8 || byte == '>' as u8 ||
+                (byte == '=' as u8 && !name.is_empty()) {
+            break
+        }
+        name.push_char(to_lower(byte));
+        *i += 1;
+    }
+    while *i < bytes.len() && is_space(bytes[*i]) {
+        *i += 1;
+    }
+    if *i >= bytes.len() || bytes[*i] != '=' as u8 {
+        return Some((name, ~""))
+    }
+    *i += 1;
+    while *i < bytes.len() && is_space(bytes[*i]) {
+        *i += 1;
+    }
+
+    let mut value = ~"";
+    if *i < bytes.len() && (bytes[*i] == '"' as u8 || bytes[*i] == '\'' as u8) {
+        let quote = bytes[*i];
+        *i += 1;
+        while *i < bytes.len() && bytes[*i] != quote {
+            value.push_char(to_lower(bytes[*i]));
+            *i += 1;
+        }
+        *i += 1;
+    } else {
+        while *i < bytes.len() && !is_space(bytes[*i]) && bytes[*i] != '>' as u8 {
+            value.push_char(to_lower(bytes[*i]));
+            *i += 1;
+        }
+    }
+    Some((name, value))
+}
+
+/// Extracts the charset from the value of a `Content-Type`, as given in a `<meta>` element.
+fn charset_from_content(content: ~str) -> Option<~str> {
+    let start = match str::find_str(content, "charset") {
+        Some(position) => position + 7,
+        None => return None,
+    };
+    let rest = content.slice(start, content.len()).trim_left();
+    if !rest.starts_with("=") {
+        return None
+    }
+    let rest = rest.slice(1, rest.len()).trim_left();
+    let value = if rest.starts_with("\"") || rest.starts_with("'") {
+        let quote = rest.char_at(0);
+        match str::find_char_from(rest, quote, 1) {
+            Some(end) => rest.slice(1, end),
+            None => return None,
+        }
+    } else {
+        match str::find(rest, |c| c == ';' || char::is_whitespace(c)) {
+            Some(end) => rest.slice(0, end),
+            None => rest,
+        }
+    };
+    if value.is_empty() { None } else { Some(value.to_owned()) }
+}
+
+/// Looks for an `@charset "...";` rule at the very start of a style sheet.
+fn charset_rule(start: &[u8]) -> Option<~str> {
+    if !starts_with_at(start, 0, "@charset \"") {
+        return None
+    }
+    let bytes = vec::slice(start, 0, uint::min(start.len(), PRESCAN_LENGTH));
+    match find_at(bytes, 10, "\";") {
+        Some(end) => {
+            let mut label = ~"";
+            for vec::slice(bytes, 10, end).each |byte| {
+                label.push_char(*byte as char);
+            }
+            resolve_label(label)
+        }
+        None => None,
+    }
+}
+
+/// Returns true if the bytes at the position match the ASCII string, ignoring case.
+fn starts_with_at(bytes: &[u8], position: uint, string: &str) -> bool {
+    if position + string.len() > bytes.len() {
+        return false
+    }
+    for uint::range(0, string.len()) |j| {
+        if to_lower(bytes[position + j]) != string[j] as char {
+            return false
+        }
+    }
+    true
+}
+
+fn find_at(bytes: &[u8], position: uint, string: &str) -> Option<uint> {
+    for uint::range(position, bytes.len()) |i| {
+        if starts_with_at(bytes, i, string) {
+            return Some(i)
+        }
+    }
+    None
+}
+
+fn is_space(byte: u8) -> bool {
+    byte == ' ' as u8 || byte == '\t' as u8 || byte == '\n' as u8 || byte == '\x0c' as u8 ||
+        byte == '\r' as u8
+}
+
+fn is_letter(byte: u8) -> bool {
+    (byte >= 'a' as u8 && byte <= 'z' as u8) || (byte >= 'A' as u8 && byte <= 'Z' as u8)
+}
+
+fn to_lower(byte: u8) -> char {
+    if byte >= 'A' as u8 && byte <= 'Z' as u8 {
+        (byte - 'A' as u8 + 'a' as u8) as char
+    } else {
+        byte as char
+    }
+}
+
+/// Decodes a resource as its bytes arrive, holding them back until there are enough to pick an
+/// encoding.
+pub struct SniffingDecoder {
+    priv syntax: Syntax,
+    priv http_charset: Option<~str>,
+    priv buffered: ~[u8],
+    priv decoder: Option<Decoder>,
+}
+
+pub impl SniffingDecoder {
+    fn new(syntax: Syntax, http_charset: Option<~str>) -> SniffingDecoder {
+        SniffingDecoder {
+            syntax: syntax,
+            http_charset: http_charset,
+            buffered: ~[],
+            decoder: None,
+        }
+    }
+
+    /// Decodes the next bytes of the resource. Returns nothing while the encoding is unknown.
+    fn decode(&mut self, bytes: &[u8]) -> ~str {
+        match self.decoder {
+            Some(ref mut decoder) => return decoder.decode(bytes),
+            None => {}
+        }
+        self.buffered.push_all(bytes);
+        if self.buffered.len() < PRESCAN_LENGTH {
+            return ~""
+        }
+        self.start_decoding()
+    }
+
+    /// Decodes whatever is left once all of the resource has arrived.
+    fn finish(&mut self) -> ~str {
+        let text = if self.decoder.is_none() { self.start_decoding() } else { ~"" };
+        match self.decoder {
+            Some(ref mut decoder) => text + decoder.finish(),
+            None => fail!(~"no decoder after sniffing"),
+        }
+    }
+
+    /// The name of the encoding the resource is decoded from, once it is known.
+    fn encoding(&self) -> Option<~str> {
+        match self.decoder {
+            Some(ref decoder) => Some(copy decoder.encoding),
+            None => None,
+        }
+    }
+
+    priv fn start_decoding(&mut self) -> ~str {
+        let buffered = replace(&mut self.buffered, ~[]);
+        let (encoding, bom_length) = sniff(self.syntax, buffered, copy self.http_charset);
+        debug!("encoding: decoding as %s", encoding);
+        let mut decoder = match Decoder::new(encoding) {
+            Some(decoder) => decoder,
+            None => {
+                debug!("encoding: can't decode %s, falling back to UTF-8", encoding);
+                Decoder::new("UTF-8").get()
+            }
+        };
+        let text = decoder.decode(vec::slice(buffered, bom_length, buffered.len()));
+        self.decoder = Some(decoder);
+        text
+    }
+}
+
+/// Decodes text in some encoding, a chunk at a time. Malformed input is replaced with U+FFFD.
+pub struct Decoder {
+    /// The name of the encoding decoded from
+    encoding: ~str,
+    priv kind: DecoderKind,
+    /// Bytes at the end of the last chunk that may be the start of a character
+    priv pending: ~[u8],
+}
+
+enum DecoderKind {
+    Utf8Decoder,
+    Utf16Decoder(bool /* big endian */),
+    Windows1252Decoder,
+    IconvDecoder(Iconv),
+}
+
+pub impl Decoder {
+    /// Creates a decoder for the named encoding, or returns None if it is not supported.
+    fn new(encoding: &str) -> Option<Decoder> {
+        let kind = match encoding {
+            "UTF-8" => Utf8Decoder,
+            "UTF-16LE" => Utf16Decoder(false),
+            "UTF-16BE" => Utf16Decoder(true),
+            "windows-1252" => Windows1252Decoder,
+            _ => match Iconv::new(iconv_name(encoding)) {
+                Some(iconv) => IconvDecoder(iconv),
+                None => return None,
+            }
+        };
+        Some(Decoder {
+            encoding: encoding.to_owned(),
+            kind: kind,
+            pending: ~[],
+        })
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> ~str {
+        let mut input = replace(&mut self.pending, ~[]);
+        input.push_all(bytes);
+
+        let mut output = ~"";
+        let left_over = match self.kind {
+            Utf8Decoder => decode_utf8(input, &mut output, &mut 0),
+            Utf16Decoder(big_endian) => decode_utf16(input, big_endian, &mut output),
+            Windows1252Decoder => {
+                for input.each |byte| {
+                    output.push_char(windows_1252_char(*byte));
+                }
+                0
+            }
+            IconvDecoder(ref iconv) => iconv.convert(input, &mut output),
+        };
+        self.pending = vec::slice(input, input.len() - left_over, input.len()).to_owned();
+        output
+    }
+
+    /// Finishes decoding. An incomplete character at the end of the input is replaced.
+    fn finish(&mut self) -> ~str {
+        if self.pending.is_empty() {
+            ~""
+        } else {
+            self.pending = ~[];
+            str::from_char(REPLACEMENT_CHARACTER)
+        }
+    }
+}
+
+fn windows_1252_char(byte: u8) -> char {
+    if byte >= 0x80 && byte <= 0x9f {
+        WINDOWS_1252_HIGH[(byte - 0x80) as uint] as char
+    } else {
+        byte as char
+    }
+}
+
+/// Decodes UTF-8, counting the malformed sequences. Returns the number of bytes at the end that
+/// may be the start of a character completed by the next chunk.
+fn decode_utf8(input: &[u8], output: &mut ~str, errors: &mut uint) -> uint {
+    let mut i = 0;
+    while i < input.len() {
+        let lead = input[i];
+        // The length of the sequence and the range of its second byte, which rules out overlong
+        // forms, surrogates and code points past U+10FFFF.
+        let (length, min_second, max_second) = match lead {
+            0x00 .. 0x7f => {
+                output.push_char(lead as char);
+                i += 1;
+                loop
+            }
+            0xc2 .. 0xdf => (2, 0x80, 0xbf),
+            0xe0 => (3, 0xa0, 0xbf),
+            0xed => (3, 0x80, 0x9f),
+            0xe1 .. 0xef => (3, 0x80, 0xbf),
+            0xf0 => (4, 0x90, 0xbf),
+            0xf1 .. 0xf3 => (4, 0x80, 0xbf),
+            0xf4 => (4, 0x80, 0x8f),
+            _ => {
+                output.push_char(REPLACEMENT_CHARACTER);
+                *errors += 1;
+                i += 1;
+                loop
+            }
+        };
+
+        let mut valid = 1;
+        while valid < length && i + valid < input.len() {
+            let byte = input[i + valid];
+            let (min, max) = if valid == 1 { (min_second, max_second) } else { (0x80, 0xbf) };
+            if byte < min || byte > max {
+                break
+            }
+            valid += 1;
+        }
+
+        if valid == length {
+            let mut code_point = (lead as u32) & (0x7f >> length);
+            for uint::range(1, length) |j| {
+                code_point = (code_point << 6) | ((input[i + j] as u32) & 0x3f);
+            }
+            output.push_char(code_point as char);
+            i += length;
+        } else if i + valid == input.len() {
+            return input.len() - i
+        } else {
+            output.push_char(REPLACEMENT_CHARACTER);
+            *errors += 1;
+            i += valid;
+        }
+    }
+    0
+}
+
+/// Decodes UTF-16. Returns the number of bytes at the end that may be the start of a character
+/// completed by the next chunk.
+fn decode_utf16(input: &[u8], big_endian: bool, output: &mut ~str) -> uint {
+    let code_unit = |i: uint| -> u32 {
+        if big_endian {
+            ((input[i] as u32) << 8) | (input[i + 1] as u32)
+        } else {
+            ((input[i + 1] as u32) << 8) | (input[i] as u32)
+        }
+    };
+
+    let mut i = 0;
+    while i + 1 < input.len() {
+        let unit = code_unit(i);
+        if unit < 0xd800 || unit > 0xdfff {
+            output.push_char(unit as char);
+            i += 2;
+        } else if unit <= 0xdbff {
+            if i + 3 >= input.len() {
+                break
+            }
+            let trail = code_unit(i + 2);
+            if trail >= 0xdc00 && trail <= 0xdfff {
+                output.push_char((0x10000 + ((unit - 0xd800) << 10) + (trail - 0xdc00)) as char);
+                i += 4;
+            } else {
+                output.push_char(REPLACEMENT_CHARACTER);
+                i += 2;
+            }
+        } else {
+            output.push_char(REPLACEMENT_CHARACTER);
+            i += 2;
+        }
+    }
+    input.len() - i
+}
+
+/// The name iconv knows an encoding by. The WHATWG encodings are supersets of the ones their
+/// names suggest.
+fn iconv_name(encoding: &str) -> ~str {
+    match encoding {
+        "Shift_JIS" => ~"CP932",
+        "EUC-KR" => ~"CP949",
+        "Big5" => ~"BIG5-HKSCS",
+        "windows-874" => ~"CP874",
+        _ => encoding.to_owned(),
+    }
+}
+
+#[allow(non_camel_case_types)]
+type iconv_t = *c_void;
+
+#[cfg(target_os = "linux")]
+#[nolink]
+#[abi = "cdecl"]
+extern mod iconv {
+    unsafe fn iconv_open(tocode: *c_char, fromcode: *c_char) -> iconv_t;
+    unsafe fn iconv(cd: iconv_t,
+                    inbuf: *mut *mut c_char,
+                    inbytesleft: *mut size_t,
+                    outbuf: *mut *mut c_char,
+                    outbytesleft: *mut size_t)
+                    -> size_t;
+    unsafe fn iconv_close(cd: iconv_t) -> c_int;
+}
+
+// iconv is a separate library on Mac OS X.
+#[cfg(target_os = "macos")]
+#[abi = "cdecl"]
+extern mod iconv {
+    unsafe fn iconv_open(tocode: *c_char, fromcode: *c_char) -> iconv_t;
+    unsafe fn iconv(cd: iconv_t,
+                    inbuf: *mut *mut c_char,
+                    inbytesleft: *mut size_t,
+                    outbuf: *mut *mut c_char,
+                    outbytesleft: *mut size_t)
+                    -> size_t;
+    unsafe fn iconv_close(cd: iconv_t) -> c_int;
+}
+
+/// A conversion to UTF-8 by iconv.
+struct Iconv {
+    cd: iconv_t,
+}
+
+impl Drop for Iconv {
+    fn finalize(&self) {
+        unsafe {
+            iconv::iconv_close(self.cd);
+        }
+    }
+}
+
+impl Iconv {
+    fn new(encoding: &str) -> Option<Iconv> {
+        let cd = do str::as_c_str("UTF-8") |to_code| {
+            do str::as_c_str(encoding) |from_code| {
+                unsafe { iconv::iconv_open(to_code, from_code) }
+            }
+        };
+        if cd as int == -1 {
+            None
+        } else {
+            Some(Iconv { cd: cd })
+        }
+    }
+
+    /// Converts as much of the input as possible, replacing malformed sequences. Returns the
+    /// number of bytes at the end that may be the start of a character completed by the next
+    /// chunk.
+    fn convert(&self, input: &[u8], output: &mut ~str) -> uint {
+        let mut converted = ~[];
+        let mut buffer = vec::from_elem(input.len() * 4 + 16, 0u8);
+        let mut position = 0;
+        while position < input.len() {
+            let mut in_left = (input.len() - position) as size_t;
+            let mut out_left = buffer.len() as size_t;
+            let (result, errno) = unsafe {
+                let mut in_ptr = ptr::offset(vec::raw::to_ptr(input), position) as *mut c_char;
+                let mut out_ptr = vec::raw::to_mut_ptr(buffer) as *mut c_char;
+                let result = iconv::iconv(self.cd,
+                                          &mut in_ptr,
+                                          &mut in_left,
+                                          &mut out_ptr,
+                                          &mut out_left);
+                (result, os::errno() as c_int)
+            };
+            converted.push_all(vec::slice(buffer, 0, buffer.len() - out_left as uint));
+            position = input.len() - in_left as uint;
+
+            if result as int != -1 || errno == E2BIG {
+                // Either everything was converted, or the buffer is full and we go round again.
+                loop
+            }
+            if errno == EINVAL {
+                break
+            }
+            // An invalid sequence.
+            converted.push_all(str::to_bytes(str::from_char(REPLACEMENT_CHARACTER)));
+            position += 1;
+        }
+        output.push_str(str::from_bytes(converted));
+        input.len() - position
+    }
+}
+
+#[test]
+fn should_resolve_labels() {
+    assert!(resolve_label(" Latin1 ") == Some(~"windows-1252"));
+    assert!(resolve_label("x-sjis") == Some(~"Shift_JIS"));
+    assert!(resolve_label("utf8") == Some(~"UTF-8"));
+    assert!(resolve_label("klingon").is_none());
+}
+
+#[test]
+fn should_prefer_bom_then_http_charset_then_declaration() {
+    let page = str::to_bytes("<!-- <meta charset=koi8-r> --><html><meta charset='shift_jis'>");
+    assert!(sniff(HtmlSyntax, page, None) == (~"Shift_JIS", 0));
+    assert!(sniff(HtmlSyntax, page, Some(~"iso-8859-2")) == (~"ISO-8859-2", 0));
+    let with_bom = ~[0xef, 0xbb, 0xbf] + page;
+    assert!(sniff(HtmlSyntax, with_bom, Some(~"iso-8859-2")) == (~"UTF-8", 3));
+}
+
+#[test]
+fn should_read_http_equiv_declarations() {
+    let page = str::to_bytes("<meta http-equiv=\"Content-Type\" \
+                              content=\"text/html; charset=EUC-JP\">");
+    assert!(sniff(HtmlSyntax, page, None) == (~"EUC-JP", 0));
+}
+
+#[test]
+fn should_fall_back_to_windows_1252_for_html_that_is_not_utf8() {
+    assert!(sniff(HtmlSyntax, [0x63, 0x61, 0x66, 0xe9], None) == (~"windows-1252", 0));
+    assert!(sniff(HtmlSyntax, str::to_bytes("café"), None) == (~"UTF-8", 0));
+}
+
+#[test]
+fn should_read_css_charset_rules() {
+    let sheet = str::to_bytes("@charset \"iso-8859-15\"; p { color: red }");
+    assert!(sniff(CssSyntax, sheet, None) == (~"ISO-8859-15", 0));
+    let sheet = str::to_bytes("p { color: red } @charset \"iso-8859-15\";");
+    assert!(sniff(CssSyntax, sheet, None) == (~"UTF-8", 0));
+}
+
+#[test]
+fn should_decode_utf8_split_across_chunks() {
+    let bytes = str::to_bytes("né€𝄞");
+    let mut decoder = Decoder::new("UTF-8").get();
+    let mut text = ~"";
+    for bytes.each |byte| {
+        text.push_str(decoder.decode([*byte]));
+    }
+    text.push_str(decoder.finish());
+    assert!(text == ~"né€𝄞");
+}
+
+#[test]
+fn should_replace_malformed_utf8() {
+    let mut decoder = Decoder::new("UTF-8").get();
+    let text = decoder.decode([0x61, 0xc0, 0x62, 0xe2, 0x82]);
+    assert!(text == ~"a�b");
+    assert!(decoder.finish() == ~"�");
+}
+
+#[test]
+fn should_decode_windows_1252_and_utf16() {
+    let mut decoder = Decoder::new("windows-1252").get();
+    assert!(decoder.decode([0x80, 0xe9]) == ~"€é");
+
+    let mut decoder = Decoder::new("UTF-16LE").get();
+    let mut text = decoder.decode([0x3d, 0xd8, 0x00]);
+    text.push_str(decoder.decode([0xde, 0x41, 0x00]));
+    assert!(text == ~"\U0001f600A");
+}
+
+#[test]
+fn should_decode_shift_jis() {
+    let mut decoder = SniffingDecoder::new(HtmlSyntax, Some(~"shift_jis"));
+    let mut text = decoder.decode([0x93, 0xfa]);
+    text.push_str(decoder.decode([0x96]));
+    text.push_str(decoder.finish());
+    assert!(decoder.encoding() == Some(~"Shift_JIS"));
+    assert!(text == ~"日�");
+}
@@ -2,31 +2,72 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use resource_task::{Done, LoaderTask, Payload};
+use resource_task::{Done, LoaderTask, Metadata, Payload};
 
+use core::comm::GenericSmartChan;
 use core::io::{ReaderUtil, file_reader};
 use core::task;
+use std::net::url;
 
 static READ_SIZE: uint = 1024;
 
 pub fn factory() -> LoaderTask {
-	let f: LoaderTask = |url, progress_chan| {
+	let f: LoaderTask = |url, _headers, progress_chan| {
 		assert!("file" == url.scheme);
 		do task::spawn {
 			// FIXME: Resolve bug prevents us from moving the path out of the URL.
-			match file_reader(&Path(url.path)) {
+			let path = Path(url::decode(url.path));
+			match file_reader(&path) {
 				Ok(reader) => {
+					let mut metadata = Metadata::default(url.clone());
+					metadata.content_type = guess_content_type(&path);
+					if !progress_chan.try_send(Metadata(metadata)) {
+						return
+					}
+
 					while !reader.eof() {
 						let data = reader.read_bytes(READ_SIZE);
-						progress_chan.send(Payload(data));
+						if !progress_chan.try_send(Payload(data)) {
+							// The load was cancelled.
+							return
+						}
 					}
-					progress_chan.send(Done(Ok(())));
+					progress_chan.try_send(Done(Ok(())));
 				}
 				Err(*) => {
-					progress_chan.send(Done(Err(())));
+					progress_chan.try_send(Done(Err(())));
 				}
 			};
 		}
 	};
 	f
 }
+
+/// Guesses the MIME type of a local file from its extension.
+pub fn guess_content_type(path: &Path) -> Option<(~str, ~str)> {
+	let extension = match path.filetype() {
+		Some(extension) => str::to_lower(extension),
+		None => return None,
+	};
+	let (main_type, sub_type) = match extension {
+		~".html" | ~".htm" | ~".xhtml" => ("text", "html"),
+		~".css" => ("text", "css"),
+		~".js" => ("application", "javascript"),
+		~".txt" => ("text", "plain"),
+		~".xml" => ("application", "xml"),
+		~".png" => ("image", "png"),
+		~".jpg" | ~".jpeg" => ("image", "jpeg"),
+		~".gif" => ("image", "gif"),
+		~".bmp" => ("image", "bmp"),
+		_ => return None,
+	};
+	Some((main_type.to_owned(), sub_type.to_owned()))
+}
+
+#[test]
+fn should_guess_content_type_from_extension() {
+	assert!(guess_content_type(&Path("a/b/test.HTML")) == Some((~"text", ~"html")));
+	assert!(guess_content_type(&Path("style.css")) == Some((~"text", ~"css")));
+	assert!(guess_content_type(&Path("image.jpeg")) == Some((~"image", ~"jpeg")));
+	assert!(guess_content_type(&Path("README")).is_none());
+}
@@ -0,0 +1,592 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A cache of HTTP responses, kept in memory and optionally persisted to a directory.
+//!
+//! Fresh responses are replayed without touching the network. Stale responses that carry an
+//! `ETag` or `Last-Modified` validator are revalidated with a conditional request, and a `304 Not
+//! Modified` answer replays the cached body. Freshness follows `Cache-Control`, `Expires` and, for
+//! responses with neither, the usual `Last-Modified` heuristic.
+//!
+//! Requests that carry credentials bypass the cache, and responses that vary on request headers
+//! or are too big aren't stored.
+
+use resource_task::{Aborted, Done, LoaderTask, Metadata, Payload, ProgressMsg};
+use util::parse_http_date;
+
+use core::cell::Cell;
+use core::comm::{Chan, GenericSmartChan, Port, SharedChan, stream};
+use core::hash::Hash;
+use core::io::{ReaderUtil, WriterUtil};
+use core::task;
+use servo_util::cache::{Cache, LRUCache};
+use std::net::url::Url;
+use std::net::url;
+use std::time;
+
+/// The number of responses kept in memory.
+static MEMORY_CACHE_SIZE: uint = 64;
+/// The longest heuristic freshness lifetime given to responses without explicit freshness
+/// information, in seconds.
+static MAX_HEURISTIC_LIFETIME: i64 = 24 * 60 * 60;
+/// The biggest body stored, in bytes.
+static MAX_ENTRY_SIZE: uint = 4 * 1024 * 1024;
+
+pub enum Msg {
+    /// Look up the cached response for a URL, if any.
+    Lookup(Url, Chan<CacheLookup>),
+    /// Store a complete response for a URL, replacing any previous one.
+    Store(Url, CachedResponse),
+    Exit
+}
+
+/// The result of looking a URL up in the cache.
+pub enum CacheLookup {
+    /// The response may be used without contacting the server.
+    Fresh(CachedResponse),
+    /// The response must be revalidated with the server before use.
+    Stale(CachedResponse),
+    /// There is no usable response.
+    Miss
+}
+
+/// A complete response, as stored in the cache.
+#[deriving(Eq)]
+pub struct CachedResponse {
+    metadata: Metadata,
+    body: ~[u8],
+    /// When the response was received or last revalidated, in seconds since the epoch.
+    stored_at: i64,
+}
+
+pub type HttpCacheTask = SharedChan<Msg>;
+
+/// Creates a task that caches responses in memory and, if a directory is given, on disk. The
+/// directory is created if it does not exist.
+pub fn HttpCacheTask(cache_dir: Option<Path>) -> HttpCacheTask {
+    let (port, chan) = stream();
+    let port = Cell(port);
+    let cache_dir = Cell(cache_dir);
+    do task::spawn {
+        let mut cache = HttpCache {
+            port: port.take(),
+            memory: LRUCache::new(MEMORY_CACHE_SIZE),
+            cache_dir: cache_dir.take(),
+        };
+        cache.run();
+    }
+    SharedChan::new(chan)
+}
+
+struct HttpCache {
+    port: Port<Msg>,
+    memory: LRUCache<Url, @CachedResponse>,
+    cache_dir: Option<Path>,
+}
+
+impl HttpCache {
+    fn run(&mut self) {
+        match self.cache_dir {
+            Some(ref dir) if !os::path_is_dir(dir) => {
+                if !os::mkdir_recursive(dir, 0x1ed /* 0755 */) {
+                    debug!("http_cache: failed to create %s", dir.to_str());
+                }
+            }
+            _ => {}
+        }
+
+        loop {
+            match self.port.recv() {
+                Lookup(url, response_chan) => response_chan.send(self.lookup(&url)),
+                Store(url, response) => self.store(&url, response),
+                Exit => break,
+            }
+        }
+    }
+
+    fn lookup(&mut self, url: &Url) -> CacheLookup {
+        let response = match self.memory.find(url) {
+            Some(response) => response,
+            None => match self.read_from_disk(url) {
+                Some(response) => {
+                    let response = @response;
+                    self.memory.insert(url, response);
+                    response
+                }
+                None => return Miss,
+            }
+        };
+
+        let now = time::get_time().sec;
+        if response.is_fresh(now) {
+            debug!("http_cache: fresh hit for %s", url::to_str(url));
+            Fresh(copy *response)
+        } else if response.has_validators() {
+            debug!("http_cache: stale hit for %s", url::to_str(url));
+            Stale(copy *response)
+        } else {
+            Miss
+        }
+    }
+
+    fn store(&mut self, url: &Url, response: CachedResponse) {
+        debug!("http_cache: storing %s", url::to_str(url));
+        self.write_to_disk(url, &response);
+        self.memory.insert(url, @response);
+    }
+
+    fn path_for(&self, url: &Url) -> Option<Path> {
+        do self.cache_dir.map |dir| {
+            dir.push(u64::to_str_radix(url::to_str(url).hash(), 16))
+        }
+    }
+
+    /// Reads a response written by `write_to_disk`. The file starts with the URL, the time the
+    /// response was stored and its status, one per line. The headers and the body follow, laid out
+    /// as in an HTTP response.
+    fn read_from_disk(&self, url: &Url) -> Option<CachedResponse> {
+        let path = match self.path_for(url) {
+            Some(path) => path,
+            None => return None,
+        };
+        let reader = match io::file_reader(&path) {
+            Ok(reader) => reader,
+            Err(*) => return None,
+        };
+
+        // Different URLs may hash to the same file.
+        if reader.read_line() != url::to_str(url) {
+            return None
+        }
+        let stored_at = match i64::from_str(reader.read_line()) {
+            Some(stored_at) => stored_at,
+            None => return None,
+        };
+        let status = match uint::from_str(reader.read_line()) {
+            Some(status) => status,
+            None => return None,
+        };
+
+        let mut metadata = Metadata::default(url.clone());
+        metadata.status = Some(status);
+        loop {
+            if reader.eof() {
+                return None
+            }
+            let line = reader.read_line();
+            if line.is_empty() {
+                break
+            }
+            match str::find_char(line, ':') {
+                Some(colon) => {
+                    let name = line.slice(0, colon).to_owned();
+                    let value = line.slice(colon + 1, line.len()).trim().to_owned();
+                    metadata.headers.push((name, value));
+                }
+                None => return None,
+            }
+        }
+        match metadata.header("content-type") {
+            Some(content_type) => metadata.set_content_type(content_type),
+            None => {}
+        }
+
+        Some(CachedResponse {
+            metadata: metadata,
+            body: reader.read_whole_stream(),
+            stored_at: stored_at,
+        })
+    }
+
+    fn write_to_disk(&self, url: &Url, response: &CachedResponse) {
+        let path = match self.path_for(url) {
+            Some(path) => path,
+            None => return,
+        };
+        match io::file_writer(&path, [io::Create, io::Truncate]) {
+            Ok(writer) => {
+                writer.write_line(url::to_str(url));
+                writer.write_line(response.stored_at.to_str());
+                writer.write_line(response.metadata.status.get_or_default(0).to_str());
+                for response.metadata.headers.each |&(ref name, ref value)| {
+                    writer.write_line(fmt!("%s: %s", *name, *value));
+                }
+                writer.write_line("");
+                writer.write(response.body);
+            }
+            Err(message) => debug!("http_cache: failed to write %s: %s", path.to_str(), message),
+        }
+    }
+}
+
+/// Loads a URL through the cache, sending the given request headers. Fresh responses are replayed
+/// from the cache, stale ones are revalidated, and anything else is fetched with the loader and
+/// stored if it is cacheable. Requests with credentials go straight to the loader, since their
+/// responses may be specific to the user.
+pub fn load(cache: HttpCacheTask,
+            loader: LoaderTask,
+            url: Url,
+            headers: ~[(~str, ~str)],
+            progress_chan: Chan<ProgressMsg>) {
+    if has_credentials(headers) {
+        debug!("http_cache: not caching credentialed load of %s", url::to_str(&url));
+        return loader(url, headers, progress_chan)
+    }
+
+    let loader = Cell(loader);
+    let headers = Cell(headers);
+    do task::spawn {
+        let (lookup_port, lookup_chan) = stream();
+        cache.send(Lookup(url.clone(), lookup_chan));
+        let mut stale = match lookup_port.recv() {
+            Fresh(response) => {
+                replay(response, &progress_chan);
+                return
+            }
+            Stale(response) => Some(response),
+            Miss => None,
+        };
+
+        let mut headers = headers.take();
+        match stale {
+            Some(ref response) => headers.push_all_move(response.conditional_headers()),
+            None => {}
+        }
+        let (loader_port, loader_chan) = stream();
+        loader.take()(url.clone(), headers, loader_chan);
+
+        let mut response = None;
+        loop {
+            match loader_port.recv() {
+                Metadata(metadata) => {
+                    if metadata.status == Some(304) && metadata.final_url == url &&
+                            stale.is_some() {
+                        debug!("http_cache: revalidated %s", url::to_str(&url));
+                        let mut cached = stale.swap_unwrap();
+                        cached.update_from(&metadata, time::get_time().sec);
+                        cache.send(Store(url.clone(), copy cached));
+                        replay(cached, &progress_chan);
+                        // Dropping the port stops the loader.
+                        break
+                    }
+                    if is_cacheable(&url, &metadata) {
+                        response = Some(CachedResponse {
+                            metadata: copy metadata,
+                            body: ~[],
+                            stored_at: time::get_time().sec,
+                        });
+                    }
+                    if !progress_chan.try_send(Metadata(metadata)) {
+                        break
+                    }
+                }
+                Payload(data) => {
+                    let too_big = match response {
+                        Some(ref mut response) => {
+                            response.body.push_all(data);
+                            response.body.len() > MAX_ENTRY_SIZE
+                        }
+                        None => false,
+                    };
+                    if too_big {
+                        debug!("http_cache: %s is too big to store", url::to_str(&url));
+                        response = None;
+                    }
+                    if !progress_chan.try_send(Payload(data)) {
+                        break
+                    }
+                }
+                Done(result) => {
+                    if result.is_ok() && response.is_some() {
+                        cache.send(Store(url.clone(), response.swap_unwrap()));
+                    }
+                    progress_chan.try_send(Done(result));
+                    break
+                }
+                Aborted => {
+                    progress_chan.try_send(Aborted);
+                    break
+                }
+            }
+        }
+    }
+}
+
+/// Sends a cached response to the progress channel as if it had just been loaded.
+fn replay(response: CachedResponse, progress_chan: &Chan<ProgressMsg>) {
+    let CachedResponse { metadata: metadata, body: body, stored_at: _ } = response;
+    if progress_chan.try_send(Metadata(metadata)) && progress_chan.try_send(Payload(body)) {
+        progress_chan.try_send(Done(Ok(())));
+    }
+}
+
+/// Returns true if the request headers include credentials.
+fn has_credentials(headers: &[(~str, ~str)]) -> bool {
+    do headers.any |&(ref name, _)| {
+        let name = str::to_lower(*name);
+        name == ~"cookie" || name == ~"authorization"
+    }
+}
+
+/// Returns true if the response may be stored and reused for later loads of the URL.
+fn is_cacheable(url: &Url, metadata: &Metadata) -> bool {
+    // The cache is keyed on the requested URL, so a redirect would have to be cached separately
+    // from its target.
+    if metadata.final_url != *url {
+        return false
+    }
+    match metadata.status {
+        Some(200) | Some(203) | Some(300) | Some(301) | Some(410) => {}
+        _ => return false,
+    }
+    if cache_control(metadata).contains(&~"no-store") {
+        return false
+    }
+    // Responses are stored per URL only, so one that depends on other request headers could be
+    // replayed for a request it doesn't match.
+    if metadata.header("vary").is_some() {
+        return false
+    }
+
+    let response = CachedResponse {
+        metadata: copy *metadata,
+        body: ~[],
+        stored_at: 0,
+    };
+    response.has_validators() || response.explicit_freshness_lifetime().is_some()
+}
+
+/// Returns the lowercased directives of the `Cache-Control` header, such as `max-age=60`.
+fn cache_control(metadata: &Metadata) -> ~[~str] {
+    let mut directives = ~[];
+    match metadata.header("cache-control") {
+        Some(value) => {
+            for str::each_split_char(value, ',') |directive| {
+                directives.push(str::to_lower(directive.trim()));
+            }
+        }
+        None => {}
+    }
+    directives
+}
+
+impl CachedResponse {
+    /// The freshness lifetime given by `Cache-Control` or `Expires`, in seconds.
+    fn explicit_freshness_lifetime(&self) -> Option<i64> {
+        let directives = cache_control(&self.metadata);
+        if directives.contains(&~"no-cache") {
+            return Some(0)
+        }
+        for directives.each |directive| {
+            if directive.starts_with("max-age=") {
+                return Some(match i64::from_str(directive.slice(8, directive.len())) {
+                    Some(max_age) => max_age,
+                    // A malformed max-age makes the response stale, as required by RFC 7234.
+                    None => 0,
+                })
+            }
+        }
+
+        match self.metadata.header("expires") {
+            Some(expires) => match parse_http_date(expires) {
+                Some(expires) => Some(expires - self.date()),
+                // Invalid dates, such as `0`, mean the response has already expired.
+                None => Some(0),
+            },
+            None => None,
+        }
+    }
+
+    /// The freshness lifetime of the response in seconds, guessing one from `Last-Modified` when
+    /// the server gives none: a tenth of the time since the last modification.
+    fn freshness_lifetime(&self) -> i64 {
+        match self.explicit_freshness_lifetime() {
+            Some(lifetime) => lifetime,
+            None => match self.metadata.header("last-modified").chain(|lm| parse_http_date(lm)) {
+                Some(last_modified) => {
+                    i64::min((self.date() - last_modified) / 10, MAX_HEURISTIC_LIFETIME)
+                }
+                None => 0,
+            }
+        }
+    }
+
+    /// When the server generated the response, according to its `Date` header.
+    fn date(&self) -> i64 {
+        match self.metadata.header("date").chain(|date| parse_http_date(date)) {
+            Some(date) => date,
+            None => self.stored_at,
+        }
+    }
+
+    fn is_fresh(&self, now: i64) -> bool {
+        let initial_age = match self.metadata.header("age").chain(|age| i64::from_str(age)) {
+            Some(age) => age,
+            None => 0,
+        };
+        let age = initial_age + i64::max(now - self.stored_at, 0);
+        self.freshness_lifetime() > age
+    }
+
+    fn has_validators(&self) -> bool {
+        self.metadata.header("etag").is_some() || self.metadata.header("last-modified").is_some()
+    }
+
+    /// The headers that make a request conditional on the cached response being out of date.
+    fn conditional_headers(&self) -> ~[(~str, ~str)] {
+        let mut headers = ~[];
+        match self.metadata.header("etag") {
+            Some(etag) => headers.push((~"If-None-Match", etag)),
+            None => {}
+        }
+        match self.metadata.header("last-modified") {
+            Some(last_modified) => headers.push((~"If-Modified-Since", last_modified)),
+            None => {}
+        }
+        headers
+    }
+
+    /// Refreshes the response with the headers of a `304 Not Modified` response to a conditional
+    /// request, which replace the stored headers of the same name.
+    fn update_from(&mut self, not_modified: &Metadata, now: i64) {
+        for not_modified.headers.each |&(ref name, ref value)| {
+            let lower_name = str::to_lower(*name);
+            match self.metadata.headers.position(|&(ref n, _)| str::to_lower(*n) == lower_name) {
+                Some(index) => self.metadata.headers[index] = (copy *name, copy *value),
+                None => self.metadata.headers.push((copy *name, copy *value)),
+            }
+        }
+        self.stored_at = now;
+    }
+}
+
+#[cfg(test)]
+fn response_with_headers(headers: ~[(~str, ~str)]) -> CachedResponse {
+    let mut metadata = Metadata::default(url::from_str(~"http://example.com/").get());
+    metadata.status = Some(200);
+    metadata.headers = headers;
+    CachedResponse {
+        metadata: metadata,
+        body: str::to_bytes("body"),
+        stored_at: 1000,
+    }
+}
+
+#[test]
+fn should_honour_max_age() {
+    let response = response_with_headers(~[(~"Cache-Control", ~"public, max-age=60")]);
+    assert!(response.is_fresh(1059));
+    assert!(!response.is_fresh(1060));
+}
+
+#[test]
+fn should_prefer_max_age_to_expires() {
+    let response = response_with_headers(~[
+        (~"Date", ~"Sun, 06 Nov 1994 08:49:37 GMT"),
+        (~"Expires", ~"Mon, 07 Nov 1994 08:49:37 GMT"),
+        (~"Cache-Control", ~"max-age=10"),
+    ]);
+    assert!(response.freshness_lifetime() == 10);
+}
+
+#[test]
+fn should_compute_lifetime_from_expires() {
+    let response = response_with_headers(~[
+        (~"Date", ~"Sun, 06 Nov 1994 08:49:37 GMT"),
+        (~"Expires", ~"Sun, 06 Nov 1994 09:49:37 GMT"),
+    ]);
+    assert!(response.freshness_lifetime() == 60 * 60);
+
+    let response = response_with_headers(~[(~"Expires", ~"0")]);
+    assert!(!response.is_fresh(1000));
+}
+
+#[test]
+fn should_guess_lifetime_from_last_modified() {
+    let response = response_with_headers(~[
+        (~"Date", ~"Sun, 06 Nov 1994 08:49:37 GMT"),
+        (~"Last-Modified", ~"Sun, 06 Nov 1994 07:49:37 GMT"),
+    ]);
+    assert!(response.freshness_lifetime() == 6 * 60);
+    assert!(response.has_validators());
+}
+
+#[test]
+fn should_not_cache_uncacheable_responses() {
+    let url = url::from_str(~"http://example.com/").get();
+    let response = response_with_headers(~[(~"Cache-Control", ~"no-store, max-age=60")]);
+    assert!(!is_cacheable(&url, &response.metadata));
+
+    let response = response_with_headers(~[]);
+    assert!(!is_cacheable(&url, &response.metadata));
+
+    let response = response_with_headers(~[(~"ETag", ~"\"abc\"")]);
+    assert!(is_cacheable(&url, &response.metadata));
+    let other_url = url::from_str(~"http://example.com/other").get();
+    assert!(!is_cacheable(&other_url, &response.metadata));
+
+    let response = response_with_headers(~[(~"ETag", ~"\"abc\""), (~"Vary", ~"Accept")]);
+    assert!(!is_cacheable(&url, &response.metadata));
+}
+
+#[test]
+fn should_detect_credentials() {
+    assert!(has_credentials([(~"Cookie", ~"a=b")]));
+    assert!(has_credentials([(~"authorization", ~"Basic YTpi")]));
+    assert!(!has_credentials([(~"If-None-Match", ~"\"abc\"")]));
+}
+
+#[test]
+fn should_build_conditional_headers_and_update_on_revalidation() {
+    let mut response = response_with_headers(~[
+        (~"ETag", ~"\"abc\""),
+        (~"Last-Modified", ~"Sun, 06 Nov 1994 07:49:37 GMT"),
+        (~"Cache-Control", ~"no-cache"),
+    ]);
+    assert!(!response.is_fresh(1000));
+    assert!(response.conditional_headers() == ~[
+        (~"If-None-Match", ~"\"abc\""),
+        (~"If-Modified-Since", ~"Sun, 06 Nov 1994 07:49:37 GMT"),
+    ]);
+
+    let mut not_modified = Metadata::default(copy response.metadata.final_url);
+    not_modified.status = Some(304);
+    not_modified.headers = ~[(~"cache-control", ~"max-age=60")];
+    response.update_from(&not_modified, 2000);
+    assert!(response.stored_at == 2000);
+    assert!(response.metadata.header("Cache-Control") == Some(~"max-age=60"));
+    assert!(response.is_fresh(2030));
+    assert!(response.body == str::to_bytes("body"));
+}
+
+#[test]
+fn should_persist_responses_to_disk() {
+    let dir = os::tmpdir().push(fmt!("servo-http-cache-test-%?", time::precise_time_ns()));
+    let url = url::from_str(~"http://example.com/").get();
+    let mut response = response_with_headers(~[
+        (~"Content-Type", ~"text/css"),
+        (~"Cache-Control", ~"max-age=60"),
+    ]);
+    response.metadata.set_content_type("text/css");
+    response.body = ~[0u8, 10, 13, 10, 255];
+    response.stored_at = time::get_time().sec;
+
+    let cache = HttpCacheTask(Some(copy dir));
+    cache.send(Store(url.clone(), copy response));
+    // Wait for the store to finish before reading the directory from another cache.
+    let (port, chan) = stream();
+    cache.send(Lookup(url.clone(), chan));
+    port.recv();
+    cache.send(Exit);
+
+    // A new cache reads the response back from the directory.
+    let cache = HttpCacheTask(Some(copy dir));
+    let (port, chan) = stream();
+    cache.send(Lookup(url.clone(), chan));
+    match port.recv() {
+        Fresh(cached) => assert!(cached == response),
+        _ => fail!(~"expected a fresh response")
+    }
+    cache.send(Exit);
+}
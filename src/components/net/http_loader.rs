@@ -2,47 +2,423 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use resource_task::{Payload, Done, LoaderTask};
+//! A loader for `http` URLs, on top of `http_client`.
 
-use core::comm::SharedChan;
+use cookie::HTTP;
+use resource_task::{Done, GetCookies, LoaderTask, LoaderTaskFactory, Metadata, Payload};
+use resource_task::{ProgressMsg, ResourceTask, SetCookies};
+
+use core::comm::{Chan, GenericSmartChan, stream};
 use core::task;
 use http_client::uv_http_request;
 use http_client;
+use std::net::url::Url;
+use std::net::url;
+use servo_util::url_parser;
+#[cfg(test)]
+use core::libc::{c_int, size_t, ssize_t};
+
+/// The number of redirects followed before giving up, as in Firefox.
+static DEFAULT_REDIRECT_LIMIT: uint = 20;
+
+/// Creates a loader factory whose loads store the cookies responses set in the given resource
+/// task's jar, and send the jar's cookies with each request after a redirect.
+pub fn factory(resource_task: ResourceTask) -> LoaderTaskFactory {
+	factory_with_redirect_limit(DEFAULT_REDIRECT_LIMIT, Some(resource_task))
+}
+
+/// Creates a loader factory that follows at most `redirect_limit` redirects per load, keeping
+/// cookies in the resource task's jar if one is given.
+pub fn factory_with_redirect_limit(redirect_limit: uint, resource_task: Option<ResourceTask>)
+								   -> LoaderTaskFactory {
+	let f: LoaderTaskFactory = || {
+		let resource_task = resource_task.map(|chan| chan.clone());
+		let loader: LoaderTask = |url, headers, progress_chan| {
+			assert!(url.scheme == ~"http");
 
-pub fn factory() -> LoaderTask {
-	let f: LoaderTask = |url, progress_chan| {
-		assert!(url.scheme == ~"http");
-
-		let progress_chan = SharedChan::new(progress_chan);
-		do task::spawn {
-			debug!("http_loader: requesting via http: %?", url.clone());
-			let mut request = uv_http_request(url.clone());
-			let errored = @mut false;
-			let url = url.clone();
-			{
-				let progress_chan = progress_chan.clone();
-				do request.begin |event| {
-					let url = url.clone();
-					match event {
-						http_client::Status(*) => { }
-						http_client::Payload(data) => {
-							debug!("http_loader: got data from %?", url);
-							let data = data.take();
-							progress_chan.send(Payload(data));
-						}
-						http_client::Error(*) => {
-							debug!("http_loader: error loading %?", url);
-							*errored = true;
-							progress_chan.send(Done(Err(())));
-						}
+			let resource_task = resource_task.map(|chan| chan.clone());
+			do task::spawn {
+				debug!("http_loader: requesting via http: %?", url.clone());
+				match load(&url, headers, &progress_chan, redirect_limit, &resource_task) {
+					Ok(()) => {
+						progress_chan.try_send(Done(Ok(())));
+					}
+					Err(()) => {
+						debug!("http_loader: error loading %?", url);
+						progress_chan.try_send(Done(Err(())));
 					}
 				}
 			}
+		};
+		loader
+	};
+	f
+}
+
+/// What became of a single request.
+enum Response {
+	/// The response was sent to the progress channel.
+	Delivered,
+	/// The response redirected to the given location; nothing was sent.
+	Redirected(~str),
+}
 
-			if !*errored {
-				progress_chan.send(Done(Ok(())));
+/// Loads a URL, following redirects, and sends the metadata and body of the final response to
+/// the progress channel. The extra headers are sent with the first request only, since they may
+/// be specific to its URL; later requests carry the cookies for their own URL instead.
+fn load(url: &Url,
+		headers: ~[(~str, ~str)],
+		progress_chan: &Chan<ProgressMsg>,
+		redirect_limit: uint,
+		resource_task: &Option<ResourceTask>)
+		-> Result<(), ()> {
+	let mut url = url.clone();
+	let mut headers = headers;
+	let mut visited = ~[];
+
+	loop {
+		let location = match request(&url, headers, progress_chan, resource_task) {
+			Ok(Delivered) => return Ok(()),
+			Ok(Redirected(location)) => location,
+			Err(()) => return Err(()),
+		};
+
+		visited.push(url.clone());
+		if visited.len() > redirect_limit {
+			debug!("http_loader: too many redirects loading %s", url::to_str(&url));
+			return Err(())
+		}
+
+		let new_url = match url_parser::parse(location, Some(&url)) {
+			Ok(new_url) => new_url,
+			Err(error) => {
+				debug!("http_loader: invalid redirect location %s: %?", location, error);
+				return Err(())
+			}
+		};
+		if new_url.scheme != ~"http" {
+			debug!("http_loader: can't follow redirect to %s", url::to_str(&new_url));
+			return Err(())
+		}
+		if visited.contains(&new_url) {
+			debug!("http_loader: redirect loop at %s", url::to_str(&new_url));
+			return Err(())
+		}
+
+		debug!("http_loader: redirected from %s to %s", url::to_str(&url),
+			   url::to_str(&new_url));
+		url = new_url;
+		headers = ~[];
+		match *resource_task {
+			Some(ref resource_task) => {
+				let (cookies_port, cookies_chan) = stream();
+				if resource_task.try_send(GetCookies(url.clone(), HTTP, cookies_chan)) {
+					for cookies_port.recv().each |cookies| {
+						headers.push((~"Cookie", copy *cookies));
+					}
+				}
 			}
+			None => {}
 		}
+	}
+}
+
+/// Returns true if the status code asks the client to fetch another URL instead.
+fn is_redirect(status: Option<uint>) -> bool {
+	match status {
+		Some(301) | Some(302) | Some(303) | Some(307) | Some(308) => true,
+		_ => false,
+	}
+}
+
+/// The progress of a single request, as its events come in.
+struct RequestState {
+	metadata: Metadata,
+	/// Whether the status and headers are all in.
+	head_complete: bool,
+	/// Where the response redirects to, once the head is complete.
+	location: Option<~str>,
+	errored: bool,
+	cancelled: bool,
+}
+
+impl RequestState {
+	/// Called when the head of the response is complete, which `http_client` signals by
+	/// delivering the body. Stores the cookies it sets and sends it on unless it is a redirect.
+	/// Returns whether the body should be sent on too.
+	fn finish_head(&mut self,
+				   progress_chan: &Chan<ProgressMsg>,
+				   resource_task: &Option<ResourceTask>)
+				   -> bool {
+		if !self.head_complete {
+			self.head_complete = true;
+			// The cookies are stored before the next request asks for them, since messages to
+			// the resource task arrive in order.
+			let set_cookies = self.metadata.header_values("set-cookie");
+			match *resource_task {
+				Some(ref resource_task) if !set_cookies.is_empty() => {
+					let url = self.metadata.final_url.clone();
+					let _ = resource_task.try_send(SetCookies(url, set_cookies, HTTP));
+				}
+				_ => {}
+			}
+			if is_redirect(self.metadata.status) {
+				self.location = self.metadata.header("location");
+			}
+			if self.location.is_none() {
+				match self.metadata.header("content-type") {
+					Some(content_type) => self.metadata.set_content_type(content_type),
+					None => {}
+				}
+				self.cancelled = !progress_chan.try_send(Metadata(copy self.metadata));
+			}
+		}
+		self.location.is_none() && !self.cancelled
+	}
+}
+
+/// Performs a single request. Unless the response is a redirect with a location, its status and
+/// headers are sent to the progress channel, followed by the body as it arrives.
+fn request(url: &Url,
+		   headers: &[(~str, ~str)],
+		   progress_chan: &Chan<ProgressMsg>,
+		   resource_task: &Option<ResourceTask>)
+		   -> Result<Response, ()> {
+	let mut request = uv_http_request(request_url(url));
+	for headers.each |&(ref name, ref value)| {
+		request.add_header(copy *name, copy *value);
+	}
+
+	let state = @mut RequestState {
+		metadata: Metadata::default(url.clone()),
+		head_complete: false,
+		location: None,
+		errored: false,
+		cancelled: false,
 	};
-	f
+	do request.begin |event| {
+		match event {
+			http_client::Status(status) => state.metadata.status = Some(status as uint),
+			http_client::Header(name, value) => state.metadata.headers.push((name, value)),
+			http_client::Payload(data) => {
+				// The body of a redirect is dropped, and so is anything after a cancel.
+				if state.finish_head(progress_chan, resource_task) {
+					let data = data.take();
+					debug!("http_loader: got data from %?", url);
+					if !progress_chan.try_send(Payload(data)) {
+						debug!("http_loader: load of %? cancelled", url);
+						state.cancelled = true;
+					}
+				}
+			}
+			http_client::Error(*) => state.errored = true,
+		}
+	}
+
+	if state.errored || state.metadata.status.is_none() {
+		return Err(())
+	}
+	// Responses without a body haven't been sent on yet.
+	state.finish_head(progress_chan, resource_task);
+	if state.cancelled {
+		return Err(())
+	}
+	match copy state.location {
+		Some(location) => Ok(Redirected(location)),
+		None => Ok(Delivered),
+	}
+}
+
+/// Returns the URL to hand to `http_client`, with the query moved to the end of the path so the
+/// client sends it as written instead of joining decoded pairs.
+fn request_url(url: &Url) -> Url {
+	let mut path = if url.path.is_empty() { ~"/" } else { copy url.path };
+	match url_parser::query_str(url) {
+		Some(query) => path = path + "?" + query,
+		None => {}
+	}
+	Url::new(copy url.scheme, copy url.user, copy url.host, copy url.port, path, ~[], None)
+}
+
+#[test]
+fn should_send_query_as_written() {
+	let url = url_parser::parse("http://example.com:8000/a/b?c+d&&e#f", None).get();
+	let url = request_url(&url);
+	assert!(url.path == ~"/a/b?c+d&&e");
+	assert!(url.query.is_empty());
+	assert!(url.fragment.is_none());
+	assert!(url.port == Some(~"8000"));
+}
+
+/// The socket calls the test server makes itself, since `std::net::tcp` can't tell which port the
+/// OS picked for a listener.
+#[cfg(test)]
+#[nolink]
+#[abi = "cdecl"]
+extern mod test_socket {
+	unsafe fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int;
+	unsafe fn bind(socket: c_int, address: *u8, address_len: u32) -> c_int;
+	unsafe fn listen(socket: c_int, backlog: c_int) -> c_int;
+	unsafe fn getsockname(socket: c_int, address: *mut u8, address_len: *mut u32) -> c_int;
+	unsafe fn accept(socket: c_int, address: *mut u8, address_len: *mut u32) -> c_int;
+	unsafe fn read(fd: c_int, buf: *mut u8, count: size_t) -> ssize_t;
+	unsafe fn write(fd: c_int, buf: *u8, count: size_t) -> ssize_t;
+	unsafe fn close(fd: c_int) -> c_int;
+}
+
+/// Returns a `sockaddr_in` for 127.0.0.1 and the given port, as bytes.
+#[cfg(test)]
+fn loopback_address(port: u16) -> ~[u8] {
+	let mut address = vec::from_elem(16, 0u8);
+	set_address_family(address);
+	address[2] = (port >> 8) as u8;
+	address[3] = port as u8;
+	address[4] = 127;
+	address[7] = 1;
+	address
+}
+
+#[cfg(test, target_os = "linux")]
+fn set_address_family(address: &mut [u8]) {
+	// AF_INET, as a native-endian u16.
+	address[0] = 2;
+}
+
+#[cfg(test, target_os = "macos")]
+fn set_address_family(address: &mut [u8]) {
+	// The length of the address, then AF_INET.
+	address[0] = 16;
+	address[1] = 2;
+}
+
+/// Serves canned responses on a local port the OS picks, one per connection, then shuts down.
+/// `responses` is given the port, so that responses can refer to the server. Returns the port.
+#[cfg(test)]
+fn spawn_test_server(responses: &fn(port: uint) -> ~[~str]) -> uint {
+	let (port_port, port_chan) = stream();
+	let (responses_port, responses_chan) = stream();
+	// Accepting blocks the thread, so the server gets a thread of its own.
+	let mut server_task = task::task();
+	server_task.sched_mode(task::SingleThreaded);
+	do server_task.spawn {
+		let listener = unsafe {
+			// AF_INET, SOCK_STREAM
+			let listener = test_socket::socket(2, 1, 0);
+			let address = loopback_address(0);
+			assert!(test_socket::bind(listener, vec::raw::to_ptr(address), 16) == 0);
+			assert!(test_socket::listen(listener, 128) == 0);
+			let mut bound = vec::from_elem(16, 0u8);
+			let mut bound_len = 16u32;
+			assert!(test_socket::getsockname(listener, vec::raw::to_mut_ptr(bound),
+							 &mut bound_len) == 0);
+			port_chan.send((bound[2] as uint) << 8 | bound[3] as uint);
+			listener
+		};
+
+		let responses: ~[~str] = responses_port.recv();
+		for responses.each |response| {
+			unsafe {
+				let connection = test_socket::accept(listener, ptr::mut_null(),
+								     ptr::mut_null());
+				assert!(connection >= 0);
+				// Wait for the request before answering it.
+				let mut request = vec::from_elem(4096, 0u8);
+				test_socket::read(connection, vec::raw::to_mut_ptr(request), 4096);
+				let bytes = str::as_bytes_slice(*response);
+				test_socket::write(connection, vec::raw::to_ptr(bytes), bytes.len() as size_t);
+				test_socket::close(connection);
+			}
+		}
+		unsafe {
+			test_socket::close(listener);
+		}
+	}
+
+	let port = port_port.recv();
+	responses_chan.send(responses(port));
+	port
+}
+
+#[cfg(test)]
+fn load_from_test_server(url: ~str, redirect_limit: uint) -> ~[ProgressMsg] {
+	let (port, chan) = comm::stream();
+	let loader = factory_with_redirect_limit(redirect_limit, None)();
+	loader(url::from_str(url).get(), ~[], chan);
+
+	let mut messages = ~[];
+	loop {
+		let msg = port.recv();
+		let done = match msg { Done(*) => true, _ => false };
+		messages.push(msg);
+		if done {
+			return messages
+		}
+	}
+}
+
+#[test]
+fn should_follow_redirects_and_report_final_url() {
+	let port = do spawn_test_server |port| {
+		~[
+			fmt!("HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:%u/final\r\n\r\n", port),
+			~"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone",
+		]
+	};
+
+	let messages = load_from_test_server(fmt!("http://127.0.0.1:%u/start", port),
+					     DEFAULT_REDIRECT_LIMIT);
+	match messages[0] {
+		Metadata(ref metadata) => {
+			assert!(metadata.status == Some(200));
+			assert!(metadata.final_url.path == ~"/final");
+		}
+		_ => fail!(~"expected metadata")
+	}
+	assert!(messages[1] == Payload(str::to_bytes("done")));
+	assert!(messages[2] == Done(Ok(())));
+}
+
+#[test]
+fn should_detect_redirect_loops() {
+	let port = do spawn_test_server |port| {
+		~[
+			fmt!("HTTP/1.1 301 Moved Permanently\r\nLocation: http://127.0.0.1:%u/a\r\n\r\n",
+			     port),
+			fmt!("HTTP/1.1 307 Temporary Redirect\r\nLocation: http://127.0.0.1:%u/b\r\n\r\n",
+			     port),
+		]
+	};
+
+	// `/b` redirects to `/a`, which redirects back to `/b`.
+	let messages = load_from_test_server(fmt!("http://127.0.0.1:%u/b", port),
+					     DEFAULT_REDIRECT_LIMIT);
+	assert!(messages == ~[Done(Err(()))]);
+}
+
+#[test]
+fn should_stop_at_redirect_limit() {
+	let port = do spawn_test_server |port| {
+		~[
+			fmt!("HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:%u/2\r\n\r\n", port),
+			fmt!("HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:%u/3\r\n\r\n", port),
+		]
+	};
+
+	let messages = load_from_test_server(fmt!("http://127.0.0.1:%u/1", port), 1);
+	assert!(messages == ~[Done(Err(()))]);
+}
+
+#[test]
+fn should_deliver_redirect_without_location_as_is() {
+	let port = do spawn_test_server |_| {
+		~[~"HTTP/1.1 302 Found\r\nContent-Length: 5\r\n\r\nmoved"]
+	};
+
+	let messages = load_from_test_server(fmt!("http://127.0.0.1:%u/", port),
+					     DEFAULT_REDIRECT_LIMIT);
+	match messages[0] {
+		Metadata(ref metadata) => assert!(metadata.status == Some(302)),
+		_ => fail!(~"expected metadata")
+	}
+	assert!(messages[1] == Payload(str::to_bytes("moved")));
 }
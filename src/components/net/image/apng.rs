@@ -0,0 +1,381 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A decoder for animated PNGs. Still PNGs are left to stb_image, as are animated ones that are
+//! interlaced or not 8 bits per channel; stb_image shows their default image.
+
+use image::base::{AnimatedImage, Frame, Image, animation_fits, frame_delay, rgba_to_bgra};
+
+use core::libc::{c_void, size_t};
+use core::libc;
+use std::flate;
+
+static PNG_SIGNATURE: [u8, ..8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// The contents of an `fcTL` chunk, which describes a frame.
+struct FrameControl {
+    width: uint,
+    height: uint,
+    x: uint,
+    y: uint,
+    /// How long the frame is shown, in milliseconds
+    delay: uint,
+    dispose_op: u8,
+    blend_op: u8,
+}
+
+static DISPOSE_OP_BACKGROUND: u8 = 1;
+static DISPOSE_OP_PREVIOUS: u8 = 2;
+static BLEND_OP_OVER: u8 = 1;
+
+/// Decodes a PNG with an animation control chunk and more than one frame. Returns None for
+/// anything else, and for broken or unsupported files. Returns Some(None) for an animation whose
+/// frames are corrupt or that is too large to decode.
+pub fn decode_animation(data: &[u8]) -> Option<Option<Image>> {
+    if data.len() < 8 || vec::slice(data, 0, 8) != PNG_SIGNATURE {
+        return None
+    }
+
+    let mut size = None;
+    let mut color_type = 0;
+    let mut palette = ~[];
+    let mut transparency = ~[];
+    let mut animated = false;
+    let mut plays = None;
+    // Each frame's control chunk and compressed data
+    let mut frames: ~[(FrameControl, ~[u8])] = ~[];
+
+    let mut position = 8;
+    while position + 8 <= data.len() {
+        let length = u32_at(data, position);
+        let kind = vec::slice(data, position + 4, position + 8);
+        let start = position + 8;
+        if start + length + 4 > data.len() {
+            return None
+        }
+        let chunk = vec::slice(data, start, start + length);
+        // Skip the CRC too.
+        position = start + length + 4;
+
+        if is_kind(kind, "IHDR") && length >= 13 {
+            // Only 8 bits per channel, without interlacing
+            if chunk[8] != 8 || chunk[12] != 0 {
+                return None
+            }
+            size = Some((u32_at(chunk, 0), u32_at(chunk, 4)));
+            color_type = chunk[9];
+        } else if is_kind(kind, "PLTE") {
+            palette = chunk.to_owned();
+        } else if is_kind(kind, "tRNS") {
+            transparency = chunk.to_owned();
+        } else if is_kind(kind, "acTL") && length >= 8 {
+            animated = true;
+            plays = match u32_at(chunk, 4) {
+                0 => None,
+                plays => Some(plays),
+            };
+        } else if is_kind(kind, "fcTL") && length >= 26 {
+            let denominator = match u16_at(chunk, 22) {
+                0 => 100,
+                denominator => denominator,
+            };
+            frames.push((FrameControl {
+                width: u32_at(chunk, 4),
+                height: u32_at(chunk, 8),
+                x: u32_at(chunk, 12),
+                y: u32_at(chunk, 16),
+                delay: frame_delay(u16_at(chunk, 20), denominator),
+                dispose_op: chunk[24],
+                blend_op: chunk[25],
+            }, ~[]));
+        } else if is_kind(kind, "IDAT") {
+            // The default image is only the first frame if a frame control chunk came before it.
+            if frames.len() == 1 {
+                match frames[0] {
+                    (_, ref mut compressed) => compressed.push_all(chunk),
+                }
+            }
+        } else if is_kind(kind, "fdAT") && length >= 4 && !frames.is_empty() {
+            // Skip the sequence number.
+            let last = frames.len() - 1;
+            match frames[last] {
+                (_, ref mut compressed) => compressed.push_all(vec::slice(chunk, 4, length)),
+            }
+        } else if is_kind(kind, "IEND") {
+            break
+        }
+    }
+
+    let (width, height) = match size {
+        Some(size) if animated && frames.len() >= 2 => size,
+        _ => return None,
+    };
+    if !animation_fits(width, height, frames.len()) {
+        return Some(None)
+    }
+
+    let mut canvas = vec::from_elem(width * height * 4, 0u8);
+    let mut decoded_frames = ~[];
+    for frames.each |&(ref control, ref compressed)| {
+        if control.x + control.width > width || control.y + control.height > height {
+            return None
+        }
+        let pixels = match decode_pixels(*compressed,
+                                         control.width,
+                                         control.height,
+                                         color_type,
+                                         palette,
+                                         transparency) {
+            Some(pixels) => pixels,
+            None => return Some(None),
+        };
+
+        let previous = if control.dispose_op == DISPOSE_OP_PREVIOUS {
+            Some(copy canvas)
+        } else {
+            None
+        };
+
+        for uint::range(0, control.height) |row| {
+            for uint::range(0, control.width) |column| {
+                let source = (row * control.width + column) * 4;
+                let target = ((control.y + row) * width + control.x + column) * 4;
+                if control.blend_op == BLEND_OP_OVER {
+                    blend_over(vec::slice(pixels, source, source + 4), &mut canvas, target);
+                } else {
+                    for uint::range(0, 4) |channel| {
+                        canvas[target + channel] = pixels[source + channel];
+                    }
+                }
+            }
+        }
+
+        decoded_frames.push(Frame {
+            data: rgba_to_bgra(canvas),
+            delay: control.delay,
+        });
+
+        match previous {
+            Some(previous) => canvas = previous,
+            None if control.dispose_op == DISPOSE_OP_BACKGROUND => {
+                for uint::range(control.y, control.y + control.height) |y| {
+                    for uint::range(control.x * 4, (control.x + control.width) * 4) |i| {
+                        canvas[y * width * 4 + i] = 0;
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    Some(Some(AnimatedImage(width, height, decoded_frames, plays)))
+}
+
+/// Decompresses and unfilters the pixels of a frame, returning them as straight RGBA.
+fn decode_pixels(compressed: &[u8],
+                 width: uint,
+                 height: uint,
+                 color_type: u8,
+                 palette: &[u8],
+                 transparency: &[u8])
+                 -> Option<~[u8]> {
+    let channels = match color_type {
+        0 | 3 => 1,   // grayscale, palette
+        4 => 2,       // grayscale and alpha
+        2 => 3,       // RGB
+        6 => 4,       // RGBA
+        _ => return None,
+    };
+    // Skip the zlib header; what follows is a plain deflate stream.
+    if compressed.len() < 2 {
+        return None
+    }
+    let raw = match inflate(vec::slice(compressed, 2, compressed.len())) {
+        Some(raw) => raw,
+        None => return None,
+    };
+    let stride = width * channels;
+    if raw.len() < (stride + 1) * height {
+        return None
+    }
+
+    let mut pixels = vec::with_capacity(width * height * 4);
+    let mut previous = vec::from_elem(stride, 0u8);
+    let mut current = vec::from_elem(stride, 0u8);
+    for uint::range(0, height) |y| {
+        let line_start = y * (stride + 1);
+        let filter = raw[line_start];
+        for uint::range(0, stride) |i| {
+            let x = raw[line_start + 1 + i] as uint;
+            let a = if i >= channels { current[i - channels] as uint } else { 0 };
+            let b = previous[i] as uint;
+            let c = if i >= channels { previous[i - channels] as uint } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => (a + b) / 2,
+                4 => paeth(a, b, c),
+                _ => return None,
+            };
+            current[i] = ((x + predicted) & 0xff) as u8;
+        }
+
+        for uint::range(0, width) |column| {
+            let pixel = vec::slice(current, column * channels, (column + 1) * channels);
+            match color_type {
+                0 => pixels.push_all([pixel[0], pixel[0], pixel[0], 0xff]),
+                4 => pixels.push_all([pixel[0], pixel[0], pixel[0], pixel[1]]),
+                2 => pixels.push_all([pixel[0], pixel[1], pixel[2], 0xff]),
+                3 => {
+                    let index = pixel[0] as uint;
+                    if index * 3 + 2 >= palette.len() {
+                        return None
+                    }
+                    let alpha = if index < transparency.len() { transparency[index] } else { 0xff };
+                    pixels.push_all([palette[index * 3],
+                                     palette[index * 3 + 1],
+                                     palette[index * 3 + 2],
+                                     alpha]);
+                }
+                _ => pixels.push_all(pixel),
+            }
+        }
+        previous <-> current;
+    }
+    Some(pixels)
+}
+
+/// Decompresses a deflate stream. Unlike `flate::inflate_bytes`, returns None for corrupt data
+/// instead of failing.
+fn inflate(compressed: &[u8]) -> Option<~[u8]> {
+    do vec::as_imm_buf(compressed) |buf, len| {
+        unsafe {
+            let out_len: size_t = 0;
+            let out = flate::rustrt::tinfl_decompress_mem_to_heap(buf as *c_void,
+                                                                  len as size_t,
+                                                                  ptr::addr_of(&out_len),
+                                                                  0);
+            if out.is_null() {
+                None
+            } else {
+                let data = vec::raw::from_buf_raw(out as *u8, out_len as uint);
+                libc::free(out);
+                Some(data)
+            }
+        }
+    }
+}
+
+fn paeth(a: uint, b: uint, c: uint) -> uint {
+    let p = (a + b) as int - c as int;
+    let pa = int::abs(p - a as int);
+    let pb = int::abs(p - b as int);
+    let pc = int::abs(p - c as int);
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+/// Draws a straight RGBA pixel over the one at `target` in the canvas.
+fn blend_over(source: &[u8], canvas: &mut ~[u8], target: uint) {
+    let source_alpha = source[3] as uint;
+    let target_alpha = canvas[target + 3] as uint;
+    let alpha = source_alpha + target_alpha * (255 - source_alpha) / 255;
+    if alpha == 0 {
+        return
+    }
+    for uint::range(0, 3) |channel| {
+        let blended = (source[channel] as uint * source_alpha +
+                       canvas[target + channel] as uint * target_alpha * (255 - source_alpha) / 255)
+                      / alpha;
+        canvas[target + channel] = blended as u8;
+    }
+    canvas[target + 3] = alpha as u8;
+}
+
+fn is_kind(kind: &[u8], name: &str) -> bool {
+    kind == str::to_bytes(name)
+}
+
+fn u32_at(data: &[u8], position: uint) -> uint {
+    (data[position] as uint << 24) | (data[position + 1] as uint << 16) |
+        (data[position + 2] as uint << 8) | (data[position + 3] as uint)
+}
+
+fn u16_at(data: &[u8], position: uint) -> uint {
+    (data[position] as uint << 8) | (data[position + 1] as uint)
+}
+
+#[cfg(test)]
+fn push_chunk(png: &mut ~[u8], kind: &str, data: &[u8]) {
+    let length = data.len();
+    png.push_all([(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8]);
+    png.push_all(str::to_bytes(kind));
+    png.push_all(data);
+    // The CRC isn't checked.
+    png.push_all([0, 0, 0, 0]);
+}
+
+#[cfg(test)]
+fn zlib(data: &[u8]) -> ~[u8] {
+    // The Adler-32 checksum isn't checked either.
+    ~[0x78, 0x01] + flate::deflate_bytes(data) + ~[0, 0, 0, 0]
+}
+
+#[cfg(test)]
+fn frame_control(sequence: u8, width: u8, x: u8, blend_op: u8) -> ~[u8] {
+    ~[0, 0, 0, sequence, 0, 0, 0, width, 0, 0, 0, 1, 0, 0, 0, x, 0, 0, 0, 0,
+      0, 1, 0, 4, 0, blend_op]
+}
+
+#[test]
+fn should_decode_frames_over_each_other() {
+    let mut png = PNG_SIGNATURE.to_owned();
+    // 2x1, 8-bit RGBA
+    push_chunk(&mut png, "IHDR", [0, 0, 0, 2, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+    // Two frames, played three times
+    push_chunk(&mut png, "acTL", [0, 0, 0, 2, 0, 0, 0, 3]);
+    // The default image is the first frame: opaque red, then transparent.
+    push_chunk(&mut png, "fcTL", frame_control(0, 2, 0, 0));
+    push_chunk(&mut png, "IDAT", zlib([0, 0xff, 0, 0, 0xff, 0, 0, 0, 0]));
+    // The second frame puts half transparent blue over the right pixel.
+    push_chunk(&mut png, "fcTL", frame_control(1, 1, 1, BLEND_OP_OVER));
+    push_chunk(&mut png, "fdAT", ~[0, 0, 0, 2] + zlib([0, 0, 0, 0xff, 0x80]));
+    push_chunk(&mut png, "IEND", []);
+
+    let image = decode_animation(png).get().get();
+    let animation = image.animation.get_ref();
+    assert!(animation.plays == Some(3));
+    assert!(animation.frames.len() == 2);
+    assert!(animation.frames[0].delay == 250);
+    // Premultiplied BGRA
+    assert!(animation.frames[0].data == ~[0, 0, 0xff, 0xff, 0, 0, 0, 0]);
+    assert!(animation.frames[1].data == ~[0, 0, 0xff, 0xff, 0x80, 0, 0, 0x80]);
+}
+
+#[test]
+fn should_leave_still_pngs_to_stb_image() {
+    let mut png = PNG_SIGNATURE.to_owned();
+    push_chunk(&mut png, "IHDR", [0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+    push_chunk(&mut png, "IDAT", zlib([0, 0xff, 0, 0, 0xff]));
+    push_chunk(&mut png, "IEND", []);
+    assert!(decode_animation(png).is_none());
+}
+
+#[test]
+fn should_fail_animations_with_corrupt_frames() {
+    let mut png = PNG_SIGNATURE.to_owned();
+    push_chunk(&mut png, "IHDR", [0, 0, 0, 2, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+    push_chunk(&mut png, "acTL", [0, 0, 0, 2, 0, 0, 0, 3]);
+    push_chunk(&mut png, "fcTL", frame_control(0, 2, 0, 0));
+    // A deflate block of a reserved type
+    push_chunk(&mut png, "IDAT", [0x78, 0x01, 0xff, 0xff, 0xff]);
+    push_chunk(&mut png, "fcTL", frame_control(1, 1, 1, BLEND_OP_OVER));
+    push_chunk(&mut png, "fdAT", ~[0, 0, 0, 2] + zlib([0, 0, 0, 0xff, 0x80]));
+    push_chunk(&mut png, "IEND", []);
+
+    match decode_animation(png) {
+        Some(None) => {}
+        _ => fail!(~"decoded an animation with a corrupt frame"),
+    }
+}
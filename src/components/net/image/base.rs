@@ -2,15 +2,228 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use image::{apng, gif};
+
+use std::time::precise_time_ns;
 use stb_image = stb_image::image;
 
 // FIXME: Images must not be copied every frame. Instead we should atomically
 // reference count them.
 
-pub type Image = stb_image::Image<u8>;
+/// A decoded image. Pixels are stored in BGRA order, four bytes each.
+pub struct Image {
+    width: uint,
+    height: uint,
+    depth: uint,
+    /// The pixels. For an animated image, these are the first frame's.
+    data: ~[u8],
+    /// The frames of an animated image, or None for a still one.
+    animation: Option<Animation>,
+}
+
+/// The frames of an animated image, each as big as the whole image.
+pub struct Animation {
+    frames: ~[Frame],
+    /// How many times the animation plays, or None to loop forever.
+    plays: Option<uint>,
+    /// When the animation started, in nanoseconds as returned by `precise_time_ns`.
+    start_time: u64,
+}
+
+pub struct Frame {
+    data: ~[u8],
+    /// How long the frame is shown, in milliseconds.
+    delay: uint,
+}
 
 pub fn Image(width: uint, height: uint, depth: uint, data: ~[u8]) -> Image {
-    stb_image::new_image(width, height, depth, data)
+    Image {
+        width: width,
+        height: height,
+        depth: depth,
+        data: data,
+        animation: None,
+    }
+}
+
+/// Creates an animated image, which starts playing now. There must be at least one frame.
+pub fn AnimatedImage(width: uint, height: uint, frames: ~[Frame], plays: Option<uint>) -> Image {
+    Image {
+        width: width,
+        height: height,
+        depth: 4,
+        data: copy frames[0].data,
+        animation: Some(Animation {
+            frames: frames,
+            plays: plays,
+            start_time: precise_time_ns(),
+        }),
+    }
+}
+
+pub impl Image {
+    /// The pixels to show at the given time, in nanoseconds as returned by `precise_time_ns`.
+    fn data_at<'a>(&'a self, time: u64) -> &'a [u8] {
+        match self.animation {
+            Some(ref animation) => {
+                let (index, _) = animation.position_at(time);
+                let data: &'a [u8] = animation.frames[index].data;
+                data
+            }
+            None => {
+                let data: &'a [u8] = self.data;
+                data
+            }
+        }
+    }
+
+    /// How many milliseconds after the given time the image next changes, or None if it won't.
+    fn next_frame_delay(&self, time: u64) -> Option<uint> {
+        match self.animation {
+            Some(ref animation) => {
+                let (_, delay) = animation.position_at(time);
+                delay
+            }
+            None => None,
+        }
+    }
+
+    /// The number of bytes the pixels of all the frames take up.
+    fn byte_size(&self) -> uint {
+        match self.animation {
+            Some(ref animation) => animation.frames.len() * self.data.len(),
+            None => self.data.len(),
+        }
+    }
+
+    /// Returns a copy of the image at half the width and height, rounded down, with each pixel
+    /// the average of the four it replaces.
+    fn half_size(&self) -> Image {
+        let width = uint::max(self.width / 2, 1);
+        let height = uint::max(self.height / 2, 1);
+        let data = half_size(self.data, self.width, self.height, width, height);
+        let animation = match self.animation {
+            Some(ref animation) => Some(Animation {
+                frames: do animation.frames.map |frame| {
+                    Frame {
+                        data: half_size(frame.data, self.width, self.height, width, height),
+                        delay: frame.delay,
+                    }
+                },
+                plays: animation.plays,
+                start_time: animation.start_time,
+            }),
+            None => None,
+        };
+        Image {
+            width: width,
+            height: height,
+            depth: self.depth,
+            data: data,
+            animation: animation,
+        }
+    }
+}
+
+fn half_size(data: &[u8], width: uint, height: uint, new_width: uint, new_height: uint) -> ~[u8] {
+    do vec::from_fn(new_width * new_height * 4) |i| {
+        let channel = i % 4;
+        let x = (i / 4) % new_width * 2;
+        let y = (i / 4) / new_width * 2;
+        // An odd row or column at the edge is dropped, unless the image is only one pixel across.
+        let next_x = uint::min(x + 1, width - 1);
+        let next_y = uint::min(y + 1, height - 1);
+        let mut sum = 0;
+        for [(x, y), (next_x, y), (x, next_y), (next_x, next_y)].each |&(x, y)| {
+            sum += data[(y * width + x) * 4 + channel] as uint;
+        }
+        (sum / 4) as u8
+    }
+}
+
+impl Animation {
+    /// Returns the index of the frame shown at the given time, and the milliseconds until the
+    /// next one, unless the animation has finished.
+    fn position_at(&self, time: u64) -> (uint, Option<uint>) {
+        let mut duration = 0;
+        for self.frames.each |frame| {
+            duration += frame.delay;
+        }
+        let elapsed = if time > self.start_time {
+            ((time - self.start_time) / 1000000) as uint
+        } else {
+            0
+        };
+        if duration == 0 {
+            return (self.frames.len() - 1, None)
+        }
+        match self.plays {
+            Some(plays) if elapsed >= duration * plays => return (self.frames.len() - 1, None),
+            _ => {}
+        }
+
+        let mut offset = elapsed % duration;
+        for self.frames.eachi |index, frame| {
+            if offset < frame.delay {
+                return (index, Some(frame.delay - offset))
+            }
+            offset -= frame.delay;
+        }
+        (self.frames.len() - 1, None)
+    }
+}
+
+/// The most pixels an animation's canvas may have.
+pub static MAX_ANIMATION_PIXELS: uint = 16 * 1024 * 1024;
+/// The most bytes the decoded frames of an animation may take up together.
+pub static MAX_ANIMATION_BYTES: uint = 256 * 1024 * 1024;
+
+/// Returns true if an animation of the given size is within the limits on its canvas and on the
+/// bytes its frames take up. Animations over them aren't decoded at all.
+pub fn animation_fits(width: uint, height: uint, frame_count: uint) -> bool {
+    if width == 0 || height == 0 {
+        return true
+    }
+    if width > MAX_ANIMATION_PIXELS / height {
+        return false
+    }
+    frame_count <= MAX_ANIMATION_BYTES / (width * height * 4)
+}
+
+/// Converts a frame delay to milliseconds. Like other browsers, delays of 10ms or less are taken
+/// to mean 100ms, since many animations rely on that.
+pub fn frame_delay(numerator: uint, denominator: uint) -> uint {
+    let delay = numerator * 1000 / denominator;
+    if delay <= 10 { 100 } else { delay }
+}
+
+/// Converts straight RGBA pixels to the premultiplied BGRA pixels images are stored in.
+pub fn rgba_to_bgra(rgba: &[u8]) -> ~[u8] {
+    do vec::from_fn(rgba.len()) |i| {
+        let pixel = i - i % 4;
+        let alpha = rgba[pixel + 3] as uint;
+        match i % 4 {
+            3 => rgba[i],
+            channel => ((rgba[pixel + 2 - channel] as uint) * alpha / 255) as u8,
+        }
+    }
+}
+
+/// Converts premultiplied BGRA pixels, as images are stored in, back to straight RGBA pixels.
+/// Fully transparent pixels come out black.
+pub fn bgra_to_rgba(bgra: &[u8]) -> ~[u8] {
+    do vec::from_fn(bgra.len()) |i| {
+        let pixel = i - i % 4;
+        let alpha = bgra[pixel + 3] as uint;
+        match i % 4 {
+            3 => bgra[i],
+            _ if alpha == 0 => 0,
+            channel => {
+                let color = bgra[pixel + 2 - channel] as uint;
+                uint::min((color * 255 + alpha / 2) / alpha, 255) as u8
+            }
+        }
+    }
 }
 
 static TEST_IMAGE: [u8, ..4962] = include_bin!("test.jpeg");
@@ -20,6 +233,16 @@ pub fn test_image_bin() -> ~[u8] {
 }
 
 pub fn load_from_memory(buffer: &[u8]) -> Option<Image> {
+    // stb_image only decodes the first frame of an animation.
+    match gif::decode_animation(buffer) {
+        Some(result) => return result,
+        None => {}
+    }
+    match apng::decode_animation(buffer) {
+        Some(result) => return result,
+        None => {}
+    }
+
     // Can't remember why we do this. Maybe it's what cairo wants
     static FORCE_DEPTH: uint = 4;
 
@@ -47,3 +270,37 @@ pub fn load_from_memory(buffer: &[u8]) -> Option<Image> {
         stb_image::Error => None
     }
 }
+
+#[test]
+fn should_loop_through_frames_the_given_number_of_times() {
+    let frames = ~[Frame { data: ~[0, 0, 0, 0], delay: 100 },
+                   Frame { data: ~[1, 1, 1, 1], delay: 50 }];
+    let image = AnimatedImage(1, 1, frames, Some(2));
+    let start = image.animation.get_ref().start_time;
+    let ms = |ms: u64| start + ms * 1000000;
+
+    assert!(image.data_at(ms(0)) == &[0, 0, 0, 0]);
+    assert!(image.next_frame_delay(ms(30)) == Some(70));
+    assert!(image.data_at(ms(120)) == &[1, 1, 1, 1]);
+    assert!(image.data_at(ms(160)) == &[0, 0, 0, 0]);
+    assert!(image.data_at(ms(400)) == &[1, 1, 1, 1]);
+    assert!(image.next_frame_delay(ms(400)).is_none());
+    assert!(image.byte_size() == 8);
+}
+
+#[test]
+fn should_average_pixels_when_halving() {
+    let data = ~[0, 0, 0, 0,  4, 8, 12, 255,  100, 100, 100, 100,
+                 8, 8, 8, 8,  4, 8, 12, 255,  100, 100, 100, 100];
+    let image = Image(3, 2, 4, data).half_size();
+    assert!(image.width == 1 && image.height == 1);
+    assert!(image.data == ~[4, 6, 8, 129]);
+}
+
+#[test]
+fn should_undo_premultiplication() {
+    let rgba = ~[255, 128, 0, 128,  10, 20, 30, 255,  40, 50, 60, 0];
+    let bgra = rgba_to_bgra(rgba);
+    assert!(bgra == ~[0, 64, 128, 128,  30, 20, 10, 255,  0, 0, 0, 0]);
+    assert!(bgra_to_rgba(bgra) == ~[255, 128, 0, 128,  10, 20, 30, 255,  0, 0, 0, 0]);
+}
@@ -0,0 +1,130 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reading the intrinsic dimensions of an image from its header, before the rest of it has
+//! arrived.
+
+use geom::size::Size2D;
+
+/// Returns the width and height of the image whose data starts with `data`, or None if the
+/// format isn't recognized or its header hasn't arrived yet.
+pub fn probe(data: &[u8]) -> Option<Size2D<uint>> {
+    let dimensions = if starts_with(data, [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a]) {
+        // The IHDR chunk always comes first.
+        if data.len() < 24 {
+            return None
+        }
+        Size2D(u32_be_at(data, 16), u32_be_at(data, 20))
+    } else if starts_with(data, str::to_bytes("GIF87a")) ||
+            starts_with(data, str::to_bytes("GIF89a")) {
+        if data.len() < 10 {
+            return None
+        }
+        Size2D(u16_le_at(data, 6), u16_le_at(data, 8))
+    } else if starts_with(data, str::to_bytes("BM")) {
+        probe_bmp(data)
+    } else if starts_with(data, [0xff, 0xd8]) {
+        probe_jpeg(data)
+    } else {
+        return None
+    };
+    if dimensions.width == 0 || dimensions.height == 0 {
+        None
+    } else {
+        Some(dimensions)
+    }
+}
+
+fn probe_bmp(data: &[u8]) -> Size2D<uint> {
+    if data.len() < 26 {
+        return Size2D(0, 0)
+    }
+    // Old OS/2 headers have 16-bit dimensions. In the others, a negative height means the rows
+    // are stored top to bottom.
+    if u32_le_at(data, 14) == 12 {
+        return Size2D(u16_le_at(data, 18), u16_le_at(data, 20))
+    }
+    let width = u32_le_at(data, 18) as i32;
+    let height = u32_le_at(data, 22) as i32;
+    Size2D(i32::abs(width) as uint, i32::abs(height) as uint)
+}
+
+/// Skips segments up to the start of frame marker, which holds the dimensions.
+fn probe_jpeg(data: &[u8]) -> Size2D<uint> {
+    let mut position = 2;
+    while position + 4 <= data.len() {
+        if data[position] != 0xff {
+            break
+        }
+        let marker = data[position + 1];
+        match marker {
+            // Fill bytes before a marker
+            0xff => {
+                position += 1;
+                loop
+            }
+            // Markers without a segment
+            0x01 | 0xd0 .. 0xd7 => {
+                position += 2;
+                loop
+            }
+            // Start of frame, except for the markers in that range that aren't
+            0xc0 .. 0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                if position + 9 > data.len() {
+                    break
+                }
+                return Size2D(u16_be_at(data, position + 7), u16_be_at(data, position + 5))
+            }
+            // The image data starts without a frame
+            0xd9 | 0xda => break,
+            _ => position += 2 + u16_be_at(data, position + 2),
+        }
+    }
+    Size2D(0, 0)
+}
+
+fn starts_with(data: &[u8], prefix: &[u8]) -> bool {
+    data.len() >= prefix.len() && vec::slice(data, 0, prefix.len()) == prefix
+}
+
+fn u16_be_at(data: &[u8], position: uint) -> uint {
+    (data[position] as uint << 8) | (data[position + 1] as uint)
+}
+
+fn u16_le_at(data: &[u8], position: uint) -> uint {
+    (data[position] as uint) | (data[position + 1] as uint << 8)
+}
+
+fn u32_be_at(data: &[u8], position: uint) -> uint {
+    (u16_be_at(data, position) << 16) | u16_be_at(data, position + 2)
+}
+
+fn u32_le_at(data: &[u8], position: uint) -> uint {
+    u16_le_at(data, position) | (u16_le_at(data, position + 2) << 16)
+}
+
+#[test]
+fn should_read_dimensions_from_headers() {
+    let png = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0, 0, 0, 13,
+               0x49, 0x48, 0x44, 0x52, 0, 0, 1, 0, 0, 0, 0, 3];
+    assert!(probe(png) == Some(Size2D(256, 3)));
+    assert!(probe(vec::slice(png, 0, 20)).is_none());
+
+    let gif = [0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 2, 1, 5, 0];
+    assert!(probe(gif) == Some(Size2D(258, 5)));
+
+    let bmp = [0x42, 0x4d, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 40, 0, 0, 0,
+               4, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff];
+    assert!(probe(bmp) == Some(Size2D(4, 2)));
+}
+
+#[test]
+fn should_find_the_jpeg_frame_header() {
+    use image::base::test_image_bin;
+    let jpeg = test_image_bin();
+    assert!(probe(jpeg) == Some(Size2D(450, 337)));
+    // The frame header ends at byte 431.
+    assert!(probe(vec::slice(jpeg, 0, 431)) == Some(Size2D(450, 337)));
+    assert!(probe(vec::slice(jpeg, 0, 430)).is_none());
+}
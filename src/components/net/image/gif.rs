@@ -0,0 +1,408 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A decoder for animated GIFs. Still GIFs are left to stb_image, which only decodes the first
+//! frame.
+
+use image::base::{AnimatedImage, Frame, Image, animation_fits, frame_delay, rgba_to_bgra};
+
+/// The largest number of codes an LZW table holds.
+static MAX_CODES: uint = 4096;
+
+/// The settings from a graphic control extension, which apply to the next image in the file.
+struct GraphicControl {
+    /// How long the image is shown, in hundredths of a second
+    delay: uint,
+    /// What to do with the image once its time is up
+    disposal: Disposal,
+    transparent_index: Option<uint>,
+}
+
+#[deriving(Eq)]
+enum Disposal {
+    /// Leave the image in place for the next one to be drawn over
+    KeepImage,
+    /// Clear the image's area to transparent
+    ClearImage,
+    /// Put back what was there before the image was drawn
+    RestorePrevious,
+}
+
+impl GraphicControl {
+    fn new() -> GraphicControl {
+        GraphicControl {
+            delay: 0,
+            disposal: KeepImage,
+            transparent_index: None,
+        }
+    }
+}
+
+/// Decodes a GIF with more than one image in it. Returns None for a still GIF, for anything that
+/// isn't a GIF at all, and for broken files, and Some(None) for an animation too large to decode.
+pub fn decode_animation(data: &[u8]) -> Option<Option<Image>> {
+    if data.len() < 13 || !(starts_with(data, "GIF87a") || starts_with(data, "GIF89a")) {
+        return None
+    }
+    let width = u16_at(data, 6);
+    let height = u16_at(data, 8);
+    if width == 0 || height == 0 {
+        return None
+    }
+    if !animation_fits(width, height, 1) {
+        return Some(None)
+    }
+    let mut position = 13;
+    let global_table = match read_color_table(data, data[10], &mut position) {
+        Some(table) => table,
+        None => return None,
+    };
+
+    let mut canvas = vec::from_elem(width * height * 4, 0u8);
+    let mut frames = ~[];
+    // Without a loop extension, the animation plays once.
+    let mut plays = Some(1);
+    let mut control = GraphicControl::new();
+
+    while position < data.len() {
+        match data[position] {
+            // An extension
+            0x21 => {
+                if position + 1 >= data.len() {
+                    return None
+                }
+                let label = data[position + 1];
+                position += 2;
+                let extension = match read_sub_blocks(data, &mut position) {
+                    Some(extension) => extension,
+                    None => return None,
+                };
+                if label == 0xf9 && extension.len() >= 4 {
+                    control = GraphicControl {
+                        delay: u16_at(extension, 1),
+                        disposal: match (extension[0] >> 2) & 0x7 {
+                            2 => ClearImage,
+                            3 => RestorePrevious,
+                            _ => KeepImage,
+                        },
+                        transparent_index: if extension[0] & 0x1 != 0 {
+                            Some(extension[3] as uint)
+                        } else {
+                            None
+                        },
+                    };
+                } else if label == 0xff && extension.len() >= 14 &&
+                        starts_with(extension, "NETSCAPE2.0") && extension[11] == 1 {
+                    // The number of times to repeat the animation after playing it once.
+                    plays = match u16_at(extension, 12) {
+                        0 => None,
+                        repeats => Some(repeats + 1),
+                    };
+                }
+            }
+
+            // An image
+            0x2c => {
+                if position + 10 > data.len() {
+                    return None
+                }
+                let left = u16_at(data, position + 1);
+                let top = u16_at(data, position + 3);
+                let image_width = u16_at(data, position + 5);
+                let image_height = u16_at(data, position + 7);
+                let flags = data[position + 9];
+                position += 10;
+                let table = match read_color_table(data, flags, &mut position) {
+                    Some(Some(table)) => table,
+                    Some(None) => match global_table {
+                        Some(ref table) => copy *table,
+                        None => return None,
+                    },
+                    None => return None,
+                };
+                if position >= data.len() {
+                    return None
+                }
+                let min_code_size = data[position] as uint;
+                position += 1;
+                if min_code_size < 1 || min_code_size > 11 {
+                    return None
+                }
+                let compressed = match read_sub_blocks(data, &mut position) {
+                    Some(compressed) => compressed,
+                    None => return None,
+                };
+                let indices = lzw_decode(compressed, min_code_size, image_width * image_height);
+
+                let previous = if control.disposal == RestorePrevious {
+                    Some(copy canvas)
+                } else {
+                    None
+                };
+
+                let rows = if flags & 0x40 != 0 {
+                    interlaced_rows(image_height)
+                } else {
+                    vec::from_fn(image_height, |row| row)
+                };
+                for rows.eachi |i, &row| {
+                    let y = top + row;
+                    for uint::range(0, image_width) |column| {
+                        let x = left + column;
+                        let index = indices[i * image_width + column] as uint;
+                        if x >= width || y >= height || control.transparent_index == Some(index) ||
+                                index * 3 + 2 >= table.len() {
+                            loop
+                        }
+                        let pixel = (y * width + x) * 4;
+                        canvas[pixel] = table[index * 3];
+                        canvas[pixel + 1] = table[index * 3 + 1];
+                        canvas[pixel + 2] = table[index * 3 + 2];
+                        canvas[pixel + 3] = 0xff;
+                    }
+                }
+
+                if !animation_fits(width, height, frames.len() + 1) {
+                    return Some(None)
+                }
+                frames.push(Frame {
+                    data: rgba_to_bgra(canvas),
+                    delay: frame_delay(control.delay, 100),
+                });
+
+                match (control.disposal, previous) {
+                    (RestorePrevious, Some(previous)) => canvas = previous,
+                    (ClearImage, _) => {
+                        for uint::range(top, uint::min(top + image_height, height)) |y| {
+                            for uint::range(left, uint::min(left + image_width, width)) |x| {
+                                for uint::range(0, 4) |channel| {
+                                    canvas[(y * width + x) * 4 + channel] = 0;
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                control = GraphicControl::new();
+            }
+
+            // The trailer
+            0x3b => break,
+
+            _ => return None,
+        }
+    }
+
+    if frames.len() < 2 {
+        return None
+    }
+    Some(Some(AnimatedImage(width, height, frames, plays)))
+}
+
+/// Reads the color table announced by the flags of the screen or an image, if there is one.
+/// Returns None if the file is cut short.
+fn read_color_table(data: &[u8], flags: u8, position: &mut uint) -> Option<Option<~[u8]>> {
+    if flags & 0x80 == 0 {
+        return Some(None)
+    }
+    let size = 3 << ((flags & 0x7) + 1);
+    if *position + size > data.len() {
+        return None
+    }
+    let table = vec::slice(data, *position, *position + size).to_owned();
+    *position += size;
+    Some(Some(table))
+}
+
+/// Reads a sequence of data sub-blocks, up to the empty block that ends it.
+fn read_sub_blocks(data: &[u8], position: &mut uint) -> Option<~[u8]> {
+    let mut result = ~[];
+    loop {
+        if *position >= data.len() {
+            return None
+        }
+        let size = data[*position] as uint;
+        *position += 1;
+        if size == 0 {
+            return Some(result)
+        }
+        if *position + size > data.len() {
+            return None
+        }
+        result.push_all(vec::slice(data, *position, *position + size));
+        *position += size;
+    }
+}
+
+/// Decompresses the color indices of an image. Missing or corrupt data leaves the rest of the
+/// image with index 0.
+fn lzw_decode(data: &[u8], min_code_size: uint, pixel_count: uint) -> ~[u8] {
+    let clear_code = 1 << min_code_size;
+    let end_code = clear_code + 1;
+    // Each code stands for the string of its prefix code followed by its suffix.
+    let mut prefix = vec::from_elem(MAX_CODES, 0u16);
+    let mut suffix = vec::from_elem(MAX_CODES, 0u8);
+    let mut first = vec::from_elem(MAX_CODES, 0u8);
+    for uint::range(0, clear_code) |code| {
+        suffix[code] = code as u8;
+        first[code] = code as u8;
+    }
+
+    let mut code_size = min_code_size + 1;
+    let mut next_code = end_code + 1;
+    let mut previous: Option<uint> = None;
+    let mut output = vec::with_capacity(pixel_count);
+    let mut string = ~[];
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    let mut position = 0;
+
+    while output.len() < pixel_count {
+        while bit_count < code_size && position < data.len() {
+            bits |= (data[position] as u32) << bit_count;
+            bit_count += 8;
+            position += 1;
+        }
+        if bit_count < code_size {
+            break
+        }
+        let code = (bits & ((1 << code_size) - 1)) as uint;
+        bits >>= code_size;
+        bit_count -= code_size;
+
+        if code == clear_code {
+            code_size = min_code_size + 1;
+            next_code = end_code + 1;
+            previous = None;
+            loop
+        }
+        if code == end_code {
+            break
+        }
+
+        match previous {
+            None => {
+                if code >= clear_code {
+                    break
+                }
+            }
+            Some(previous) => {
+                if code > next_code {
+                    break
+                }
+                if next_code < MAX_CODES {
+                    // The new code is the previous string plus the first byte of this one, which
+                    // is the previous string's own first byte if this is the new code.
+                    prefix[next_code] = previous as u16;
+                    suffix[next_code] = if code < next_code {
+                        first[code]
+                    } else {
+                        first[previous]
+                    };
+                    first[next_code] = first[previous];
+                    next_code += 1;
+                    if next_code == 1 << code_size && code_size < 12 {
+                        code_size += 1;
+                    }
+                }
+            }
+        }
+
+        string.truncate(0);
+        let mut current = code;
+        loop {
+            string.push(suffix[current]);
+            if current < clear_code {
+                break
+            }
+            current = prefix[current] as uint;
+        }
+        for vec::each_reverse(string) |byte| {
+            if output.len() < pixel_count {
+                output.push(*byte);
+            }
+        }
+        previous = Some(code);
+    }
+
+    while output.len() < pixel_count {
+        output.push(0);
+    }
+    output
+}
+
+/// The order in which the rows of an interlaced image are stored.
+fn interlaced_rows(height: uint) -> ~[uint] {
+    let mut rows = ~[];
+    for [(0, 8), (4, 8), (2, 4), (1, 2)].each |&(start, step)| {
+        let mut row = start;
+        while row < height {
+            rows.push(row);
+            row += step;
+        }
+    }
+    rows
+}
+
+fn u16_at(data: &[u8], position: uint) -> uint {
+    (data[position] as uint) | ((data[position + 1] as uint) << 8)
+}
+
+fn starts_with(data: &[u8], prefix: &str) -> bool {
+    data.len() >= prefix.len() && vec::slice(data, 0, prefix.len()) == str::to_bytes(prefix)
+}
+
+#[cfg(test)]
+static TWO_FRAMES: [u8, ..77] = [
+    // Header and a 2x1 screen with a two color global table: red and blue
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x02, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00,
+    0xff, 0x00, 0x00, 0x00, 0x00, 0xff,
+    // Loop forever
+    0x21, 0xff, 0x0b, 0x4e, 0x45, 0x54, 0x53, 0x43, 0x41, 0x50, 0x45, 0x32, 0x2e, 0x30,
+    0x03, 0x01, 0x00, 0x00, 0x00,
+    // Show the first image for 20/100s
+    0x21, 0xf9, 0x04, 0x00, 0x14, 0x00, 0x00, 0x00,
+    // A 2x1 image of indices 0, 1: clear, 0, 1, end with 3-bit codes
+    0x2c, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00,
+    0x02, 0x02, 0x44, 0x0a, 0x00,
+    // A 1x1 image at x = 1 of index 0
+    0x2c, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+    0x02, 0x02, 0x44, 0x01, 0x00,
+    0x3b,
+];
+
+#[test]
+fn should_decode_frames_over_each_other() {
+    let image = decode_animation(TWO_FRAMES).get().get();
+    let animation = image.animation.get_ref();
+    assert!(image.width == 2 && image.height == 1);
+    assert!(animation.plays.is_none());
+    assert!(animation.frames.len() == 2);
+    assert!(animation.frames[0].delay == 200);
+    // Red then blue, in BGRA
+    assert!(animation.frames[0].data == ~[0, 0, 0xff, 0xff, 0xff, 0, 0, 0xff]);
+    // The second frame only covers the right pixel, turning it red
+    assert!(animation.frames[1].data == ~[0, 0, 0xff, 0xff, 0, 0, 0xff, 0xff]);
+}
+
+#[test]
+fn should_leave_still_gifs_to_stb_image() {
+    let mut still = vec::slice(TWO_FRAMES, 0, 61).to_owned();
+    still.push(0x3b);
+    assert!(decode_animation(still).is_none());
+}
+
+#[test]
+fn should_reject_animations_over_the_size_limits() {
+    // A 65535x65535 screen
+    let mut huge = TWO_FRAMES.to_owned();
+    huge[6] = 0xff;
+    huge[7] = 0xff;
+    huge[8] = 0xff;
+    huge[9] = 0xff;
+    match decode_animation(huge) {
+        Some(None) => {}
+        _ => fail!(~"decoded an animation over the size limits"),
+    }
+}
@@ -9,7 +9,7 @@ use local_image_cache::LocalImageCache;
 use core::util::replace;
 use geom::size::Size2D;
 use std::net::url::Url;
-use std::arc::{ARC, clone, get};
+use std::arc::{ARC, clone};
 
 // FIXME: Nasty coupling here This will be a problem if we want to factor out image handling from
 // the network stack. This should probably be factored out into an interface and use dependency
@@ -54,20 +54,37 @@ pub impl ImageHolder {
         self.cached_size
     }
     
-    /// Query and update the current image size.
+    /// Query and update the current image size. This is the image's intrinsic size, which is
+    /// known as soon as its header has arrived, whatever size it is decoded at.
     fn get_size(&mut self) -> Option<Size2D<int>> {
         debug!("get_size() %?", self.url);
-        match self.get_image() {
-            Some(img) => { 
-                let img_ref = get(&img);
-                self.cached_size = Size2D(img_ref.width as int,
-                                          img_ref.height as int);
+        match self.local_image_cache.get_image_dimensions(&self.url) {
+            Some(dimensions) => {
+                self.cached_size = Size2D(dimensions.width as int,
+                                          dimensions.height as int);
                 Some(copy self.cached_size)
-            },
+            }
             None => None
         }
     }
 
+    /// Returns the image for drawing it at the given size, in device pixels, decoded at a size
+    /// close to that.
+    fn get_image_at_size(&mut self, size: Size2D<uint>) -> Option<ARC<~Image>> {
+        debug!("get_image_at_size() %? %?", self.url, size);
+        match self.local_image_cache.get_image_at_size(&self.url, size).recv() {
+            ImageReady(image) => Some(image),
+            ImageNotReady => {
+                debug!("image not ready for %s", self.url.to_str());
+                None
+            }
+            ImageFailed => {
+                debug!("image decoding failed for %s", self.url.to_str());
+                None
+            }
+        }
+    }
+
     fn get_image(&mut self) -> Option<ARC<~Image>> {
         debug!("get_image() %?", self.url);
 
@@ -0,0 +1,140 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A minimal PNG encoder, used to write rendered pages to disk when running headlessly.
+//!
+//! The pixel data is stored uncompressed (as "stored" deflate blocks). This keeps the encoder
+//! small at the expense of file size, which is fine for test output.
+
+use image::base::{Image, bgra_to_rgba};
+
+static PNG_SIGNATURE: [u8, ..8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// The largest payload that fits in a single stored deflate block.
+static MAX_STORED_BLOCK_SIZE: uint = 65535;
+
+/// Encodes an image as an 8-bit RGBA PNG. The image data is expected to be in the premultiplied
+/// BGRA layout produced by `load_from_memory` and by the renderer; PNG pixels are straight RGBA.
+pub fn encode(image: &Image) -> ~[u8] {
+    assert!(image.depth == 4);
+    let rgba = bgra_to_rgba(image.data);
+
+    // Every scanline starts with its filter type. We always use filter type 0 (none).
+    let mut scanlines = vec::with_capacity((image.width * 4 + 1) * image.height);
+    for uint::range(0, image.height) |y| {
+        scanlines.push(0u8);
+        for uint::range(0, image.width) |x| {
+            let i = (y * image.width + x) * 4;
+            scanlines.push_all(rgba.slice(i, i + 4));
+        }
+    }
+
+    let mut header = ~[];
+    push_u32(&mut header, image.width as u32);
+    push_u32(&mut header, image.height as u32);
+    // Bit depth 8, color type 6 (RGBA), default compression, filtering and no interlacing.
+    header.push_all(&[8u8, 6, 0, 0, 0]);
+
+    let mut png = ~[];
+    png.push_all(PNG_SIGNATURE);
+    push_chunk(&mut png, "IHDR", header);
+    push_chunk(&mut png, "IDAT", zlib_stored(scanlines));
+    push_chunk(&mut png, "IEND", []);
+    png
+}
+
+fn push_u32(out: &mut ~[u8], value: u32) {
+    out.push((value >> 24) as u8);
+    out.push((value >> 16) as u8);
+    out.push((value >> 8) as u8);
+    out.push(value as u8);
+}
+
+/// Appends a chunk: its length, its type, its data, and a CRC over the type and data.
+fn push_chunk(out: &mut ~[u8], kind: &str, data: &[u8]) {
+    let mut body = str::to_bytes(kind);
+    body.push_all(data);
+
+    push_u32(out, data.len() as u32);
+    out.push_all(body);
+    push_u32(out, crc32(body));
+}
+
+/// Wraps the data in a zlib stream made of stored (uncompressed) deflate blocks.
+fn zlib_stored(data: &[u8]) -> ~[u8] {
+    let mut out = ~[0x78u8, 0x01];
+    let mut offset = 0;
+    loop {
+        let len = uint::min(data.len() - offset, MAX_STORED_BLOCK_SIZE);
+        let last = offset + len == data.len();
+
+        out.push(if last { 1 } else { 0 });
+        out.push(len as u8);
+        out.push((len >> 8) as u8);
+        out.push(!len as u8);
+        out.push((!len >> 8) as u8);
+        out.push_all(vec::slice(data, offset, offset + len));
+
+        offset += len;
+        if last {
+            break
+        }
+    }
+    push_u32(&mut out, adler32(data));
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for data.each |&byte| {
+        crc ^= byte as u32;
+        for 8.times {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    static MOD_ADLER: u32 = 65521;
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for data.each |&byte| {
+        a = (a + byte as u32) % MOD_ADLER;
+        b = (b + a) % MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+#[test]
+fn should_write_png_signature_and_header() {
+    let image = Image(3, 2, 4, vec::from_elem(3 * 2 * 4, 0xffu8));
+    let png = encode(&image);
+
+    assert!(vec::from_slice(vec::slice(png, 0, 8)) == vec::from_slice(PNG_SIGNATURE));
+    // The IHDR chunk follows the signature: 4 bytes of length, then the type.
+    assert!(vec::from_slice(vec::slice(png, 12, 16)) == str::to_bytes("IHDR"));
+    assert!(vec::from_slice(vec::slice(png, 16, 20)) == ~[0u8, 0, 0, 3]);
+    assert!(vec::from_slice(vec::slice(png, 20, 24)) == ~[0u8, 0, 0, 2]);
+}
+
+#[test]
+fn should_end_with_iend_chunk() {
+    let image = Image(1, 1, 4, ~[0u8, 0, 0, 0xff]);
+    let png = encode(&image);
+    let len = png.len();
+
+    assert!(vec::from_slice(vec::slice(png, len - 8, len - 4)) == str::to_bytes("IEND"));
+    assert!(vec::from_slice(vec::slice(png, len - 4, len)) == ~[0xaeu8, 0x42, 0x60, 0x82]);
+}
+
+#[test]
+fn should_compute_known_checksums() {
+    assert!(crc32(str::to_bytes("123456789")) == 0xcbf43926);
+    assert!(adler32(str::to_bytes("Wikipedia")) == 0x11e60398);
+}
@@ -3,8 +3,12 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use image::base::{Image, load_from_memory};
+use image::dimensions;
+use mime_sniff;
 use resource_task;
-use resource_task::ResourceTask;
+use resource_task::{LoadGroup, PrefetchPriority, ResourceTask};
+use servo_util::mem::{DecodedImageBytesCategory, MemoryProfilerChan};
+use servo_util::time::{CountMsg, ImageCacheEvictionsCounter, ProfilerChan};
 use servo_util::url::{UrlMap, url_map};
 
 use clone_arc = std::arc::clone;
@@ -13,7 +17,9 @@ use core::comm::{Chan, Port, SharedChan, stream};
 use core::task::spawn;
 use core::to_str::ToStr;
 use core::util::replace;
+use geom::size::Size2D;
 use std::arc::ARC;
+use std::arc;
 use std::net::url::Url;
 
 pub enum Msg {
@@ -22,14 +28,34 @@ pub enum Msg {
     Prefetch(Url),
 
     // FIXME: We can probably get rid of this Cell now
-    /// Used be the prefetch tasks to post back image binaries
-    priv StorePrefetchedImageData(Url, Result<Cell<~[u8]>, ()>),
+    /// Used be the prefetch tasks to post back image binaries, along with the key of the fetch
+    priv StorePrefetchedImageData(Url, uint, Result<Cell<~[u8]>, ()>),
 
     /// Tell the cache to decode an image. Must be posted before GetImage/WaitForImage
     Decode(Url),
 
-    /// Used by the decoder tasks to post decoded images back to the cache
-    priv StoreImage(Url, Option<ARC<~Image>>),
+    /// Used by the prefetch tasks to post back the dimensions read from an image's header, along
+    /// with the key of the fetch
+    priv StoreImageDimensions(Url, uint, Size2D<uint>),
+
+    /// Used by the decoder tasks to post decoded images back to the cache, along with their
+    /// intrinsic dimensions and the index of the decoder, which is then free for the next image
+    priv StoreImage(Url, Option<(ARC<~Image>, Size2D<uint>)>, uint),
+
+    /// Like GetImage, but for an image drawn at the given size, in device pixels. Images are
+    /// decoded at the size they are drawn at rather than at their intrinsic size, and every size
+    /// decoded is kept. The one closest to the given size is returned, and one closer to it is
+    /// decoded if that is worth it. The image is decoded too, as if by Decode
+    GetImageAtSize(Url, Size2D<uint>, Chan<ImageResponseMsg>),
+
+    /// Like WaitForImage, but waits for the next size of the image to be decoded and returns the
+    /// one closest to the given size then. If no size is to be decoded, the channel is dropped
+    /// without a response
+    WaitForImageAtSize(Url, Size2D<uint>, Chan<ImageResponseMsg>),
+
+    /// Tell the cache which images are currently in the viewport. They are decoded before the
+    /// others waiting for a decoder
+    SetViewportImages(~[Url]),
 
     /// Request an Image object for a URL. If the image is not is not immediately
     /// available then ImageNotReady is returned.
@@ -38,6 +64,21 @@ pub enum Msg {
     /// Wait for an image to become available (or fail to load).
     WaitForImage(Url, Chan<ImageResponseMsg>),
 
+    /// Request the intrinsic dimensions of an image, which are known as soon as its header has
+    /// arrived. If they are not known yet then None is returned.
+    GetImageDimensions(Url, Chan<Option<Size2D<uint>>>),
+
+    /// Wait for the intrinsic dimensions of an image to become known. None is returned if the
+    /// image fails to load or decode without them becoming known.
+    WaitForImageDimensions(Url, Chan<Option<Size2D<uint>>>),
+
+    /// Stop fetching every image whose data has not arrived yet, e.g. because the page that
+    /// wanted them has been navigated away from. Anyone waiting on them gets ImageFailed
+    CancelPendingFetches,
+
+    /// Describe the state of every image the cache knows about, for diagnostics
+    DescribeImages(Chan<~[(Url, ~str)]>),
+
     /// For testing
     priv OnMsg(~fn(msg: &Msg)),
 
@@ -82,16 +123,68 @@ pub type ImageCacheTask = SharedChan<Msg>;
 
 type DecoderFactory = ~fn() -> ~fn(&[u8]) -> Option<Image>;
 
+/// An image for a decoder task to make: its URL, what to make it from, the size it is drawn at,
+/// if known, and the decoder to use
+type DecodeJob = (Url, DecodeSource, Option<Size2D<uint>>, ~fn(&[u8]) -> Option<Image>);
+
+/// What a decoder task makes an image from
+enum DecodeSource {
+    /// The encoded data of the image, which is decoded and then reduced
+    EncodedData(ARC<~[u8]>),
+    /// A kept size of the decoded image at least twice as big as the size it is drawn at, which
+    /// is reduced without decoding anything
+    KeptImage(ARC<~Image>),
+}
+
+/// The number of images decoded at once, unless told otherwise
+static DEFAULT_DECODER_COUNT: uint = 4;
+
 pub fn ImageCacheTask(resource_task: ResourceTask) -> ImageCacheTask {
     ImageCacheTask_(resource_task, default_decoder_factory)
 }
 
+/// Creates an image cache that keeps the decoded images within `byte_budget` bytes, by evicting
+/// the least recently used ones, and decodes up to `decoder_count` images at once. Evictions are
+/// counted by the profiler, and the decoded bytes reported to the memory profiler.
+pub fn BudgetedImageCacheTask(resource_task: ResourceTask,
+                              byte_budget: uint,
+                              decoder_count: uint,
+                              profiler_chan: ProfilerChan,
+                              memory_profiler_chan: MemoryProfilerChan)
+                              -> ImageCacheTask {
+    create_image_cache_task(resource_task,
+                            default_decoder_factory,
+                            decoder_count,
+                            Some(byte_budget),
+                            Some(profiler_chan),
+                            Some(memory_profiler_chan))
+}
+
 pub fn ImageCacheTask_(resource_task: ResourceTask, decoder_factory: DecoderFactory)
                        -> ImageCacheTask {
+    create_image_cache_task(resource_task,
+                            decoder_factory,
+                            DEFAULT_DECODER_COUNT,
+                            None,
+                            None,
+                            None)
+}
+
+fn create_image_cache_task(resource_task: ResourceTask,
+                           decoder_factory: DecoderFactory,
+                           decoder_count: uint,
+                           byte_budget: Option<uint>,
+                           profiler_chan: Option<ProfilerChan>,
+                           memory_profiler_chan: Option<MemoryProfilerChan>)
+                           -> ImageCacheTask {
+    assert!(decoder_count > 0);
+
     // FIXME: Doing some dancing to avoid copying decoder_factory, our test
     // version of which contains an uncopyable type which rust will currently
     // copy unsoundly
     let decoder_factory_cell = Cell(decoder_factory);
+    let profiler_chan_cell = Cell(profiler_chan);
+    let memory_profiler_chan_cell = Cell(memory_profiler_chan);
 
     let (port, chan) = stream();
     let chan = SharedChan::new(chan);
@@ -99,13 +192,39 @@ pub fn ImageCacheTask_(resource_task: ResourceTask, decoder_factory: DecoderFact
     let chan_cell = Cell(chan.clone());
 
     do spawn {
+        let memory_profiler_chan = memory_profiler_chan_cell.take();
+        let _reports = memory_profiler_chan.map(|chan| chan.guard_reports());
+        let chan = chan_cell.take();
+        let decoders = do vec::from_fn(decoder_count) |index| {
+            spawn_decoder(index, chan.clone())
+        };
         let mut cache = ImageCache {
             resource_task: resource_task.clone(),
             decoder_factory: decoder_factory_cell.take(),
             port: port_cell.take(),
-            chan: chan_cell.take(),
+            chan: chan,
+            decoders: decoders,
+            idle_decoders: vec::from_fn(decoder_count, |index| index),
+            decode_queue: ~[],
+            viewport_images: url_map(),
             state_map: url_map(),
             wait_map: url_map(),
+            fetches: LoadGroup::new(),
+            pending_loads: url_map(),
+            next_load_key: 0,
+            dimensions: url_map(),
+            dimension_wait_map: url_map(),
+            target_sizes: url_map(),
+            resolutions: url_map(),
+            resolution_wait_map: url_map(),
+            resizing: url_map(),
+            encoded_data: url_map(),
+            byte_budget: byte_budget,
+            decoded_bytes: 0,
+            encoded_bytes: 0,
+            lru: ~[],
+            profiler_chan: profiler_chan_cell.take(),
+            memory_profiler_chan: memory_profiler_chan,
             need_exit: None
         };
         cache.run();
@@ -150,10 +269,54 @@ struct ImageCache {
     port: Port<Msg>,
     /// A copy of the shared chan to give to child tasks
     chan: SharedChan<Msg>,
+    /// The tasks that decode images, one at a time each
+    decoders: ~[Chan<DecodeJob>],
+    /// The indices of the decoders that aren't decoding anything
+    idle_decoders: ~[uint],
+    /// The images waiting for a decoder, with what to make them from, oldest first
+    decode_queue: ~[(Url, DecodeSource)],
+    /// The images currently in the viewport, which are decoded first
+    viewport_images: UrlMap<()>,
     /// The state of processsing an image for a URL
     state_map: UrlMap<ImageState>,
     /// List of clients waiting on a WaitForImage response
     wait_map: UrlMap<@mut ~[Chan<ImageResponseMsg>]>,
+    /// The loads of the images being prefetched, cancelled by `CancelPendingFetches`
+    fetches: LoadGroup,
+    /// The key of the fetch of each image being prefetched. Messages from a cancelled fetch carry
+    /// a different key from those of a later fetch of the same URL
+    pending_loads: UrlMap<uint>,
+    /// The key to give the next fetch
+    next_load_key: uint,
+    /// The intrinsic dimensions of the images whose headers have arrived
+    dimensions: UrlMap<Size2D<uint>>,
+    /// List of clients waiting on a WaitForImageDimensions response
+    dimension_wait_map: UrlMap<@mut ~[Chan<Option<Size2D<uint>>>]>,
+    /// The size, in device pixels, each image was last asked for at
+    target_sizes: UrlMap<Size2D<uint>>,
+    /// Every size decoded images have been decoded at, largest first
+    resolutions: UrlMap<~[ARC<~Image>]>,
+    /// List of clients waiting on a WaitForImageAtSize response, with the sizes they asked for
+    resolution_wait_map: UrlMap<@mut ~[(Size2D<uint>, Chan<ImageResponseMsg>)]>,
+    /// The decoded images being decoded again at another size, and that size. Only one size of
+    /// an image is decoded at a time
+    resizing: UrlMap<Size2D<uint>>,
+    /// The encoded data of decoded images, kept so they can be decoded again at other sizes. It is
+    /// freed along with the decoded image when that is evicted
+    encoded_data: UrlMap<ARC<~[u8]>>,
+    /// The most bytes the decoded images and their encoded data may take up, if there is a limit
+    byte_budget: Option<uint>,
+    /// The bytes the decoded images take up
+    decoded_bytes: uint,
+    /// The bytes the encoded data of the decoded images takes up. Only counted when there is a
+    /// byte budget
+    encoded_bytes: uint,
+    /// The decoded images, least recently used first. Only kept when there is a byte budget
+    lru: ~[Url],
+    /// Where to report evictions
+    profiler_chan: Option<ProfilerChan>,
+    /// Where to report the decoded bytes
+    memory_profiler_chan: Option<MemoryProfilerChan>,
     need_exit: Option<Chan<()>>,
 }
 
@@ -163,7 +326,30 @@ enum ImageState {
     Prefetched(@Cell<~[u8]>),
     Decoding,
     Decoded(@ARC<~Image>),
-    Failed
+    Failed,
+    /// The prefetch was cancelled before the data arrived
+    Cancelled,
+    /// The image was decoded, then evicted along with its encoded data to stay within the byte
+    /// budget. It is fetched and decoded again when next asked for
+    Evicted,
+}
+
+impl ImageState {
+    fn describe(&self) -> ~str {
+        match *self {
+            Init => ~"not requested",
+            Prefetching(*) => ~"fetching",
+            Prefetched(*) => ~"fetched",
+            Decoding => ~"decoding",
+            Decoded(image) => {
+                let image = arc::get(&*image);
+                fmt!("decoded, %ux%u", image.width, image.height)
+            }
+            Failed => ~"failed",
+            Cancelled => ~"cancelled",
+            Evicted => ~"evicted",
+        }
+    }
 }
 
 enum AfterPrefetch {
@@ -187,18 +373,51 @@ impl ImageCache {
 
             match msg {
                 Prefetch(url) => self.prefetch(url),
-                StorePrefetchedImageData(url, data) => {
-                    self.store_prefetched_image_data(url, data);
+                StorePrefetchedImageData(url, key, data) => {
+                    self.store_prefetched_image_data(url, key, data);
+                }
+                StoreImageDimensions(url, key, dimensions) => {
+                    self.store_image_dimensions(url, key, dimensions)
                 }
                 Decode(url) => self.decode(url),
-                StoreImage(url, image) => self.store_image(url, image),
+                StoreImage(url, image, decoder) => {
+                    self.idle_decoders.push(decoder);
+                    self.store_image(url, image);
+                    self.start_queued_decodes();
+                }
+                GetImageAtSize(url, size, response) => {
+                    self.get_image_at_size(url, size, response)
+                }
+                WaitForImageAtSize(url, size, response) => {
+                    self.wait_for_image_at_size(url, size, response)
+                }
+                SetViewportImages(urls) => {
+                    self.viewport_images.clear();
+                    for urls.each |url| {
+                        self.viewport_images.insert(copy *url, ());
+                    }
+                }
                 GetImage(url, response) => self.get_image(url, response),
                 WaitForImage(url, response) => {
                     self.wait_for_image(url, response)
                 }
+                GetImageDimensions(url, response) => self.get_image_dimensions(url, response),
+                WaitForImageDimensions(url, response) => {
+                    self.wait_for_image_dimensions(url, response)
+                }
+                CancelPendingFetches => self.cancel_pending_fetches(),
+                DescribeImages(response) => {
+                    let mut images = ~[];
+                    for self.state_map.each |url, state| {
+                        images.push((copy *url, state.describe()));
+                    }
+                    response.send(images);
+                }
                 OnMsg(handler) => msg_handlers.push(handler),
                 Exit(response) => {
                     assert!(self.need_exit.is_none());
+                    // There's no point in waiting for images nobody will ask for
+                    self.cancel_pending_fetches();
                     self.need_exit = Some(response);
                 }
             }
@@ -209,13 +428,13 @@ impl ImageCache {
               Some(response) => {
                 // Wait until we have no outstanding requests and subtasks
                 // before exiting
-                let mut can_exit = true;
+                let mut can_exit = self.resizing.is_empty();
                 for self.state_map.each_value |state| {
                     match *state {
                         Prefetching(*) => can_exit = false,
                         Decoding => can_exit = false,
 
-                        Init | Prefetched(*) | Decoded(*) | Failed => ()
+                        Init | Prefetched(*) | Decoded(*) | Failed | Cancelled | Evicted => ()
                     }
                 }
 
@@ -242,38 +461,69 @@ impl ImageCache {
         self.state_map.insert(url, state);
     }
 
-    priv fn prefetch(&self, url: Url) {
+    priv fn prefetch(&mut self, url: Url) {
         match self.get_state(copy url) {
-            Init => {
-                let to_cache = self.chan.clone();
-                let resource_task = self.resource_task.clone();
-                let url_cell = Cell(copy url);
+            Init | Cancelled => self.start_fetch(url, DoNotDecode),
 
-                do spawn {
-                    let url = url_cell.take();
-                    debug!("image_cache_task: started fetch for %s", url.to_str());
+            Prefetching(*) | Prefetched(*) | Decoding | Decoded(*) | Failed | Evicted => {
+                // We've already begun working on this image
+            }
+        }
+    }
 
-                    let image = load_image_data(copy url, resource_task.clone());
+    /// Asks the resource task for an image's data, then decodes it once it arrives if told to.
+    priv fn start_fetch(&mut self, url: Url, next_step: AfterPrefetch) {
+        let to_cache = self.chan.clone();
+        let url_cell = Cell(copy url);
+        let key = self.next_load_key;
+        self.next_load_key += 1;
 
+        let (response_port, response_chan) = stream();
+        // Images must not hold up the style sheets and scripts the page needs first.
+        self.resource_task.send(resource_task::CancelableLoad(copy url,
+                                                              PrefetchPriority,
+                                                              response_chan,
+                                                              self.fetches.add_load()));
+        self.pending_loads.insert(copy url, key);
+        let response_port = Cell(response_port);
+
+        do spawn {
+            let url = url_cell.take();
+            debug!("image_cache_task: started fetch for %s", url.to_str());
+
+            let on_dimensions: &fn(Size2D<uint>) = |dimensions| {
+                to_cache.send(StoreImageDimensions(copy url, key, dimensions));
+            };
+            match load_image_data(response_port.take(), on_dimensions) {
+                Some(image) => {
                     let result = if image.is_ok() {
                         Ok(Cell(result::unwrap(image)))
                     } else {
                         Err(())
                     };
-                    to_cache.send(StorePrefetchedImageData(copy url, result));
+                    to_cache.send(StorePrefetchedImageData(copy url, key, result));
                     debug!("image_cache_task: ended fetch for %s", (copy url).to_str());
                 }
-
-                self.set_state(url, Prefetching(DoNotDecode));
-            }
-
-            Prefetching(*) | Prefetched(*) | Decoding | Decoded(*) | Failed => {
-                // We've already begun working on this image
+                None => {
+                    // The cache has already forgotten about this fetch
+                    debug!("image_cache_task: cancelled fetch for %s", url.to_str());
+                }
             }
         }
+
+        self.set_state(url, Prefetching(next_step));
     }
 
-    priv fn store_prefetched_image_data(&self, url: Url, data: Result<Cell<~[u8]>, ()>) {
+    priv fn store_prefetched_image_data(&mut self,
+                                        url: Url,
+                                        key: uint,
+                                        data: Result<Cell<~[u8]>, ()>) {
+        if !self.is_current_fetch(&url, key) {
+            // The data arrived before the resource task saw the cancellation
+            return
+        }
+        self.pending_loads.remove(&url);
+
         match self.get_state(copy url) {
           Prefetching(next_step) => {
             match data {
@@ -282,27 +532,32 @@ impl ImageCache {
                 self.set_state(copy url, Prefetched(@Cell(data)));
                 match next_step {
                   DoDecode => self.decode(url),
-                  _ => ()
+                  // Nothing more will be learned about the dimensions until a decode
+                  _ => self.purge_dimension_waiters(&url)
                 }
               }
               Err(*) => {
                 self.set_state(copy url, Failed);
+                self.purge_dimension_waiters(&url);
+                self.purge_resolution_waiters(&url);
                 self.purge_waiters(url, || ImageFailed);
               }
             }
           }
 
           Init
+          | Cancelled
           | Prefetched(*)
           | Decoding
           | Decoded(*)
-          | Failed => {
+          | Failed
+          | Evicted => {
             fail!(~"wrong state for storing prefetched image")
           }
         }
     }
 
-    priv fn decode(&self, url: Url) {
+    priv fn decode(&mut self, url: Url) {
         match self.get_state(copy url) {
             Init => fail!(~"decoding image before prefetch"),
 
@@ -318,60 +573,290 @@ impl ImageCache {
             Prefetched(data_cell) => {
                 assert!(!data_cell.is_empty());
 
-                let data = data_cell.take();
-                let to_cache = self.chan.clone();
-                let url_cell = Cell(copy url);
-                let decode = (self.decoder_factory)();
-
-                do spawn {
-                    let url = url_cell.take();
-                    debug!("image_cache_task: started image decode for %s", url.to_str());
-                    let image = decode(data);
-                    let image = if image.is_some() {
-                        Some(ARC(~image.unwrap()))
-                    } else {
-                        None
-                    };
-                    to_cache.send(StoreImage(copy url, image));
-                    debug!("image_cache_task: ended image decode for %s", url.to_str());
-                }
+                let data = ARC(data_cell.take());
+                self.encoded_data.insert(copy url, clone_arc(&data));
+                self.start_decode(url, data);
+            }
 
-                self.set_state(url, Decoding);
+            Evicted => {
+                debug!("image_cache_task: fetching evicted %s again", url.to_str());
+                self.start_fetch(url, DoDecode);
             }
 
             Decoding | Decoded(*) | Failed => {
                 // We've already begun decoding
             }
+
+            Cancelled => {
+                // There's nothing to decode until the image is prefetched again
+            }
         }
     }
 
-    priv fn store_image(&self, url: Url, image: Option<ARC<~Image>>) {
+    /// Queues an image for decoding, which starts as soon as a decoder is free.
+    priv fn start_decode(&mut self, url: Url, data: ARC<~[u8]>) {
+        self.set_state(copy url, Decoding);
+        self.decode_queue.push((url, EncodedData(data)));
+        self.start_queued_decodes();
+    }
+
+    /// Hands queued images to the idle decoders, those in the viewport first. Each is decoded at
+    /// the size it is being decoded again at, or else at the size it was last asked for at.
+    priv fn start_queued_decodes(&mut self) {
+        while !self.idle_decoders.is_empty() && !self.decode_queue.is_empty() {
+            let next = {
+                let viewport_images = &self.viewport_images;
+                match self.decode_queue.position(|&(ref url, _)| {
+                    viewport_images.contains_key(url)
+                }) {
+                    Some(index) => index,
+                    None => 0,
+                }
+            };
+            let (url, source) = self.decode_queue.remove(next);
+            let size = match self.resizing.find(&url) {
+                Some(size) => Some(copy *size),
+                None => self.target_sizes.find(&url).map(|size| copy **size),
+            };
+            let decoder = self.idle_decoders.pop();
+            self.decoders[decoder].send((url, source, size, (self.decoder_factory)()));
+        }
+    }
+
+    priv fn store_image(&mut self, url: Url, image: Option<(ARC<~Image>, Size2D<uint>)>) {
+        self.resizing.remove(&url);
 
         match self.get_state(copy url) {
           Decoding => {
             match image {
-              Some(image) => {
+              Some((image, dimensions)) => {
                 self.set_state(copy url, Decoded(@clone_arc(&image)));
+                self.resolutions.insert(copy url, ~[clone_arc(&image)]);
+                if self.byte_budget.is_some() {
+                    self.encoded_bytes += arc::get(self.encoded_data.get(&url)).len();
+                }
+                self.add_decoded_bytes(copy url, arc::get(&image).byte_size());
+                if !self.dimensions.contains_key(&url) {
+                    // The header wasn't one we could read the dimensions from
+                    self.dimensions.insert(copy url, dimensions);
+                }
+                self.purge_dimension_waiters(&url);
+                self.purge_resolution_waiters(&url);
                 self.purge_waiters(url, || ImageReady(clone_arc(&image)) );
               }
               None => {
+                self.encoded_data.remove(&url);
                 self.set_state(copy url, Failed);
+                self.purge_dimension_waiters(&url);
+                self.purge_resolution_waiters(&url);
                 self.purge_waiters(url, || ImageFailed );
               }
             }
           }
 
+          Decoded(*) => {
+            // The image has been decoded at another size
+            match image {
+              Some((image, _)) => self.add_resolution(url, image),
+              None => self.purge_resolution_waiters(&url),
+            }
+          }
+
+          Evicted => {
+            // The image was evicted while it was being decoded at another size
+            if self.resolution_wait_map.contains_key(&url) {
+                self.decode(url);
+            }
+          }
+
+          Prefetching(*) => {
+            // The image was evicted while it was being decoded at another size, and has been
+            // asked for again since
+          }
+
           Init
-          | Prefetching(*)
           | Prefetched(*)
-          | Decoded(*)
-          | Failed => {
+          | Failed
+          | Cancelled => {
             fail!(~"incorrect state in store_image")
           }
         }
 
     }
 
+    /// Keeps another size of a decoded image, unless one of the same width is already kept.
+    priv fn add_resolution(&mut self, url: Url, image: ARC<~Image>) {
+        let width = arc::get(&image).width;
+        let mut resolutions = self.resolutions.pop(&url).get();
+        if !resolutions.any(|resolution| arc::get(resolution).width == width) {
+            let index = match resolutions.position(|resolution| {
+                arc::get(resolution).width < width
+            }) {
+                Some(index) => index,
+                None => resolutions.len(),
+            };
+            resolutions.insert(index, clone_arc(&image));
+            self.resolutions.insert(copy url, resolutions);
+            self.add_decoded_bytes(copy url, arc::get(&image).byte_size());
+        } else {
+            self.resolutions.insert(copy url, resolutions);
+        }
+        self.purge_resolution_waiters(&url);
+    }
+
+    /// Counts newly decoded bytes of an image and marks it as the most recently used one, then
+    /// evicts the least recently used images, with every size kept of them and their encoded data,
+    /// until the rest fit in the byte budget. The newest image is never evicted, even if it doesn't
+    /// fit by itself.
+    priv fn add_decoded_bytes(&mut self, url: Url, bytes: uint) {
+        let budget = match self.byte_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        self.decoded_bytes += bytes;
+        match self.lru.position(|lru_url| *lru_url == url) {
+            Some(index) => { self.lru.remove(index); }
+            None => {}
+        }
+        self.lru.push(url);
+
+        let mut evictions = 0;
+        while self.decoded_bytes + self.encoded_bytes > budget && self.lru.len() > 1 {
+            let url = self.lru.shift();
+            match self.resolutions.pop(&url) {
+                Some(resolutions) => {
+                    for resolutions.each |resolution| {
+                        self.decoded_bytes -= arc::get(resolution).byte_size();
+                    }
+                }
+                None => fail!(~"evicting an image that isn't decoded"),
+            }
+            match self.encoded_data.pop(&url) {
+                Some(data) => self.encoded_bytes -= arc::get(&data).len(),
+                None => fail!(~"evicting an image without its encoded data"),
+            }
+            debug!("image_cache_task: evicting %s", url.to_str());
+            self.set_state(url, Evicted);
+            evictions += 1;
+        }
+        if evictions > 0 {
+            for self.profiler_chan.each |profiler_chan| {
+                profiler_chan.send(CountMsg(ImageCacheEvictionsCounter, evictions));
+            }
+        }
+        self.report_decoded_bytes();
+    }
+
+    priv fn report_decoded_bytes(&self) {
+        for self.memory_profiler_chan.each |memory_profiler_chan| {
+            memory_profiler_chan.report(DecodedImageBytesCategory, self.decoded_bytes);
+        }
+    }
+
+    /// Returns the size of a decoded image to draw at the given size: the smallest kept that is at
+    /// least that big, or the biggest if none is.
+    priv fn closest_resolution(&self, url: &Url, size: Size2D<uint>) -> ARC<~Image> {
+        let resolutions = self.resolutions.get(url);
+        let mut closest = 0;
+        for resolutions.eachi |i, resolution| {
+            let resolution = arc::get(resolution);
+            if resolution.width >= size.width && resolution.height >= size.height {
+                closest = i;
+            }
+        }
+        clone_arc(&resolutions[closest])
+    }
+
+    /// Starts making another size of a decoded image for the given size. If the closest size kept
+    /// is at least twice as big, that is reduced; if it is smaller than both the given size and the
+    /// image itself, the image is decoded again.
+    priv fn decode_closer_resolution(&mut self, url: &Url, size: Size2D<uint>) {
+        if self.resizing.contains_key(url) {
+            // The closest size is looked at again once the one being decoded is kept
+            return
+        }
+        let closest = self.closest_resolution(url, size);
+        let source = if needs_halving(arc::get(&closest), &size) {
+            debug!("image_cache_task: reducing %s to %ux%u",
+                   url.to_str(), size.width, size.height);
+            KeptImage(closest)
+        } else {
+            let too_small = {
+                let closest = arc::get(&closest);
+                (closest.width < size.width || closest.height < size.height) &&
+                    match self.dimensions.find(url) {
+                        Some(dimensions) => closest.width < dimensions.width,
+                        None => false,
+                    }
+            };
+            if !too_small {
+                return
+            }
+            match self.encoded_data.find(url) {
+                Some(data) => {
+                    debug!("image_cache_task: decoding %s again at %ux%u",
+                           url.to_str(), size.width, size.height);
+                    EncodedData(clone_arc(data))
+                }
+                None => return,
+            }
+        };
+        self.resizing.insert(copy *url, size);
+        self.decode_queue.push((copy *url, source));
+        self.start_queued_decodes();
+    }
+
+    /// Sends everyone waiting on a size of an image the one closest to the size they asked for,
+    /// or ImageFailed if the image has failed.
+    priv fn purge_resolution_waiters(&self, url: &Url) {
+        match self.resolution_wait_map.pop(url) {
+            Some(waiters) => {
+                for waiters.each |waiter| {
+                    let (ref size, ref response) = *waiter;
+                    let msg = match self.get_state(copy *url) {
+                        Decoded(*) => ImageReady(self.closest_resolution(url, copy *size)),
+                        _ => ImageFailed,
+                    };
+                    response.send(msg);
+                }
+            }
+            None => ()
+        }
+    }
+
+    /// Marks a decoded image as the most recently used one.
+    priv fn touch(&mut self, url: &Url) {
+        match self.lru.position(|lru_url| *lru_url == *url) {
+            Some(index) => {
+                let url = self.lru.remove(index);
+                self.lru.push(url);
+            }
+            None => {}
+        }
+    }
+
+    /// Returns true if the key is that of the image's unfinished fetch, rather than one that has
+    /// been cancelled.
+    priv fn is_current_fetch(&self, url: &Url, key: uint) -> bool {
+        match self.pending_loads.find(url) {
+            Some(&current_key) => current_key == key,
+            None => false,
+        }
+    }
+
+    priv fn cancel_pending_fetches(&mut self) {
+        self.fetches.cancel();
+        self.fetches = LoadGroup::new();
+        for self.pending_loads.each_key |url| {
+            debug!("image_cache_task: cancelling fetch for %s", url.to_str());
+            self.set_state(copy *url, Cancelled);
+            self.purge_dimension_waiters(url);
+            self.purge_resolution_waiters(url);
+            self.purge_waiters(copy *url, || ImageFailed);
+        }
+        self.pending_loads.clear();
+    }
+
     priv fn purge_waiters(&self, url: Url, f: &fn() -> ImageResponseMsg) {
         match self.wait_map.pop(&url) {
             Some(waiters) => {
@@ -383,18 +868,92 @@ impl ImageCache {
         }
     }
 
-    priv fn get_image(&self, url: Url, response: Chan<ImageResponseMsg>) {
+    priv fn store_image_dimensions(&self, url: Url, key: uint, dimensions: Size2D<uint>) {
+        if !self.is_current_fetch(&url, key) {
+            // The header arrived before the resource task saw the cancellation
+            return
+        }
+        match self.get_state(copy url) {
+            Prefetching(*) => {
+                self.dimensions.insert(copy url, dimensions);
+                self.purge_dimension_waiters(&url);
+            }
+
+            Init | Cancelled | Prefetched(*) | Decoding | Decoded(*) | Failed | Evicted => {
+                fail!(~"wrong state for storing image dimensions")
+            }
+        }
+    }
+
+    /// Sends the dimensions of an image, if they are known, to everyone waiting on them.
+    priv fn purge_dimension_waiters(&self, url: &Url) {
+        match self.dimension_wait_map.pop(url) {
+            Some(waiters) => {
+                let dimensions = self.dimensions.find(url).map(|dimensions| copy **dimensions);
+                for waiters.each |response| {
+                    response.send(dimensions);
+                }
+            }
+            None => ()
+        }
+    }
+
+    priv fn get_image_dimensions(&self, url: Url, response: Chan<Option<Size2D<uint>>>) {
+        match self.get_state(copy url) {
+            Init => fail!(~"request for image dimensions before prefetch"),
+            Failed | Cancelled => response.send(None),
+            _ => response.send(self.dimensions.find(&url).map(|dimensions| copy **dimensions)),
+        }
+    }
+
+    priv fn wait_for_image_dimensions(&self, url: Url, response: Chan<Option<Size2D<uint>>>) {
+        match self.dimensions.find(&url) {
+            Some(dimensions) => {
+                response.send(Some(copy *dimensions));
+                return
+            }
+            None => ()
+        }
+
+        match self.get_state(copy url) {
+            Init => fail!(~"request for image dimensions before prefetch"),
+
+            Prefetching(*) | Decoding => {
+                // The header, or failing that the decoded image, is still to come
+                if self.dimension_wait_map.contains_key(&url) {
+                    let waiters = self.dimension_wait_map.find_mut(&url).unwrap();
+                    waiters.push(response);
+                } else {
+                    self.dimension_wait_map.insert(url, @mut ~[response]);
+                }
+            }
+
+            Prefetched(*) | Decoded(*) | Failed | Cancelled | Evicted => {
+                // All the data is here and the dimensions couldn't be read from it
+                response.send(None);
+            }
+        }
+    }
+
+    priv fn get_image(&mut self, url: Url, response: Chan<ImageResponseMsg>) {
         match self.get_state(copy url) {
             Init => fail!(~"request for image before prefetch"),
             Prefetching(DoDecode) => response.send(ImageNotReady),
             Prefetching(DoNotDecode) | Prefetched(*) => fail!(~"request for image before decode"),
             Decoding => response.send(ImageNotReady),
-            Decoded(image) => response.send(ImageReady(clone_arc(image))),
-            Failed => response.send(ImageFailed),
+            Decoded(image) => {
+                self.touch(&url);
+                response.send(ImageReady(clone_arc(image)))
+            }
+            Failed | Cancelled => response.send(ImageFailed),
+            Evicted => {
+                self.decode(url);
+                response.send(ImageNotReady)
+            }
         }
     }
 
-    priv fn wait_for_image(&self, url: Url, response: Chan<ImageResponseMsg>) {
+    priv fn wait_for_image(&mut self, url: Url, response: Chan<ImageResponseMsg>) {
         match self.get_state(copy url) {
             Init => fail!(~"request for image before prefetch"),
 
@@ -411,17 +970,142 @@ impl ImageCache {
             }
 
             Decoded(image) => {
+                self.touch(&url);
                 response.send(ImageReady(clone_arc(image)));
             }
 
-            Failed => {
+            Failed | Cancelled => {
+                response.send(ImageFailed);
+            }
+
+            Evicted => {
+                self.decode(copy url);
+                self.wait_for_image(url, response);
+            }
+        }
+    }
+
+    priv fn get_image_at_size(&mut self,
+                              url: Url,
+                              size: Size2D<uint>,
+                              response: Chan<ImageResponseMsg>) {
+        self.target_sizes.insert(copy url, size);
+        self.decode(copy url);
+        match self.get_state(copy url) {
+            Decoded(*) => {
+                self.touch(&url);
+                self.decode_closer_resolution(&url, size);
+                response.send(ImageReady(self.closest_resolution(&url, size)));
+            }
+            _ => self.get_image(url, response),
+        }
+    }
+
+    priv fn wait_for_image_at_size(&mut self,
+                                   url: Url,
+                                   size: Size2D<uint>,
+                                   response: Chan<ImageResponseMsg>) {
+        self.target_sizes.insert(copy url, size);
+        self.decode(copy url);
+        match self.get_state(copy url) {
+            Decoded(*) => {
+                self.decode_closer_resolution(&url, size);
+                if !self.resizing.contains_key(&url) {
+                    // No other size is to come, so the response channel is dropped
+                    return
+                }
+            }
+            Failed | Cancelled => {
                 response.send(ImageFailed);
+                return
+            }
+            Init | Prefetching(*) | Prefetched(*) | Decoding | Evicted => {
+                // The first size is still to come
+            }
+        }
+
+        if self.resolution_wait_map.contains_key(&url) {
+            let waiters = self.resolution_wait_map.find_mut(&url).unwrap();
+            waiters.push((size, response));
+        } else {
+            self.resolution_wait_map.insert(url, @mut ~[(size, response)]);
+        }
+    }
+
+}
+
+
+/// Starts a task that decodes the images it is sent one at a time, posting them back to the cache.
+/// It exits along with the cache. An image whose decoder fails is posted back as a failed image,
+/// so the decoder is free for the next one either way.
+fn spawn_decoder(index: uint, to_cache: SharedChan<Msg>) -> Chan<DecodeJob> {
+    let (port, chan) = stream();
+    let port = Cell(port);
+
+    do spawn {
+        let port: Port<DecodeJob> = port.take();
+        loop {
+            match port.try_recv() {
+                Some((url, source, size, decode)) => {
+                    debug!("image_cache_task: started image decode for %s", url.to_str());
+                    let job = Cell((source, size, decode));
+                    let result = do task::try {
+                        let (source, size, decode) = job.take();
+                        match source {
+                            EncodedData(data) => {
+                                match decode(*arc::get(&data)) {
+                                    Some(image) => {
+                                        let dimensions = Size2D(image.width, image.height);
+                                        Some((ARC(~reduce_to_size(image, size)), dimensions))
+                                    }
+                                    None => None,
+                                }
+                            }
+                            KeptImage(image) => {
+                                // Halved once up front, so the kept image is never copied whole
+                                let image = arc::get(&image);
+                                let dimensions = Size2D(image.width, image.height);
+                                Some((ARC(~reduce_to_size(image.half_size(), size)), dimensions))
+                            }
+                        }
+                    };
+                    let image = match result {
+                        Ok(image) => image,
+                        Err(()) => {
+                            debug!("image_cache_task: decoder failed for %s", url.to_str());
+                            None
+                        }
+                    };
+                    debug!("image_cache_task: ended image decode for %s", url.to_str());
+                    to_cache.send(StoreImage(url, image, index));
+                }
+                None => break,
             }
         }
     }
 
+    chan
+}
+
+/// Returns true if the image is at least twice as big as the target size in both dimensions, so
+/// that a copy of half its size would still be big enough.
+fn needs_halving(image: &Image, target_size: &Size2D<uint>) -> bool {
+    image.width >= target_size.width * 2 && image.height >= target_size.height * 2 &&
+        image.width > 1 && image.height > 1
 }
 
+/// Halves a decoded image for as long as it stays at least as big as the size it is drawn at.
+// FIXME: stb_image can't scale while decoding, so the full size is decoded and then reduced. Sizes
+// smaller than one already kept are reduced from that instead.
+fn reduce_to_size(image: Image, size: Option<Size2D<uint>>) -> Image {
+    let mut image = image;
+    for size.each |size| {
+        while needs_halving(&image, size) {
+            image = image.half_size();
+        }
+    }
+    image
+}
 
 trait ImageCacheTaskClient {
     fn exit(&self);
@@ -435,29 +1119,71 @@ impl ImageCacheTaskClient for ImageCacheTask {
     }
 }
 
-fn load_image_data(url: Url, resource_task: ResourceTask) -> Result<~[u8], ()> {
-    let (response_port, response_chan) = stream();
-    resource_task.send(resource_task::Load(url, response_chan));
-
+/// Reads an image binary from the resource task, reporting its dimensions as soon as its header
+/// has arrived. Returns None if the load was cancelled.
+fn load_image_data(response_port: Port<resource_task::ProgressMsg>,
+                   on_dimensions: &fn(Size2D<uint>))
+                   -> Option<Result<~[u8], ()>> {
     let mut image_data = ~[];
+    let mut succeeded = true;
+    let mut content_type = None;
+    let mut found_dimensions = false;
 
     loop {
         match response_port.recv() {
+            resource_task::Metadata(metadata) => {
+                // The body of an HTTP error is not the image we asked for.
+                succeeded = metadata.is_success();
+                content_type = metadata.content_type;
+            }
             resource_task::Payload(data) => {
                 image_data += data;
+                if succeeded && !found_dimensions {
+                    match dimensions::probe(image_data) {
+                        Some(dimensions) => {
+                            found_dimensions = true;
+                            on_dimensions(dimensions);
+                        }
+                        None => {}
+                    }
+                }
             }
             resource_task::Done(result::Ok(*)) => {
-                return Ok(image_data);
+                // Anything that isn't known not to be an image is left for the decoder to try.
+                match mime_sniff::sniff_image(&content_type, image_data) {
+                    Some(mime) if !is_decodable(&mime) => {
+                        debug!("image_cache_task: not decoding resource of type %?", mime);
+                        succeeded = false;
+                    }
+                    _ => {}
+                }
+                return Some(if succeeded { Ok(image_data) } else { Err(()) });
             }
             resource_task::Done(result::Err(*)) => {
-                return Err(());
+                return Some(Err(()));
+            }
+            resource_task::Aborted => {
+                return None;
             }
         }
     }
 }
 
+/// Returns true for the image formats `load_from_memory` understands.
+fn is_decodable(mime: &(~str, ~str)) -> bool {
+    match *mime {
+        (~"image", ~"png") | (~"image", ~"jpeg") | (~"image", ~"gif") | (~"image", ~"bmp") => true,
+        _ => false,
+    }
+}
+
 fn default_decoder_factory() -> ~fn(&[u8]) -> Option<Image> {
-    let foo: ~fn(&[u8]) -> Option<Image> = |data: &[u8]| { load_from_memory(data) };
+    let foo: ~fn(&[u8]) -> Option<Image> = |data: &[u8]| {
+        match mime_sniff::image_type(data) {
+            Some(mime) if is_decodable(&mime) => load_from_memory(data),
+            _ => None,
+        }
+    };
     foo
 }
 
@@ -466,10 +1192,28 @@ fn mock_resource_task(on_load: ~fn(resource: Chan<resource_task::ProgressMsg>))
     do spawn_listener |port: Port<resource_task::ControlMsg>| {
         loop {
             match port.recv() {
-              resource_task::Load(_, response) => {
+              resource_task::CancelableLoad(_, _, response, _) => {
                 on_load(response);
               }
-              resource_task::Exit => break
+              resource_task::Exit => break,
+              _ => ()
+            }
+        }
+    }
+}
+
+/// A mock resource task that serves every image with its path as its data
+#[cfg(test)]
+fn path_resource_task() -> ResourceTask {
+    do spawn_listener |port: Port<resource_task::ControlMsg>| {
+        loop {
+            match port.recv() {
+              resource_task::CancelableLoad(url, _, response, _) => {
+                response.send(resource_task::Payload(str::to_bytes(url.path)));
+                response.send(resource_task::Done(result::Ok(())));
+              }
+              resource_task::Exit => break,
+              _ => ()
             }
         }
     }
@@ -684,7 +1428,7 @@ fn should_not_request_image_from_resource_task_if_image_is_already_available() {
     let mock_resource_task = do spawn_listener |port: comm::Port<resource_task::ControlMsg>| {
         loop {
             match port.recv() {
-                resource_task::Load(_, response) => {
+                resource_task::CancelableLoad(_, _, response, _) => {
                     response.send(resource_task::Payload(test_image_bin()));
                     response.send(resource_task::Done(result::Ok(())));
                     image_bin_sent_chan.send(());
@@ -693,6 +1437,7 @@ fn should_not_request_image_from_resource_task_if_image_is_already_available() {
                     resource_task_exited_chan.send(());
                     break
                 }
+                _ => ()
             }
         }
     };
@@ -728,7 +1473,7 @@ fn should_not_request_image_from_resource_task_if_image_fetch_already_failed() {
     let mock_resource_task = do spawn_listener |port: comm::Port<resource_task::ControlMsg>| {
         loop {
             match port.recv() {
-                resource_task::Load(_, response) => {
+                resource_task::CancelableLoad(_, _, response, _) => {
                     response.send(resource_task::Payload(test_image_bin()));
                     response.send(resource_task::Done(result::Err(())));
                     image_bin_sent_chan.send(());
@@ -737,6 +1482,7 @@ fn should_not_request_image_from_resource_task_if_image_fetch_already_failed() {
                     resource_task_exited_chan.send(());
                     break
                 }
+                _ => ()
             }
         }
     };
@@ -801,6 +1547,46 @@ fn should_return_failed_if_image_bin_cannot_be_fetched() {
     mock_resource_task.send(resource_task::Exit);
 }
 
+#[test]
+fn should_return_failed_for_image_served_as_html() {
+    let mock_resource_task = do mock_resource_task |response| {
+        let mut metadata = resource_task::Metadata::default(make_url(~"file", None));
+        metadata.set_content_type("text/html");
+        response.send(resource_task::Metadata(metadata));
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_prefetech = comm::Port();
+    let wait_for_prefetech_chan = wait_for_prefetech.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) => wait_for_prefetech_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the page to the image cache
+    wait_for_prefetech.recv();
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(url, response_chan));
+    match response_port.recv() {
+      ImageFailed => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
 #[test]
 fn should_return_failed_for_multiple_get_image_requests_if_image_bin_cannot_be_fetched() {
     let mock_resource_task = do mock_resource_task |response | {
@@ -1062,3 +1848,355 @@ fn sync_cache_should_wait_for_images() {
     mock_resource_task.send(resource_task::Exit);
 }
 
+
+#[test]
+fn should_cancel_pending_fetches_and_fail_their_waiters() {
+    let (cancelled_port, cancelled_chan) = stream();
+    let cancelled_chan = SharedChan::new(cancelled_chan);
+
+    let mock_resource_task = do spawn_listener |port: comm::Port<resource_task::ControlMsg>| {
+        // Hold on to the response chans so the loads never finish on their own
+        let mut responses = ~[];
+        loop {
+            match port.recv() {
+                resource_task::CancelableLoad(_, _, response, cancel_port) => {
+                    responses.push(response);
+                    let cancel_port = Cell(cancel_port);
+                    let cancelled_chan = cancelled_chan.clone();
+                    do spawn {
+                        cancel_port.take().recv();
+                        cancelled_chan.send(());
+                    }
+                }
+                resource_task::Exit => break,
+                _ => ()
+            }
+        }
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, response_chan));
+
+    image_cache_task.send(CancelPendingFetches);
+
+    cancelled_port.recv();
+    assert!(response_port.recv() == ImageFailed);
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_evict_least_recently_used_images_over_budget() {
+    let (loads_port, loads_chan) = stream();
+    let loads_chan = SharedChan::new(loads_chan);
+    let mock_resource_task = do mock_resource_task |response| {
+        loads_chan.send(());
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    // Every image decodes to 4 bytes, and there's only room for one along with its encoded data
+    let decoder_factory: DecoderFactory = || {
+        let decoder: ~fn(&[u8]) -> Option<Image> = |_data| Some(Image(1, 1, 4, ~[0, 0, 0, 0]));
+        decoder
+    };
+    let (profiler_port, profiler_chan) = stream();
+    let image_cache_task = create_image_cache_task(mock_resource_task,
+                                                   decoder_factory,
+                                                   DEFAULT_DECODER_COUNT,
+                                                   Some(test_image_bin().len() + 4),
+                                                   Some(ProfilerChan::new(profiler_chan)),
+                                                   None);
+    let first_url = make_url(~"http://example.com/first.png", None);
+    let second_url = make_url(~"http://example.com/second.png", None);
+
+    for [copy first_url, copy second_url].each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+        image_cache_task.send(Decode(copy *url));
+        let (response_port, response_chan) = stream();
+        image_cache_task.send(WaitForImage(copy *url, response_chan));
+        match response_port.recv() {
+            ImageReady(*) => (),
+            _ => fail
+        }
+        loads_port.recv();
+    }
+
+    match profiler_port.recv() {
+        CountMsg(ImageCacheEvictionsCounter, 1) => (),
+        _ => fail
+    }
+    let (images_port, images_chan) = stream();
+    image_cache_task.send(DescribeImages(images_chan));
+    for images_port.recv().each |&(ref url, ref state)| {
+        if *url == first_url {
+            assert!(*state == ~"evicted");
+        } else {
+            assert!(state.starts_with("decoded"));
+        }
+    }
+
+    // The evicted image is fetched and decoded again on demand, evicting the other one
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(first_url, response_chan));
+    match response_port.recv() {
+        ImageReady(*) => (),
+        _ => fail
+    }
+    loads_port.recv();
+    match profiler_port.recv() {
+        CountMsg(ImageCacheEvictionsCounter, 1) => (),
+        _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_dimensions_once_the_header_has_arrived() {
+    let (wait_chan, wait_port) = pipes::stream();
+
+    let mock_resource_task = do mock_resource_task |response| {
+        let data = test_image_bin();
+        // The JPEG frame header, with the dimensions, ends at byte 431
+        response.send(resource_task::Payload(vec::slice(data, 0, 440).to_owned()));
+        wait_port.recv();
+        response.send(resource_task::Payload(vec::slice(data, 440, data.len()).to_owned()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImageDimensions(copy url, response_chan));
+    assert!(response_port.recv() == Some(Size2D(450, 337)));
+
+    // The image itself is still on its way
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetImage(copy url, response_chan));
+    assert!(response_port.recv() == ImageNotReady);
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetImageDimensions(url, response_chan));
+    assert!(response_port.recv() == Some(Size2D(450, 337)));
+
+    wait_chan.send(());
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_decode_images_at_the_size_they_are_drawn_at() {
+    let mock_resource_task = do mock_resource_task |response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    // Every image decodes to 8x8 pixels
+    let decoder_factory: DecoderFactory = || {
+        let decoder: ~fn(&[u8]) -> Option<Image> = |_data| {
+            Some(Image(8, 8, 4, vec::from_elem(8 * 8 * 4, 0)))
+        };
+        decoder
+    };
+    let image_cache_task = ImageCacheTask_(mock_resource_task, decoder_factory);
+    let url = make_url(~"file", None);
+
+    let width_at = |msg: ImageResponseMsg| {
+        match msg {
+            ImageReady(image) => arc::get(&image).width,
+            _ => fail
+        }
+    };
+
+    image_cache_task.send(Prefetch(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImageAtSize(copy url, Size2D(3, 2), response_chan));
+    assert!(width_at(response_port.recv()) == 4);
+
+    // A bigger size is decoded, and the closest one is drawn until it is
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetImageAtSize(copy url, Size2D(5, 5), response_chan));
+    assert!(width_at(response_port.recv()) == 4);
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImageAtSize(copy url, Size2D(5, 5), response_chan));
+    assert!(width_at(response_port.recv()) == 8);
+
+    // Both sizes are kept
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetImageAtSize(copy url, Size2D(3, 2), response_chan));
+    assert!(width_at(response_port.recv()) == 4);
+
+    // Nothing closer is to come, so the channel is dropped
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImageAtSize(url, Size2D(8, 8), response_chan));
+    assert!(response_port.try_recv().is_none());
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_reduce_kept_sizes_instead_of_decoding_again() {
+    let mock_resource_task = do mock_resource_task |response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    // Every image decodes to 8x8 pixels, and each decode is counted
+    let (decodes_port, decodes_chan) = stream();
+    let decodes_chan = SharedChan::new(decodes_chan);
+    let decoder_factory: DecoderFactory = || {
+        let decodes_chan = decodes_chan.clone();
+        let decoder: ~fn(&[u8]) -> Option<Image> = |_data| {
+            decodes_chan.send(());
+            Some(Image(8, 8, 4, vec::from_elem(8 * 8 * 4, 0)))
+        };
+        decoder
+    };
+    let image_cache_task = ImageCacheTask_(mock_resource_task, decoder_factory);
+    let url = make_url(~"file", None);
+
+    let width_at = |msg: ImageResponseMsg| {
+        match msg {
+            ImageReady(image) => arc::get(&image).width,
+            _ => fail
+        }
+    };
+
+    image_cache_task.send(Prefetch(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImageAtSize(copy url, Size2D(8, 8), response_chan));
+    assert!(width_at(response_port.recv()) == 8);
+    decodes_port.recv();
+
+    // The smaller size is made from the kept one
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImageAtSize(copy url, Size2D(2, 2), response_chan));
+    assert!(width_at(response_port.recv()) == 2);
+    assert!(!decodes_port.peek());
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_decode_images_in_the_viewport_first() {
+    let mock_resource_task = path_resource_task();
+
+    // Each decode waits to be told to go on
+    let (decodes_port, decodes_chan) = stream();
+    let decodes_chan = SharedChan::new(decodes_chan);
+    let decoder_factory: DecoderFactory = || {
+        let decodes_chan = decodes_chan.clone();
+        let decoder: ~fn(&[u8]) -> Option<Image> = |data| {
+            let (go_port, go_chan) = stream();
+            decodes_chan.send((str::from_bytes(data), go_chan));
+            go_port.recv();
+            Some(Image(1, 1, 4, ~[0, 0, 0, 0]))
+        };
+        decoder
+    };
+    let image_cache_task = create_image_cache_task(mock_resource_task,
+                                                   decoder_factory,
+                                                   1,
+                                                   None,
+                                                   None,
+                                                   None);
+
+    let (fetched_port, fetched_chan) = stream();
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+            StorePrefetchedImageData(*) => fetched_chan.send(()),
+            _ => ()
+        }
+    }));
+
+    let first_url = make_url(~"http://example.com/first.png", None);
+    image_cache_task.send(Prefetch(copy first_url));
+    image_cache_task.send(Decode(first_url));
+    let (path, go_first) = decodes_port.recv();
+    assert!(path == ~"/first.png");
+
+    // The only decoder is busy, so these wait in the queue
+    let second_url = make_url(~"http://example.com/second.png", None);
+    let third_url = make_url(~"http://example.com/third.png", None);
+    for [copy second_url, copy third_url].each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+        image_cache_task.send(Decode(copy *url));
+    }
+    fetched_port.recv();
+    fetched_port.recv();
+    fetched_port.recv();
+    image_cache_task.send(SetViewportImages(~[third_url]));
+    go_first.send(());
+
+    let (path, go_third) = decodes_port.recv();
+    assert!(path == ~"/third.png");
+    go_third.send(());
+    let (path, go_second) = decodes_port.recv();
+    assert!(path == ~"/second.png");
+    go_second.send(());
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_fail_images_whose_decoder_fails_and_go_on_decoding() {
+    let mock_resource_task = path_resource_task();
+
+    // Decoding the first image fails the decoder
+    let decoder_factory: DecoderFactory = || {
+        let decoder: ~fn(&[u8]) -> Option<Image> = |data| {
+            if str::from_bytes(data) == ~"/first.png" {
+                fail!(~"decoder failed");
+            }
+            Some(Image(1, 1, 4, ~[0, 0, 0, 0]))
+        };
+        decoder
+    };
+    let image_cache_task = create_image_cache_task(mock_resource_task,
+                                                   decoder_factory,
+                                                   1,
+                                                   None,
+                                                   None,
+                                                   None);
+
+    let first_url = make_url(~"http://example.com/first.png", None);
+    let second_url = make_url(~"http://example.com/second.png", None);
+    for [copy first_url, copy second_url].each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+        image_cache_task.send(Decode(copy *url));
+    }
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(first_url, response_chan));
+    match response_port.recv() {
+        ImageFailed => (),
+        _ => fail
+    }
+
+    // The only decoder is free for the next image
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(second_url, response_chan));
+    match response_port.recv() {
+        ImageReady(*) => (),
+        _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
@@ -9,19 +9,35 @@ multiple times and thus triggering reflows multiple times.
 */
 
 use image_cache_task::{Decode, GetImage, ImageCacheTask, ImageFailed, ImageNotReady, ImageReady};
-use image_cache_task::{ImageResponseMsg, Prefetch, WaitForImage};
+use image_cache_task::{GetImageAtSize, GetImageDimensions, ImageResponseMsg, Prefetch};
+use image_cache_task::{SetViewportImages, WaitForImage, WaitForImageAtSize};
+use image_cache_task::WaitForImageDimensions;
+
+use image::base::Image;
 
 use clone_arc = std::arc::clone;
-use core::comm::Port;
+use core::comm::{Port, SharedChan};
+use core::util::replace;
+use geom::size::Size2D;
 use servo_util::url::{UrlMap, url_map};
+use std::arc;
+use std::arc::ARC;
 use std::net::url::Url;
+use std::time::precise_time_ns;
+use std::timer;
+use std::uv_global_loop;
 
 pub fn LocalImageCache(image_cache_task: ImageCacheTask) -> LocalImageCache {
+    let (resolution_port, resolution_chan) = comm::stream();
     LocalImageCache {
         image_cache_task: image_cache_task,
         round_number: 1,
         mut on_image_available: None,
-        state_map: url_map()
+        state_map: url_map(),
+        viewport_images: ~[],
+        sent_viewport_images: ~[],
+        resolution_port: resolution_port,
+        resolution_chan: SharedChan::new(resolution_chan)
     }
 }
 
@@ -29,14 +45,33 @@ pub struct LocalImageCache {
     priv image_cache_task: ImageCacheTask,
     priv round_number: uint,
     priv on_image_available: Option<@fn() -> ~fn(ImageResponseMsg)>,
-    priv state_map: UrlMap<@mut ImageState>
+    priv state_map: UrlMap<@mut ImageState>,
+    /// The images found in the viewport so far this round
+    priv viewport_images: ~[Url],
+    /// The images last reported to be in the viewport
+    priv sent_viewport_images: ~[Url],
+    /// Tells the next round which images have stopped waiting for another size to be decoded,
+    /// and whether one was
+    priv resolution_port: Port<(Url, bool)>,
+    priv resolution_chan: SharedChan<(Url, bool)>
 }
 
 priv struct ImageState {
     prefetched: bool,
     decoded: bool,
     last_request_round: uint,
-    last_response: ImageResponseMsg
+    last_response: ImageResponseMsg,
+    dimensions: Option<Size2D<uint>>,
+    /// Whether a reflow has been asked for once the dimensions are known
+    waiting_for_dimensions: bool,
+    /// The sizes the image has been asked for at, in device pixels, with the image returned for
+    /// each. Forgotten once another size of the image is decoded
+    sized_images: ~[(Size2D<uint>, ARC<~Image>)],
+    /// Whether a reflow has been asked for once another size of the image is decoded
+    waiting_for_resolution: bool,
+    /// When the next frame of an animated image is due, in nanoseconds as returned by
+    /// `precise_time_ns`. A repaint has already been asked for until then.
+    animation_due: u64
 }
 
 #[allow(non_implicitly_copyable_typarams)] // Using maps of Urls
@@ -46,6 +81,32 @@ pub impl LocalImageCache {
     pub fn next_round(&mut self, on_image_available: @fn() -> ~fn(ImageResponseMsg)) {
         self.round_number += 1;
         self.on_image_available = Some(on_image_available);
+
+        while self.resolution_port.peek() {
+            let (url, decoded) = self.resolution_port.recv();
+            let state = self.get_state(&url);
+            state.waiting_for_resolution = false;
+            if decoded {
+                state.sized_images = ~[];
+            }
+        }
+    }
+
+    /// Notes that the image is in the viewport this round, so it is decoded before others.
+    pub fn mark_in_viewport(&mut self, url: &Url) {
+        if !self.viewport_images.contains(url) {
+            self.viewport_images.push(copy *url);
+        }
+    }
+
+    /// Tells the image cache which images have been found in the viewport this round, if that
+    /// has changed since it was last told. Layout should call this each time it finishes.
+    pub fn send_viewport_images(&mut self) {
+        let viewport_images = replace(&mut self.viewport_images, ~[]);
+        if viewport_images != self.sent_viewport_images {
+            self.image_cache_task.send(SetViewportImages(copy viewport_images));
+            self.sent_viewport_images = viewport_images;
+        }
     }
 
     pub fn prefetch(&self, url: &Url) {
@@ -64,6 +125,61 @@ pub impl LocalImageCache {
         }
     }
 
+    /// Returns the image for drawing at the given size, in device pixels, decoded at a size close
+    /// to it. A reflow is triggered once the image, or a size closer to the given one, is decoded.
+    pub fn get_image_at_size(&self, url: &Url, size: Size2D<uint>) -> Port<ImageResponseMsg> {
+        let state = self.get_state(url);
+        let (port, chan) = comm::stream();
+
+        match state.last_response {
+            ImageFailed => {
+                chan.send(ImageFailed);
+                return port;
+            }
+            ImageReady(*) | ImageNotReady => ()
+        }
+        match state.sized_images.position(|&(ref image_size, _)| {
+            image_size.width == size.width && image_size.height == size.height
+        }) {
+            Some(index) => {
+                let image = match state.sized_images[index] {
+                    (_, ref image) => clone_arc(image)
+                };
+                self.animate(state, &image);
+                chan.send(ImageReady(image));
+                return port;
+            }
+            None => ()
+        }
+        if state.sized_images.is_empty() && state.waiting_for_resolution {
+            // The image itself is still to come
+            chan.send(ImageNotReady);
+            return port;
+        }
+
+        let (response_port, response_chan) = comm::stream();
+        self.image_cache_task.send(GetImageAtSize(copy *url, size, response_chan));
+        let response = response_port.recv();
+        match response {
+            ImageReady(ref image) => {
+                state.sized_images.push((size, clone_arc(image)));
+                self.animate(state, image);
+            }
+            ImageNotReady => (),
+            ImageFailed => state.last_response = ImageFailed
+        }
+        match response {
+            ImageFailed => (),
+            ImageReady(*) | ImageNotReady if !state.waiting_for_resolution => {
+                self.wait_for_resolution(state, url, size)
+            }
+            ImageReady(*) | ImageNotReady => ()
+        }
+
+        chan.send(response);
+        port
+    }
+
     // FIXME: Should return a Future
     pub fn get_image(&self, url: &Url) -> Port<ImageResponseMsg> {
         let state = self.get_state(url);
@@ -75,6 +191,7 @@ pub impl LocalImageCache {
 
         match state.last_response {
             ImageReady(ref image) => {
+                self.animate(state, image);
                 let (port, chan) = comm::stream();
                 chan.send(ImageReady(clone_arc(image)));
                 return port;
@@ -117,7 +234,8 @@ pub impl LocalImageCache {
                     on_image_available(response_port.recv());
                 }
             }
-            _ => ()
+            ImageReady(ref image) => self.animate(state, image),
+            ImageFailed => ()
         }
 
         // Put a copy of the response in the cache
@@ -133,13 +251,94 @@ pub impl LocalImageCache {
         return port;
     }
 
+    /// Returns the intrinsic dimensions of the image, which may be known before the image itself
+    /// is available. If they aren't known yet, a reflow is triggered once they are.
+    pub fn get_image_dimensions(&self, url: &Url) -> Option<Size2D<uint>> {
+        let state = self.get_state(url);
+        if state.dimensions.is_some() {
+            return state.dimensions
+        }
+
+        let (response_port, response_chan) = comm::stream();
+        self.image_cache_task.send(GetImageDimensions(copy *url, response_chan));
+        let dimensions = response_port.recv();
+        match dimensions {
+            Some(_) => state.dimensions = dimensions,
+            None if !state.waiting_for_dimensions => {
+                state.waiting_for_dimensions = true;
+                let image_cache_task = self.image_cache_task.clone();
+                assert!(self.on_image_available.is_some());
+                let on_image_available = self.on_image_available.get()();
+                let url = copy *url;
+                do task::spawn {
+                    let (response_port, response_chan) = comm::stream();
+                    image_cache_task.send(WaitForImageDimensions(copy url, response_chan));
+                    if response_port.recv().is_some() {
+                        on_image_available(ImageNotReady);
+                    }
+                }
+            }
+            None => ()
+        }
+        dimensions
+    }
+
+    /// Waits for the next size of the image to be decoded, and triggers a reflow if one is. The
+    /// next round is told when the wait is over either way.
+    priv fn wait_for_resolution(&self, state: @mut ImageState, url: &Url, size: Size2D<uint>) {
+        state.waiting_for_resolution = true;
+        let image_cache_task = self.image_cache_task.clone();
+        assert!(self.on_image_available.is_some());
+        let on_image_available = self.on_image_available.get()();
+        let resolution_chan = self.resolution_chan.clone();
+        let url = copy *url;
+        do task::spawn {
+            let (response_port, response_chan) = comm::stream();
+            image_cache_task.send(WaitForImageAtSize(copy url, size, response_chan));
+            match response_port.try_recv() {
+                Some(response) => {
+                    resolution_chan.send((url, true));
+                    on_image_available(response);
+                }
+                None => resolution_chan.send((url, false))
+            }
+        }
+    }
+
+    /// Asks for the image to be shown again when its next animation frame is due, through the
+    /// same callback that reports images becoming available.
+    priv fn animate(&self, state: @mut ImageState, image: &ARC<~Image>) {
+        let now = precise_time_ns();
+        if now < state.animation_due {
+            return
+        }
+        match arc::get(image).next_frame_delay(now) {
+            Some(delay) => {
+                state.animation_due = now + (delay as u64) * 1000000;
+                assert!(self.on_image_available.is_some());
+                let on_image_available = self.on_image_available.get()();
+                let image = clone_arc(image);
+                do task::spawn {
+                    timer::sleep(&uv_global_loop::get(), delay);
+                    on_image_available(ImageReady(image));
+                }
+            }
+            None => ()
+        }
+    }
+
     priv fn get_state(&self, url: &Url) -> @mut ImageState {
         let state = do self.state_map.find_or_insert_with(url.clone()) |_| {
             let new_state = @mut ImageState {
                 prefetched: false,
                 decoded: false,
                 last_request_round: 0,
-                last_response: ImageNotReady
+                last_response: ImageNotReady,
+                dimensions: None,
+                waiting_for_dimensions: false,
+                sized_images: ~[],
+                waiting_for_resolution: false,
+                animation_due: 0
             };
             new_state
         };
@@ -0,0 +1,253 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Working out the MIME type of a resource from its `Content-Type` and its first bytes, following
+//! the WHATWG MIME Sniffing Standard. Audio and video signatures are not recognized, since
+//! nothing here can play them.
+
+use resource_task::Metadata;
+
+/// The number of bytes of a resource that sniffing looks at.
+pub static RESOURCE_HEADER_LENGTH: uint = 1445;
+
+/// Tags whose presence near the start of a resource marks it as HTML. Each must be followed by a
+/// space or `>`.
+static HTML_TAGS: [&'static str, ..17] = [
+    "<!doctype html", "<html", "<head", "<script", "<iframe", "<h1", "<div", "<font", "<table",
+    "<a", "<style", "<title", "<b", "<body", "<br", "<p", "<!--",
+];
+
+/// Computes the MIME type of a resource to be shown in a browsing context, from its metadata and
+/// the start of its body.
+pub fn sniff(metadata: &Metadata, start: &[u8]) -> (~str, ~str) {
+    let supplied = match metadata.content_type {
+        Some(ref mime) if !is_unknown(mime) => copy *mime,
+        _ => return sniff_unknown(start, true),
+    };
+
+    // Some servers label everything they don't know as text/plain, so binary files that claim
+    // to be text are shown as binary.
+    if is_http(metadata) && supplied == (~"text", ~"plain") {
+        match metadata.charset {
+            None | Some(~"iso-8859-1") | Some(~"utf-8") => return sniff_text_or_binary(start),
+            Some(_) => {}
+        }
+    }
+
+    if is_xml(&supplied) || is_html(&supplied) {
+        return supplied
+    }
+    match supplied {
+        (~"image", _) => match image_type(start) {
+            Some(mime) => mime,
+            None => supplied,
+        },
+        _ => supplied,
+    }
+}
+
+/// Computes the MIME type of a resource loaded as an image, such as by an `<img>` element.
+/// Returns None if there is neither a recognizable signature nor a `Content-Type`.
+pub fn sniff_image(supplied: &Option<(~str, ~str)>, start: &[u8]) -> Option<(~str, ~str)> {
+    match *supplied {
+        Some(ref mime) if is_xml(mime) || is_html(mime) => return Some(copy *mime),
+        _ => {}
+    }
+    match image_type(start) {
+        Some(mime) => Some(mime),
+        None => match *supplied {
+            Some(ref mime) if !is_unknown(mime) => Some(copy *mime),
+            _ => None,
+        }
+    }
+}
+
+/// Recognizes the signatures of image formats.
+pub fn image_type(start: &[u8]) -> Option<(~str, ~str)> {
+    let (main_type, sub_type) = if starts_with(start, [0x00, 0x00, 0x01, 0x00]) ||
+            starts_with(start, [0x00, 0x00, 0x02, 0x00]) {
+        ("image", "x-icon")
+    } else if starts_with(start, str::to_bytes("BM")) {
+        ("image", "bmp")
+    } else if starts_with(start, str::to_bytes("GIF87a")) ||
+            starts_with(start, str::to_bytes("GIF89a")) {
+        ("image", "gif")
+    } else if starts_with(start, str::to_bytes("RIFF")) && start.len() >= 14 &&
+            starts_with(vec::slice(start, 8, start.len()), str::to_bytes("WEBPVP")) {
+        ("image", "webp")
+    } else if starts_with(start, [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a]) {
+        ("image", "png")
+    } else if starts_with(start, [0xff, 0xd8, 0xff]) {
+        ("image", "jpeg")
+    } else {
+        return None
+    };
+    Some((main_type.to_owned(), sub_type.to_owned()))
+}
+
+/// Returns true if the MIME type is one that scripts may be served with.
+pub fn is_javascript(mime: &(~str, ~str)) -> bool {
+    match *mime {
+        (~"application", ~"ecmascript") | (~"application", ~"javascript") |
+        (~"application", ~"x-ecmascript") | (~"application", ~"x-javascript") |
+        (~"text", ~"ecmascript") | (~"text", ~"javascript") | (~"text", ~"javascript1.0") |
+        (~"text", ~"javascript1.1") | (~"text", ~"javascript1.2") |
+        (~"text", ~"javascript1.3") | (~"text", ~"javascript1.4") |
+        (~"text", ~"javascript1.5") | (~"text", ~"jscript") | (~"text", ~"livescript") |
+        (~"text", ~"x-ecmascript") | (~"text", ~"x-javascript") => true,
+        _ => false,
+    }
+}
+
+/// Computes the MIME type of a resource with no usable `Content-Type`. Signatures of formats
+/// that may run script, such as HTML, are only recognized if `allow_scriptable` is set.
+pub fn sniff_unknown(start: &[u8], allow_scriptable: bool) -> (~str, ~str) {
+    if allow_scriptable {
+        let mut i = 0;
+        while i < start.len() && is_whitespace(start[i]) {
+            i += 1;
+        }
+        let rest = vec::slice(start, i, start.len());
+        for HTML_TAGS.each |tag| {
+            if starts_with_ignoring_case(rest, *tag) && rest.len() > tag.len() &&
+                    (rest[tag.len()] == ' ' as u8 || rest[tag.len()] == '>' as u8) {
+                return (~"text", ~"html")
+            }
+        }
+        if starts_with(rest, str::to_bytes("<?xml")) {
+            return (~"text", ~"xml")
+        }
+        if starts_with(start, str::to_bytes("%PDF-")) {
+            return (~"application", ~"pdf")
+        }
+    }
+
+    if starts_with(start, str::to_bytes("%!PS-Adobe-")) {
+        return (~"application", ~"postscript")
+    }
+    if has_byte_order_mark(start) {
+        return (~"text", ~"plain")
+    }
+    match image_type(start) {
+        Some(mime) => return mime,
+        None => {}
+    }
+    if starts_with(start, [0x1f, 0x8b, 0x08]) {
+        return (~"application", ~"x-gzip")
+    }
+    if starts_with(start, [0x50, 0x4b, 0x03, 0x04]) {
+        return (~"application", ~"zip")
+    }
+    if starts_with(start, [0x52, 0x61, 0x72, 0x20, 0x1a, 0x07, 0x00]) {
+        return (~"application", ~"x-rar-compressed")
+    }
+    sniff_text_or_binary(start)
+}
+
+fn sniff_text_or_binary(start: &[u8]) -> (~str, ~str) {
+    if has_byte_order_mark(start) || !start.any(|byte| is_binary(*byte)) {
+        (~"text", ~"plain")
+    } else {
+        (~"application", ~"octet-stream")
+    }
+}
+
+fn is_unknown(mime: &(~str, ~str)) -> bool {
+    match *mime {
+        (~"unknown", ~"unknown") | (~"application", ~"unknown") | (~"*", ~"*") => true,
+        _ => false,
+    }
+}
+
+fn is_xml(mime: &(~str, ~str)) -> bool {
+    match *mime {
+        (~"text", ~"xml") | (~"application", ~"xml") => true,
+        (_, ref sub_type) => sub_type.ends_with("+xml"),
+    }
+}
+
+fn is_html(mime: &(~str, ~str)) -> bool {
+    *mime == (~"text", ~"html")
+}
+
+fn is_http(metadata: &Metadata) -> bool {
+    metadata.final_url.scheme == ~"http" || metadata.final_url.scheme == ~"https"
+}
+
+fn has_byte_order_mark(start: &[u8]) -> bool {
+    starts_with(start, [0xfe, 0xff]) || starts_with(start, [0xff, 0xfe]) ||
+        starts_with(start, [0xef, 0xbb, 0xbf])
+}
+
+/// Returns true for the control characters that don't appear in text.
+fn is_binary(byte: u8) -> bool {
+    byte <= 0x08 || byte == 0x0b || (byte >= 0x0e && byte <= 0x1a) || (byte >= 0x1c && byte <= 0x1f)
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    byte == 0x09 || byte == 0x0a || byte == 0x0c || byte == 0x0d || byte == 0x20
+}
+
+fn starts_with(bytes: &[u8], pattern: &[u8]) -> bool {
+    bytes.len() >= pattern.len() && vec::slice(bytes, 0, pattern.len()) == pattern
+}
+
+fn starts_with_ignoring_case(bytes: &[u8], pattern: &str) -> bool {
+    if bytes.len() < pattern.len() {
+        return false
+    }
+    for uint::range(0, pattern.len()) |i| {
+        let byte = bytes[i];
+        let lower = if byte >= 'A' as u8 && byte <= 'Z' as u8 { byte + 0x20 } else { byte };
+        if lower != pattern[i] {
+            return false
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+fn metadata(url: &str, content_type: &str) -> Metadata {
+    use std::net::url;
+    let mut metadata = Metadata::default(url::from_str(url.to_owned()).get());
+    metadata.set_content_type(content_type);
+    metadata
+}
+
+#[test]
+fn should_sniff_resources_without_content_type() {
+    let metadata = metadata("file:///page", "");
+    assert!(sniff(&metadata, str::to_bytes("\n  <HTML><body>")) == (~"text", ~"html"));
+    assert!(sniff(&metadata, str::to_bytes("<?xml version=\"1.0\"?>")) == (~"text", ~"xml"));
+    assert!(sniff(&metadata, str::to_bytes("alert('hi');")) == (~"text", ~"plain"));
+    assert!(sniff(&metadata, [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00]) ==
+            (~"image", ~"png"));
+    assert!(sniff(&metadata, [0x00, 0x01, 0x02]) == (~"application", ~"octet-stream"));
+}
+
+#[test]
+fn should_trust_supplied_types_except_for_text_plain_over_http() {
+    let script = metadata("http://example.com/a.js", "text/javascript");
+    assert!(sniff(&script, str::to_bytes("<html>")) == (~"text", ~"javascript"));
+
+    let binary = metadata("http://example.com/a.bin", "text/plain; charset=UTF-8");
+    assert!(sniff(&binary, [0x00, 0x01, 0x02]) == (~"application", ~"octet-stream"));
+    let binary = metadata("http://example.com/a.bin", "text/plain; charset=koi8-r");
+    assert!(sniff(&binary, [0x00, 0x01, 0x02]) == (~"text", ~"plain"));
+}
+
+#[test]
+fn should_sniff_images_by_signature() {
+    let gif = str::to_bytes("GIF89a....");
+    assert!(sniff_image(&Some((~"image", ~"png")), gif) == Some((~"image", ~"gif")));
+    assert!(sniff_image(&None, gif) == Some((~"image", ~"gif")));
+    assert!(sniff_image(&Some((~"text", ~"html")), gif) == Some((~"text", ~"html")));
+    assert!(sniff_image(&None, [0x00]).is_none());
+}
+
+#[test]
+fn should_recognize_javascript_types() {
+    assert!(is_javascript(&(~"application", ~"x-javascript")));
+    assert!(!is_javascript(&(~"text", ~"html")));
+}
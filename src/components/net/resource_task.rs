@@ -4,27 +4,237 @@
 
 //! A task that takes a URL and streams back the binary data.
 
+use about_loader;
+use archive;
+use archive::ArchiveMode;
+use cookie::{Cookie, CookieJar, CookieSource, HTTP};
+use data_loader;
 use file_loader;
+use http_cache;
+use http_cache::HttpCacheTask;
 use http_loader;
+use image_cache_task::ImageCacheTask;
 
 use core::cell::Cell;
-use core::comm::{Chan, Port, SharedChan};
+use core::comm::{Chan, GenericSmartChan, Port, SharedChan, select2i, stream};
+use core::either::{Left, Right};
+use core::hashmap::HashMap;
+use core::task;
+use servo_util::time::ProfilerChan;
 use std::net::url::{Url, to_str};
-use util::spawn_listener;
+use std::time;
+use std::timer;
+use std::uv_global_loop;
+
+/// How urgently a load is needed. Loads waiting for a connection start in this order, and in the
+/// order they were requested within each class.
+#[deriving(Eq)]
+pub enum LoadPriority {
+    /// The document being navigated to
+    DocumentPriority,
+    /// A style sheet, which blocks the first layout
+    StylesheetPriority,
+    /// A script, which blocks parsing
+    ScriptPriority,
+    /// A resource that may be needed later, such as an image prefetched by the image cache
+    PrefetchPriority,
+}
+
+/// The most loads that may be in progress for a single host at once.
+static MAX_LOADS_PER_HOST: uint = 6;
+/// The most loads from the network that may be in progress at once.
+static MAX_NETWORK_LOADS: uint = 24;
+/// How long changed cookies wait before they are saved, so that a burst of changes is saved at
+/// once, in milliseconds.
+static COOKIE_SAVE_DELAY: uint = 1000;
 
 pub enum ControlMsg {
     /// Request the data associated with a particular URL
-    Load(Url, Chan<ProgressMsg>),
+    Load(Url, LoadPriority, Chan<ProgressMsg>),
+    /// Like `Load`, but the load stops when a message arrives on the port, such as one from
+    /// `LoadGroup::add_load`. Unless the load has already finished, its progress channel then
+    /// receives `Aborted` and nothing more
+    CancelableLoad(Url, LoadPriority, Chan<ProgressMsg>, Port<()>),
+    /// Used by the tasks forwarding the progress of cancelable loads to report that they are over
+    priv RemoveCancelableLoad(RequestId),
+    /// Used by the tasks forwarding the progress of network loads to report that a load from the
+    /// given host is over, freeing its connection for the next one
+    priv LoadFinished(~str),
+    /// Store cookies set on behalf of a URL, given as `Set-Cookie` header values or strings
+    /// assigned to `document.cookie`
+    SetCookies(Url, ~[~str], CookieSource),
+    /// Retrieve the cookies to send to a URL, formatted as the value of a `Cookie` header
+    GetCookies(Url, CookieSource, Chan<Option<~str>>),
+    /// Used by the timer started when the cookies change to have them saved
+    priv SaveCookies,
+    /// Give the `about:diagnostics` page the profiler and image cache to report on
+    RegisterDiagnosticSources(ProfilerChan, ImageCacheTask),
+    /// Used by the `about:` loader to get the tasks registered for diagnostics, if any
+    priv GetDiagnosticSources(Chan<Option<(ProfilerChan, ImageCacheTask)>>),
     Exit
 }
 
+/// Metadata about a loaded resource, such as is obtained from HTTP headers.
+#[deriving(Eq)]
+pub struct Metadata {
+    /// The URL the resource was ultimately loaded from, after any redirects.
+    final_url: Url,
+    /// The HTTP status code, for resources loaded over HTTP.
+    status: Option<uint>,
+    /// The response headers, in the order they were received.
+    headers: ~[(~str, ~str)],
+    /// The MIME type and subtype, lowercased, e.g. `("text", "html")`.
+    content_type: Option<(~str, ~str)>,
+    /// The character set, lowercased, if one was declared.
+    charset: Option<~str>,
+}
+
+impl Metadata {
+    /// Metadata for a resource about which nothing is known except its URL.
+    pub fn default(url: Url) -> Metadata {
+        Metadata {
+            final_url: url,
+            status: None,
+            headers: ~[],
+            content_type: None,
+            charset: None,
+        }
+    }
+
+    /// Extracts the MIME type and charset from the value of a `Content-Type` header, such as
+    /// `text/html; charset=utf-8`. Malformed values leave the content type unset.
+    pub fn set_content_type(&mut self, content_type: &str) {
+        let mut parts = ~[];
+        for str::each_split_char(content_type, ';') |part| {
+            parts.push(str::to_lower(part.trim()));
+        }
+        if parts.is_empty() {
+            return
+        }
+
+        match str::find_char(parts[0], '/') {
+            Some(slash) if slash > 0 && slash < parts[0].len() - 1 => {
+                let main_type = parts[0].slice(0, slash).trim().to_owned();
+                let sub_type = parts[0].slice(slash + 1, parts[0].len()).trim().to_owned();
+                self.content_type = Some((main_type, sub_type));
+            }
+            _ => return,
+        }
+
+        for parts.tailn(1).each |param| {
+            if param.starts_with("charset=") {
+                let charset = param.slice(8, param.len()).trim_chars(&['"', '\'']);
+                self.charset = Some(charset.to_owned());
+            }
+        }
+    }
+
+    /// Returns the value of the first header with the given name, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<~str> {
+        let mut values = self.header_values(name);
+        if values.is_empty() { None } else { Some(values.swap_remove(0)) }
+    }
+
+    /// Returns the values of all headers with the given name, compared case-insensitively.
+    pub fn header_values(&self, name: &str) -> ~[~str] {
+        let name = str::to_lower(name);
+        let mut values = ~[];
+        for self.headers.each |&(ref header_name, ref value)| {
+            if str::to_lower(*header_name) == name {
+                values.push(copy *value);
+            }
+        }
+        values
+    }
+
+    /// Returns true unless the resource came with an HTTP status outside the 2xx range.
+    pub fn is_success(&self) -> bool {
+        match self.status {
+            None => true,
+            Some(status) => status >= 200 && status < 300,
+        }
+    }
+}
+
 /// Messages sent in response to a `Load` message
 #[deriving(Eq)]
 pub enum ProgressMsg {
+    /// Metadata about the resource. Loaders send this at most once, before the first payload
+    Metadata(Metadata),
     /// Binary data - there may be multiple of these
     Payload(~[u8]),
     /// Indicates loading is complete, either successfully or not
-    Done(Result<(), ()>)
+    Done(Result<(), ()>),
+    /// Indicates the load was cancelled. Nothing is sent after this
+    Aborted,
+}
+
+/// Identifies a load started with `CancelableLoad` within the resource task
+pub type RequestId = uint;
+
+/// Loads that are cancelled together, such as those of a page. Clones refer to the same group.
+pub struct LoadGroup {
+    priv chan: SharedChan<LoadGroupMsg>,
+}
+
+enum LoadGroupMsg {
+    /// Add a load, cancelled by sending on the channel
+    AddLoad(Chan<()>),
+    /// Cancel every load in the group
+    CancelLoads,
+}
+
+pub impl LoadGroup {
+    /// Creates an empty group. A task holds on to the cancel channels of its loads until the
+    /// group is cancelled or every handle to it is gone.
+    fn new() -> LoadGroup {
+        let (port, chan) = stream();
+        do task::spawn {
+            let mut cancel_chans = ~[];
+            let mut cancelled = false;
+            loop {
+                match port.try_recv() {
+                    Some(AddLoad(cancel_chan)) if cancelled => {
+                        let _ = cancel_chan.try_send(());
+                    }
+                    Some(AddLoad(cancel_chan)) => cancel_chans.push(cancel_chan),
+                    Some(CancelLoads) => {
+                        cancelled = true;
+                        for cancel_chans.each |cancel_chan| {
+                            // The load may be finishing at this very moment.
+                            let _ = cancel_chan.try_send(());
+                        }
+                        cancel_chans = ~[];
+                    }
+                    None => break,
+                }
+            }
+        }
+        LoadGroup {
+            chan: SharedChan::new(chan),
+        }
+    }
+
+    /// Returns the port to start a load in the group with, as part of a `CancelableLoad`. Loads
+    /// added after the group is cancelled are cancelled right away.
+    fn add_load(&self) -> Port<()> {
+        let (cancel_port, cancel_chan) = stream();
+        let _ = self.chan.try_send(AddLoad(cancel_chan));
+        cancel_port
+    }
+
+    /// Cancels every unfinished load in the group, and any added later.
+    fn cancel(&self) {
+        let _ = self.chan.try_send(CancelLoads);
+    }
+}
+
+impl Clone for LoadGroup {
+    fn clone(&self) -> LoadGroup {
+        LoadGroup {
+            chan: self.chan.clone(),
+        }
+    }
 }
 
 /// Handle to a resource task
@@ -36,66 +246,419 @@ Creates a task to load a specific resource
 The ResourceManager delegates loading to a different type of loader task for
 each URL scheme
 */
-type LoaderTaskFactory = ~fn() -> ~fn(url: Url, Chan<ProgressMsg>);
+pub type LoaderTaskFactory = ~fn() -> LoaderTask;
 
-pub type LoaderTask = ~fn(url: Url, Chan<ProgressMsg>);
+/// Loads a URL, sending the given extra request headers if the scheme has any. Loaders should
+/// stop as soon as sending progress fails, since that means the load was cancelled
+pub type LoaderTask = ~fn(url: Url, headers: ~[(~str, ~str)], Chan<ProgressMsg>);
 
-/// Create a ResourceTask with the default loaders
+/// Create a ResourceTask with the default loaders, caching HTTP responses and cookies in memory
 pub fn ResourceTask() -> ResourceTask {
+    ResourceTask_(None, None, None)
+}
+
+/// Create a ResourceTask with the default loaders. If a cache directory is given, HTTP responses
+/// are cached there as well as in memory; if a cookie file is given, cookies are loaded from it
+/// at startup and saved to it shortly after they change. If an archive mode is given, loads are
+/// recorded into or replayed from an archive, and HTTP responses aren't cached
+pub fn ResourceTask_(cache_dir: Option<Path>,
+                     cookie_file: Option<Path>,
+                     archive_mode: Option<ArchiveMode>)
+                     -> ResourceTask {
+    // The about: loader needs a channel to the resource task before the task exists.
+    let (port, chan) = stream();
+    let chan = SharedChan::new(chan);
+
+    let about_loader_factory = about_loader::factory(chan.clone());
+    let data_loader_factory: LoaderTaskFactory = data_loader::factory;
     let file_loader_factory: LoaderTaskFactory = file_loader::factory;
-    let http_loader_factory: LoaderTaskFactory = http_loader::factory;
+    let http_loader_factory = http_loader::factory(chan.clone());
     let loaders = ~[
+        (~"about", about_loader_factory),
+        (~"data", data_loader_factory),
         (~"file", file_loader_factory),
         (~"http", http_loader_factory)
     ];
-    create_resource_task_with_loaders(loaders)
+    match archive_mode {
+        // Cached responses would never reach the archive.
+        Some(mode) => start_resource_manager(port,
+                                             chan,
+                                             archive::wrap_loaders(mode, loaders),
+                                             None,
+                                             cookie_file),
+        None => start_resource_manager(port,
+                                       chan,
+                                       loaders,
+                                       Some(HttpCacheTask(cache_dir)),
+                                       cookie_file),
+    }
+}
+
+fn create_resource_task_with_loaders(loaders: ~[(~str, LoaderTaskFactory)],
+                                     http_cache: Option<HttpCacheTask>,
+                                     cookie_file: Option<Path>)
+                                     -> ResourceTask {
+    let (port, chan) = stream();
+    start_resource_manager(port, SharedChan::new(chan), loaders, http_cache, cookie_file)
 }
 
-fn create_resource_task_with_loaders(loaders: ~[(~str, LoaderTaskFactory)]) -> ResourceTask {
+/// Spawns a resource manager receiving on the given port, and returns its channel
+fn start_resource_manager(port: Port<ControlMsg>,
+                          chan: ResourceTask,
+                          loaders: ~[(~str, LoaderTaskFactory)],
+                          http_cache: Option<HttpCacheTask>,
+                          cookie_file: Option<Path>)
+                          -> ResourceTask {
+    let port_cell = Cell(port);
+    let chan_cell = Cell(chan.clone());
     let loaders_cell = Cell(loaders);
-    let chan = do spawn_listener |from_client| {
+    let http_cache_cell = Cell(http_cache);
+    let cookie_file_cell = Cell(cookie_file);
+    do task::spawn {
         // TODO: change copy to move once we can move out of closures
-        ResourceManager(from_client, loaders_cell.take()).start()
-    };
-    SharedChan::new(chan)
+        let mut manager = ResourceManager(port_cell.take(),
+                                          chan_cell.take(),
+                                          loaders_cell.take(),
+                                          http_cache_cell.take(),
+                                          cookie_file_cell.take());
+        manager.start()
+    }
+    chan
 }
 
 pub struct ResourceManager {
     from_client: Port<ControlMsg>,
+    /// A channel to this resource task, for the tasks it spawns to post back on
+    chan: ResourceTask,
     /// Per-scheme resource loaders
     loaders: ~[(~str, LoaderTaskFactory)],
+    /// The cache that HTTP loads go through, if any
+    http_cache: Option<HttpCacheTask>,
+    /// The cookies sent with HTTP requests and visible to scripts
+    cookie_jar: CookieJar,
+    /// The file cookies are loaded from and saved to, if any
+    cookie_file: Option<Path>,
+    /// Whether the cookies have changed since they were last saved
+    cookies_dirty: bool,
+    /// The unfinished cancelable loads
+    cancelable_loads: HashMap<RequestId, ()>,
+    /// The ID to give the next cancelable load
+    next_request_id: RequestId,
+    /// The profiler and image cache reported on by `about:diagnostics`
+    diagnostic_sources: Option<(ProfilerChan, ImageCacheTask)>,
+    /// Loads waiting for a connection, in the order they were requested
+    queued_loads: ~[QueuedLoad],
+    /// The number of loads in progress for each host
+    host_loads: HashMap<~str, uint>,
+    /// The number of loads in progress from the network
+    network_loads: uint,
+}
+
+/// A load waiting for a connection.
+struct QueuedLoad {
+    url: Url,
+    priority: LoadPriority,
+    progress_chan: Chan<ProgressMsg>,
+    /// The ID of the load, if it can be cancelled
+    id: Option<RequestId>,
+}
+
+impl LoadPriority {
+    /// Lower ranks are more urgent.
+    fn rank(&self) -> uint {
+        *self as uint
+    }
 }
 
 
 pub fn ResourceManager(from_client: Port<ControlMsg>, 
-                       loaders: ~[(~str, LoaderTaskFactory)]) -> ResourceManager {
+                       chan: ResourceTask,
+                       loaders: ~[(~str, LoaderTaskFactory)],
+                       http_cache: Option<HttpCacheTask>,
+                       cookie_file: Option<Path>) -> ResourceManager {
+    let mut cookie_jar = CookieJar::new();
+    for cookie_file.each |path| {
+        if os::path_exists(path) {
+            match cookie_jar.load(path, time::get_time().sec) {
+                Ok(()) => {}
+                Err(message) => debug!("resource_task: can't load cookies: %s", message),
+            }
+        }
+    }
+
     ResourceManager {
         from_client : from_client,
+        chan : chan,
         loaders : loaders,
+        http_cache : http_cache,
+        cookie_jar : cookie_jar,
+        cookie_file : cookie_file,
+        cookies_dirty : false,
+        cancelable_loads : HashMap::new(),
+        next_request_id : 0,
+        diagnostic_sources : None,
+        queued_loads : ~[],
+        host_loads : HashMap::new(),
+        network_loads : 0,
     }
 }
 
 
 impl ResourceManager {
-    fn start(&self) {
+    fn start(&mut self) {
         loop {
             match self.from_client.recv() {
-              Load(url, progress_chan) => {
-                self.load(url.clone(), progress_chan)
+              Load(url, priority, progress_chan) => {
+                self.schedule(url, priority, progress_chan, None)
+              }
+              CancelableLoad(url, priority, progress_chan, cancel_port) => {
+                let id = self.next_request_id;
+                self.next_request_id += 1;
+
+                self.cancelable_loads.insert(id, ());
+                let progress_chan = self.forward_until_cancelled(id, progress_chan, cancel_port);
+                self.schedule(url, priority, progress_chan, Some(id))
+              }
+              RemoveCancelableLoad(id) => {
+                self.cancelable_loads.remove(&id);
+              }
+              LoadFinished(host) => {
+                match self.loads_for_host(host) {
+                    0 => fail!(~"finished a load that was never started"),
+                    1 => {
+                        self.host_loads.remove(&host);
+                    }
+                    count => {
+                        self.host_loads.insert(copy host, count - 1);
+                    }
+                }
+                self.network_loads -= 1;
+                self.start_queued_loads()
+              }
+              SetCookies(url, headers, source) => {
+                self.set_cookies(&url, headers, source)
+              }
+              GetCookies(url, source, response_chan) => {
+                let now = time::get_time().sec;
+                response_chan.send(self.cookie_jar.cookies_for_url(&url, source, now))
+              }
+              SaveCookies => {
+                self.save_cookies()
+              }
+              RegisterDiagnosticSources(profiler_chan, image_cache_task) => {
+                self.diagnostic_sources = Some((profiler_chan, image_cache_task));
+              }
+              GetDiagnosticSources(response_chan) => {
+                let sources = match self.diagnostic_sources {
+                    Some((ref profiler_chan, ref image_cache_task)) => {
+                        Some((profiler_chan.clone(), image_cache_task.clone()))
+                    }
+                    None => None,
+                };
+                response_chan.send(sources)
               }
               Exit => {
+                self.save_cookies();
+                for self.http_cache.each |http_cache| {
+                    http_cache.send(http_cache::Exit);
+                }
                 break
               }
             }
         }
     }
 
+    fn set_cookies(&mut self, url: &Url, headers: ~[~str], source: CookieSource) {
+        let now = time::get_time().sec;
+        for headers.each |header| {
+            match Cookie::parse(*header, url, now) {
+                Some(cookie) => self.cookie_jar.set_cookie(cookie, source, now),
+                None => debug!("resource_task: ignoring cookie `%s`", *header),
+            }
+        }
+        if self.cookie_file.is_none() || self.cookies_dirty {
+            return
+        }
+        // Nothing waits for the resource task to exit, so the cookies are saved soon after they
+        // change rather than only at exit.
+        self.cookies_dirty = true;
+        let chan = self.chan.clone();
+        do task::spawn {
+            timer::sleep(&uv_global_loop::get(), COOKIE_SAVE_DELAY);
+            let _ = chan.try_send(SaveCookies);
+        }
+    }
+
+    /// Saves the cookies if they have changed since they were last saved.
+    fn save_cookies(&mut self) {
+        if !self.cookies_dirty {
+            return
+        }
+        self.cookies_dirty = false;
+        for self.cookie_file.each |path| {
+            match self.cookie_jar.save(path, time::get_time().sec) {
+                Ok(()) => {}
+                Err(message) => debug!("resource_task: can't save cookies: %s", message),
+            }
+        }
+    }
+
+    /// Starts a load, or queues it until a connection to its host is free. Loads that don't use
+    /// the network are never queued.
+    fn schedule(&mut self,
+                url: Url,
+                priority: LoadPriority,
+                progress_chan: Chan<ProgressMsg>,
+                id: Option<RequestId>) {
+        if url.host.is_empty() {
+            return self.load(url, progress_chan)
+        }
+        self.queued_loads.push(QueuedLoad {
+            url: url,
+            priority: priority,
+            progress_chan: progress_chan,
+            id: id,
+        });
+        self.start_queued_loads()
+    }
+
+    /// Starts the most urgent queued loads, as long as connections are free.
+    fn start_queued_loads(&mut self) {
+        while self.network_loads < MAX_NETWORK_LOADS {
+            let mut next = None;
+            for self.queued_loads.eachi |i, queued| {
+                if self.loads_for_host(queued.url.host) >= MAX_LOADS_PER_HOST {
+                    loop
+                }
+                match next {
+                    Some(j) if self.queued_loads[j].priority.rank() <= queued.priority.rank() => {}
+                    _ => next = Some(i),
+                }
+            }
+            let QueuedLoad { url, progress_chan, id, _ } = match next {
+                Some(i) => self.queued_loads.remove(i),
+                None => break,
+            };
+
+            // A load cancelled while it was queued has already been told it was aborted.
+            match id {
+                Some(id) if !self.cancelable_loads.contains_key(&id) => loop,
+                _ => {}
+            }
+
+            debug!("resource_task: starting queued load of %s", to_str(&url));
+            let host = copy url.host;
+            let count = self.loads_for_host(host);
+            self.host_loads.insert(copy host, count + 1);
+            self.network_loads += 1;
+            let progress_chan = self.notify_when_finished(host, progress_chan);
+            self.load(url, progress_chan)
+        }
+    }
+
+    fn loads_for_host(&self, host: &str) -> uint {
+        match self.host_loads.find(&host.to_owned()) {
+            Some(count) => *count,
+            None => 0,
+        }
+    }
+
+    /// Returns a channel whose messages are passed on to the progress channel, posting
+    /// `LoadFinished` once the load is over or nobody is listening anymore.
+    fn notify_when_finished(&self, host: ~str, progress_chan: Chan<ProgressMsg>)
+                            -> Chan<ProgressMsg> {
+        let (port, chan) = stream();
+        let manager_chan = self.chan.clone();
+        let host = Cell(host);
+        do task::spawn {
+            loop {
+                let msg = match port.try_recv() {
+                    Some(msg) => msg,
+                    None => break,
+                };
+                let done = match msg {
+                    Done(*) | Aborted => true,
+                    Metadata(*) | Payload(*) => false,
+                };
+                if !progress_chan.try_send(msg) || done {
+                    break
+                }
+            }
+            let _ = manager_chan.try_send(LoadFinished(host.take()));
+        }
+        chan
+    }
+
+    /// Returns a channel whose messages are passed on to the progress channel until a message
+    /// arrives on the cancel port, at which point `Aborted` is sent instead. Dropping the returned
+    /// channel's port then makes the loader stop. If the cancel port's channel goes away without
+    /// sending anything, the load can no longer be cancelled and runs to the end.
+    fn forward_until_cancelled(&self,
+                               id: RequestId,
+                               progress_chan: Chan<ProgressMsg>,
+                               cancel_port: Port<()>)
+                               -> Chan<ProgressMsg> {
+        let (port, chan) = stream();
+        let manager_chan = self.chan.clone();
+        do task::spawn {
+            let mut cancelable = true;
+            loop {
+                let ready = if cancelable {
+                    select2i(&cancel_port, &port)
+                } else {
+                    Right(())
+                };
+                match ready {
+                    Left(()) => {
+                        match cancel_port.try_recv() {
+                            Some(()) => {
+                                let _ = progress_chan.try_send(Aborted);
+                                break
+                            }
+                            None => cancelable = false,
+                        }
+                    }
+                    Right(()) => {
+                        let msg = match port.try_recv() {
+                            Some(msg) => msg,
+                            // The loader went away without saying it was done.
+                            None => Done(Err(())),
+                        };
+                        let done = match msg {
+                            Done(*) | Aborted => true,
+                            Metadata(*) | Payload(*) => false,
+                        };
+                        if !progress_chan.try_send(msg) || done {
+                            break
+                        }
+                    }
+                }
+            }
+            let _ = manager_chan.try_send(RemoveCancelableLoad(id));
+        }
+        chan
+    }
+
     fn load(&self, url: Url, progress_chan: Chan<ProgressMsg>) {
 
         match self.get_loader_factory(&url) {
-            Some(loader_factory) => {
+            Some(loader) => {
                 debug!("resource_task: loading url: %s", to_str(&url));
-                loader_factory(url, progress_chan);
+                if url.scheme != ~"http" {
+                    return loader(url, ~[], progress_chan)
+                }
+
+                let mut headers = ~[];
+                let now = time::get_time().sec;
+                for self.cookie_jar.cookies_for_url(&url, HTTP, now).each |cookies| {
+                    headers.push((~"Cookie", copy *cookies));
+                }
+                match self.http_cache {
+                    Some(ref http_cache) => {
+                        http_cache::load(http_cache.clone(), loader, url, headers, progress_chan)
+                    }
+                    None => loader(url, headers, progress_chan),
+                }
             }
             None => {
                 debug!("resource_task: no loader for scheme %s", url.scheme);
@@ -129,7 +692,8 @@ fn test_exit() {
 fn test_bad_scheme() {
     let resource_task = ResourceTask();
     let progress = Port();
-    resource_task.send(Load(url::from_str(~"bogus://whatever").get(), progress.chan()));
+    resource_task.send(Load(url::from_str(~"bogus://whatever").get(), DocumentPriority,
+                            progress.chan()));
     match progress.recv() {
       Done(result) => { assert!(result.is_err()) }
       _ => fail
@@ -141,15 +705,257 @@ fn test_bad_scheme() {
 #[allow(non_implicitly_copyable_typarams)]
 fn should_delegate_to_scheme_loader() {
     let payload = ~[1, 2, 3];
-    let loader_factory = |_url: Url, progress_chan: Chan<ProgressMsg>| {
+    let loader_factory = |_url: Url,
+                          _headers: ~[(~str, ~str)],
+                          progress_chan: Chan<ProgressMsg>| {
         progress_chan.send(Payload(copy payload));
         progress_chan.send(Done(Ok(())));
     };
     let loader_factories = ~[(~"snicklefritz", loader_factory)];
-    let resource_task = create_resource_task_with_loaders(loader_factories);
+    let resource_task = create_resource_task_with_loaders(loader_factories, None, None);
     let progress = Port();
-    resource_task.send(Load(url::from_str(~"snicklefritz://heya").get(), progress.chan()));
+    resource_task.send(Load(url::from_str(~"snicklefritz://heya").get(), DocumentPriority,
+                            progress.chan()));
     assert!(progress.recv() == Payload(payload));
     assert!(progress.recv() == Done(Ok(())));
     resource_task.send(Exit);
 }
+
+#[test]
+fn should_serve_fresh_http_responses_from_cache() {
+    let (load_port, load_chan) = comm::stream();
+    let load_chan = SharedChan::new(load_chan);
+    let loader_factory: LoaderTaskFactory = || {
+        let load_chan = load_chan.clone();
+        let loader: LoaderTask = |url, _headers, progress_chan| {
+            load_chan.send(());
+            let mut metadata = Metadata::default(url);
+            metadata.status = Some(200);
+            metadata.headers = ~[(~"Cache-Control", ~"max-age=60")];
+            progress_chan.send(Metadata(metadata));
+            progress_chan.send(Payload(~[1, 2, 3]));
+            progress_chan.send(Done(Ok(())));
+        };
+        loader
+    };
+    let resource_task = create_resource_task_with_loaders(~[(~"http", loader_factory)],
+                                                          Some(HttpCacheTask(None)),
+                                                          None);
+
+    for 2.times {
+        let (progress_port, progress_chan) = comm::stream();
+        resource_task.send(Load(url::from_str(~"http://example.com/").get(), DocumentPriority,
+                                progress_chan));
+        match progress_port.recv() {
+          Metadata(metadata) => assert!(metadata.status == Some(200)),
+          _ => fail
+        }
+        assert!(progress_port.recv() == Payload(~[1, 2, 3]));
+        assert!(progress_port.recv() == Done(Ok(())));
+    }
+
+    // Only the first load reached the loader.
+    load_port.recv();
+    assert!(!load_port.peek());
+    resource_task.send(Exit);
+}
+
+#[test]
+fn should_store_and_send_cookies() {
+    use cookie::NonHTTP;
+
+    // The loader stores the cookies in the resource task, as the HTTP loader does.
+    let (port, chan) = comm::stream();
+    let chan = SharedChan::new(chan);
+    let (headers_port, headers_chan) = comm::stream();
+    let headers_chan = SharedChan::new(headers_chan);
+    let cookie_chan = chan.clone();
+    let loader_factory: LoaderTaskFactory = || {
+        let headers_chan = headers_chan.clone();
+        let cookie_chan = cookie_chan.clone();
+        let loader: LoaderTask = |url, headers, progress_chan| {
+            headers_chan.send(headers);
+            cookie_chan.send(SetCookies(url.clone(), ~[~"a=1", ~"b=2; HttpOnly"], HTTP));
+            let mut metadata = Metadata::default(url);
+            metadata.status = Some(200);
+            progress_chan.send(Metadata(metadata));
+            progress_chan.send(Done(Ok(())));
+        };
+        loader
+    };
+    let resource_task = start_resource_manager(port,
+                                               chan,
+                                               ~[(~"http", loader_factory)],
+                                               None,
+                                               None);
+
+    let url = url::from_str(~"http://example.com/").get();
+    for 2.times {
+        let (progress_port, progress_chan) = comm::stream();
+        resource_task.send(Load(url.clone(), DocumentPriority, progress_chan));
+        progress_port.recv();
+        assert!(progress_port.recv() == Done(Ok(())));
+    }
+    assert!(headers_port.recv() == ~[]);
+    assert!(headers_port.recv() == ~[(~"Cookie", ~"a=1; b=2")]);
+
+    // Scripts don't see HttpOnly cookies.
+    let (cookie_port, cookie_chan) = comm::stream();
+    resource_task.send(GetCookies(url.clone(), NonHTTP, cookie_chan));
+    assert!(cookie_port.recv() == Some(~"a=1"));
+    resource_task.send(Exit);
+}
+
+#[test]
+fn should_abort_cancelled_loads_and_stop_their_loaders() {
+    let (stopped_port, stopped_chan) = comm::stream();
+    let stopped_chan = SharedChan::new(stopped_chan);
+    let loader_factory: LoaderTaskFactory = || {
+        let stopped_chan = stopped_chan.clone();
+        let loader: LoaderTask = |_url, _headers, progress_chan| {
+            let stopped_chan = stopped_chan.clone();
+            do task::spawn {
+                // Send data until nobody listens.
+                while progress_chan.try_send(Payload(~[0])) {
+                    task::yield();
+                }
+                stopped_chan.send(());
+            }
+        };
+        loader
+    };
+    let resource_task = create_resource_task_with_loaders(~[(~"endless", loader_factory)],
+                                                          None,
+                                                          None);
+
+    let (progress_port, progress_chan) = comm::stream();
+    let group = LoadGroup::new();
+    let url = url::from_str(~"endless://forever").get();
+    resource_task.send(CancelableLoad(url.clone(), DocumentPriority, progress_chan,
+                                      group.add_load()));
+    assert!(progress_port.recv() == Payload(~[0]));
+
+    group.cancel();
+    stopped_port.recv();
+    let mut msg = progress_port.recv();
+    while msg != Aborted {
+        assert!(msg == Payload(~[0]));
+        msg = progress_port.recv();
+    }
+
+    // Loads added to a cancelled group are aborted too.
+    let (progress_port, progress_chan) = comm::stream();
+    resource_task.send(CancelableLoad(url, DocumentPriority, progress_chan, group.add_load()));
+    stopped_port.recv();
+    let mut msg = progress_port.recv();
+    while msg != Aborted {
+        assert!(msg == Payload(~[0]));
+        msg = progress_port.recv();
+    }
+    resource_task.send(Exit);
+}
+
+#[test]
+fn should_limit_loads_per_host_and_start_urgent_ones_first() {
+    // The loader hands each load to the test, which finishes it when it likes.
+    let (started_port, started_chan) = comm::stream();
+    let started_chan = SharedChan::new(started_chan);
+    let loader_factory: LoaderTaskFactory = || {
+        let started_chan = started_chan.clone();
+        let loader: LoaderTask = |url, _headers, progress_chan| {
+            started_chan.send((to_str(&url), progress_chan));
+        };
+        loader
+    };
+    let resource_task = create_resource_task_with_loaders(~[(~"held", loader_factory)],
+                                                          None,
+                                                          None);
+    let mut progress_ports = ~[];
+    let load = |url: ~str, priority: LoadPriority| {
+        let (progress_port, progress_chan) = comm::stream();
+        progress_ports.push(progress_port);
+        resource_task.send(Load(url::from_str(url).get(), priority, progress_chan));
+    };
+
+    let mut running = ~[];
+    for uint::range(0, MAX_LOADS_PER_HOST) |i| {
+        load(fmt!("held://a/%u.png", i), PrefetchPriority);
+        running.push(started_port.recv());
+    }
+    load(~"held://a/late.png", PrefetchPriority);
+    load(~"held://a/style.css", StylesheetPriority);
+    load(~"held://b/other.png", PrefetchPriority);
+
+    // Other hosts aren't held up.
+    match started_port.recv() {
+        (url, _) => assert!(url == ~"held://b/other.png"),
+    }
+
+    // Finishing a load lets the most urgent queued one for the host start.
+    match running.pop() {
+        (_, progress_chan) => progress_chan.send(Done(Ok(()))),
+    }
+    match started_port.recv() {
+        (url, progress_chan) => {
+            assert!(url == ~"held://a/style.css");
+            progress_chan.send(Done(Ok(())));
+        }
+    }
+    match started_port.recv() {
+        (url, _) => assert!(url == ~"held://a/late.png"),
+    }
+    resource_task.send(Exit);
+}
+
+#[test]
+fn should_load_about_blank() {
+    let resource_task = ResourceTask();
+    let progress = Port();
+    resource_task.send(Load(url::from_str(~"about:blank").get(), DocumentPriority,
+                            progress.chan()));
+    match progress.recv() {
+      Metadata(metadata) => assert!(metadata.content_type == Some((~"text", ~"html"))),
+      _ => fail
+    }
+    assert!(progress.recv() == Payload(~[]));
+    assert!(progress.recv() == Done(Ok(())));
+    resource_task.send(Exit);
+}
+
+#[test]
+fn should_load_data_urls() {
+    let resource_task = ResourceTask();
+    let progress = Port();
+    resource_task.send(Load(url::from_str(~"data:,heya").get(), DocumentPriority, progress.chan()));
+    match progress.recv() {
+      Metadata(metadata) => assert!(metadata.content_type == Some((~"text", ~"plain"))),
+      _ => fail
+    }
+    assert!(progress.recv() == Payload(str::to_bytes("heya")));
+    assert!(progress.recv() == Done(Ok(())));
+    resource_task.send(Exit);
+}
+
+#[test]
+fn should_parse_content_type_and_charset() {
+    let mut metadata = Metadata::default(url::from_str(~"http://example.com/").get());
+    metadata.set_content_type("Text/HTML; charset=\"UTF-8\"");
+    assert!(metadata.content_type == Some((~"text", ~"html")));
+    assert!(metadata.charset == Some(~"utf-8"));
+}
+
+#[test]
+fn should_ignore_malformed_content_type() {
+    let mut metadata = Metadata::default(url::from_str(~"http://example.com/").get());
+    metadata.set_content_type("text");
+    assert!(metadata.content_type.is_none());
+    assert!(metadata.charset.is_none());
+}
+
+#[test]
+fn should_find_headers_case_insensitively() {
+    let mut metadata = Metadata::default(url::from_str(~"http://example.com/").get());
+    metadata.headers = ~[(~"Content-Length", ~"12")];
+    assert!(metadata.header("content-length") == Some(~"12"));
+    assert!(metadata.header("location").is_none());
+}
@@ -3,6 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use core::comm::{Chan, Port};
+use std::time;
 
 pub fn spawn_listener<A: Owned>(f: ~fn(Port<A>)) -> Chan<A> {
     let (setup_port, setup_chan) = comm::stream();
@@ -13,3 +14,28 @@ pub fn spawn_listener<A: Owned>(f: ~fn(Port<A>)) -> Chan<A> {
     }
     setup_port.recv()
 }
+
+/// Parses an HTTP date such as `Sun, 06 Nov 1994 08:49:37 GMT` into seconds since the epoch. The
+/// obsolete RFC 850 form and the dashed form common in cookies are accepted too.
+pub fn parse_http_date(value: &str) -> Option<i64> {
+    let formats = [
+        "%a, %d %b %Y %H:%M:%S GMT",
+        "%a, %d-%b-%Y %H:%M:%S GMT",
+        "%A, %d-%b-%y %H:%M:%S GMT",
+    ];
+    for formats.each |format| {
+        match time::strptime(value.trim(), *format) {
+            Ok(tm) => return Some(tm.to_timespec().sec),
+            Err(*) => {}
+        }
+    }
+    None
+}
+
+#[test]
+fn should_parse_http_dates() {
+    assert!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT") == Some(784111777));
+    assert!(parse_http_date("Sun, 06-Nov-1994 08:49:37 GMT") == Some(784111777));
+    assert!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT") == Some(784111777));
+    assert!(parse_http_date("yesterday") == None);
+}
@@ -2,11 +2,14 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use dom::bindings::utils::{DOMString, rust_box, squirrel_away, str};
+use dom::bindings::utils::{DOMString, rust_box, squirrel_away, str, unsquirrel};
 use dom::bindings::utils::{WrapperCache, DerivedWrapper};
 use dom::bindings::utils::{jsval_to_str, WrapNewBindingObject, CacheableWrapper};
+use dom::bindings::utils::domstring_to_jsval;
+use dom::bindings::eventtarget;
 use dom::bindings::utils;
 use dom::document::Document;
+use dom::eventtarget::DocumentTarget;
 use dom::htmlcollection::HTMLCollection;
 use js::glue::bindgen::*;
 use js::glue::{PROPERTY_STUB, STRICT_PROPERTY_STUB};
@@ -37,6 +40,51 @@ extern fn getDocumentElement(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> J
     }
 }
 
+extern fn getCookie(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::transmute(vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let doc = &mut (*unwrap(obj)).payload;
+        *vp = domstring_to_jsval(cx, &doc.getCookie());
+        return 1;
+    }
+}
+
+extern fn getCharacterSet(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::transmute(vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let doc = &mut (*unwrap(obj)).payload;
+        *vp = domstring_to_jsval(cx, &doc.getCharacterSet());
+        return 1;
+    }
+}
+
+extern fn setCookie(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::transmute(vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let argv = JS_ARGV(cx, cast::transmute(vp));
+        let strval = jsval_to_str(cx, (*argv.offset(0)));
+        if strval.is_err() {
+            return 0;
+        }
+
+        let doc = &mut (*unwrap(obj)).payload;
+        doc.setCookie(&str(strval.get()));
+        return 1;
+    }
+}
+
 extern fn getElementsByTagName(cx: *JSContext, _argc: c_uint, vp: *JSVal) -> JSBool {
     unsafe {
         let obj = JS_THIS_OBJECT(cx, vp);
@@ -72,6 +120,28 @@ unsafe fn unwrap(obj: *JSObject) -> *mut rust_box<Document> {
     RUST_JSVAL_TO_PRIVATE(val) as *mut rust_box<Document>
 }
 
+extern fn addEventListener(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+        let doc = unsquirrel(unwrap(obj) as *rust_box<Document>);
+        eventtarget::add_event_listener(cx, argc, vp, DocumentTarget(doc))
+    }
+}
+
+extern fn removeEventListener(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+        let doc = unsquirrel(unwrap(obj) as *rust_box<Document>);
+        eventtarget::remove_event_listener(cx, argc, vp, DocumentTarget(doc))
+    }
+}
+
 extern fn finalize(_fop: *JSFreeOp, obj: *JSObject) {
     debug!("document finalize!");
     unsafe {
@@ -90,6 +160,18 @@ pub fn init(compartment: @mut Compartment) {
          flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
          getter: JSPropertyOpWrapper {op: getDocumentElement, info: null()},
          setter: JSStrictPropertyOpWrapper {op: null(), info: null()}},
+        JSPropertySpec {
+         name: compartment.add_name(~"cookie"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: JSPropertyOpWrapper {op: getCookie, info: null()},
+         setter: JSStrictPropertyOpWrapper {op: setCookie, info: null()}},
+        JSPropertySpec {
+         name: compartment.add_name(~"characterSet"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: JSPropertyOpWrapper {op: getCharacterSet, info: null()},
+         setter: JSStrictPropertyOpWrapper {op: null(), info: null()}},
         JSPropertySpec {
          name: null(),
          tinyid: 0,
@@ -106,6 +188,16 @@ pub fn init(compartment: @mut Compartment) {
                                      nargs: 0,
                                      flags: 0,
                                      selfHostedName: null()},
+                     JSFunctionSpec {name: compartment.add_name(~"addEventListener"),
+                                     call: JSNativeWrapper {op: addEventListener, info: null()},
+                                     nargs: 3,
+                                     flags: 0,
+                                     selfHostedName: null()},
+                     JSFunctionSpec {name: compartment.add_name(~"removeEventListener"),
+                                     call: JSNativeWrapper {op: removeEventListener, info: null()},
+                                     nargs: 3,
+                                     flags: 0,
+                                     selfHostedName: null()},
                      JSFunctionSpec {name: null(),
                                      call: JSNativeWrapper {op: null(), info: null()},
                                      nargs: 0,
@@ -7,6 +7,7 @@ use dom::bindings::utils::jsval_to_str;
 use dom::bindings::utils::{domstring_to_jsval, WrapNewBindingObject};
 use dom::bindings::utils::{str, CacheableWrapper, DOM_OBJECT_SLOT, DOMString};
 use dom::element::*;
+use dom::eventtarget::NodeTarget;
 use dom::node::{AbstractNode, Element, ElementNodeTypeId, ScriptView};
 use layout_interface::{ContentBoxQuery, ContentBoxResponse};
 use script_task::task_from_context;
@@ -27,6 +28,8 @@ extern fn finalize(_fop: *JSFreeOp, obj: *JSObject) {
     debug!("element finalize: %x!", obj as uint);
     unsafe {
         let node: AbstractNode<ScriptView> = unwrap(obj);
+        // The node's listeners die with it, so their functions mustn't stay rooted.
+        NodeTarget(node).remove_all_listeners();
         //XXXjdm We need separate finalizers for each specialty element type like headings
         let _elem: ~Element = cast::transmute(node.raw_object());
     }
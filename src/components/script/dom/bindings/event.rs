@@ -4,7 +4,8 @@
 
 use dom::bindings::codegen::EventBinding;
 use dom::bindings::utils::{CacheableWrapper, WrapperCache, BindingObject, DerivedWrapper};
-use dom::event::Event_;
+use dom::event::{Event_, KeyboardEventTypeId, PlainEventTypeId};
+use dom::keyboardevent::KeyboardEvent;
 use script_task::{task_from_context, global_script_context};
 
 use js::glue::bindgen::RUST_OBJECT_TO_JSVAL;
@@ -27,8 +28,16 @@ impl CacheableWrapper for Event_ {
     }
 
     fn wrap_object_shared(@mut self, cx: *JSContext, scope: *JSObject) -> *JSObject {
-        let mut unused = false;
-        EventBinding::Wrap(cx, scope, self, &mut unused)
+        match self.type_id {
+            PlainEventTypeId => {
+                let mut unused = false;
+                EventBinding::Wrap(cx, scope, self, &mut unused)
+            }
+            KeyboardEventTypeId => {
+                let event: @mut KeyboardEvent = unsafe { cast::transmute(self) };
+                event.wrap_object_shared(cx, scope)
+            }
+        }
     }
 }
 
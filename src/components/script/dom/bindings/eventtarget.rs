@@ -4,11 +4,61 @@
 
 use dom::bindings::codegen::EventTargetBinding;
 use dom::bindings::utils::{CacheableWrapper, WrapperCache, BindingObject, DerivedWrapper};
-use dom::eventtarget::EventTarget;
+use dom::bindings::utils::jsval_to_str;
+use dom::eventtarget::{AbstractEventTarget, EventTarget};
 use script_task::{task_from_context, global_script_context};
 
-use js::glue::bindgen::RUST_OBJECT_TO_JSVAL;
-use js::jsapi::{JSObject, JSContext, JSVal};
+use core::libc::c_uint;
+use js::glue::bindgen::{RUST_JSVAL_IS_VOID, RUST_OBJECT_TO_JSVAL};
+use js::jsapi::{JSObject, JSContext, JSVal, JSBool};
+use js::{JS_ARGV, JS_SET_RVAL, JSVAL_NULL, JSVAL_TRUE};
+
+/// Implements `addEventListener(type, listener, capture)` on `target`, for the natives of the
+/// node, document and window prototypes.
+pub unsafe fn add_event_listener(cx: *JSContext, argc: c_uint, vp: *JSVal,
+                                 target: AbstractEventTarget) -> JSBool {
+    match listener_args(cx, argc, vp) {
+        Ok((type_, Some(funval), capture)) => target.add_script_listener(type_, funval, capture),
+        Ok((_, None, _)) => {}
+        Err(()) => return 0,
+    }
+    JS_SET_RVAL(cx, vp, JSVAL_NULL);
+    return 1;
+}
+
+/// Implements `removeEventListener(type, listener, capture)` on `target`.
+pub unsafe fn remove_event_listener(cx: *JSContext, argc: c_uint, vp: *JSVal,
+                                    target: AbstractEventTarget) -> JSBool {
+    match listener_args(cx, argc, vp) {
+        Ok((type_, Some(funval), capture)) => target.remove_event_listener(type_, funval, capture),
+        Ok((_, None, _)) => {}
+        Err(()) => return 0,
+    }
+    JS_SET_RVAL(cx, vp, JSVAL_NULL);
+    return 1;
+}
+
+/// Reads the type, the listener function, which is None if script passed null, and whether to
+/// capture, which defaults to false.
+unsafe fn listener_args(cx: *JSContext, argc: c_uint, vp: *JSVal)
+                        -> Result<(~str, Option<JSVal>, bool), ()> {
+    if argc < 2 {
+        return Err(())
+    }
+    let argv = JS_ARGV(cx, vp);
+    let type_ = jsval_to_str(cx, *argv.offset(0));
+    if type_.is_err() {
+        return Err(())
+    }
+    let funval = *argv.offset(1);
+    let funval = if funval == JSVAL_NULL || RUST_JSVAL_IS_VOID(funval) == 1 {
+        None
+    } else {
+        Some(funval)
+    };
+    let capture = argc > 2 && *argv.offset(2) == JSVAL_TRUE;
+    Ok((type_.get(), funval, capture))
+}
 
 pub impl EventTarget {
     pub fn init_wrapper(@mut self) {
@@ -0,0 +1,47 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use dom::bindings::codegen::KeyboardEventBinding;
+use dom::bindings::utils::{CacheableWrapper, WrapperCache, BindingObject, DerivedWrapper};
+use dom::keyboardevent::KeyboardEvent;
+use script_task::task_from_context;
+
+use js::glue::bindgen::RUST_OBJECT_TO_JSVAL;
+use js::jsapi::{JSObject, JSContext, JSVal};
+
+impl CacheableWrapper for KeyboardEvent {
+    fn get_wrappercache(&mut self) -> &mut WrapperCache {
+        unsafe { cast::transmute(&self.parent.wrapper) }
+    }
+
+    fn wrap_object_shared(@mut self, cx: *JSContext, scope: *JSObject) -> *JSObject {
+        let mut unused = false;
+        KeyboardEventBinding::Wrap(cx, scope, self, &mut unused)
+    }
+}
+
+impl BindingObject for KeyboardEvent {
+    fn GetParentObject(&self, cx: *JSContext) -> @mut CacheableWrapper {
+        let script_context = task_from_context(cx);
+        unsafe {
+            (*script_context).root_frame.get_ref().window as @mut CacheableWrapper
+        }
+    }
+}
+
+impl DerivedWrapper for KeyboardEvent {
+    fn wrap(&mut self, _cx: *JSContext, _scope: *JSObject, _vp: *mut JSVal) -> i32 {
+        fail!(~"nyi")
+    }
+
+    fn wrap_shared(@mut self, cx: *JSContext, scope: *JSObject, vp: *mut JSVal) -> i32 {
+        let obj = self.wrap_object_shared(cx, scope);
+        if obj.is_null() {
+            return 0;
+        } else {
+            unsafe { *vp = RUST_OBJECT_TO_JSVAL(obj) };
+            return 1;
+        }
+    }
+}
@@ -3,17 +3,20 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use dom::bindings::element;
+use dom::bindings::eventtarget;
 use dom::bindings::text;
 use dom::bindings::utils;
 use dom::bindings::utils::{CacheableWrapper, WrapperCache, DerivedWrapper};
 use dom::node::{AbstractNode, Node, ElementNodeTypeId, TextNodeTypeId, CommentNodeTypeId};
+use dom::eventtarget::NodeTarget;
 use dom::node::{DoctypeNodeTypeId, ScriptView};
 
 use core::libc::c_uint;
 use core::ptr::null;
 use js::jsapi::bindgen::*;
 use js::jsapi::{JSContext, JSVal, JSObject, JSBool, JSPropertySpec};
-use js::jsapi::{JSPropertyOpWrapper, JSStrictPropertyOpWrapper};
+use js::jsapi::{JSPropertyOpWrapper, JSStrictPropertyOpWrapper, JSFunctionSpec};
+use js::jsapi::{JSNativeWrapper};
 use js::jsval::{INT_TO_JSVAL};
 use js::rust::{Compartment, jsobj};
 use js::{JSPROP_ENUMERATE, JSPROP_SHARED, JSVAL_NULL};
@@ -55,6 +58,25 @@ pub fn init(compartment: @mut Compartment) {
     vec::as_imm_buf(*attrs, |specs, _len| {
         JS_DefineProperties(compartment.cx.ptr, obj.ptr, specs);
     });
+
+    let methods = @~[JSFunctionSpec {name: compartment.add_name(~"addEventListener"),
+                                     call: JSNativeWrapper {op: addEventListener, info: null()},
+                                     nargs: 3,
+                                     flags: 0,
+                                     selfHostedName: null()},
+                     JSFunctionSpec {name: compartment.add_name(~"removeEventListener"),
+                                     call: JSNativeWrapper {op: removeEventListener, info: null()},
+                                     nargs: 3,
+                                     flags: 0,
+                                     selfHostedName: null()},
+                     JSFunctionSpec {name: null(),
+                                     call: JSNativeWrapper {op: null(), info: null()},
+                                     nargs: 0,
+                                     flags: 0,
+                                     selfHostedName: null()}];
+    vec::as_imm_buf(*methods, |fns, _len| {
+        JS_DefineFunctions(compartment.cx.ptr, obj.ptr, fns);
+    });
 }
 
 #[allow(non_implicitly_copyable_typarams)]
@@ -72,6 +94,26 @@ pub unsafe fn unwrap(obj: *JSObject) -> AbstractNode<ScriptView> {
     AbstractNode::from_raw(raw)
 }
 
+extern fn addEventListener(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+        eventtarget::add_event_listener(cx, argc, vp, NodeTarget(unwrap(obj)))
+    }
+}
+
+extern fn removeEventListener(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+        eventtarget::remove_event_listener(cx, argc, vp, NodeTarget(unwrap(obj)))
+    }
+}
+
 #[allow(non_implicitly_copyable_typarams)]
 extern fn getFirstChild(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
     unsafe {
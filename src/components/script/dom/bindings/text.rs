@@ -6,6 +6,7 @@ use dom::bindings::element;
 use dom::bindings::node::unwrap;
 use dom::bindings::utils;
 use dom::bindings::utils::{DOM_OBJECT_SLOT, CacheableWrapper};
+use dom::eventtarget::NodeTarget;
 use dom::node::{AbstractNode, Text, Comment, Doctype, TextNodeTypeId, CommentNodeTypeId};
 use dom::node::{DoctypeNodeTypeId, ScriptView};
 
@@ -18,6 +19,7 @@ extern fn finalize_text(_fop: *JSFreeOp, obj: *JSObject) {
     debug!("text finalize: %?!", obj as uint);
     unsafe {
         let node: AbstractNode<ScriptView> = unwrap(obj);
+        NodeTarget(node).remove_all_listeners();
         let _elem: ~Text = cast::transmute(node.raw_object());
     }
 }
@@ -26,6 +28,7 @@ extern fn finalize_comment(_fop: *JSFreeOp, obj: *JSObject) {
     debug!("comment finalize: %?!", obj as uint);
     unsafe {
         let node: AbstractNode<ScriptView> = unwrap(obj);
+        NodeTarget(node).remove_all_listeners();
         let _elem: ~Comment = cast::transmute(node.raw_object());
     }
 }
@@ -34,6 +37,7 @@ extern fn finalize_doctype(_fop: *JSFreeOp, obj: *JSObject) {
     debug!("doctype finalize: %?!", obj as uint);
     unsafe {
         let node: AbstractNode<ScriptView> = unwrap(obj);
+        NodeTarget(node).remove_all_listeners();
         let _elem: ~Doctype<ScriptView> = cast::transmute(node.raw_object());
     }
 }
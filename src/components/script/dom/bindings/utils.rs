@@ -95,6 +95,13 @@ pub impl DOMString {
           null_string => ~""
         }
     }
+
+    fn len(&self) -> uint {
+        match *self {
+          str(ref s) => s.len(),
+          null_string => 0
+        }
+    }
 }
 
 pub struct rust_box<T> {
@@ -127,6 +134,12 @@ pub unsafe fn squirrel_away<T>(x: @mut T) -> *rust_box<T> {
     y
 }
 
+/// Returns a new reference to a box put away by `squirrel_away`, which keeps its own.
+pub unsafe fn unsquirrel<T>(x: *rust_box<T>) -> @mut T {
+    let borrowed: &@mut T = cast::transmute(&x);
+    *borrowed
+}
+
 //XXX very incomplete
 pub fn jsval_to_str(cx: *JSContext, v: JSVal) -> Result<~str, ()> {
     let jsstr;
@@ -4,8 +4,10 @@
 
 // DOM bindings for the Window object.
 
-use dom::bindings::utils::{rust_box, squirrel_away, CacheableWrapper};
+use dom::bindings::eventtarget;
+use dom::bindings::utils::{rust_box, squirrel_away, unsquirrel, CacheableWrapper};
 use dom::bindings::utils::{WrapperCache};
+use dom::eventtarget::WindowTarget;
 use dom::window::Window;
 use super::utils;
 
@@ -66,6 +68,28 @@ extern fn close(cx: *JSContext, _argc: c_uint, vp: *JSVal) -> JSBool {
     }
 }
 
+extern fn addEventListener(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+        let window = unsquirrel(unwrap(obj));
+        eventtarget::add_event_listener(cx, argc, vp, WindowTarget(window))
+    }
+}
+
+extern fn removeEventListener(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+        let window = unsquirrel(unwrap(obj));
+        eventtarget::remove_event_listener(cx, argc, vp, WindowTarget(window))
+    }
+}
+
 extern fn gc(cx: *JSContext, _argc: c_uint, _vp: *JSVal) -> JSBool {
     let runtime = JS_GetRuntime(cx);
     JS_GC(runtime);
@@ -112,6 +136,20 @@ pub fn init(compartment: @mut Compartment) {
             flags: 0,
             selfHostedName: null()
         },
+        JSFunctionSpec {
+            name: compartment.add_name(~"addEventListener"),
+            call: JSNativeWrapper { op: addEventListener, info: null() },
+            nargs: 3,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: compartment.add_name(~"removeEventListener"),
+            call: JSNativeWrapper { op: removeEventListener, info: null() },
+            nargs: 3,
+            flags: 0,
+            selfHostedName: null()
+        },
         JSFunctionSpec {
             name: compartment.add_name(~"_trigger_gc"),
             call: JSNativeWrapper { op: gc, info: null() },
@@ -3,26 +3,48 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use dom::bindings::document;
-use dom::bindings::utils::{DOMString, WrapperCache};
+use dom::bindings::utils::{DOMString, WrapperCache, str};
+use dom::eventtarget::{DocumentTarget, EventListener, NodeTarget, WindowTarget};
 use dom::htmlcollection::HTMLCollection;
 use dom::node::{AbstractNode, ScriptView};
 use dom::window::Window;
 use script_task::global_script_context;
 
 use js::jsapi::bindgen::{JS_AddObjectRoot, JS_RemoveObjectRoot};
+use servo_net::cookie::NonHTTP;
+use servo_net::resource_task::{GetCookies, SetCookies};
 use servo_util::tree::{TreeNodeRef, TreeUtils};
+use std::net::url::Url;
 
 pub struct Document {
     root: AbstractNode<ScriptView>,
     wrapper: WrapperCache,
     window: Option<@mut Window>,
+    /// The name of the encoding the document was decoded from.
+    character_set: ~str,
+    /// The event listeners registered on the document, in the order they were added.
+    event_listeners: ~[EventListener],
+    /// The element key events are dispatched at, if any.
+    focused: Option<AbstractNode<ScriptView>>,
+    /// The innermost element the pointer is over, if any.
+    hovered: Option<AbstractNode<ScriptView>>,
+    /// Whether any of the document's style sheets may have `:hover` rules.
+    hover_rules: bool,
 }
 
-pub fn Document(root: AbstractNode<ScriptView>, window: Option<@mut Window>) -> @mut Document {
+pub fn Document(root: AbstractNode<ScriptView>,
+                window: Option<@mut Window>,
+                character_set: ~str)
+             -> @mut Document {
     let doc = @mut Document {
         root: root,
         wrapper: WrapperCache::new(),
-        window: window
+        window: window,
+        character_set: character_set,
+        event_listeners: ~[],
+        focused: None,
+        hovered: None,
+        hover_rules: false,
     };
     let compartment = global_script_context().js_compartment;
     do root.with_base |base| {
@@ -50,13 +72,61 @@ pub impl Document {
         Some(HTMLCollection::new(elements))
     }
 
+    /// Returns the cookies visible to scripts for the document's URL, as `name=value` pairs
+    /// separated by `; `.
+    fn getCookie(&self) -> DOMString {
+        let url = match self.url() {
+            Some(url) => url,
+            None => return str(~""),
+        };
+        let (port, chan) = comm::stream();
+        global_script_context().resource_task.send(GetCookies(url, NonHTTP, chan));
+        match port.recv() {
+            Some(cookies) => str(cookies),
+            None => str(~""),
+        }
+    }
+
+    fn getCharacterSet(&self) -> DOMString {
+        str(copy self.character_set)
+    }
+
+    /// Sets a single cookie for the document's URL, given in the syntax of a `Set-Cookie` header.
+    fn setCookie(&self, cookie: &DOMString) {
+        match self.url() {
+            Some(url) => {
+                let script_context = global_script_context();
+                script_context.resource_task.send(SetCookies(url, ~[cookie.to_str()], NonHTTP));
+            }
+            None => {}
+        }
+    }
+
+    /// The URL the document was loaded from.
+    fn url(&self) -> Option<Url> {
+        match global_script_context().root_frame {
+            Some(ref frame) => Some(frame.url.clone()),
+            None => None,
+        }
+    }
+
     fn content_changed(&self) {
         for self.window.each |window| {
             window.content_changed()
         }
     }
 
-    fn teardown(&self) {
+    /// Unroots the document's root wrapper and the script functions listening for events on the
+    /// document, its window and its nodes, once the document is going away.
+    fn teardown(@mut self) {
+        let _ = for self.root.traverse_preorder |node| {
+            NodeTarget(node).remove_all_listeners();
+        };
+        DocumentTarget(self).remove_all_listeners();
+        for self.window.each |&window| {
+            WindowTarget(window).remove_all_listeners();
+        }
+
         let compartment = global_script_context().js_compartment;
         do self.root.with_base |node| {
             assert!(node.wrapper.get_wrapper().is_not_null());
@@ -44,7 +44,7 @@ impl DOMParser {
             };
 
             let root = Node::as_abstract_node(root);
-            Document(root, None)
+            Document(root, None, ~"UTF-8")
         }
     }
 }
@@ -7,6 +7,7 @@
 use dom::bindings::utils::DOMString;
 use dom::clientrect::ClientRect;
 use dom::clientrectlist::ClientRectList;
+use dom::eventtarget::{event_handler_type, set_event_handler_attribute};
 use dom::node::{ElementNodeTypeId, Node, ScriptView};
 use layout_interface::{ContentBoxQuery, ContentBoxResponse, ContentBoxesQuery};
 use layout_interface::{ContentBoxesResponse};
@@ -137,6 +138,9 @@ pub impl<'self> Element {
     fn set_attr(&mut self, name: &DOMString, value: &DOMString) {
         let name = name.to_str();
         let value = value.to_str();
+        for event_handler_type(name).each |&type_| {
+            set_event_handler_attribute(&mut self.parent.event_listeners, type_, value);
+        }
         // FIXME: We need a better each_mut in Rust; this is ugly.
         let value_cell = Cell(value);
         let mut found = false;
@@ -2,44 +2,186 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use dom::eventtarget::EventTarget;
+use dom::eventtarget::{AbstractEventTarget, EventTarget, NodeTarget};
+use dom::node::{AbstractNode, ScriptView};
 use dom::window::Window;
 use dom::bindings::codegen::EventBinding;
 use dom::bindings::utils::{DOMString, ErrorResult, WrapperCache};
 
 use geom::point::Point2D;
 
+#[cfg(test)]
+use dom::bindings::utils::str;
+#[cfg(test)]
+use dom::eventdispatcher::dispatch_event;
+#[cfg(test)]
+use dom::eventtarget::{EventListener, NativeListener};
+#[cfg(test)]
+use dom::node::test_element;
+#[cfg(test)]
+use core::cast::transmute;
+#[cfg(test)]
+use js::jsapi::JSObject;
+#[cfg(test)]
+use servo_util::tree::TreeUtils;
+
 pub enum Event {
     ResizeEvent(uint, uint), 
     ReflowEvent,
     ClickEvent(uint, Point2D<f32>),
     MouseDownEvent(uint, Point2D<f32>),
     MouseUpEvent(uint, Point2D<f32>),
+    MouseMoveEvent(Point2D<f32>),
+    KeyDownEvent(Key, KeyModifiers),
+    KeyUpEvent(Key, KeyModifiers),
+    /// A key press that produced a character.
+    KeyPressEvent(char, KeyModifiers),
+}
+
+/// A key on the keyboard, as the windowing system reports it.
+#[deriving(Eq)]
+pub enum Key {
+    /// A key that produces a character, with the shift key applied.
+    CharacterKey(char),
+    BackspaceKey,
+    TabKey,
+    EnterKey,
+    EscapeKey,
+    DeleteKey,
+    InsertKey,
+    HomeKey,
+    EndKey,
+    PageUpKey,
+    PageDownKey,
+    ArrowLeftKey,
+    ArrowUpKey,
+    ArrowRightKey,
+    ArrowDownKey,
+    /// One of F1 to F12.
+    FunctionKey(uint),
+}
+
+impl Key {
+    /// The value of the `key` attribute of keyboard events for this key.
+    pub fn key_value(&self) -> ~str {
+        match *self {
+            CharacterKey(c) => str::from_char(c),
+            BackspaceKey => ~"Backspace",
+            TabKey => ~"Tab",
+            EnterKey => ~"Enter",
+            EscapeKey => ~"Escape",
+            DeleteKey => ~"Delete",
+            InsertKey => ~"Insert",
+            HomeKey => ~"Home",
+            EndKey => ~"End",
+            PageUpKey => ~"PageUp",
+            PageDownKey => ~"PageDown",
+            ArrowLeftKey => ~"ArrowLeft",
+            ArrowUpKey => ~"ArrowUp",
+            ArrowRightKey => ~"ArrowRight",
+            ArrowDownKey => ~"ArrowDown",
+            FunctionKey(n) => fmt!("F%u", n),
+        }
+    }
+
+    /// The legacy `keyCode` of this key, which is 0 for characters without one.
+    pub fn key_code(&self) -> u32 {
+        match *self {
+            CharacterKey(c) if c >= 'a' && c <= 'z' => c as u32 - 32,
+            CharacterKey(c) if (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == ' ' => {
+                c as u32
+            }
+            CharacterKey(_) => 0,
+            BackspaceKey => 8,
+            TabKey => 9,
+            EnterKey => 13,
+            EscapeKey => 27,
+            PageUpKey => 33,
+            PageDownKey => 34,
+            EndKey => 35,
+            HomeKey => 36,
+            ArrowLeftKey => 37,
+            ArrowUpKey => 38,
+            ArrowRightKey => 39,
+            ArrowDownKey => 40,
+            InsertKey => 45,
+            DeleteKey => 46,
+            FunctionKey(n) => 111 + n as u32,
+        }
+    }
+}
+
+/// The modifier keys that were held down during a key event.
+#[deriving(Eq)]
+pub struct KeyModifiers {
+    shift: bool,
+    ctrl: bool,
+    alt: bool,
+    meta: bool,
+}
+
+/// The kinds of `Event_`. Events of other kinds start with an `Event_`, like elements start
+/// with a `Node`, and can be transmuted to their own type after checking this.
+#[deriving(Eq)]
+pub enum EventTypeId {
+    PlainEventTypeId,
+    KeyboardEventTypeId,
+}
+
+/// The phases of dispatching an event, as numbered by the `Event` interface's constants.
+#[deriving(Eq)]
+pub enum EventPhase {
+    PhaseNone = 0,
+    PhaseCapturing = 1,
+    PhaseAtTarget = 2,
+    PhaseBubbling = 3,
 }
 
 pub struct Event_ {
+    type_id: EventTypeId,
     wrapper: WrapperCache,
     type_: DOMString,
     default_prevented: bool,
     cancelable: bool,
     bubbles: bool,
     trusted: bool,
+
+    /// The phase of dispatch the event is in, if it is being dispatched.
+    phase: EventPhase,
+    /// The node the event was dispatched at.
+    target: Option<AbstractNode<ScriptView>>,
+    /// The node, document or window whose listeners are being invoked.
+    current_target: Option<AbstractEventTarget>,
+    /// Whether the event should not propagate past the current target.
+    stop_propagation: bool,
+    /// Whether no more listeners should be invoked at all, even on the current target.
+    stop_immediate_propagation: bool,
+    /// Whether the event is being dispatched.
+    dispatching: bool,
 }
 
 impl Event_ {
     pub fn new(type_: DOMString) -> Event_ {
         Event_ {
+            type_id: PlainEventTypeId,
             wrapper: WrapperCache::new(),
             type_: type_,
             default_prevented: false,
             cancelable: true,
             bubbles: true,
-            trusted: false
+            trusted: false,
+
+            phase: PhaseNone,
+            target: None,
+            current_target: None,
+            stop_propagation: false,
+            stop_immediate_propagation: false,
+            dispatching: false,
         }
     }
 
     pub fn EventPhase(&self) -> u16 {
-        0
+        self.phase as u16
     }
 
     pub fn Type(&self) -> DOMString {
@@ -47,11 +189,11 @@ impl Event_ {
     }
 
     pub fn GetTarget(&self) -> Option<@mut EventTarget> {
-        None
+        self.target.map(|&node| EventTarget::for_target(NodeTarget(node)))
     }
 
     pub fn GetCurrentTarget(&self) -> Option<@mut EventTarget> {
-        None
+        self.current_target.map(|&current_target| EventTarget::for_target(current_target))
     }
 
     pub fn DefaultPrevented(&self) -> bool {
@@ -59,13 +201,18 @@ impl Event_ {
     }
 
     pub fn PreventDefault(&mut self) {
-        self.default_prevented = true
+        if self.cancelable {
+            self.default_prevented = true
+        }
     }
 
     pub fn StopPropagation(&mut self) {
+        self.stop_propagation = true
     }
 
     pub fn StopImmediatePropagation(&mut self) {
+        self.stop_propagation = true;
+        self.stop_immediate_propagation = true
     }
 
     pub fn Bubbles(&self) -> bool {
@@ -85,6 +232,9 @@ impl Event_ {
                      bubbles: bool,
                      cancelable: bool,
                      _rv: &mut ErrorResult) {
+        if self.dispatching {
+            return
+        }
         self.type_ = type_;
         self.cancelable = cancelable;
         self.bubbles = bubbles;
@@ -101,3 +251,48 @@ impl Event_ {
         @mut Event_::new(type_)
     }
 }
+
+#[test]
+fn should_give_the_legacy_key_codes() {
+    assert!(CharacterKey('a').key_code() == 65);
+    assert!(CharacterKey('A').key_code() == 65);
+    assert!(CharacterKey('7').key_code() == 55);
+    assert!(CharacterKey(' ').key_code() == 32);
+    assert!(CharacterKey('!').key_code() == 0);
+    assert!(TabKey.key_code() == 9);
+    assert!(EnterKey.key_code() == 13);
+    assert!(ArrowDownKey.key_code() == 40);
+    assert!(FunctionKey(1).key_code() == 112);
+    assert!(FunctionKey(12).key_code() == 123);
+}
+
+#[test]
+fn should_give_the_target_and_current_target_while_dispatching() {
+    let (parent, child) = (test_element("div", []), test_element("div", []));
+    parent.add_child(child);
+
+    // The wrappers are never dereferenced, so any distinct addresses will do
+    let parent_wrapper: *JSObject = unsafe { transmute(1u) };
+    let child_wrapper: *JSObject = unsafe { transmute(2u) };
+    parent.with_mut_base(|base| base.wrapper.set_wrapper(parent_wrapper));
+    child.with_mut_base(|base| base.wrapper.set_wrapper(child_wrapper));
+
+    let seen = @mut ~[];
+    for [parent, child].each |&node| {
+        NodeTarget(node).add_event_listener(EventListener {
+            type_: ~"click",
+            capture: false,
+            callback: NativeListener(|event: @mut Event_| {
+                let target = event.GetTarget().get().wrapper.get_wrapper();
+                let current_target = event.GetCurrentTarget().get().wrapper.get_wrapper();
+                seen.push((target, current_target));
+            }),
+        });
+    }
+
+    let event = @mut Event_::new(str(~"click"));
+    dispatch_event(child, event);
+    assert!(*seen == ~[(child_wrapper, child_wrapper), (child_wrapper, parent_wrapper)]);
+    assert!(event.GetTarget().get().wrapper.get_wrapper() == child_wrapper);
+    assert!(event.GetCurrentTarget().is_none());
+}
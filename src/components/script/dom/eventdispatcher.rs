@@ -0,0 +1,133 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Dispatching events through the node tree, as described in the DOM standard: listeners on the
+//! target's ancestors are invoked from the window down in the capturing phase, then the target's
+//! own listeners, then the ancestors' again from the target up in the bubbling phase.
+
+use dom::event::{Event_, PhaseAtTarget, PhaseBubbling, PhaseCapturing, PhaseNone};
+use dom::eventtarget::{AbstractEventTarget, AttributeHandler, DocumentTarget, NativeListener};
+use dom::eventtarget::{NodeTarget, ScriptListener, WindowTarget};
+use dom::node::{AbstractNode, ScriptView};
+use script_task::global_script_context;
+
+use js::glue::bindgen::RUST_OBJECT_TO_JSVAL;
+use js::jsapi::JSVal;
+use js::jsapi::bindgen::{JS_CallFunctionValue, JS_ReportPendingException};
+use js::{JSVAL_FALSE, JSVAL_NULL};
+use servo_util::tree::TreeNodeRef;
+
+/// Dispatches `event` at `target`. Returns false if a listener prevented the default action.
+pub fn dispatch_event(target: AbstractNode<ScriptView>, event: @mut Event_) -> bool {
+    assert!(!event.dispatching);
+    event.dispatching = true;
+    event.target = Some(target);
+
+    // The target's ancestors, nearest first. The path is fixed before any listener runs, so
+    // moving nodes around in a listener doesn't change where the event goes.
+    let mut path = ~[];
+    let mut top = target;
+    let mut ancestor = target.parent_node();
+    while ancestor.is_some() {
+        let node = ancestor.get();
+        path.push(NodeTarget(node));
+        top = node;
+        ancestor = node.parent_node();
+    }
+
+    // Events at nodes in a document go on to the document and its window.
+    match top.with_base(|base| base.owner_doc) {
+        Some(document) if document.root == top => {
+            path.push(DocumentTarget(document));
+            match document.window {
+                Some(window) => path.push(WindowTarget(window)),
+                None => {}
+            }
+        }
+        _ => {}
+    }
+
+    event.phase = PhaseCapturing;
+    for path.each_reverse |&current_target| {
+        if event.stop_propagation {
+            break
+        }
+        invoke_listeners(current_target, event);
+    }
+
+    if !event.stop_propagation {
+        event.phase = PhaseAtTarget;
+        invoke_listeners(NodeTarget(target), event);
+    }
+
+    if event.bubbles {
+        event.phase = PhaseBubbling;
+        for path.each |&current_target| {
+            if event.stop_propagation {
+                break
+            }
+            invoke_listeners(current_target, event);
+        }
+    }
+
+    event.dispatching = false;
+    event.phase = PhaseNone;
+    event.current_target = None;
+    !event.default_prevented
+}
+
+/// Invokes the listeners on `current_target` for the event's type and phase, in the order they
+/// were added.
+fn invoke_listeners(current_target: AbstractEventTarget, event: @mut Event_) {
+    event.current_target = Some(current_target);
+    let type_ = event.type_.to_str();
+
+    // Listeners added by a listener aren't invoked until the next event.
+    let listeners = current_target.listeners();
+    for listeners.each |listener| {
+        if event.stop_immediate_propagation {
+            break
+        }
+        let wanted = match event.phase {
+            PhaseCapturing => listener.capture,
+            PhaseBubbling => !listener.capture,
+            PhaseAtTarget | PhaseNone => true,
+        };
+        if !wanted || listener.type_ != type_ {
+            loop
+        }
+        match listener.callback {
+            NativeListener(callback) => callback(event),
+            ScriptListener(root) => {
+                call_script_listener(*root, current_target, event);
+            }
+            AttributeHandler(root) => {
+                // A handler returning false prevents the default action, like `preventDefault`.
+                if call_script_listener(*root, current_target, event) == JSVAL_FALSE {
+                    event.PreventDefault()
+                }
+            }
+        }
+    }
+}
+
+/// Calls a script function with the wrapper of `current_target` as `this` and the event's
+/// wrapper as its argument, returning what the function returned. An exception thrown by the
+/// function is reported, and null returned.
+fn call_script_listener(funval: JSVal, current_target: AbstractEventTarget, event: @mut Event_)
+                        -> JSVal {
+    let cx = global_script_context().js_compartment.cx.ptr;
+    let this_obj = current_target.get_or_create_wrapper();
+    if event.wrapper.get_wrapper().is_null() {
+        event.init_wrapper();
+    }
+    let argv = [RUST_OBJECT_TO_JSVAL(event.wrapper.get_wrapper())];
+    let rval = JSVAL_NULL;
+    if JS_CallFunctionValue(cx, this_obj, funval, 1, vec::raw::to_ptr(argv), &rval) == 0 {
+        debug!("a listener for %s threw an exception", event.type_.to_str());
+        JS_ReportPendingException(cx);
+        return JSVAL_NULL
+    }
+    rval
+}
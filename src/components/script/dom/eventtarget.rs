@@ -2,7 +2,236 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use dom::bindings::node;
 use dom::bindings::utils::WrapperCache;
+use dom::document::Document;
+use dom::event::Event_;
+use dom::node::{AbstractNode, ScriptView};
+use dom::window::Window;
+use script_task::global_script_context;
+
+use core::libc::{c_uint, size_t};
+use core::ptr::to_unsafe_ptr;
+use core::str::eq_slice;
+use js::glue::bindgen::RUST_OBJECT_TO_JSVAL;
+use js::jsapi::{JSContext, JSObject, JSVal};
+use js::jsapi::bindgen::{JS_AddValueRoot, JS_CompileFunction, JS_GetFunctionObject};
+use js::jsapi::bindgen::JS_RemoveValueRoot;
+
+/// The event types that have an `on<type>` content attribute for setting a handler.
+static EVENT_HANDLER_TYPES: [&'static str, ..24] = [
+    "abort", "blur", "change", "click", "contextmenu", "dblclick", "error", "focus", "input",
+    "keydown", "keypress", "keyup", "load", "mousedown", "mousemove", "mouseout", "mouseover",
+    "mouseup", "reset", "resize", "scroll", "select", "submit", "unload",
+];
+
+/// What to call when an event is dispatched to a listener.
+pub enum EventListenerCallback {
+    /// A listener added by servo itself.
+    NativeListener(@fn(@mut Event_)),
+    /// A function added from script with `addEventListener`, rooted while it is registered.
+    ScriptListener(@mut JSVal),
+    /// The function compiled from an `on<type>` content attribute, rooted while it is set.
+    AttributeHandler(@mut JSVal),
+}
+
+/// A listener for events of one type, as registered on a node.
+pub struct EventListener {
+    type_: ~str,
+    /// Whether the listener is invoked in the capturing phase rather than the bubbling phase.
+    /// Listeners on the target itself are invoked either way.
+    capture: bool,
+    callback: EventListenerCallback,
+}
+
+/// Something events can be dispatched through: a node, a document or a window.
+pub enum AbstractEventTarget {
+    NodeTarget(AbstractNode<ScriptView>),
+    DocumentTarget(@mut Document),
+    WindowTarget(@mut Window),
+}
+
+impl AbstractEventTarget {
+    /// Calls `f` with the listeners registered on this target.
+    pub fn with_mut_listeners<R>(&self, f: &fn(&mut ~[EventListener]) -> R) -> R {
+        match *self {
+            NodeTarget(node) => node.with_mut_base(|base| f(&mut base.event_listeners)),
+            DocumentTarget(document) => f(&mut document.event_listeners),
+            WindowTarget(window) => f(&mut window.event_listeners),
+        }
+    }
+
+    /// Returns a copy of the listeners registered on this target.
+    pub fn listeners(&self) -> ~[EventListener] {
+        self.with_mut_listeners(|listeners| copy *listeners)
+    }
+
+    /// Adds a listener for events dispatched at or through this target.
+    pub fn add_event_listener(&self, listener: EventListener) {
+        do self.with_mut_listeners |listeners| {
+            listeners.push(copy listener)
+        }
+    }
+
+    /// Adds a listener for a script function, rooting the function while it is registered.
+    /// Adding the same function twice for the same type and phase has no effect.
+    pub fn add_script_listener(&self, type_: ~str, funval: JSVal, capture: bool) {
+        do self.with_mut_listeners |listeners| {
+            if !vec::any(*listeners, |other| is_script_listener(other, type_, funval, capture)) {
+                let cx = global_script_context().js_compartment.cx.ptr;
+                listeners.push(EventListener {
+                    type_: copy type_,
+                    capture: capture,
+                    callback: ScriptListener(root_function(cx, funval)),
+                })
+            }
+        }
+    }
+
+    /// Removes the listener added from script with the same arguments, if there is one.
+    pub fn remove_event_listener(&self, type_: &str, funval: JSVal, capture: bool) {
+        do self.with_mut_listeners |listeners| {
+            match vec::position(*listeners, |other| is_script_listener(other, type_, funval,
+                                                                       capture)) {
+                Some(index) => {
+                    let cx = global_script_context().js_compartment.cx.ptr;
+                    unroot_callback(cx, &listeners.remove(index).callback);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Unroots the script functions of all the listeners on this target, and removes them, for
+    /// when the target goes away.
+    pub fn remove_all_listeners(&self) {
+        do self.with_mut_listeners |listeners| {
+            let cx = global_script_context().js_compartment.cx.ptr;
+            for listeners.each |listener| {
+                unroot_callback(cx, &listener.callback);
+            }
+            *listeners = ~[];
+        }
+    }
+
+    /// Returns the JS object wrapping this target, which script listeners are called on, or
+    /// null for a node that hasn't been wrapped yet.
+    pub fn wrapper(&self) -> *JSObject {
+        match *self {
+            NodeTarget(node) => node.with_base(|base| base.wrapper.get_wrapper()),
+            DocumentTarget(document) => document.wrapper.get_wrapper(),
+            WindowTarget(window) => window.wrapper.get_wrapper(),
+        }
+    }
+
+    /// Returns the JS object wrapping this target, wrapping a node script hasn't seen yet.
+    pub fn get_or_create_wrapper(&self) -> *JSObject {
+        match *self {
+            // Nodes are only wrapped once script first sees them.
+            NodeTarget(node) if self.wrapper().is_null() => {
+                let cx = global_script_context().js_compartment.cx.ptr;
+                let mut node = node;
+                node::create(cx, &mut node).ptr
+            }
+            NodeTarget(*) | DocumentTarget(*) | WindowTarget(*) => self.wrapper(),
+        }
+    }
+}
+
+/// Returns the event type the content attribute `name` sets the handler for, if it is one of
+/// the `on<type>` event handler attributes.
+pub fn event_handler_type(name: &str) -> Option<&'static str> {
+    if !name.starts_with("on") {
+        return None
+    }
+    let type_ = name.slice(2, name.len());
+    vec::find(EVENT_HANDLER_TYPES, |&known| eq_slice(known, type_))
+}
+
+/// Compiles `source` as the handler for the `on<type_>` content attribute in `listeners`. A
+/// handler that is already set keeps its place among the other listeners. A handler that fails
+/// to compile is removed.
+pub fn set_event_handler_attribute(listeners: &mut ~[EventListener], type_: &str, source: &str) {
+    let script_context = global_script_context();
+    let cx = script_context.js_compartment.cx.ptr;
+    let scope = script_context.js_compartment.global_obj.ptr;
+
+    let existing = do vec::position(*listeners) |listener| {
+        match listener.callback {
+            AttributeHandler(_) => eq_slice(listener.type_, type_),
+            NativeListener(*) | ScriptListener(*) => false,
+        }
+    };
+    for existing.each |&index| {
+        unroot_callback(cx, &listeners[index].callback);
+    }
+
+    match (existing, compile_event_handler(cx, scope, type_, source)) {
+        (Some(index), Some(funval)) => {
+            listeners[index].callback = AttributeHandler(root_function(cx, funval))
+        }
+        (None, Some(funval)) => {
+            listeners.push(EventListener {
+                type_: type_.to_owned(),
+                capture: false,
+                callback: AttributeHandler(root_function(cx, funval)),
+            })
+        }
+        (Some(index), None) => {
+            debug!("failed to compile the on%s handler", type_);
+            listeners.remove(index);
+        }
+        (None, None) => debug!("failed to compile the on%s handler", type_),
+    }
+}
+
+/// Compiles the source of an `on<type_>` attribute into a function taking the event as `event`.
+// FIXME: The handler should also see the element, its form and the document in its scope chain.
+fn compile_event_handler(cx: *JSContext, scope: *JSObject, type_: &str, source: &str)
+                         -> Option<JSVal> {
+    let fun = do str::as_c_str(~"on" + type_) |name| {
+        do str::as_c_str("event") |argname| {
+            let argnames = [argname];
+            do str::as_c_str(source) |bytes| {
+                do str::as_c_str("event handler") |filename| {
+                    JS_CompileFunction(cx, scope, name, 1 as c_uint, vec::raw::to_ptr(argnames),
+                                       bytes, source.len() as size_t, filename, 1)
+                }
+            }
+        }
+    };
+    if fun.is_null() {
+        None
+    } else {
+        Some(RUST_OBJECT_TO_JSVAL(JS_GetFunctionObject(fun)))
+    }
+}
+
+/// Boxes a function so it has a fixed address, and roots it there so it isn't collected.
+fn root_function(cx: *JSContext, funval: JSVal) -> @mut JSVal {
+    let root = @mut funval;
+    JS_AddValueRoot(cx, to_unsafe_ptr(&*root));
+    root
+}
+
+fn unroot_callback(cx: *JSContext, callback: &EventListenerCallback) {
+    match *callback {
+        ScriptListener(root) | AttributeHandler(root) => {
+            JS_RemoveValueRoot(cx, to_unsafe_ptr(&*root));
+        }
+        NativeListener(*) => {}
+    }
+}
+
+fn is_script_listener(listener: &EventListener, type_: &str, funval: JSVal, capture: bool)
+                      -> bool {
+    match listener.callback {
+        ScriptListener(root) => {
+            *root == funval && listener.capture == capture && eq_slice(listener.type_, type_)
+        }
+        NativeListener(*) | AttributeHandler(*) => false,
+    }
+}
 
 pub struct EventTarget {
     wrapper: WrapperCache
@@ -14,4 +243,14 @@ pub impl EventTarget {
             wrapper: WrapperCache::new()
         }
     }
-}
\ No newline at end of file
+
+    /// Returns an `EventTarget` for the bindings to hand `target` to script as. It caches the
+    /// object already wrapping `target`, so script gets that object rather than a new one.
+    fn for_target(target: AbstractEventTarget) -> @mut EventTarget {
+        let mut wrapper = WrapperCache::new();
+        wrapper.set_wrapper(target.get_or_create_wrapper());
+        @mut EventTarget {
+            wrapper: wrapper
+        }
+    }
+}
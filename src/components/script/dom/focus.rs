@@ -0,0 +1,128 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Which elements can be focused, and the order the tab key moves the focus through them in.
+
+use dom::node::{AbstractNode, ScriptView};
+#[cfg(test)]
+use dom::node::test_element;
+
+use servo_util::tree::{TreeNodeRef, TreeUtils};
+use std::sort;
+
+/// Returns the tab index of an element: the value of its `tabindex` attribute, or 0 for
+/// elements that can be focused without one. Returns None if the node can't be focused, which
+/// disabled elements can't be even with a `tabindex`.
+pub fn tab_index(node: AbstractNode<ScriptView>) -> Option<int> {
+    if !node.is_element() {
+        return None
+    }
+    do node.with_imm_element |element| {
+        let explicit = match element.get_attr("tabindex") {
+            Some(value) => int::from_str(str::trim(value)),
+            None => None,
+        };
+        if element.get_attr("disabled").is_some() {
+            None
+        } else if explicit.is_some() {
+            explicit
+        } else {
+            match element.tag_name {
+                ~"a" if element.get_attr("href").is_some() => Some(0),
+                ~"button" | ~"input" | ~"select" | ~"textarea" => Some(0),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Returns the elements under `root` that the tab key moves the focus through, in order:
+/// elements with a positive tab index by index, then the others in tree order. Elements with a
+/// negative tab index can only be focused by clicking them.
+pub fn navigation_order(root: AbstractNode<ScriptView>) -> ~[AbstractNode<ScriptView>] {
+    let mut indexed = ~[];
+    let mut unindexed = ~[];
+    let _ = for root.traverse_preorder |node| {
+        match tab_index(node) {
+            Some(index) if index > 0 => indexed.push((index, node)),
+            Some(0) => unindexed.push(node),
+            Some(_) | None => {}
+        }
+    };
+    // Merge sort is stable, so elements with the same index stay in tree order.
+    let indexed = sort::merge_sort(indexed, |&(a, _), &(b, _)| a <= b);
+    let mut order = indexed.map(|&(_, node)| node);
+    order.push_all_move(unindexed);
+    order
+}
+
+/// Returns the element the tab key moves the focus to from `current`, or from outside the
+/// document if there is no focused element. Returns None at the end of the order, where the
+/// focus leaves the document.
+pub fn next_in_order(root: AbstractNode<ScriptView>,
+                     current: Option<AbstractNode<ScriptView>>,
+                     backwards: bool)
+                     -> Option<AbstractNode<ScriptView>> {
+    let order = navigation_order(root);
+    if order.is_empty() {
+        return None
+    }
+    let position = match current {
+        Some(current) => vec::position(order, |&node| node == current),
+        None => None,
+    };
+    match (position, backwards) {
+        (None, false) => Some(order[0]),
+        (None, true) => Some(order[order.len() - 1]),
+        (Some(0), true) => None,
+        (Some(index), true) => Some(order[index - 1]),
+        (Some(index), false) if index + 1 < order.len() => Some(order[index + 1]),
+        (Some(_), false) => None,
+    }
+}
+
+/// Builds an element with the given tag name and attributes, outside any document so that it
+/// doesn't need a script context.
+#[test]
+fn should_not_focus_disabled_elements() {
+    assert!(tab_index(test_element("input", [])) == Some(0));
+    assert!(tab_index(test_element("input", [("disabled", "")])).is_none());
+    assert!(tab_index(test_element("input", [("disabled", ""), ("tabindex", "1")])).is_none());
+    assert!(tab_index(test_element("div", [("tabindex", " -1 ")])) == Some(-1));
+    assert!(tab_index(test_element("a", [])).is_none());
+}
+
+#[test]
+fn should_order_positive_tab_indices_first() {
+    let root = test_element("div", []);
+    let link = test_element("a", [("href", "#")]);
+    let second = test_element("div", [("tabindex", "2")]);
+    let first = test_element("button", [("tabindex", "1")]);
+    let also_second = test_element("div", [("tabindex", "2")]);
+    let unfocusable = test_element("div", [("tabindex", "-1")]);
+    let input = test_element("input", []);
+    for [link, second, first, also_second, unfocusable, input].each |&child| {
+        root.add_child(child);
+    }
+    assert!(navigation_order(root) == ~[first, second, also_second, link, input]);
+}
+
+#[test]
+fn should_move_through_the_order_and_leave_at_either_end() {
+    let root = test_element("div", []);
+    let first = test_element("input", []);
+    let second = test_element("input", []);
+    root.add_child(first);
+    root.add_child(second);
+
+    assert!(next_in_order(root, None, false) == Some(first));
+    assert!(next_in_order(root, None, true) == Some(second));
+    assert!(next_in_order(root, Some(first), false) == Some(second));
+    assert!(next_in_order(root, Some(second), false).is_none());
+    assert!(next_in_order(root, Some(second), true) == Some(first));
+    assert!(next_in_order(root, Some(first), true).is_none());
+    // Focus on an element outside the order starts from the beginning.
+    assert!(next_in_order(root, Some(root), false) == Some(first));
+    assert!(next_in_order(test_element("div", []), None, false).is_none());
+}
@@ -0,0 +1,65 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use dom::bindings::utils::{DOMString, str};
+use dom::event::{Event_, Key, KeyModifiers, KeyboardEventTypeId};
+
+pub struct KeyboardEvent {
+    parent: Event_,
+    key: ~str,
+    key_code: u32,
+    /// The character a `keypress` event is for, and 0 for other events.
+    char_code: u32,
+    modifiers: KeyModifiers,
+}
+
+impl KeyboardEvent {
+    pub fn new(type_: DOMString, key: Key, char_code: u32, modifiers: KeyModifiers)
+               -> @mut KeyboardEvent {
+        let mut parent = Event_::new(type_);
+        parent.type_id = KeyboardEventTypeId;
+        @mut KeyboardEvent {
+            parent: parent,
+            key: key.key_value(),
+            key_code: key.key_code(),
+            char_code: char_code,
+            modifiers: modifiers,
+        }
+    }
+
+    /// Returns this event as the `Event_` it starts with, for dispatching.
+    pub fn as_event(@mut self) -> @mut Event_ {
+        unsafe {
+            cast::transmute(self)
+        }
+    }
+
+    pub fn Key(&self) -> DOMString {
+        str(copy self.key)
+    }
+
+    pub fn KeyCode(&self) -> u32 {
+        self.key_code
+    }
+
+    pub fn CharCode(&self) -> u32 {
+        self.char_code
+    }
+
+    pub fn ShiftKey(&self) -> bool {
+        self.modifiers.shift
+    }
+
+    pub fn CtrlKey(&self) -> bool {
+        self.modifiers.ctrl
+    }
+
+    pub fn AltKey(&self) -> bool {
+        self.modifiers.alt
+    }
+
+    pub fn MetaKey(&self) -> bool {
+        self.modifiers.meta
+    }
+}
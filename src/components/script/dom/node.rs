@@ -12,6 +12,9 @@ use dom::characterdata::CharacterData;
 use dom::document::Document;
 use dom::element::{Element, ElementTypeId, HTMLImageElement, HTMLImageElementTypeId};
 use dom::element::{HTMLStyleElementTypeId};
+#[cfg(test)]
+use dom::element::{Attr, HTMLDivElementTypeId};
+use dom::eventtarget::EventListener;
 use script_task::global_script_context;
 
 use core::cast::transmute;
@@ -81,6 +84,12 @@ pub struct Node<View> {
     /// The document that this node belongs to.
     owner_doc: Option<@mut Document>,
 
+    /// The event listeners registered on this node, in the order they were added.
+    event_listeners: ~[EventListener],
+
+    /// Whether the pointer is over this node or one of its descendants, for `:hover`.
+    hovered: bool,
+
     /// Layout information. Only the layout task may touch this data.
     priv layout_data: Option<@mut ()>
 }
@@ -234,6 +243,11 @@ impl<View> AbstractNode<View> {
         self.with_base(|b| b.type_id)
     }
 
+    /// Returns true if the pointer is over this node or one of its descendants.
+    pub fn is_hovered(self) -> bool {
+        self.with_base(|b| b.hovered)
+    }
+
     /// Returns the parent node of this node. Fails if this node is borrowed mutably.
     pub fn parent_node(self) -> Option<AbstractNode<View>> {
         self.with_base(|b| b.parent_node)
@@ -383,6 +397,27 @@ impl<View> AbstractNode<View> {
     }
 }
 
+impl AbstractNode<ScriptView> {
+    /// Removes this node from its parent's children. If the document's focused element is this
+    /// node or inside it, nothing is focused any more.
+    pub fn remove_from_parent(self) {
+        let parent = match self.parent_node() {
+            Some(parent) => parent,
+            None => return,
+        };
+        for self.with_base(|base| base.owner_doc).each |&document| {
+            let mut node = document.focused;
+            while node.is_some() && node != Some(self) {
+                node = node.get().parent_node();
+            }
+            if node.is_some() {
+                document.focused = None;
+            }
+        }
+        parent.remove_child(self);
+    }
+}
+
 impl Node<ScriptView> {
     pub unsafe fn as_abstract_node<N>(node: ~N) -> AbstractNode<ScriptView> {
         // This surrenders memory management of the node!
@@ -422,6 +457,10 @@ impl Node<ScriptView> {
 
             owner_doc: None,
 
+            event_listeners: ~[],
+
+            hovered: false,
+
             layout_data: None,
         }
     }
@@ -470,4 +509,18 @@ pub fn define_bindings(compartment: @mut Compartment) {
     assert!(codegen::EventTargetBinding::DefineDOMInterface(compartment.cx.ptr,
                                                             compartment.global_obj.ptr,
                                                             &mut unused));
+    assert!(codegen::KeyboardEventBinding::DefineDOMInterface(compartment.cx.ptr,
+                                                              compartment.global_obj.ptr,
+                                                              &mut unused));
+}
+
+/// Builds an element with the given attributes outside any document, so that it doesn't need a
+/// script context.
+#[cfg(test)]
+pub fn test_element(tag_name: &str, attrs: &[(&str, &str)]) -> AbstractNode<ScriptView> {
+    let mut element = ~Element::new(HTMLDivElementTypeId, tag_name.to_owned());
+    for attrs.each |&(name, value)| {
+        element.attrs.push(Attr::new(name.to_owned(), value.to_owned()));
+    }
+    unsafe { AbstractNode::from_raw(transmute(element)) }
 }
@@ -4,6 +4,7 @@
 
 use dom::bindings::utils::WrapperCache;
 use dom::bindings::window;
+use dom::eventtarget::EventListener;
 
 use layout_interface::ReflowForScriptQuery;
 use script_task::{ExitMsg, FireTimerMsg, ScriptChan, ScriptContext};
@@ -25,7 +26,9 @@ pub struct Window {
     timer_chan: Chan<TimerControlMsg>,
     script_chan: ScriptChan,
     script_context: *mut ScriptContext,
-    wrapper: WrapperCache
+    wrapper: WrapperCache,
+    /// The event listeners registered on the window, in the order they were added.
+    event_listeners: ~[EventListener],
 }
 
 impl Drop for Window {
@@ -108,6 +111,7 @@ pub impl Window {
                 timer_chan
             },
             script_context: script_context,
+            event_listeners: ~[],
         };
 
         unsafe {
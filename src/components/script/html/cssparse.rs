@@ -7,9 +7,15 @@
 use core::cell::Cell;
 use core::comm::Port;
 use core::str;
+use core::util::replace;
 use newcss::stylesheet::Stylesheet;
 use newcss::util::DataStream;
-use servo_net::resource_task::{ResourceTask, ProgressMsg, Load, Payload, Done};
+use servo_net::encoding::{CssSyntax, SniffingDecoder};
+use servo_net::mime_sniff;
+use servo_net::resource_task;
+use servo_net::resource_task::{Aborted, CancelableLoad, Done, LoadGroup, Metadata, Payload};
+use servo_net::resource_task::{ProgressMsg, ResourceTask, StylesheetPriority};
+use servo_util::url_parser;
 use std::net::url::Url;
 
 /// Where a style sheet comes from.
@@ -18,9 +24,24 @@ pub enum StylesheetProvenance {
     InlineProvenance(Url, ~str),
 }
 
+/// Resolves the `href` of a `<link rel="stylesheet">` against the URL of its document. Returns
+/// None if it isn't a valid URL.
+pub fn linked_stylesheet(href: &str, document_url: &Url) -> Option<StylesheetProvenance> {
+    match url_parser::parse(href, Some(document_url)) {
+        Ok(url) => Some(UrlProvenance(url)),
+        Err(error) => {
+            debug!("cssparse: ignoring style sheet at invalid URL %s: %?", href, error);
+            None
+        }
+    }
+}
+
+/// Parses a style sheet in a new task. A linked style sheet is loaded as part of `loads`. The
+/// style sheet is sent with whether it may have `:hover` rules.
 pub fn spawn_css_parser(provenance: StylesheetProvenance,
-                        resource_task: ResourceTask)
-                     -> Port<Stylesheet> {
+                        resource_task: ResourceTask,
+                        loads: LoadGroup)
+                     -> Port<(Stylesheet, bool)> {
     let (result_port, result_chan) = comm::stream();
 
     let provenance_cell = Cell(provenance);
@@ -32,20 +53,25 @@ pub fn spawn_css_parser(provenance: StylesheetProvenance,
             }
         };
 
-        let sheet = Stylesheet::new(url, data_stream(provenance_cell.take(),
-                                                     resource_task.clone()));
-        result_chan.send(sheet);
+        let mentions_hover = @mut false;
+        let stream = data_stream(provenance_cell.take(), resource_task.clone(), &loads);
+        let sheet = Stylesheet::new(url, noting_hover(stream, mentions_hover));
+        result_chan.send((sheet, *mentions_hover));
     }
 
     return result_port;
 }
 
-fn data_stream(provenance: StylesheetProvenance, resource_task: ResourceTask) -> DataStream {
+fn data_stream(provenance: StylesheetProvenance, resource_task: ResourceTask, loads: &LoadGroup)
+               -> DataStream {
     match provenance {
         UrlProvenance(url) => {
             debug!("cssparse: loading style sheet at %s", url.to_str());
             let (input_port, input_chan) = comm::stream();
-            resource_task.send(Load(url, input_chan));
+            resource_task.send(CancelableLoad(url,
+                                              StylesheetPriority,
+                                              input_chan,
+                                              loads.add_load()));
             resource_port_to_data_stream(input_port)
         }
         InlineProvenance(_, data) => {
@@ -54,11 +80,88 @@ fn data_stream(provenance: StylesheetProvenance, resource_task: ResourceTask) ->
     }
 }
 
+/// Passes a style sheet through, setting `found` if its text mentions `hover`, as it must for it
+/// to have `:hover` rules. The end of each chunk is kept to find the word across chunks.
+fn noting_hover(stream: DataStream, found: @mut bool) -> DataStream {
+    let word = str::to_bytes("hover");
+    let tail = @mut ~[];
+    return || {
+        let data = stream();
+        for data.each |data| {
+            if !*found {
+                let mut text = copy *tail;
+                text.push_all(*data);
+                let text = do text.map |&byte| {
+                    if byte >= 'A' as u8 && byte <= 'Z' as u8 { byte + 32 } else { byte }
+                };
+                let mut start = 0;
+                while !*found && start + word.len() <= text.len() {
+                    *found = text.slice(start, start + word.len()) == word.slice(0, word.len());
+                    start += 1;
+                }
+                *tail = text.slice(start, text.len()).to_owned();
+            }
+        }
+        data
+    }
+}
+
+/// Decodes a style sheet to UTF-8 as it arrives. The decoder is kept in a cell between calls, and
+/// is gone once the style sheet has ended. The metadata is kept until the first bytes arrive, to
+/// check that the resource really is a style sheet.
 fn resource_port_to_data_stream(input_port: Port<ProgressMsg>) -> DataStream {
+    let state_cell = Cell((SniffingDecoder::new(CssSyntax, None), None));
     return || {
-        match input_port.recv() {
-            Payload(data) => Some(data),
-            Done(*) => None
+        if state_cell.is_empty() {
+            return None
+        }
+        let (mut decoder, mut unsniffed_metadata) = state_cell.take();
+        loop {
+            match input_port.recv() {
+                Metadata(metadata) => {
+                    // Don't try to parse an error page as a style sheet.
+                    if !metadata.is_success() {
+                        debug!("cssparse: style sheet at %s failed with status %?",
+                               metadata.final_url.to_str(), metadata.status);
+                        return None
+                    }
+                    decoder = SniffingDecoder::new(CssSyntax, copy metadata.charset);
+                    unsniffed_metadata = Some(metadata);
+                }
+                Payload(data) => {
+                    match replace(&mut unsniffed_metadata, None) {
+                        Some(metadata) => {
+                            if !is_stylesheet(&metadata, data) {
+                                return None
+                            }
+                        }
+                        None => {}
+                    }
+                    let text = decoder.decode(data);
+                    if !text.is_empty() {
+                        state_cell.put_back((decoder, None));
+                        return Some(str::to_bytes(text))
+                    }
+                }
+                Done(*) => {
+                    let text = decoder.finish();
+                    return if text.is_empty() { None } else { Some(str::to_bytes(text)) }
+                }
+                Aborted => return None
+            }
+        }
+    }
+}
+
+/// Returns true if a resource should be parsed as a style sheet: it must be served as CSS, or
+/// served without a type and look like text.
+fn is_stylesheet(metadata: &resource_task::Metadata, start: &[u8]) -> bool {
+    match (mime_sniff::sniff(metadata, start), &metadata.content_type) {
+        ((~"text", ~"css"), _) | ((~"text", ~"plain"), &None) => true,
+        (mime, _) => {
+            debug!("cssparse: not parsing %s of type %? as a style sheet",
+                   metadata.final_url.to_str(), mime);
+            false
         }
     }
 }
@@ -3,20 +3,26 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use dom::element::*;
+use dom::eventtarget::{event_handler_type, set_event_handler_attribute};
 use dom::node::{AbstractNode, Comment, Doctype, Element, ElementNodeTypeId, Node, ScriptView};
 use dom::node::{Text};
-use html::cssparse::{InlineProvenance, StylesheetProvenance, UrlProvenance, spawn_css_parser};
+use html::cssparse::{InlineProvenance, StylesheetProvenance, linked_stylesheet};
+use html::cssparse::spawn_css_parser;
 use newcss::stylesheet::Stylesheet;
 
 use core::cell::Cell;
 use core::comm::{Chan, Port, SharedChan};
 use core::str::eq_slice;
+use core::util::replace;
 use hubbub::hubbub;
 use servo_net::image_cache_task::ImageCacheTask;
+use servo_net::encoding::{HtmlSyntax, SniffingDecoder};
 use servo_net::image_cache_task;
-use servo_net::resource_task::{Done, Load, Payload, ResourceTask};
+use servo_net::resource_task::{Aborted, CancelableLoad, Done, LoadGroup, Metadata, Payload};
+use servo_net::resource_task::{ProgressMsg, ResourceTask, ScriptPriority};
+use servo_net::resource_task;
 use servo_util::tree::TreeUtils;
-use servo_util::url::make_url;
+use servo_util::url_parser;
 use std::net::url::Url;
 use std::net::url;
 
@@ -50,8 +56,13 @@ enum JSMessage {
 
 struct HtmlParserResult {
     root: AbstractNode<ScriptView>,
-    style_port: Port<Option<Stylesheet>>,
+    /// The URL the document was finally loaded from, after any redirects.
+    url: Url,
+    /// The style sheets, in order, each with whether it may have `:hover` rules.
+    style_port: Port<Option<(Stylesheet, bool)>>,
     js_port: Port<JSResult>,
+    /// The name of the encoding the document was decoded from.
+    encoding: ~str,
 }
 
 trait NodeWrapping {
@@ -83,15 +94,16 @@ spawned, collates them, and sends them to the given result channel.
 * `from_parent` - A port on which to receive new links.
 
 */
-fn css_link_listener(to_parent: Chan<Option<Stylesheet>>,
+fn css_link_listener(to_parent: Chan<Option<(Stylesheet, bool)>>,
                      from_parent: Port<CSSMessage>,
-                     resource_task: ResourceTask) {
+                     resource_task: ResourceTask,
+                     loads: LoadGroup) {
     let mut result_vec = ~[];
 
     loop {
         match from_parent.recv() {
             CSSTaskNewFile(provenance) => {
-                result_vec.push(spawn_css_parser(provenance, resource_task.clone()));
+                result_vec.push(spawn_css_parser(provenance, resource_task.clone(), loads.clone()));
             }
             CSSTaskExit => {
                 break;
@@ -109,7 +121,8 @@ fn css_link_listener(to_parent: Chan<Option<Stylesheet>>,
 
 fn js_script_listener(to_parent: Chan<~[~[u8]]>,
                       from_parent: Port<JSMessage>,
-                      resource_task: ResourceTask) {
+                      resource_task: ResourceTask,
+                      loads: LoadGroup) {
     let mut result_vec = ~[];
 
     loop {
@@ -117,19 +130,33 @@ fn js_script_listener(to_parent: Chan<~[~[u8]]>,
             JSTaskNewFile(url) => {
                 let (result_port, result_chan) = comm::stream();
                 let resource_task = resource_task.clone();
+                let cancel_port = Cell(loads.add_load());
                 do task::spawn {
                     let (input_port, input_chan) = comm::stream();
                     // TODO: change copy to move once we can move into closures
-                    resource_task.send(Load(copy url, input_chan));
+                    resource_task.send(CancelableLoad(copy url,
+                                                      ScriptPriority,
+                                                      input_chan,
+                                                      cancel_port.take()));
 
                     let mut buf = ~[];
+                    let mut succeeded = true;
                     loop {
                         match input_port.recv() {
+                            Metadata(metadata) => {
+                                // Error pages are not scripts.
+                                if !metadata.is_success() {
+                                    error!("error loading script %s: status %?",
+                                           url.to_str(),
+                                           metadata.status);
+                                    succeeded = false;
+                                }
+                            }
                             Payload(data) => {
                                 buf += data;
                             }
                             Done(Ok(*)) => {
-                                result_chan.send(Some(buf));
+                                result_chan.send(if succeeded { Some(buf) } else { None });
                                 break;
                             }
                             Done(Err(*)) => {
@@ -137,6 +164,10 @@ fn js_script_listener(to_parent: Chan<~[~[u8]]>,
                                 result_chan.send(None);
                                 break;
                             }
+                            Aborted => {
+                                result_chan.send(None);
+                                break;
+                            }
                         }
                     }
                 }
@@ -203,41 +234,66 @@ fn build_element_from_tag(tag: &str) -> AbstractNode<ScriptView> {
     }
 }
 
+/// Parses a document as it loads. The caller has already received the document's metadata and
+/// possibly the message after it, which are passed in with the port the rest arrives on. The style
+/// sheets and scripts the document links to are loaded as part of `loads`, so cancelling it stops
+/// them all.
 #[allow(non_implicitly_copyable_typarams)]
-pub fn parse_html(url: Url,
+pub fn parse_html(metadata: resource_task::Metadata,
+                  pending_msg: Option<ProgressMsg>,
+                  input_port: Port<ProgressMsg>,
                   resource_task: ResourceTask,
-                  image_cache_task: ImageCacheTask) -> HtmlParserResult {
+                  image_cache_task: ImageCacheTask,
+                  loads: LoadGroup) -> HtmlParserResult {
     // Spawn a CSS parser to receive links to CSS style sheets.
     let resource_task2 = resource_task.clone();
+    let loads2 = loads.clone();
 
     let (stylesheet_port, stylesheet_chan) = comm::stream();
     let stylesheet_chan = Cell(stylesheet_chan);
     let (css_msg_port, css_msg_chan) = comm::stream();
     let css_msg_port = Cell(css_msg_port);
     do spawn {
-        css_link_listener(stylesheet_chan.take(), css_msg_port.take(), resource_task2.clone());
+        css_link_listener(stylesheet_chan.take(),
+                          css_msg_port.take(),
+                          resource_task2.clone(),
+                          loads2.clone());
     }
 
     let css_chan = SharedChan::new(css_msg_chan);
 
     // Spawn a JS parser to receive JavaScript.
     let resource_task2 = resource_task.clone();
+    let loads2 = loads.clone();
     let (js_result_port, js_result_chan) = comm::stream();
     let js_result_chan = Cell(js_result_chan);
     let (js_msg_port, js_msg_chan) = comm::stream();
     let js_msg_port = Cell(js_msg_port);
     do spawn {
-        js_script_listener(js_result_chan.take(), js_msg_port.take(), resource_task2.clone());
+        js_script_listener(js_result_chan.take(),
+                           js_msg_port.take(),
+                           resource_task2.clone(),
+                           loads2.clone());
     }
     let js_chan = SharedChan::new(js_msg_chan);
 
+    // Relative URLs in the document must be resolved against the URL it was finally loaded from,
+    // which differs from the requested one after a redirect. Error pages are still parsed and
+    // displayed, as other browsers do.
+    debug!("parsing document: status %?, content type %?",
+           metadata.status,
+           metadata.content_type);
+    let mut pending_msg = pending_msg;
+    let (url, charset) = (metadata.final_url, metadata.charset);
+
     let url2 = url.clone(), url3 = url.clone();
 
     // Build the root node.
     let root = ~HTMLHtmlElement { parent: Element::new(HTMLHtmlElementTypeId, ~"html") };
     let root = unsafe { Node::as_abstract_node(root) };
     debug!("created new node");
-    let mut parser = hubbub::Parser("UTF-8", false);
+    // The document is decoded before it reaches the parser, so its encoding is fixed.
+    let mut parser = hubbub::Parser("UTF-8", true);
     debug!("created parser");
     parser.set_document_node(root.to_hubbub_node());
     parser.enable_scripting(true);
@@ -289,6 +345,11 @@ pub fn parse_html(url: Url,
             do node.as_mut_element |element| {
                 for tag.attributes.each |attr| {
                     let &hubbub::Attribute {name: name, value: value, _} = attr;
+                    for event_handler_type(name).each |&type_| {
+                        set_event_handler_attribute(&mut element.parent.event_listeners,
+                                                    type_,
+                                                    value);
+                    }
                     element.attrs.push(Attr::new(name, value));
                 }
             }
@@ -302,8 +363,12 @@ pub fn parse_html(url: Url,
                             (Some(rel), Some(href)) => {
                                 if rel == ~"stylesheet" {
                                     debug!("found CSS stylesheet: %s", href);
-                                    let url = make_url(href.to_str(), Some(url2.clone()));
-                                    css_chan2.send(CSSTaskNewFile(UrlProvenance(url)));
+                                    match linked_stylesheet(href, &url2) {
+                                        Some(provenance) => {
+                                            css_chan2.send(CSSTaskNewFile(provenance));
+                                        }
+                                        None => {}
+                                    }
                                 }
                             }
                             _ => {}
@@ -316,12 +381,19 @@ pub fn parse_html(url: Url,
                         match src_opt {
                             None => {}
                             Some(src) => {
-                                let img_url = make_url(src, Some(url2.clone()));
-                                image_element.image = Some(copy img_url);
-                                // inform the image cache to load this, but don't store a handle.
-                                // TODO (Issue #84): don't prefetch if we are within a <noscript>
-                                // tag.
-                                image_cache_task.send(image_cache_task::Prefetch(img_url));
+                                match url_parser::parse(src, Some(&url2)) {
+                                    Ok(img_url) => {
+                                        image_element.image = Some(copy img_url);
+                                        // inform the image cache to load this, but don't store a
+                                        // handle.
+                                        // TODO (Issue #84): don't prefetch if we are within a
+                                        // <noscript> tag.
+                                        image_cache_task.send(image_cache_task::Prefetch(img_url));
+                                    }
+                                    Err(error) => {
+                                        debug!("ignoring image at invalid URL %s: %?", src, error);
+                                    }
+                                }
                             }
                         }
                     }
@@ -354,9 +426,13 @@ pub fn parse_html(url: Url,
             debug!("insert before");
             0u
         },
-        remove_child: |_parent, _child| {
-            debug!("remove child");
-            0u
+        remove_child: |_parent, child: hubbub::NodeDataPtr| {
+            unsafe {
+                debug!("remove child %x", cast::transmute(child));
+                let child: AbstractNode<ScriptView> = NodeWrapping::from_hubbub_node(child);
+                child.remove_from_parent();
+            }
+            child
         },
         clone_node: |_node, deep| {
             debug!("clone node");
@@ -399,8 +475,12 @@ pub fn parse_html(url: Url,
                         match script.get_attr(~"src") {
                             Some(src) => {
                                 debug!("found script: %s", src);
-                                let new_url = make_url(src.to_str(), Some(url.clone()));
-                                js_chan.send(JSTaskNewFile(new_url));
+                                match url_parser::parse(src, Some(&url)) {
+                                    Ok(new_url) => js_chan.send(JSTaskNewFile(new_url)),
+                                    Err(error) => {
+                                        debug!("ignoring script at invalid URL %s: %?", src, error);
+                                    }
+                                }
                             }
                             None => {}
                         }
@@ -413,16 +493,26 @@ pub fn parse_html(url: Url,
     });
     debug!("set tree handler");
 
-    let (input_port, input_chan) = comm::stream();
-    resource_task.send(Load(url.clone(), input_chan));
-    debug!("loaded page");
+    let mut decoder = SniffingDecoder::new(HtmlSyntax, charset);
     loop {
-        match input_port.recv() {
+        let msg = match replace(&mut pending_msg, None) {
+            Some(msg) => msg,
+            None => input_port.recv(),
+        };
+        match msg {
+            Metadata(*) => fail!(~"received metadata twice"),
             Payload(data) => {
                 debug!("received data");
-                parser.parse_chunk(data);
+                let text = decoder.decode(data);
+                if !text.is_empty() {
+                    parser.parse_chunk(str::to_bytes(text));
+                }
             }
-            Done(*) => {
+            Done(*) | Aborted => {
+                let text = decoder.finish();
+                if !text.is_empty() {
+                    parser.parse_chunk(str::to_bytes(text));
+                }
                 break;
             }
         }
@@ -433,8 +523,10 @@ pub fn parse_html(url: Url,
 
     HtmlParserResult {
         root: root,
+        url: url,
         style_port: stylesheet_port,
         js_port: js_result_port,
+        encoding: decoder.encoding().get(),
     }
 }
 
@@ -35,6 +35,14 @@ pub enum Msg {
     /// Routes a message (usually from the compositor) to the appropriate script task
     RouteScriptMsg(ScriptMsg),
 
+    /// Sets the number of device pixels the compositor draws each CSS pixel with, so that images
+    /// are decoded at the size they are drawn at.
+    SetZoomMsg(f32),
+
+    /// Sets the point of the page, in CSS pixels, that the compositor has scrolled to the top left
+    /// corner of the window. Images in the part of the page that is shown are decoded first.
+    SetScrollPositionMsg(Point2D<f32>),
+
     /// Requests that the layout task shut down and exit.
     ExitMsg,
 }
@@ -9,8 +9,16 @@ use servo_msg::compositor::{ReadyState, Loading, PerformingLayout, FinishedLoadi
 use dom::bindings::utils::GlobalStaticData;
 use dom::document::Document;
 use dom::element::Element;
-use dom::event::{Event, ResizeEvent, ReflowEvent, ClickEvent, MouseDownEvent, MouseUpEvent};
-use dom::node::{AbstractNode, ScriptView, define_bindings};
+use dom::bindings::utils::str;
+use dom::event::{Event, Event_, ResizeEvent, ReflowEvent, ClickEvent, MouseDownEvent};
+use dom::event::{CharacterKey, EnterKey, Key, KeyDownEvent, KeyModifiers, KeyPressEvent};
+use dom::event::{KeyUpEvent, MouseMoveEvent, MouseUpEvent, TabKey};
+use dom::eventdispatcher::dispatch_event;
+use dom::focus;
+use dom::keyboardevent::KeyboardEvent;
+use dom::node::{AbstractNode, Node, ScriptView, define_bindings};
+#[cfg(test)]
+use dom::node::test_element;
 use dom::window::Window;
 use layout_interface::{AddStylesheetMsg, DocumentDamage, DocumentDamageLevel, HitTestQuery};
 use layout_interface::{HitTestResponse, LayoutQuery, LayoutResponse, LayoutChan};
@@ -22,12 +30,12 @@ use servo_msg::engine::{EngineChan, LoadUrlMsg};
 use core::cast::transmute;
 use core::cell::Cell;
 use core::comm::{Port, SharedChan};
-use core::io::read_whole_file;
 use core::local_data;
 use core::ptr::null;
 use core::task::{SingleThreaded, task};
 use core::util::replace;
 use dom::window::TimerData;
+use geom::point::Point2D;
 use geom::size::Size2D;
 use html::hubbub_html_parser;
 use js::JSVAL_NULL;
@@ -37,19 +45,21 @@ use js::jsapi::JSContext;
 use js::jsapi::bindgen::{JS_CallFunctionValue, JS_GetContextPrivate};
 use js::rust::{Compartment, Cx};
 use js;
-use servo_net::image_cache_task::ImageCacheTask;
-use servo_net::resource_task::ResourceTask;
-use servo_util::tree::TreeNodeRef;
-use servo_util::url::make_url;
+use servo_net::image_cache_task::{CancelPendingFetches, ImageCacheTask};
+use servo_net::mime_sniff;
+use servo_net::resource_task::{Aborted, CancelableLoad, DocumentPriority, Done, LoadGroup};
+use servo_net::resource_task::{Metadata, Payload, ProgressMsg, ResourceTask};
+use servo_net::resource_task;
+use servo_util::mem::{DomNodeBytesCategory, DomNodeCountCategory, MemoryProfilerChan};
+use servo_util::tree::{TreeNodeRef, TreeUtils};
+use servo_util::url_parser;
 use std::net::url::Url;
 use std::net::url;
 
 /// Messages used to control the script task.
 pub enum ScriptMsg {
-    /// Loads a new URL.
+    /// Loads a new URL, which may be a document or a standalone script.
     LoadMsg(Url),
-    /// Executes a standalone script.
-    ExecuteMsg(Url),
     /// Sends a DOM event.
     SendEventMsg(Event),
     /// Fires a JavaScript timeout.
@@ -97,6 +107,10 @@ pub struct ScriptContext {
     image_cache_task: ImageCacheTask,
     /// A handle to the resource task.
     resource_task: ResourceTask,
+    /// The loads of the current page's document, style sheets and scripts.
+    page_loads: LoadGroup,
+    /// A channel to the memory profiler, if memory is being profiled.
+    memory_profiler_chan: Option<MemoryProfilerChan>,
 
     /// The port that we will use to join layout. If this is `None`, then layout is not currently
     /// running.
@@ -111,6 +125,8 @@ pub struct ScriptContext {
     engine_chan: EngineChan,
     /// For communicating loading messages to the compositor
     compositor_task: ~fn(ReadyState),
+    /// The ready state the compositor was last told about.
+    ready_state: ReadyState,
 
     /// The JavaScript runtime.
     js_runtime: js::rust::rt,
@@ -131,6 +147,9 @@ pub struct ScriptContext {
     window_size: Size2D<uint>,
     /// What parts of the document are dirty, if any.
     damage: Option<DocumentDamage>,
+    /// Whether a listener prevented the default action of the last `keydown` event, which
+    /// suppresses the `keypress` event for the same key.
+    key_press_suppressed: bool,
 }
 
 fn global_script_context_key(_: @ScriptContext) {}
@@ -166,7 +185,8 @@ impl ScriptContext {
                engine_chan: EngineChan,
                compositor_task: ~fn(ReadyState),
                resource_task: ResourceTask,
-               img_cache_task: ImageCacheTask)
+               img_cache_task: ImageCacheTask,
+               memory_profiler_chan: Option<MemoryProfilerChan>)
                -> @mut ScriptContext {
         let js_runtime = js::rust::rt();
         let js_context = js_runtime.cx();
@@ -183,6 +203,8 @@ impl ScriptContext {
             layout_chan: layout_chan,
             image_cache_task: img_cache_task,
             resource_task: resource_task,
+            page_loads: LoadGroup::new(),
+            memory_profiler_chan: memory_profiler_chan,
 
             layout_join_port: None,
             script_port: script_port,
@@ -190,6 +212,7 @@ impl ScriptContext {
 
             engine_chan: engine_chan,
             compositor_task: compositor_task,
+            ready_state: FinishedLoading,
 
             js_runtime: js_runtime,
             js_context: js_context,
@@ -202,6 +225,7 @@ impl ScriptContext {
 
             window_size: Size2D(800, 600),
             damage: None,
+            key_press_suppressed: false,
         };
         // Indirection for Rust Issue #6248, dynamic freeze scope artifically extended
         let script_context_ptr = {
@@ -231,20 +255,23 @@ impl ScriptContext {
                                  engine_chan: EngineChan,
                                  compositor_task: ~fn(ReadyState),
                                  resource_task: ResourceTask,
-                                 image_cache_task: ImageCacheTask) {
+                                 image_cache_task: ImageCacheTask,
+                                 memory_profiler_chan: Option<MemoryProfilerChan>) {
         let script_port = Cell(script_port);
         let compositor_task = Cell(compositor_task);
         // FIXME: rust#6399
         let mut the_task = task();
         the_task.sched_mode(SingleThreaded);
         do the_task.spawn {
+            let _reports = memory_profiler_chan.map(|chan| chan.guard_reports());
             let script_context = ScriptContext::new(layout_chan.clone(),
                                                     script_port.take(),
                                                     script_chan.clone(),
                                                     engine_chan.clone(),
                                                     compositor_task.take(),
                                                     resource_task.clone(),
-                                                    image_cache_task.clone());
+                                                    image_cache_task.clone(),
+                                                    memory_profiler_chan.map(|chan| chan.clone()));
             script_context.start();
         }
     }
@@ -256,10 +283,6 @@ impl ScriptContext {
                 self.load(url);
                 true
             }
-            ExecuteMsg(url) => {
-                self.handle_execute_msg(url);
-                true
-            }
             SendEventMsg(event) => {
                 self.handle_event(event);
                 true
@@ -279,23 +302,6 @@ impl ScriptContext {
         }
     }
 
-    /// Handles a request to execute a script.
-    fn handle_execute_msg(&self, url: Url) {
-        debug!("script: Received url `%s` to execute", url::to_str(&url));
-
-        match read_whole_file(&Path(url.path)) {
-            Err(msg) => println(fmt!("Error opening %s: %s", url::to_str(&url), msg)),
-
-            Ok(bytes) => {
-                self.js_compartment.define_functions(debug_fns);
-                let _ = self.js_context.evaluate_script(self.js_compartment.global_obj,
-                                                        bytes,
-                                                        copy url.path,
-                                                        1);
-            }
-        }
-    }
-
     /// Handles a timer that fired.
     fn handle_fire_timer_msg(&mut self, timer_data: ~TimerData) {
         let this_value = if timer_data.args.len() > 0 {
@@ -334,7 +340,8 @@ impl ScriptContext {
 
     // tells the compositor when loading starts and finishes
     // FIXME ~compositor_interface doesn't work right now, which is why this is necessary
-    fn set_ready_state(&self, msg: ReadyState) {
+    fn set_ready_state(&mut self, msg: ReadyState) {
+        self.ready_state = msg;
         (self.compositor_task)(msg);
     }
 
@@ -349,23 +356,68 @@ impl ScriptContext {
             self.bindings_initialized = true
         }
 
+        let previous_ready_state = self.ready_state;
         self.set_ready_state(Loading);
+
+        // Loaders send the metadata before any data. Whether the resource is a document or a
+        // standalone script is sniffed from it and the data in the message after it. Until it is
+        // known to be a document, the page being displayed stays as it is, so the fetch isn't
+        // one of its loads.
+        let navigation_loads = LoadGroup::new();
+        let (input_port, input_chan) = comm::stream();
+        self.resource_task.send(CancelableLoad(copy url,
+                                               DocumentPriority,
+                                               input_chan,
+                                               navigation_loads.add_load()));
+        let (metadata, first_msg) = match input_port.recv() {
+            Metadata(metadata) => (metadata, input_port.recv()),
+            msg => (resource_task::Metadata::default(copy url), msg),
+        };
+        let is_script = match first_msg {
+            Payload(ref data) => mime_sniff::is_javascript(&mime_sniff::sniff(&metadata, *data)),
+            _ => mime_sniff::is_javascript(&mime_sniff::sniff(&metadata, [])),
+        };
+        if is_script {
+            // Scripts run in the page being displayed, which goes on as it was.
+            self.execute_script(url, metadata, first_msg, input_port);
+            self.set_ready_state(previous_ready_state);
+            return
+        }
+
+        // Resources still being fetched for the page we're leaving are no longer needed.
+        if self.root_frame.is_some() {
+            self.image_cache_task.send(CancelPendingFetches);
+        }
+        self.page_loads.cancel();
+        self.page_loads = navigation_loads;
+
         // Parse HTML.
         //
         // Note: We can parse the next document in parallel with any previous documents.
-        let html_parsing_result = hubbub_html_parser::parse_html(copy url,
+        let html_parsing_result = hubbub_html_parser::parse_html(metadata,
+                                                                 Some(first_msg),
+                                                                 input_port,
                                                                  self.resource_task.clone(),
-                                                                 self.image_cache_task.clone());
+                                                                 self.image_cache_task.clone(),
+                                                                 self.page_loads.clone());
 
         let root_node = html_parsing_result.root;
 
+        // Use the URL the document was actually loaded from, so that relative URLs (in links,
+        // for example) resolve against the target of any redirects.
+        let url = copy html_parsing_result.url;
+
         // Send style sheets over to layout.
         //
         // FIXME: These should be streamed to layout as they're parsed. We don't need to stop here
         // in the script task.
+        let mut hover_rules = false;
         loop {
               match html_parsing_result.style_port.recv() {
-                  Some(sheet) => self.layout_chan.send(AddStylesheetMsg(sheet)),
+                  Some((sheet, may_have_hover_rules)) => {
+                      hover_rules = hover_rules || may_have_hover_rules;
+                      self.layout_chan.send(AddStylesheetMsg(sheet))
+                  }
                   None => break,
               }
         }
@@ -376,14 +428,18 @@ impl ScriptContext {
 
         // Create the window and document objects.
         let window = Window::new(self.script_chan.clone(), &mut *self);
-        let document = Document(root_node, Some(window));
+        let document = Document(root_node, Some(window), copy html_parsing_result.encoding);
+        document.hover_rules = hover_rules;
 
         // Tie the root into the document.
         do root_node.with_mut_base |base| {
             base.add_to_doc(document)
         }
 
-        // Create the root frame.
+        // Tear down the document being replaced, and create the root frame.
+        for self.root_frame.each |frame| {
+            frame.document.teardown();
+        }
         self.root_frame = Some(Frame {
             document: document,
             window: window,
@@ -409,6 +465,41 @@ impl ScriptContext {
         }
     }
 
+    /// Executes a standalone script as it finishes loading, given the first message after its
+    /// metadata.
+    fn execute_script(&mut self,
+                      url: Url,
+                      metadata: resource_task::Metadata,
+                      first_msg: ProgressMsg,
+                      input_port: Port<ProgressMsg>) {
+        debug!("script: executing standalone script `%s`", url::to_str(&url));
+        // The body of an error response is an error page, not the script.
+        if !metadata.is_success() {
+            println(fmt!("Error loading %s: status %?", url::to_str(&url), metadata.status));
+            return
+        }
+        let mut bytes = ~[];
+        let mut msg = first_msg;
+        loop {
+            match msg {
+                Payload(data) => bytes.push_all_move(data),
+                Metadata(*) => fail!(~"received metadata twice"),
+                Done(Ok(())) => break,
+                Done(Err(())) | Aborted => {
+                    println(fmt!("Error loading %s", url::to_str(&url)));
+                    return
+                }
+            }
+            msg = input_port.recv();
+        }
+
+        self.js_compartment.define_functions(debug_fns);
+        let _ = self.js_context.evaluate_script(self.js_compartment.global_obj,
+                                                bytes,
+                                                copy url.path,
+                                                1);
+    }
+
     /// Sends a ping to layout and waits for the response. The response will arrive when the
     /// layout task has finished any pending request messages.
     fn join_layout(&mut self) {
@@ -450,6 +541,8 @@ impl ScriptContext {
         match self.root_frame {
             None => fail!(~"Tried to relayout with no root frame!"),
             Some(ref root_frame) => {
+                self.report_memory(root_frame.document.root);
+
                 // Send new document and relevant styles to layout.
                 let reflow = ~Reflow {
                     document_root: root_frame.document.root,
@@ -468,6 +561,35 @@ impl ScriptContext {
         debug!("script: layout forked")
     }
 
+    /// Reports the number of nodes in the document, and roughly the bytes they take up, to the
+    /// memory profiler.
+    fn report_memory(&self, root: AbstractNode<ScriptView>) {
+        let memory_profiler_chan = match self.memory_profiler_chan {
+            Some(ref memory_profiler_chan) => memory_profiler_chan,
+            None => return,
+        };
+        let mut count = 0;
+        let mut bytes = 0;
+        for root.traverse_preorder |node| {
+            count += 1;
+            bytes += sys::size_of::<Node<ScriptView>>();
+            if node.is_text() {
+                do node.with_imm_text |text| {
+                    bytes += text.parent.data.len();
+                }
+            } else if node.is_element() {
+                do node.with_imm_element |element| {
+                    bytes += element.tag_name.len();
+                    for element.attrs.each |attr| {
+                        bytes += attr.name.len() + attr.value.len();
+                    }
+                }
+            }
+        }
+        memory_profiler_chan.report(DomNodeCountCategory, count);
+        memory_profiler_chan.report(DomNodeBytesCategory, bytes);
+    }
+
     /// Reflows the entire document.
     ///
     /// FIXME: This should basically never be used.
@@ -497,8 +619,7 @@ impl ScriptContext {
         match *damage {
             None => {}
             Some(ref mut damage) => {
-                // FIXME(pcwalton): This is wrong. We should trace up to the nearest ancestor.
-                damage.root = root;
+                damage.root = common_ancestor(damage.root, root);
                 damage.level.add(level);
                 return
             }
@@ -548,42 +669,265 @@ impl ScriptContext {
 
             ClickEvent(_button, point) => {
                 debug!("ClickEvent: clicked at %?", point);
-                let root = match self.root_frame {
-                    Some(ref frame) => frame.document.root,
-                    None => fail!("root frame is None")
-                };
-                match self.query_layout(HitTestQuery(root, point)) {
-                    Ok(node) => match node {
-                        HitTestResponse(node) => {
-                            debug!("clicked on %?", node.debug_str());
-                            let mut node = node;
-                            // traverse node generations until a node that is an element is found
-                            while !node.is_element() {
-                                match node.parent_node() {
-                                    Some(parent) => {
-                                        node = parent;
-                                    }
-                                    None => break
-                                }
-                            }
-                            if node.is_element() {
-                                do node.with_imm_element |element| {
-                                    match element.tag_name {
-                                        ~"a" => self.load_url_from_element(element),
-                                        _ => {}
-                                    }
-                                }
-                            }
+                match self.hit_test(point) {
+                    Some(node) => {
+                        debug!("clicked on %?", node.debug_str());
+                        if self.fire_event(node, ~"click") {
+                            self.activate(node)
                         }
-                        _ => fail!(~"unexpected layout reply")
-                    },
-                    Err(()) => {
-                        debug!(fmt!("layout query error"));
                     }
+                    None => {}
+                }
+            }
+            MouseDownEvent(_button, point) => {
+                for self.hit_test(point).each |&node| {
+                    if self.fire_event(node, ~"mousedown") {
+                        self.focus_from_click(node)
+                    }
+                }
+            }
+            MouseUpEvent(_button, point) => {
+                for self.hit_test(point).each |&node| {
+                    self.fire_event(node, ~"mouseup");
+                }
+            }
+            MouseMoveEvent(point) => {
+                // The pointer moves around before there's a page to hit test, too.
+                if self.root_frame.is_none() {
+                    return
+                }
+                let node = self.hit_test(point);
+                self.set_hover(node);
+                for node.each |&node| {
+                    self.fire_event(node, ~"mousemove");
+                }
+            }
+            KeyDownEvent(key, modifiers) => {
+                for self.focus_target().each |&target| {
+                    let not_prevented = self.fire_keyboard_event(target, ~"keydown", key, 0,
+                                                                 modifiers);
+                    self.key_press_suppressed = !not_prevented;
+                    if not_prevented {
+                        self.key_default_action(target, key, modifiers)
+                    }
+                }
+            }
+            KeyPressEvent(c, modifiers) => {
+                if !self.key_press_suppressed {
+                    let key = if c == '\r' { EnterKey } else { CharacterKey(c) };
+                    for self.focus_target().each |&target| {
+                        self.fire_keyboard_event(target, ~"keypress", key, c as u32, modifiers);
+                    }
+                }
+            }
+            KeyUpEvent(key, modifiers) => {
+                for self.focus_target().each |&target| {
+                    self.fire_keyboard_event(target, ~"keyup", key, 0, modifiers);
+                }
+            }
+        }
+    }
+
+    /// Moves the pointer to `node`. Updates which nodes `:hover` applies to, restyling the subtree
+    /// that contains them, and fires the mouseout, mouseleave, mouseover and mouseenter events.
+    priv fn set_hover(&mut self, node: Option<AbstractNode<ScriptView>>) {
+        let document = match self.root_frame {
+            Some(ref frame) => frame.document,
+            None => return,
+        };
+        let previous = document.hovered;
+        if previous == node {
+            return
+        }
+
+        // Both chains are innermost first. Nodes in both stay hovered.
+        let previous_chain = inclusive_ancestors(previous);
+        let chain = inclusive_ancestors(node);
+        let left = previous_chain.filtered(|node| !chain.contains(node));
+        let entered = chain.filtered(|node| !previous_chain.contains(node));
+
+        // Layout reads the hover flags, so it has to finish before they change.
+        self.join_layout();
+        document.hovered = node;
+        for left.each |&node| {
+            node.with_mut_base(|base| base.hovered = false);
+        }
+        for entered.each |&node| {
+            node.with_mut_base(|base| base.hovered = true);
+        }
+
+        for previous.each |&previous| {
+            self.fire_event(previous, ~"mouseout");
+        }
+        for left.each |&node| {
+            self.fire_non_bubbling_event(node, ~"mouseleave");
+        }
+        for node.each |&node| {
+            self.fire_event(node, ~"mouseover");
+        }
+        for entered.each_reverse |&node| {
+            self.fire_non_bubbling_event(node, ~"mouseenter");
+        }
+
+        // Without `:hover` rules, which nodes are hovered can't change any styles.
+        if !document.hover_rules {
+            return
+        }
+
+        // Only the nodes below the deepest one that stayed hovered can match different rules.
+        let restyle_root = match vec::find(chain, |node| previous_chain.contains(node)) {
+            Some(node) => node,
+            None => document.root,
+        };
+        ScriptContext::damage(&mut self.damage, restyle_root, MatchSelectorsDocumentDamage);
+        self.reflow(ReflowForDisplay)
+    }
+
+    /// Returns the node key events are dispatched at: the focused element, or the root element if
+    /// nothing is focused.
+    priv fn focus_target(&self) -> Option<AbstractNode<ScriptView>> {
+        match self.root_frame {
+            Some(ref frame) => {
+                match frame.document.focused {
+                    Some(focused) => Some(focused),
+                    None => Some(frame.document.root),
+                }
+            }
+            None => None,
+        }
+    }
+
+    /// Moves the focus to `node`, firing `blur` at the element that loses it and `focus` at the
+    /// one that gets it.
+    priv fn set_focus(&mut self, node: Option<AbstractNode<ScriptView>>) {
+        let document = match self.root_frame {
+            Some(ref frame) => frame.document,
+            None => return,
+        };
+        let previous = document.focused;
+        if previous == node {
+            return
+        }
+        document.focused = node;
+        for previous.each |&previous| {
+            self.fire_non_bubbling_event(previous, ~"blur");
+        }
+        for node.each |&node| {
+            self.fire_non_bubbling_event(node, ~"focus");
+        }
+    }
+
+    /// Focuses the nearest focusable ancestor of a node that was clicked on, or takes the focus
+    /// away from the focused element if there is none.
+    priv fn focus_from_click(&mut self, target: AbstractNode<ScriptView>) {
+        let mut node = Some(target);
+        while node.is_some() && focus::tab_index(node.get()).is_none() {
+            node = node.get().parent_node();
+        }
+        self.set_focus(node)
+    }
+
+    /// Runs the default action of a key going down: the tab key moves the focus, and the enter
+    /// key clicks the focused element.
+    priv fn key_default_action(&mut self,
+                               target: AbstractNode<ScriptView>,
+                               key: Key,
+                               modifiers: KeyModifiers) {
+        match key {
+            TabKey => {
+                let (root, focused) = match self.root_frame {
+                    Some(ref frame) => (frame.document.root, frame.document.focused),
+                    None => return,
                 };
+                self.set_focus(focus::next_in_order(root, focused, modifiers.shift))
+            }
+            EnterKey if focus::tab_index(target).is_some() => {
+                if self.fire_event(target, ~"click") {
+                    self.activate(target)
+                }
             }
-            MouseDownEvent(*) => {}
-            MouseUpEvent(*) => {}
+            _ => {}
+        }
+    }
+
+    /// Returns the element at the given point, or the element containing the text there. Returns
+    /// None if there is no such element, or no page has been loaded yet.
+    priv fn hit_test(&mut self, point: Point2D<f32>) -> Option<AbstractNode<ScriptView>> {
+        let root = match self.root_frame {
+            Some(ref frame) => frame.document.root,
+            None => return None
+        };
+        match self.query_layout(HitTestQuery(root, point)) {
+            Ok(HitTestResponse(node)) => {
+                let mut node = node;
+                // traverse node generations until a node that is an element is found
+                while !node.is_element() {
+                    match node.parent_node() {
+                        Some(parent) => node = parent,
+                        None => return None
+                    }
+                }
+                Some(node)
+            }
+            Ok(_) => fail!(~"unexpected layout reply"),
+            Err(()) => {
+                debug!(fmt!("layout query error"));
+                None
+            }
+        }
+    }
+
+    /// Dispatches a trusted event of the given type at a node, which bubbles and can be
+    /// cancelled. Returns false if a listener prevented the default action.
+    priv fn fire_event(&self, target: AbstractNode<ScriptView>, type_: ~str) -> bool {
+        let event = @mut Event_::new(str(type_));
+        event.trusted = true;
+        dispatch_event(target, event)
+    }
+
+    /// Dispatches a trusted event that doesn't bubble and can't be cancelled, like `focus` or
+    /// `mouseenter`.
+    priv fn fire_non_bubbling_event(&self, target: AbstractNode<ScriptView>, type_: ~str) {
+        let event = @mut Event_::new(str(type_));
+        event.trusted = true;
+        event.bubbles = false;
+        event.cancelable = false;
+        dispatch_event(target, event);
+    }
+
+    /// Dispatches a trusted keyboard event, which bubbles and can be cancelled. Returns false if a
+    /// listener prevented the default action.
+    priv fn fire_keyboard_event(&self,
+                                target: AbstractNode<ScriptView>,
+                                type_: ~str,
+                                key: Key,
+                                char_code: u32,
+                                modifiers: KeyModifiers)
+                                -> bool {
+        let event = KeyboardEvent::new(str(type_), key, char_code, modifiers);
+        event.parent.trusted = true;
+        dispatch_event(target, event.as_event())
+    }
+
+    /// Runs the default action of a click on a node, which is to follow the link the node is
+    /// in, if any.
+    priv fn activate(&self, target: AbstractNode<ScriptView>) {
+        let mut node = Some(target);
+        while node.is_some() {
+            let current = node.get();
+            if current.is_element() {
+                let mut is_link = false;
+                do current.with_imm_element |element| {
+                    if element.tag_name == ~"a" {
+                        self.load_url_from_element(element);
+                        is_link = true;
+                    }
+                }
+                if is_link {
+                    return
+                }
+            }
+            node = current.parent_node();
         }
     }
 
@@ -592,14 +936,67 @@ impl ScriptContext {
         for element.attrs.each |attr| {
             if attr.name == ~"href" {
                 debug!("clicked on link to %?", attr.value); 
-                let current_url = match self.root_frame {
-                    Some(ref frame) => Some(frame.url.clone()),
-                    None => None
+                let result = match self.root_frame {
+                    Some(ref frame) => url_parser::parse(attr.value, Some(&frame.url)),
+                    None => url_parser::parse(attr.value, None)
                 };
-                let url = make_url(attr.value.clone(), current_url);
-                self.engine_chan.send(LoadUrlMsg(url));
+                match result {
+                    Ok(url) => self.engine_chan.send(LoadUrlMsg(url)),
+                    Err(error) => debug!("not following link to %s: %?", attr.value, error),
+                }
             }
         }
     }
 }
 
+/// Returns a node and its ancestors, innermost first.
+fn inclusive_ancestors(node: Option<AbstractNode<ScriptView>>) -> ~[AbstractNode<ScriptView>] {
+    let mut ancestors = ~[];
+    let mut node = node;
+    while node.is_some() {
+        let current = node.get();
+        ancestors.push(current);
+        node = current.parent_node();
+    }
+    ancestors
+}
+
+/// Returns the deepest node that contains both nodes, or `b` if they aren't in the same tree.
+fn common_ancestor(a: AbstractNode<ScriptView>, b: AbstractNode<ScriptView>)
+                   -> AbstractNode<ScriptView> {
+    let a_ancestors = inclusive_ancestors(Some(a));
+    match vec::find(inclusive_ancestors(Some(b)), |node| a_ancestors.contains(node)) {
+        Some(node) => node,
+        None => b,
+    }
+}
+
+#[test]
+fn should_list_a_node_and_its_ancestors_innermost_first() {
+    let root = test_element("div", []);
+    let child = test_element("div", []);
+    let grandchild = test_element("div", []);
+    root.add_child(child);
+    child.add_child(grandchild);
+    assert!(inclusive_ancestors(Some(grandchild)) == ~[grandchild, child, root]);
+    assert!(inclusive_ancestors(Some(root)) == ~[root]);
+    assert!(inclusive_ancestors(None).is_empty());
+}
+
+#[test]
+fn should_find_the_deepest_common_ancestor() {
+    let root = test_element("div", []);
+    let left = test_element("div", []);
+    let right = test_element("div", []);
+    let leaf = test_element("div", []);
+    root.add_child(left);
+    root.add_child(right);
+    left.add_child(leaf);
+    assert!(common_ancestor(leaf, right) == root);
+    assert!(common_ancestor(right, leaf) == root);
+    assert!(common_ancestor(leaf, left) == left);
+    assert!(common_ancestor(leaf, leaf) == leaf);
+    // Nodes in different trees have nothing in common, so the whole of `b` is given.
+    let other = test_element("div", []);
+    assert!(common_ancestor(leaf, other) == other);
+}
@@ -73,6 +73,10 @@ pub impl<K: Copy + Eq, V: Copy> LRUCache<K,V> {
         }
     }
 
+    fn len(&self) -> uint {
+        self.entries.len()
+    }
+
     fn touch(&mut self, pos: uint) -> V {
         let (key, val) = copy self.entries[pos];
         if pos != self.cache_size {
@@ -85,6 +89,10 @@ pub impl<K: Copy + Eq, V: Copy> LRUCache<K,V> {
 
 impl<K: Copy + Eq, V: Copy> Cache<K,V> for LRUCache<K,V> {
     fn insert(&mut self, key: &K, val: V) {
+        match self.entries.position(|&(k, _)| k == *key) {
+            Some(pos) => { self.entries.remove(pos); }
+            None => {}
+        }
         if self.entries.len() == self.cache_size {
             self.entries.remove(0);
         }
@@ -139,6 +147,12 @@ fn test_lru_cache() {
     assert!(cache.find(&3).is_none());  // (4, 2) (no change)
     assert!(cache.find(&4).is_some());  // (2, 4) (no change)
 
+    // Test that inserting an existing key replaces its value.
+    cache.insert(&4, three); // (2, 4)
+    assert!(cache.find(&4) == Some(three)); // (2, 4)
+    assert!(cache.find(&2).is_some()); // (4, 2)
+    cache.insert(&4, four); // (2, 4)
+
     // Test find_or_create.
     do cache.find_or_create(&1) |_| { one } // (4, 1)
 
@@ -0,0 +1,226 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Memory profiling functions.
+use core::cell::Cell;
+use core::comm::{Chan, Port, SharedChan};
+use core::task;
+use core::task::Task;
+
+// front-end representation of the memory profiler used to communicate with it
+pub struct MemoryProfilerChan {
+    chan: SharedChan<MemoryProfilerMsg>,
+}
+
+impl Clone for MemoryProfilerChan {
+    fn clone(&self) -> MemoryProfilerChan {
+        MemoryProfilerChan {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl MemoryProfilerChan {
+    pub fn new(chan: Chan<MemoryProfilerMsg>) -> MemoryProfilerChan {
+        MemoryProfilerChan {
+            chan: SharedChan::new(chan),
+        }
+    }
+    pub fn send(&self, msg: MemoryProfilerMsg) {
+        self.chan.send(msg);
+    }
+    // reports how much of something the calling task is holding on to right now
+    pub fn report(&self, category: MemoryCategory, value: uint) {
+        self.send(ReportMsg(category, task::get_task(), value));
+    }
+    // returns a guard for the calling task to keep until it exits, so that its reports are
+    // dropped then, even if it fails
+    pub fn guard_reports(&self) -> ReportsGuard {
+        ReportsGuard {
+            memory_profiler_chan: self.clone(),
+            task: task::get_task(),
+        }
+    }
+}
+
+// drops the reports of the task that created it when it goes out of scope
+pub struct ReportsGuard {
+    priv memory_profiler_chan: MemoryProfilerChan,
+    priv task: Task,
+}
+
+impl Drop for ReportsGuard {
+    fn finalize(&self) {
+        self.memory_profiler_chan.send(TaskExitedMsg(self.task));
+    }
+}
+
+#[deriving(Eq)]
+pub enum MemoryCategory {
+    DomNodeCountCategory,
+    DomNodeBytesCategory,
+    FlowCountCategory,
+    BoxCountCategory,
+    GlyphStoreBytesCategory,
+    FontCountCategory,
+    DecodedImageBytesCategory,
+    TileBufferBytesCategory,
+}
+
+impl MemoryCategory {
+    // categories of sizes in bytes, as opposed to numbers of things
+    pub fn is_bytes(self) -> bool {
+        match self {
+            DomNodeBytesCategory | GlyphStoreBytesCategory | DecodedImageBytesCategory |
+            TileBufferBytesCategory => true,
+            DomNodeCountCategory | FlowCountCategory | BoxCountCategory |
+            FontCountCategory => false,
+        }
+    }
+}
+
+pub enum MemoryProfilerMsg {
+    // Message used to report the current value of a category, replacing the last one reported
+    // by the same task
+    ReportMsg(MemoryCategory, Task, uint),
+    // Message used to drop the reports of a task that has exited
+    TaskExitedMsg(Task),
+    // Message used to force print the memory usage
+    PrintMsg,
+    // Message used to get the totals reported so far, e.g. for benchmark harnesses
+    TotalsMsg(Chan<~[(MemoryCategory, uint)]>),
+}
+
+// back end of the memory profiler that keeps the latest report of every task
+pub struct MemoryProfiler {
+    port: Port<MemoryProfilerMsg>,
+    // the latest value of each category reported by each task, in the order first reported
+    reports: ~[(MemoryCategory, Task, uint)],
+    // whether anything was reported since the last print
+    changed: bool,
+}
+
+impl MemoryProfiler {
+    pub fn create(port: Port<MemoryProfilerMsg>) {
+        let port = Cell(port);
+        do spawn {
+            let mut memory_profiler = MemoryProfiler::new(port.take());
+            memory_profiler.start();
+        }
+    }
+
+    pub fn new(port: Port<MemoryProfilerMsg>) -> MemoryProfiler {
+        MemoryProfiler {
+            port: port,
+            reports: ~[],
+            changed: false,
+        }
+    }
+
+    pub fn start(&mut self) {
+        loop {
+            let msg = self.port.recv();
+            self.handle_msg(msg);
+        }
+    }
+
+    priv fn handle_msg(&mut self, msg: MemoryProfilerMsg) {
+        match msg {
+            ReportMsg(category, task, value) => {
+                let position = do vec::position(self.reports) |&(other_category, other_task, _)| {
+                    other_category == category && other_task == task
+                };
+                match position {
+                    Some(index) => self.reports[index] = (category, task, value),
+                    None => self.reports.push((category, task, value)),
+                }
+                self.changed = true;
+            }
+            TaskExitedMsg(task) => {
+                self.reports = do vec::filtered(self.reports) |&(_, other_task, _)| {
+                    other_task != task
+                };
+                self.changed = true;
+            }
+            PrintMsg => {
+                if self.changed {
+                    self.print_totals();
+                }
+                self.changed = false;
+            }
+            // Answering doesn't change the reports, so it doesn't make the next PrintMsg print
+            TotalsMsg(response) => response.send(self.totals()),
+        }
+    }
+
+    // sums each category's latest reports over the tasks that sent them
+    priv fn totals(&self) -> ~[(MemoryCategory, uint)] {
+        let mut totals: ~[(MemoryCategory, uint)] = ~[];
+        for self.reports.each |&(category, _, value)| {
+            match vec::position(totals, |&(other, _)| other == category) {
+                Some(index) => {
+                    match totals[index] {
+                        (_, ref mut total) => *total += value,
+                    }
+                }
+                None => totals.push((category, value)),
+            }
+        }
+        totals
+    }
+
+    priv fn print_totals(&self) {
+        println(fmt!("%31s %15s", "_category_", "_total_"));
+        for self.totals().each |&(category, total)| {
+            if category.is_bytes() {
+                println(fmt!("%-30s: %15.2f MB", fmt!("%?", category),
+                             total as float / (1024f * 1024f)));
+            } else {
+                println(fmt!("%-30s: %15u", fmt!("%?", category), total));
+            }
+        }
+        println("");
+    }
+}
+
+#[test]
+fn should_sum_the_latest_report_of_each_task() {
+    let (port, _chan) = comm::stream();
+    let mut memory_profiler = MemoryProfiler::new(port);
+    let task = task::get_task();
+    let (other_port, other_chan) = comm::stream();
+    do task::spawn {
+        other_chan.send(task::get_task());
+    }
+    let other_task = other_port.recv();
+
+    memory_profiler.handle_msg(ReportMsg(FlowCountCategory, task, 10));
+    memory_profiler.handle_msg(ReportMsg(FlowCountCategory, other_task, 5));
+    memory_profiler.handle_msg(ReportMsg(FlowCountCategory, task, 3));
+    memory_profiler.handle_msg(ReportMsg(TileBufferBytesCategory, other_task, 1024));
+    assert!(memory_profiler.totals() == ~[(FlowCountCategory, 8), (TileBufferBytesCategory, 1024)]);
+}
+
+#[test]
+fn should_drop_the_reports_of_tasks_that_have_exited() {
+    let (port, chan) = comm::stream();
+    let memory_profiler_chan = MemoryProfilerChan::new(chan);
+    let mut memory_profiler = MemoryProfiler::new(port);
+    memory_profiler_chan.report(FlowCountCategory, 10);
+
+    let other_chan = memory_profiler_chan.clone();
+    let result = do task::try {
+        let _reports = other_chan.guard_reports();
+        other_chan.report(FlowCountCategory, 5);
+        fail!(~"exiting early");
+    };
+    assert!(result.is_err());
+
+    // The two reports and the exit of the other task
+    for 3.times {
+        let msg = memory_profiler.port.recv();
+        memory_profiler.handle_msg(msg);
+    }
+    assert!(memory_profiler.totals() == ~[(FlowCountCategory, 10)]);
+}
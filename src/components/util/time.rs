@@ -5,19 +5,32 @@
 // Timing functions.
 use std::time::precise_time_ns;
 use core::cell::Cell;
-use core::comm::{Port, SharedChan};
+use core::comm::{Chan, Port, SharedChan};
+use core::io::WriterUtil;
+use core::task;
+use core::task::Task;
 use std::sort::tim_sort;
 
 // front-end representation of the profiler used to communicate with the profiler
 #[deriving(Clone)]
 pub struct ProfilerChan {
     chan: SharedChan<ProfilerMsg>,
+    // the page, e.g. its URL, that the times profiled through this channel are spent on
+    tag: Option<~str>,
 }
 
 impl ProfilerChan {
     pub fn new(chan: Chan<ProfilerMsg>) -> ProfilerChan {
         ProfilerChan {
             chan: SharedChan::new(chan),
+            tag: None,
+        }
+    }
+    // a channel to the same profiler whose times are attributed to the given page
+    pub fn tagged(&self, tag: ~str) -> ProfilerChan {
+        ProfilerChan {
+            chan: self.chan.clone(),
+            tag: Some(tag),
         }
     }
     pub fn send(&self, msg: ProfilerMsg) {
@@ -46,18 +59,75 @@ pub enum ProfilerCategory {
 // FIXME(#5873) this should be initialized by a NUM_BUCKETS cast,
 static BUCKETS: uint = 13;
 
+// things that are counted rather than timed
+#[deriving(Eq)]
+pub enum ProfilerCounter {
+    ImageCacheEvictionsCounter,
+}
+
+// a single call to `profile`, as recorded for the trace file
+pub struct Span {
+    category: ProfilerCategory,
+    // start and end in nanoseconds, as returned by precise_time_ns
+    start: u64,
+    end: u64,
+    task: Task,
+    tag: Option<~str>,
+}
+
+// statistics of the times in a bucket, in milliseconds
+#[deriving(Eq)]
+pub struct Summary {
+    mean: f64,
+    median: f64,
+    p90: f64,
+    p99: f64,
+    min: f64,
+    max: f64,
+    count: uint,
+}
+
+// the statistics gathered so far, for benchmark harnesses
+pub struct ProfilerSnapshot {
+    // every category with times, over all pages
+    categories: ~[(ProfilerCategory, Summary)],
+    // the same, for the times tagged with each page, in the order the pages were first seen
+    pages: ~[(~str, ~[(ProfilerCategory, Summary)])],
+    counters: ~[(ProfilerCounter, uint)],
+}
+
 pub enum ProfilerMsg {
     // Normal message used for reporting time
     TimeMsg(ProfilerCategory, f64),
+    // Message used for reporting time, along with when and on which task it was spent
+    SpanMsg(Span),
+    // Message used for adding to a counter
+    CountMsg(ProfilerCounter, uint),
     // Message used to force print the profiling metrics
     PrintMsg,
+    // Message used to get a copy of the times reported so far, e.g. for diagnostic pages
+    BucketsMsg(Chan<~[(ProfilerCategory, ~[f64])]>),
+    // Message used to write the trace file, if there is one, before servo exits
+    WriteTraceMsg(Chan<()>),
+    // Message used to get the statistics gathered so far
+    SnapshotMsg(Chan<ProfilerSnapshot>),
+    // Message used to forget everything reported so far, e.g. before loading a page to measure
+    ResetMsg,
 }
 
 // back end of the profiler that handles data aggregation and performance metrics
 pub struct Profiler {
     port: Port<ProfilerMsg>,
     buckets: ~[(ProfilerCategory, ~[f64])],
+    // the times tagged with each page, in the order the pages were first seen
+    pages: ~[(~str, ~[(ProfilerCategory, ~[f64])])],
+    counters: ~[(ProfilerCounter, uint)],
     last_msg: Option<ProfilerMsg>,
+    // the file to write the spans to in the Chrome trace event format, if tracing
+    trace_file: Option<Path>,
+    spans: ~[Span],
+    // the time the profiler was created, which the trace starts from
+    start_time: u64,
 }
 
 impl ProfilerCategory {
@@ -107,22 +177,41 @@ impl ProfilerCategory {
         };
         fmt!("%s%?", padding, self)
     }
+
+    // the group of categories this belongs to, for filtering trace events
+    pub fn trace_category(self) -> &'static str {
+        match self {
+            CompositingCategory => "compositing",
+            LayoutQueryCategory | LayoutPerformCategory | LayoutAuxInitCategory |
+            LayoutSelectorMatchCategory | LayoutTreeBuilderCategory | LayoutMainCategory |
+            LayoutShapingCategory | LayoutDispListBuildCategory => "layout",
+            GfxRegenAvailableFontsCategory => "gfx",
+            RenderingDrawingCategory | RenderingPrepBuffCategory | RenderingCategory => "rendering",
+            NUM_BUCKETS => fail!("NUM_BUCKETS is not a category"),
+        }
+    }
 }
 
 impl Profiler {
-    pub fn create(port: Port<ProfilerMsg>) {
+    pub fn create(port: Port<ProfilerMsg>, trace_file: Option<Path>) {
         let port = Cell(port);
+        let trace_file = Cell(trace_file);
         do spawn {
-            let mut profiler = Profiler::new(port.take());
+            let mut profiler = Profiler::new(port.take(), trace_file.take());
             profiler.start();
         }
     }
 
-    pub fn new(port: Port<ProfilerMsg>) -> Profiler {
+    pub fn new(port: Port<ProfilerMsg>, trace_file: Option<Path>) -> Profiler {
         Profiler {
             port: port,
             buckets: ProfilerCategory::empty_buckets(),
+            pages: ~[],
+            counters: ~[(ImageCacheEvictionsCounter, 0)],
             last_msg: None,
+            trace_file: trace_file,
+            spans: ~[],
+            start_time: precise_time_ns(),
         }
     }
 
@@ -135,46 +224,208 @@ impl Profiler {
 
     priv fn handle_msg(&mut self, msg: ProfilerMsg) {
         match msg {
-            TimeMsg(category, t) => match self.buckets[category as uint] {
-                // FIXME(#3874): this should be a let (cat, ref mut bucket) = ...,
-                // not a match
-                (_, ref mut data) => {
-                    data.push(t);
+            TimeMsg(category, t) => {
+                self.record(category, None, t);
+                self.last_msg = Some(TimeMsg(category, t));
+            }
+            SpanMsg(span) => {
+                let ms = (span.end - span.start) as f64 / 1000000f64;
+                self.record(span.category, copy span.tag, ms);
+                self.last_msg = Some(TimeMsg(span.category, ms));
+                if self.trace_file.is_some() {
+                    self.spans.push(span);
+                }
+            }
+            CountMsg(counter, n) => {
+                match self.counters[counter as uint] {
+                    (_, ref mut count) => *count += n,
                 }
-            },
-            PrintMsg => match self.last_msg {
-                Some(TimeMsg(*)) => self.print_buckets(),
-                _ => {}
-            },
+                self.last_msg = Some(CountMsg(counter, n));
+            }
+            PrintMsg => {
+                match self.last_msg {
+                    Some(TimeMsg(*)) | Some(CountMsg(*)) => self.print_buckets(),
+                    _ => {}
+                }
+                self.last_msg = Some(PrintMsg);
+            }
+            // Doesn't count as a message for the purpose of deciding whether to print
+            BucketsMsg(response) => response.send(copy self.buckets),
+            WriteTraceMsg(response) => {
+                self.write_trace();
+                response.send(());
+            }
+            SnapshotMsg(response) => {
+                let pages = do self.pages.map |&(ref page, ref buckets)| {
+                    (copy *page, summarize_buckets(*buckets))
+                };
+                response.send(ProfilerSnapshot {
+                    categories: summarize_buckets(self.buckets),
+                    pages: pages,
+                    counters: copy self.counters,
+                });
+            }
+            ResetMsg => {
+                self.buckets = ProfilerCategory::empty_buckets();
+                self.pages = ~[];
+                for vec::each_mut(self.counters) |counter| {
+                    match *counter {
+                        (_, ref mut count) => *count = 0,
+                    }
+                }
+                self.spans = ~[];
+                self.last_msg = Some(ResetMsg);
+            }
+        }
+    }
+
+    // adds a time to its category's bucket, and to the page's if it's tagged with one
+    priv fn record(&mut self, category: ProfilerCategory, tag: Option<~str>, t: f64) {
+        match self.buckets[category as uint] {
+            // FIXME(#3874): this should be a let (cat, ref mut bucket) = ...,
+            // not a match
+            (_, ref mut data) => {
+                data.push(t);
+            }
+        }
+        let page = match tag {
+            Some(page) => page,
+            None => return,
+        };
+        let index = match vec::position(self.pages, |&(ref other, _)| *other == page) {
+            Some(index) => index,
+            None => {
+                self.pages.push((page, ProfilerCategory::empty_buckets()));
+                self.pages.len() - 1
+            }
+        };
+        match self.pages[index] {
+            (_, ref mut buckets) => {
+                match buckets[category as uint] {
+                    (_, ref mut data) => data.push(t),
+                }
+            }
+        }
+    }
+
+    priv fn write_trace(&self) {
+        let path = match self.trace_file {
+            Some(ref path) => path,
+            None => return,
         };
-        self.last_msg = Some(msg);
+        match io::file_writer(path, [io::Create, io::Truncate]) {
+            Ok(writer) => writer.write_str(trace_json(self.spans, self.start_time)),
+            Err(message) => debug!("profiler: failed to write %s: %s", path.to_str(), message),
+        }
     }
 
     priv fn print_buckets(&mut self) {
-        println(fmt!("%31s %15s %15s %15s %15s %15s",
-                         "_category (ms)_", "_mean (ms)_", "_median (ms)_",
-                         "_min (ms)_", "_max (ms)_", "_bucket size_"));
-        for vec::each_mut(self.buckets) |bucket| {
-            match *bucket {
-                (category, ref mut data) => {
-                    tim_sort(*data);
-                    let data_len = data.len();
-                    if data_len > 0 {
-                        let (mean, median, min, max) =
-                            (data.foldl(0f64, |a, b| a + *b) / (data_len as f64),
-                             data[data_len / 2],
-                             data.min(),
-                             data.max());
-                        println(fmt!("%-30s: %15.4? %15.4? %15.4? %15.4? %15u",
-                                     category.format(), mean, median, min, max, data_len));
+        print_table(self.buckets);
+        for self.counters.each |&(counter, count)| {
+            if count > 0 {
+                println(fmt!("%-30s: %15u", fmt!("%?", counter), count));
+            }
+        }
+        println("");
+        for vec::each_mut(self.pages) |page| {
+            match *page {
+                (ref url, ref mut buckets) => {
+                    println(fmt!("_page_ %s", *url));
+                    print_table(*buckets);
+                    println("");
+                }
+            }
+        }
+    }
+}
+
+priv fn print_table(buckets: &mut [(ProfilerCategory, ~[f64])]) {
+    println(fmt!("%31s %15s %15s %15s %15s %15s %15s %15s",
+                     "_category (ms)_", "_mean (ms)_", "_median (ms)_", "_p90 (ms)_",
+                     "_p99 (ms)_", "_min (ms)_", "_max (ms)_", "_bucket size_"));
+    for vec::each_mut(buckets) |bucket| {
+        match *bucket {
+            (category, ref mut data) => {
+                match summarize(*data) {
+                    Some(summary) => {
+                        println(fmt!("%-30s: %15.4? %15.4? %15.4? %15.4? %15.4? %15.4? %15u",
+                                     category.format(), summary.mean, summary.median,
+                                     summary.p90, summary.p99, summary.min, summary.max,
+                                     summary.count));
                     }
+                    None => {}
                 }
             }
         }
-        println("");
     }
 }
 
+// sorts the times in a bucket and returns their statistics, or None if there are none
+pub fn summarize(data: &mut [f64]) -> Option<Summary> {
+    let data_len = data.len();
+    if data_len == 0 {
+        return None;
+    }
+    tim_sort(data);
+    let mut total = 0f64;
+    for data.each |t| {
+        total += *t;
+    }
+    Some(Summary {
+        mean: total / (data_len as f64),
+        median: data[data_len / 2],
+        p90: percentile(data, 90),
+        p99: percentile(data, 99),
+        min: data[0],
+        max: data[data_len - 1],
+        count: data_len,
+    })
+}
+
+// the nearest-rank percentile of sorted, non-empty times
+priv fn percentile(sorted: &[f64], percent: uint) -> f64 {
+    let rank = (sorted.len() * percent + 99) / 100;
+    sorted[uint::max(rank, 1) - 1]
+}
+
+// the statistics of every bucket with times in it
+priv fn summarize_buckets(buckets: &[(ProfilerCategory, ~[f64])])
+                          -> ~[(ProfilerCategory, Summary)] {
+    let mut summaries = ~[];
+    for buckets.each |&(category, ref data)| {
+        let mut data = copy *data;
+        match summarize(data) {
+            Some(summary) => summaries.push((category, summary)),
+            None => {}
+        }
+    }
+    summaries
+}
+
+// formats spans as Chrome trace events, with timestamps in microseconds since `start_time`.
+// Each task becomes a thread of its own, numbered in the order it was first seen.
+pub fn trace_json(spans: &[Span], start_time: u64) -> ~str {
+    let mut tasks: ~[Task] = ~[];
+    let mut events = ~[];
+    for spans.each |span| {
+        let tid = match vec::position(tasks, |task| *task == span.task) {
+            Some(tid) => tid,
+            None => {
+                tasks.push(span.task);
+                events.push(fmt!("{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":%u,\
+                                  \"args\":{\"name\":\"%?\"}}",
+                                 tasks.len() - 1, span.task));
+                tasks.len() - 1
+            }
+        };
+        let start = if span.start > start_time { span.start - start_time } else { 0 };
+        events.push(fmt!("{\"name\":\"%?\",\"cat\":\"%s\",\"ph\":\"X\",\"ts\":%.3f,\
+                          \"dur\":%.3f,\"pid\":1,\"tid\":%u}",
+                         span.category, span.category.trace_category(),
+                         start as float / 1000f, (span.end - span.start) as float / 1000f, tid));
+    }
+    fmt!("{\"traceEvents\":[\n%s\n]}\n", str::connect(events, ",\n"))
+}
 
 pub fn profile<T>(category: ProfilerCategory, 
                   profiler_chan: ProfilerChan,
@@ -183,8 +434,13 @@ pub fn profile<T>(category: ProfilerCategory,
     let start_time = precise_time_ns();
     let val = callback();
     let end_time = precise_time_ns();
-    let ms = ((end_time - start_time) as f64 / 1000000f64);
-    profiler_chan.send(TimeMsg(category, ms));
+    profiler_chan.send(SpanMsg(Span {
+        category: category,
+        start: start_time,
+        end: end_time,
+        task: task::get_task(),
+        tag: copy profiler_chan.tag,
+    }));
     return val;
 }
 
@@ -199,4 +455,67 @@ pub fn time<T>(msg: &str, callback: &fn() -> T) -> T{
     return val;
 }
 
+#[test]
+fn should_write_spans_as_trace_events() {
+    let task = task::get_task();
+    let spans = [
+        Span { category: LayoutMainCategory, start: 3000, end: 5500, task: task, tag: None },
+        Span { category: RenderingCategory, start: 4000, end: 4250, task: task, tag: None },
+    ];
+    let json = trace_json(spans, 1000);
+    assert!(json.contains(fmt!("\"tid\":0,\"args\":{\"name\":\"%?\"}}", task)));
+    assert!(json.contains("{\"name\":\"LayoutMainCategory\",\"cat\":\"layout\",\"ph\":\"X\",\
+                           \"ts\":2.000,\"dur\":2.500,\"pid\":1,\"tid\":0}"));
+    assert!(json.contains("\"cat\":\"rendering\",\"ph\":\"X\",\"ts\":3.000,\"dur\":0.250"));
+    // Only one thread name for the one task
+    let first = str::find_str(json, "thread_name").get();
+    assert!(!json.slice(first + 1, json.len()).contains("thread_name"));
+}
 
+#[test]
+fn should_summarize_percentiles() {
+    let mut data = vec::from_fn(100, |i| (100 - i) as f64);
+    let summary = summarize(data).get();
+    assert!(summary.min == 1f64 && summary.max == 100f64);
+    assert!(summary.p90 == 90f64 && summary.p99 == 99f64);
+    assert!(summary.mean == 50.5f64 && summary.count == 100);
+    assert!(percentile([3f64], 99) == 3f64);
+}
+
+#[test]
+fn should_attribute_times_to_pages_until_reset() {
+    let (port, chan) = comm::stream();
+    let mut profiler = Profiler::new(port, None);
+    let profiler_chan = ProfilerChan::new(chan).tagged(~"http://example.com/");
+    let tag = copy profiler_chan.tag;
+    let task = task::get_task();
+    profiler.handle_msg(SpanMsg(Span {
+        category: LayoutMainCategory, start: 0, end: 2000000, task: task, tag: tag,
+    }));
+    profiler.handle_msg(TimeMsg(LayoutMainCategory, 4f64));
+
+    let (snapshot_port, snapshot_chan) = comm::stream();
+    profiler.handle_msg(SnapshotMsg(snapshot_chan));
+    let snapshot = snapshot_port.recv();
+    assert!(snapshot.categories.len() == 1);
+    match snapshot.categories[0] {
+        (category, summary) => {
+            assert!(category == LayoutMainCategory);
+            assert!(summary.count == 2 && summary.mean == 3f64);
+        }
+    }
+    assert!(snapshot.pages.len() == 1);
+    match snapshot.pages[0] {
+        (ref page, ref summaries) => {
+            assert!(*page == ~"http://example.com/");
+            assert!(summaries.len() == 1);
+            match summaries[0] { (_, summary) => assert!(summary.max == 2f64) }
+        }
+    }
+
+    profiler.handle_msg(ResetMsg);
+    let (snapshot_port, snapshot_chan) = comm::stream();
+    profiler.handle_msg(SnapshotMsg(snapshot_chan));
+    let snapshot = snapshot_port.recv();
+    assert!(snapshot.categories.is_empty() && snapshot.pages.is_empty());
+}
@@ -2,59 +2,47 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use url_parser;
+
 use std::net::url;
 use std::net::url::Url;
 use core::hashmap::HashMap;
 
 /**
-Create a URL object from a string. Does various helpful browsery things like
+Create a URL object from a string given by the user, such as on the command
+line. Does various helpful browsery things like
 
 * If there's no current url and the path looks like a file then it will
   create a file url based of the current working directory
 * If there's a current url and the new path is relative then the new url
   is based off the current url
 
+Fails if the string isn't a valid URL. URLs from documents should be parsed
+with `url_parser::parse` instead, which returns an error.
+
 */
 #[allow(non_implicitly_copyable_typarams)]
 pub fn make_url(str_url: ~str, current_url: Option<Url>) -> Url {
-    let schm = url::get_scheme(str_url);
-    let str_url = if result::is_err(&schm) {
-        if current_url.is_none() {
-            // Assume we've been given a file path. If it's absolute just return
-            // it, otherwise make it absolute with the cwd.
-            if str_url.starts_with("/") {
-                ~"file://" + str_url
-            } else {
-                ~"file://" + os::getcwd().push(str_url).to_str()
-            }
+    let str_url = if current_url.is_none() && result::is_err(&url::get_scheme(str_url)) {
+        // Assume we've been given a file path. If it's absolute just return
+        // it, otherwise make it absolute with the cwd.
+        if str_url.starts_with("/") {
+            ~"file://" + str_url
         } else {
-            let current_url = current_url.get();
-            debug!("make_url: current_url: %?", current_url);
-            if str_url.starts_with("//") {
-                current_url.scheme + ":" + str_url
-            } else if current_url.path.is_empty() ||
-                      str_url.starts_with("/") {
-                current_url.scheme + "://" +
-                current_url.host + "/" +
-                str_url.trim_left_chars([ '/' ])
-            } else {
-                let mut path = ~[];
-                for str::each_split_char(current_url.path, '/') |p| {
-                    path.push(p.to_str());
-                }
-                let path = path; // FIXME: borrow checker workaround
-                let path = path.init();
-                let path = str::connect(path.map(|x| copy *x) + ~[str_url], "/");
-
-                current_url.scheme + "://" + current_url.host + path
-            }
+            ~"file://" + os::getcwd().push(str_url).to_str()
         }
     } else {
         str_url
     };
 
-    // FIXME: Need to handle errors
-    url::from_str(str_url).get()
+    let result = match current_url {
+        Some(ref current_url) => url_parser::parse(str_url, Some(current_url)),
+        None => url_parser::parse(str_url, None),
+    };
+    match result {
+        Ok(url) => url,
+        Err(error) => fail!(fmt!("make_url: %s is not a valid URL: %?", str_url, error)),
+    }
 }
 
 mod make_url_tests {